@@ -0,0 +1,82 @@
+//! Connection-rate benchmark comparing a single accept loop with SO_REUSEPORT
+//! sharded acceptors.
+//!
+//! Every request opens a fresh connection, so accepting is the bottleneck
+//! rather than request handling. Run with:
+//!
+//!     cargo bench --bench accept
+//!
+//! Set `BENCH_SECS`, `BENCH_CLIENTS`, and `BENCH_ACCEPTORS` to change the run.
+
+mod common;
+
+use common::{benchmarking, env_or, free_port, start_server, stop_server};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const REQUEST: &[u8] = b"GET /health/live HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n";
+
+/// Issue one request per connection from `clients` threads; returns connections/second
+fn run(port: u16, clients: usize, duration: Duration) -> (f64, u64) {
+    let done = Arc::new(AtomicBool::new(false));
+    let completed = Arc::new(AtomicU64::new(0));
+    let failed = Arc::new(AtomicU64::new(0));
+
+    let threads: Vec<_> = (0..clients)
+        .map(|_| {
+            let (done, completed, failed) = (Arc::clone(&done), Arc::clone(&completed), Arc::clone(&failed));
+            std::thread::spawn(move || {
+                let mut buf = [0u8; 1024];
+                while !done.load(Ordering::Relaxed) {
+                    let ok = TcpStream::connect(("127.0.0.1", port)).and_then(|mut stream| {
+                        stream.write_all(REQUEST)?;
+                        while stream.read(&mut buf)? > 0 {}
+                        Ok(())
+                    });
+                    match ok {
+                        Ok(()) => completed.fetch_add(1, Ordering::Relaxed),
+                        Err(_) => failed.fetch_add(1, Ordering::Relaxed),
+                    };
+                }
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    std::thread::sleep(duration);
+    done.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    (completed.load(Ordering::Relaxed) as f64 / elapsed, failed.load(Ordering::Relaxed))
+}
+
+fn main() {
+    if !benchmarking() {
+        return;
+    }
+
+    let duration = Duration::from_secs(env_or("BENCH_SECS", 5) as u64);
+    let clients = env_or("BENCH_CLIENTS", 64);
+    let cpus = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let sharded = env_or("BENCH_ACCEPTORS", cpus.max(2));
+
+    println!("accept benchmark: {} clients, {:?} per run, {} workers", clients, duration, cpus * 4);
+    let mut baseline = None;
+    for acceptors in [1, sharded] {
+        let port = free_port();
+        let (acceptors_arg, workers_arg) = (acceptors.to_string(), (cpus * 4).to_string());
+        let server = start_server(port, &["--acceptors", &acceptors_arg, "--workers", &workers_arg])
+            .expect("http-server exited during startup");
+        let (rate, failed) = run(port, clients, duration);
+        stop_server(server);
+
+        let speedup = baseline.map(|base: f64| format!(" ({:.2}x)", rate / base)).unwrap_or_default();
+        println!("  acceptors={:<3} {:>10.0} conn/s{}  failed={}", acceptors, rate, speedup, failed);
+        baseline.get_or_insert(rate);
+    }
+}
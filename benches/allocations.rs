@@ -0,0 +1,129 @@
+//! Heap allocations per request for each execution mode.
+//!
+//! Needs the counting allocator, which exports `process_heap_allocations_total`
+//! on `/metrics`:
+//!
+//!     cargo bench --bench allocations --features alloc-stats,tokio,io-uring
+//!
+//! One client sends `BENCH_REQUESTS` keep-alive requests per path and the
+//! counter delta is divided by the request count. Set `BENCH_FILE_KB` to
+//! change the size of the static file.
+
+mod common;
+
+use common::{benchmarking, env_or, free_port, start_server, stop_server};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+
+const BACKENDS: [(&str, &str); 3] = [("threads", ""), ("tokio", ",tokio"), ("io-uring", ",io-uring")];
+
+struct Client {
+    writer: TcpStream,
+    reader: BufReader<TcpStream>,
+    body: Vec<u8>,
+}
+
+impl Client {
+    fn connect(port: u16) -> Client {
+        let stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.set_nodelay(true).unwrap();
+        Client {
+            writer: stream.try_clone().unwrap(),
+            reader: BufReader::new(stream),
+            body: Vec::new(),
+        }
+    }
+
+    /// Send one keep-alive GET and read the whole response
+    fn get(&mut self, path: &str) -> &[u8] {
+        let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\nAccept-Encoding: identity\r\n\r\n", path);
+        self.writer.write_all(request.as_bytes()).unwrap();
+        let mut line = String::new();
+        let mut content_length = 0;
+        loop {
+            line.clear();
+            assert!(self.reader.read_line(&mut line).unwrap() > 0, "server closed the connection");
+            let header = line.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap();
+                }
+            }
+        }
+        self.body.resize(content_length, 0);
+        self.reader.read_exact(&mut self.body).unwrap();
+        &self.body
+    }
+
+    /// Current value of the server's allocation counter, if it exports one
+    fn allocations(&mut self) -> Option<u64> {
+        let metrics = String::from_utf8_lossy(self.get("/metrics")).into_owned();
+        metrics
+            .lines()
+            .find_map(|line| line.strip_prefix("process_heap_allocations_total "))
+            .and_then(|value| value.trim().parse().ok())
+    }
+
+    /// Average allocations per request for `path`, excluding the cost of
+    /// reading the counter itself
+    fn measure(&mut self, path: &str, requests: usize) -> Option<f64> {
+        for _ in 0..requests / 10 {
+            self.get(path);
+        }
+        let overhead = {
+            let before = self.allocations()?;
+            self.allocations()? - before
+        };
+        let before = self.allocations()?;
+        for _ in 0..requests {
+            self.get(path);
+        }
+        let total = self.allocations()? - before - overhead;
+        Some(total as f64 / requests as f64)
+    }
+}
+
+fn main() {
+    if !benchmarking() {
+        return;
+    }
+
+    let requests = env_or("BENCH_REQUESTS", 10_000);
+    let file_kb = env_or("BENCH_FILE_KB", 4);
+
+    let directory = std::env::temp_dir().join(format!("http-server-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("bench.bin"), vec![b'x'; file_kb * 1024]).unwrap();
+    let directory_arg = directory.to_string_lossy().into_owned();
+    let paths = ["/echo/hello", "/files/bench.bin"];
+
+    println!("allocation benchmark: {} keep-alive requests per path", requests);
+    for (runtime, features) in BACKENDS {
+        let port = free_port();
+        let args = [
+            "--runtime", runtime,
+            "--directory", &directory_arg,
+            "--max-keep-alive-requests", "1000000",
+        ];
+        let Some(server) = start_server(port, &args) else {
+            println!("  {:<9} skipped (build with --features alloc-stats{})", runtime, features);
+            continue;
+        };
+        let mut client = Client::connect(port);
+        for path in paths {
+            match client.measure(path, requests) {
+                Some(per_request) => println!("  {:<9} {:<18} {:>8.1} allocs/request", runtime, path, per_request),
+                None => {
+                    println!("  {:<9} skipped (build with --features alloc-stats{})", runtime, features);
+                    break;
+                }
+            }
+        }
+        stop_server(server);
+    }
+
+    let _ = std::fs::remove_dir_all(&directory);
+}
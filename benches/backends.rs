@@ -0,0 +1,144 @@
+//! Static file throughput of each execution mode: the thread pool, Tokio
+//! (epoll), and io_uring.
+//!
+//! Every client holds one keep-alive connection and repeatedly downloads the
+//! same file. Modes that aren't compiled in are skipped, so enable them all:
+//!
+//!     cargo bench --bench backends --features tokio,io-uring
+//!
+//! Set `BENCH_SECS`, `BENCH_CLIENTS`, and `BENCH_FILE_KB` to change the run.
+
+mod common;
+
+use common::{benchmarking, env_or, free_port, start_server, stop_server};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const BACKENDS: [(&str, &str); 3] = [("threads", ""), ("tokio", "tokio"), ("io-uring", "io-uring")];
+
+/// Read one response, returning its body length
+fn read_response(reader: &mut BufReader<TcpStream>, body: &mut Vec<u8>) -> std::io::Result<usize> {
+    let mut line = String::new();
+    let mut content_length = 0;
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                content_length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    body.resize(content_length, 0);
+    reader.read_exact(body)?;
+    Ok(content_length)
+}
+
+/// Download `path` over keep-alive connections from `clients` threads;
+/// returns requests/second, bytes/second, and failed requests
+fn run(port: u16, path: &str, clients: usize, duration: Duration) -> (f64, f64, u64) {
+    let done = Arc::new(AtomicBool::new(false));
+    let requests = Arc::new(AtomicU64::new(0));
+    let bytes = Arc::new(AtomicU64::new(0));
+    let failed = Arc::new(AtomicU64::new(0));
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+
+    let threads: Vec<_> = (0..clients)
+        .map(|_| {
+            let (done, requests, bytes, failed) =
+                (Arc::clone(&done), Arc::clone(&requests), Arc::clone(&bytes), Arc::clone(&failed));
+            let request = request.clone();
+            std::thread::spawn(move || {
+                let mut body = Vec::new();
+                while !done.load(Ordering::Relaxed) {
+                    let Ok(stream) = TcpStream::connect(("127.0.0.1", port)) else {
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        continue;
+                    };
+                    let mut writer = stream.try_clone().unwrap();
+                    let mut reader = BufReader::new(stream);
+                    // Reconnect whenever the server closes the connection
+                    while !done.load(Ordering::Relaxed) {
+                        let result = writer
+                            .write_all(request.as_bytes())
+                            .and_then(|_| read_response(&mut reader, &mut body));
+                        match result {
+                            Ok(n) => {
+                                requests.fetch_add(1, Ordering::Relaxed);
+                                bytes.fetch_add(n as u64, Ordering::Relaxed);
+                            }
+                            Err(_) => break,
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+
+    let start = Instant::now();
+    std::thread::sleep(duration);
+    done.store(true, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+    (
+        requests.load(Ordering::Relaxed) as f64 / elapsed,
+        bytes.load(Ordering::Relaxed) as f64 / elapsed,
+        failed.load(Ordering::Relaxed),
+    )
+}
+
+fn main() {
+    if !benchmarking() {
+        return;
+    }
+
+    let duration = Duration::from_secs(env_or("BENCH_SECS", 5) as u64);
+    let clients = env_or("BENCH_CLIENTS", 32);
+    let file_kb = env_or("BENCH_FILE_KB", 64);
+    let cpus = std::thread::available_parallelism().map_or(4, |n| n.get());
+
+    // Not compressible by type, so every mode serves the raw bytes
+    let directory = std::env::temp_dir().join(format!("http-server-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&directory).unwrap();
+    std::fs::write(directory.join("bench.bin"), vec![b'x'; file_kb * 1024]).unwrap();
+    let directory_arg = directory.to_string_lossy().into_owned();
+    let (workers, acceptors) = ((cpus * 4).max(clients).to_string(), cpus.to_string());
+
+    println!("backend benchmark: {} KB file, {} clients, {:?} per run", file_kb, clients, duration);
+    for (runtime, feature) in BACKENDS {
+        let port = free_port();
+        let args = [
+            "--runtime", runtime,
+            "--directory", &directory_arg,
+            "--workers", &workers,
+            "--acceptors", &acceptors,
+            "--max-keep-alive-requests", "1000000",
+        ];
+        let Some(server) = start_server(port, &args) else {
+            println!("  {:<9} skipped (build with --features {})", runtime, feature);
+            continue;
+        };
+        let (rate, throughput, failed) = run(port, "/files/bench.bin", clients, duration);
+        stop_server(server);
+        println!(
+            "  {:<9} {:>10.0} req/s {:>9.1} MB/s  failed={}",
+            runtime,
+            rate,
+            throughput / (1024.0 * 1024.0),
+            failed
+        );
+    }
+
+    let _ = std::fs::remove_dir_all(&directory);
+}
@@ -0,0 +1,53 @@
+//! Helpers shared by the benchmarks, which drive a real `http-server` process
+
+#![allow(dead_code)]
+
+use std::net::{TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+
+/// Read a numeric setting from the environment
+pub fn env_or(name: &str, default: usize) -> usize {
+    std::env::var(name).ok().and_then(|v| v.parse().ok()).unwrap_or(default)
+}
+
+/// Pick a port that is free right now
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+/// Whether the harness asked for a benchmark run; `cargo test --benches`
+/// runs bench targets too, and those runs should do nothing
+pub fn benchmarking() -> bool {
+    std::env::args().any(|arg| arg == "--bench")
+}
+
+/// Start the server on `port` with extra arguments and wait until it accepts
+/// connections; `None` if it exited instead (e.g. a runtime that isn't built in)
+pub fn start_server(port: u16, args: &[&str]) -> Option<Child> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_http-server"))
+        .args(["--port", &port.to_string()])
+        .args(["--listen-backlog", "4096"])
+        .args(["--log-level", "error"])
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .expect("failed to start http-server");
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while TcpStream::connect(("127.0.0.1", port)).is_err() {
+        if child.try_wait().ok().flatten().is_some() {
+            return None;
+        }
+        assert!(Instant::now() < deadline, "server did not start listening");
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    Some(child)
+}
+
+/// Stop a server started by [`start_server`]
+pub fn stop_server(mut child: Child) {
+    let _ = child.kill();
+    let _ = child.wait();
+}
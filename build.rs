@@ -0,0 +1,36 @@
+//! Embed build metadata for `http_server_build_info` and `GET /version`
+
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    let commit = output("git", &["rev-parse", "--short=12", "HEAD"]).unwrap_or_else(|| "unknown".to_string());
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = output(&rustc, &["--version"]).unwrap_or_else(|| "unknown".to_string());
+    // SOURCE_DATE_EPOCH keeps reproducible builds reproducible
+    let timestamp = match std::env::var("SOURCE_DATE_EPOCH").ok().and_then(|epoch| epoch.parse::<u64>().ok()) {
+        Some(epoch) => epoch,
+        None => SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()),
+    };
+
+    println!("cargo:rustc-env=HTTP_SERVER_GIT_COMMIT={}", commit);
+    println!("cargo:rustc-env=HTTP_SERVER_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=HTTP_SERVER_BUILD_TIMESTAMP={}", timestamp);
+
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // Only watch files that exist, or cargo reruns the script on every build
+    for file in [".git/HEAD", ".git/refs/heads", ".git/packed-refs"] {
+        if Path::new(file).exists() {
+            println!("cargo:rerun-if-changed={}", file);
+        }
+    }
+}
+
+/// The trimmed standard output of a command that succeeded
+fn output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    let text = String::from_utf8(output.stdout).ok()?;
+    (output.status.success() && !text.trim().is_empty()).then(|| text.trim().to_string())
+}
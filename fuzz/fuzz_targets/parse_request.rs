@@ -0,0 +1,25 @@
+//! The parser must reject malformed input with an error, never panic, and
+//! anything it accepts must read back the same after being written out again
+
+#![no_main]
+
+use http_server::request::HttpRequest;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    // Keep reading pipelined requests until the input runs out or is rejected
+    let mut reader = data;
+    while let Ok(request) = HttpRequest::parse(&mut reader) {
+        let mut raw = format!("{}\r\n", request.request_line()).into_bytes();
+        for (name, value) in request.headers.iter() {
+            raw.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+        raw.extend_from_slice(&request.body);
+
+        let reparsed = HttpRequest::parse(&mut &raw[..]).expect("an accepted request must parse again");
+        assert_eq!(reparsed.request_line(), request.request_line());
+        assert_eq!(reparsed.headers, request.headers);
+        assert_eq!(reparsed.body, request.body);
+    }
+});
@@ -0,0 +1,30 @@
+//! Whatever the parser accepts, the router must answer without panicking
+
+#![no_main]
+
+use http_server::metrics::ServerMetrics;
+use http_server::request::HttpRequest;
+use http_server::router::{Listener, Router};
+use http_server::testing;
+use libfuzzer_sys::fuzz_target;
+use std::sync::{Arc, OnceLock};
+
+fn router() -> &'static Router {
+    static ROUTER: OnceLock<Router> = OnceLock::new();
+    ROUTER.get_or_init(|| {
+        let dir = std::env::temp_dir().join("http-server-fuzz");
+        std::fs::create_dir_all(&dir).unwrap();
+        testing::router(dir.to_str().unwrap())
+    })
+}
+
+fuzz_target!(|data: &[u8]| {
+    let metrics = Arc::new(ServerMetrics::new());
+    if let Ok(request) = HttpRequest::parse(&mut &data[..]) {
+        let response = router()
+            .route(&request, &metrics, Listener::Public)
+            .unwrap_or_else(|e| e.to_response_for(&request));
+        let mut raw = Vec::new();
+        response.write_to(&mut raw).unwrap();
+    }
+});
@@ -0,0 +1,251 @@
+use crate::log_file::{RotatingFile, RotationPolicy};
+use crate::request::HttpRequest;
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
+use serde_json::json;
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Output format for access log lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum AccessLogFormat {
+    /// NCSA Common Log Format
+    Common,
+    /// Combined Log Format (CLF plus referer, user-agent, and latency)
+    Combined,
+    /// One JSON object per line, for log pipelines like Loki or ELK
+    Json,
+}
+
+/// Everything recorded about a single request in the access log
+///
+/// `request` and `request_id` are `None` when the request could not be parsed.
+pub struct AccessLogEntry<'a> {
+    pub client_ip: Option<IpAddr>,
+    pub time: DateTime<Local>,
+    pub request: Option<&'a HttpRequest>,
+    pub request_id: Option<&'a str>,
+    pub status: u16,
+    pub bytes: usize,
+    pub duration: Duration,
+}
+
+/// Writes one line per request to stdout or a file, independent of the debug logger
+pub struct AccessLogger {
+    format: AccessLogFormat,
+    sink: Mutex<Box<dyn Write + Send>>,
+    /// Log one in this many successful requests
+    sample_rate: u64,
+    /// Requests at least this slow are always logged
+    slow_threshold: Option<Duration>,
+    sampled: AtomicU64,
+}
+
+impl AccessLogger {
+    /// Open an access log; a target of "-" or "stdout" writes to standard output,
+    /// anything else is a file rotated according to `rotation`
+    pub fn open(target: &str, format: AccessLogFormat, rotation: RotationPolicy) -> io::Result<Self> {
+        let sink: Box<dyn Write + Send> = match target {
+            "-" | "stdout" => Box::new(io::stdout()),
+            path => Box::new(RotatingFile::open(path, rotation)?),
+        };
+
+        Ok(AccessLogger {
+            format,
+            sink: Mutex::new(sink),
+            sample_rate: 1,
+            slow_threshold: None,
+            sampled: AtomicU64::new(0),
+        })
+    }
+
+    /// Log only one in `rate` successful requests; errors, and requests
+    /// taking at least `slow_threshold`, are always logged. Each line records
+    /// the rate it was sampled at, so counts can be scaled back up.
+    pub fn sampling(mut self, rate: u64, slow_threshold: Option<Duration>) -> Self {
+        self.sample_rate = rate.max(1);
+        self.slow_threshold = slow_threshold;
+        self
+    }
+
+    /// The number of requests an entry's line stands for, or `None` if it
+    /// is sampled out
+    fn sample(&self, entry: &AccessLogEntry) -> Option<u64> {
+        let always = entry.status >= 400 || self.slow_threshold.is_some_and(|threshold| entry.duration >= threshold);
+        if always || self.sample_rate == 1 {
+            return Some(1);
+        }
+        (self.sampled.fetch_add(1, Ordering::Relaxed) % self.sample_rate == 0).then_some(self.sample_rate)
+    }
+
+    /// Write a log line for a completed request, unless it is sampled out
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let Some(rate) = self.sample(entry) else {
+            return;
+        };
+        let line = self.format_line(entry, rate);
+        let mut sink = self.sink.lock().unwrap();
+        if let Err(e) = writeln!(sink, "{}", line).and_then(|_| sink.flush()) {
+            log::warn!("Failed to write access log: {}", e);
+        }
+    }
+
+    /// Format an entry according to the configured log format; with
+    /// sampling on, lines end with the sample rate they were logged at
+    fn format_line(&self, entry: &AccessLogEntry, rate: u64) -> String {
+        let header = |name: &str| entry.request.and_then(|r| r.get_header(name));
+
+        if self.format == AccessLogFormat::Json {
+            let mut line = json!({
+                "timestamp": entry.time.to_rfc3339(),
+                "request_id": entry.request_id,
+                "method": entry.request.map(|r| r.method.as_str()),
+                "path": entry.request.map(|r| r.path.as_str()),
+                "status": entry.status,
+                "duration_ms": entry.duration.as_secs_f64() * 1000.0,
+                "bytes_out": entry.bytes,
+                "client_ip": entry.client_ip.map(|ip| ip.to_string()),
+            });
+            if self.sample_rate > 1 {
+                line["sample_rate"] = json!(rate);
+            }
+            return line.to_string();
+        }
+
+        let client = entry
+            .client_ip
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "-".to_string());
+        let bytes = if entry.bytes == 0 {
+            "-".to_string()
+        } else {
+            entry.bytes.to_string()
+        };
+
+        let common = format!(
+            "{} - - [{}] \"{}\" {} {}",
+            client,
+            entry.time.format("%d/%b/%Y:%H:%M:%S %z"),
+            entry
+                .request
+                .map(|r| r.request_line())
+                .unwrap_or_else(|| "-".to_string()),
+            entry.status,
+            bytes
+        );
+
+        let line = match self.format {
+            AccessLogFormat::Combined => format!(
+                "{} \"{}\" \"{}\" {:.3}",
+                common,
+                Self::escape(header("referer").unwrap_or("-")),
+                Self::escape(header("user-agent").unwrap_or("-")),
+                entry.duration.as_secs_f64()
+            ),
+            _ => common,
+        };
+        match self.sample_rate {
+            1 => line,
+            _ => format!("{} sample_rate={}", line, rate),
+        }
+    }
+
+    /// Escape quotes and backslashes in quoted header fields
+    fn escape(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('"', "\\\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::log_file::Rotation;
+    use crate::request::HttpMethod;
+    use chrono::TimeZone;
+
+    fn open(format: AccessLogFormat) -> AccessLogger {
+        let rotation = RotationPolicy {
+            rotation: Rotation::Never,
+            max_bytes: 0,
+            keep: 0,
+        };
+        AccessLogger::open("-", format, rotation).unwrap()
+    }
+
+    fn request() -> HttpRequest {
+        HttpRequest {
+            method: HttpMethod::GET,
+            path: "/echo/hi".to_string(),
+            version: "HTTP/1.1".to_string(),
+            headers: [("user-agent", "curl/8.0 \"test\"")].into_iter().collect(),
+            body: Vec::new(),
+            body_stream: Default::default(),
+            peer_addr: None,
+            route: Default::default(),
+            state: Default::default(),
+            extensions: Default::default(),
+        }
+    }
+
+    fn entry(request: &HttpRequest) -> AccessLogEntry<'_> {
+        AccessLogEntry {
+            client_ip: Some("127.0.0.1".parse().unwrap()),
+            time: Local.with_ymd_and_hms(2024, 3, 5, 14, 7, 9).unwrap(),
+            request: Some(request),
+            request_id: Some("req-7"),
+            status: 200,
+            bytes: 2,
+            duration: Duration::from_millis(12),
+        }
+    }
+
+    #[test]
+    fn test_common_format() {
+        let logger = open(AccessLogFormat::Common);
+        let line = logger.format_line(&entry(&request()), 1);
+        assert!(line.starts_with("127.0.0.1 - - [05/Mar/2024:14:07:09 "));
+        assert!(line.ends_with("] \"GET /echo/hi HTTP/1.1\" 200 2"));
+    }
+
+    #[test]
+    fn test_combined_format() {
+        let logger = open(AccessLogFormat::Combined);
+        let line = logger.format_line(&entry(&request()), 1);
+        assert!(line.ends_with("\"GET /echo/hi HTTP/1.1\" 200 2 \"-\" \"curl/8.0 \\\"test\\\"\" 0.012"));
+    }
+
+    #[test]
+    fn test_json_format() {
+        let logger = open(AccessLogFormat::Json);
+        let line = logger.format_line(&entry(&request()), 1);
+        let value: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["request_id"], "req-7");
+        assert_eq!(value["method"], "GET");
+        assert_eq!(value["path"], "/echo/hi");
+        assert_eq!(value["status"], 200);
+        assert_eq!(value["duration_ms"], 12.0);
+        assert_eq!(value["bytes_out"], 2);
+        assert_eq!(value["client_ip"], "127.0.0.1");
+        assert!(value.get("sample_rate").is_none());
+    }
+
+    #[test]
+    fn test_sampling_keeps_errors_and_slow_requests() {
+        let logger = open(AccessLogFormat::Json).sampling(4, Some(Duration::from_millis(500)));
+        let request = request();
+        let rates: Vec<Option<u64>> = (0..8).map(|_| logger.sample(&entry(&request))).collect();
+        assert_eq!(rates, [Some(4), None, None, None, Some(4), None, None, None]);
+
+        let error = AccessLogEntry { status: 503, ..entry(&request) };
+        let slow = AccessLogEntry { duration: Duration::from_secs(1), ..entry(&request) };
+        assert_eq!((logger.sample(&error), logger.sample(&slow)), (Some(1), Some(1)));
+
+        let line: serde_json::Value = serde_json::from_str(&logger.format_line(&entry(&request), 4)).unwrap();
+        assert_eq!(line["sample_rate"], 4);
+        let logger = open(AccessLogFormat::Combined).sampling(4, None);
+        assert!(logger.format_line(&entry(&request), 1).ends_with(" 0.012 sample_rate=1"));
+    }
+}
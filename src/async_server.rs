@@ -0,0 +1,390 @@
+use crate::error::{Result, ServerError};
+use crate::metrics::CountingStream;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::router::Listener;
+use crate::ip_filter::DenyAction;
+use crate::server::{
+    begin_request, blocked_client, blocked_peer, client_aborted, error_response, finish_response, record_request,
+    route_label, RequestOutcome, ServerState, CLIENT_CLOSED_REQUEST, DRAIN_POLL,
+};
+use crate::{net, request_id};
+use std::future::Future;
+use std::io::IoSlice;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+tokio::task_local! {
+    /// ID of the request a handler is running for, so blocking work it starts
+    /// can tag its log lines
+    static REQUEST_ID: String;
+}
+
+/// Future returned by an async handler
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<HttpResponse>> + Send>>;
+
+/// An async request handler
+///
+/// Implemented for any `async fn(Arc<HttpRequest>) -> Result<HttpResponse>`.
+/// The request is shared so it can still be logged once the response is sent.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: Arc<HttpRequest>) -> HandlerFuture;
+}
+
+impl<F, Fut> Handler for F
+where
+    F: Fn(Arc<HttpRequest>) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Result<HttpResponse>> + Send + 'static,
+{
+    fn call(&self, request: Arc<HttpRequest>) -> HandlerFuture {
+        Box::pin(self(request))
+    }
+}
+
+/// The default handler: the shared router, run on Tokio's blocking pool since
+/// file handlers do synchronous disk I/O; streamed bodies are read there too
+pub fn router_handler(state: Arc<ServerState>) -> impl Handler {
+    move |request: Arc<HttpRequest>| {
+        let state = Arc::clone(&state);
+        let request_id = REQUEST_ID.try_with(String::clone).ok();
+        async move {
+            let timeout = state.router.timeout_for(&request);
+            let timed = timeout.map(|_| state.router.abandoned_handlers().start()).transpose()?;
+            let (metrics, timed_request) = (Arc::clone(&state.metrics), Arc::clone(&request));
+            let (run, wait) = timed.unzip();
+            let handler = tokio::task::spawn_blocking(move || {
+                let _run = run;
+                let _scope = request_id.as_deref().map(request_id::Scope::enter);
+                let response = state.router.route(&request, &state.metrics, Listener::Public)?;
+                Ok(response.buffered()?)
+            });
+            // Past the timeout the handler keeps its blocking thread until it
+            // returns, but its response is dropped
+            let joined = match (timeout, wait) {
+                (Some(timeout), Some(wait)) => match tokio::time::timeout(timeout, handler).await {
+                    Ok(joined) => joined,
+                    Err(_) => {
+                        wait.abandon();
+                        let route = route_label(&timed_request);
+                        metrics.record_handler_timeout(route);
+                        log::warn!("Handler for {} timed out after {:?}", route, timeout);
+                        return Err(ServerError::GatewayTimeout(format!(
+                            "The handler did not respond within {:?}",
+                            timeout
+                        )));
+                    }
+                },
+                _ => handler.await,
+            };
+            joined.map_err(|e| ServerError::InternalError(format!("Handler panicked: {}", e)))?
+        }
+    }
+}
+
+/// Serve the public listeners on a multi-threaded Tokio runtime until
+/// `shutdown` is set
+///
+/// Returns the runtime once the listeners are closed; in-flight connections
+/// keep being served until it is dropped.
+pub fn run(
+    listeners: Vec<std::net::TcpListener>,
+    state: Arc<ServerState>,
+    shutdown: Arc<AtomicBool>,
+) -> std::io::Result<tokio::runtime::Runtime> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(state.config.workers)
+        .thread_name("tokio-worker")
+        .enable_io()
+        .enable_time()
+        .build()?;
+
+    let handler: Arc<dyn Handler> = Arc::new(router_handler(Arc::clone(&state)));
+    runtime.block_on(async {
+        let mut acceptors = Vec::new();
+        for listener in listeners {
+            let listener = TcpListener::from_std(listener)?;
+            let (state, handler, shutdown) = (Arc::clone(&state), Arc::clone(&handler), Arc::clone(&shutdown));
+            acceptors.push(tokio::spawn(accept_loop(listener, state, handler, shutdown)));
+        }
+        for acceptor in acceptors {
+            let _ = acceptor.await;
+        }
+        Ok::<_, std::io::Error>(())
+    })?;
+    Ok(runtime)
+}
+
+/// Accept connections and spawn a task for each until `shutdown` is set
+async fn accept_loop(listener: TcpListener, state: Arc<ServerState>, handler: Arc<dyn Handler>, shutdown: Arc<AtomicBool>) {
+    while !shutdown.load(Ordering::Relaxed) {
+        // Wake up periodically so the shutdown flag is noticed promptly
+        let Ok(accepted) = tokio::time::timeout(Duration::from_millis(250), listener.accept()).await else {
+            continue;
+        };
+        match accepted {
+            Ok((stream, peer_addr)) => {
+                if state.config.deny_action == DenyAction::Close && blocked_peer(peer_addr.ip(), &state) {
+                    continue;
+                }
+                tokio::spawn(handle_client(stream, peer_addr, Arc::clone(&state), Arc::clone(&handler)));
+            }
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Serve requests on one connection until the client closes it, it sits idle
+/// past the timeout, or the keep-alive request limit is reached
+async fn handle_client(stream: TcpStream, peer_addr: SocketAddr, state: Arc<ServerState>, handler: Arc<dyn Handler>) {
+    let config = &state.config;
+    let metrics = &state.metrics;
+
+    // TCP_NODELAY plus any configured keepalive and linger settings
+    let stream = match stream.into_std() {
+        Ok(stream) => {
+            if let Err(e) = net::configure_stream(&stream, &config.socket_options()) {
+                log::debug!("Failed to apply socket options for {}: {}", peer_addr, e);
+            }
+            match TcpStream::from_std(stream) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    log::error!("Failed to register connection from {}: {}", peer_addr, e);
+                    return;
+                }
+            }
+        }
+        Err(e) => {
+            log::error!("Failed to configure connection from {}: {}", peer_addr, e);
+            return;
+        }
+    };
+
+    let idle_timeout = (config.idle_timeout_secs > 0).then(|| Duration::from_secs(config.idle_timeout_secs));
+    let keep_alive_timeout =
+        (config.keep_alive_timeout_secs > 0).then(|| Duration::from_secs(config.keep_alive_timeout_secs));
+
+    metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+    let connection = metrics.connections.open(Some(peer_addr));
+
+    let stream = CountingStream::new(stream)
+        .throttle_reads(state.ingress.connection())
+        .throttle_writes(state.egress.connection());
+    let mut reader = BufReader::with_capacity(8192, stream);
+    let mut served = 0;
+    loop {
+        // Wait for the next request; a clean close or idle timeout ends the connection
+        let timeout = if served == 0 { idle_timeout } else { keep_alive_timeout };
+        let ready = match timeout {
+            Some(timeout) if served > 0 => await_next_request(&mut reader, timeout, &state).await,
+            Some(timeout) => tokio::time::timeout(timeout, reader.fill_buf()).await.ok().map(|ready| ready.map(|buf| !buf.is_empty())),
+            None => Some(reader.fill_buf().await.map(|buf| !buf.is_empty())),
+        };
+        match ready {
+            Some(Ok(false)) => break,
+            Some(Ok(true)) => {}
+            Some(Err(e)) => {
+                log::debug!("Closing connection from {}: {}", peer_addr, e);
+                break;
+            }
+            None => {
+                log::debug!("Closing idle connection from {}", peer_addr);
+                break;
+            }
+        }
+
+        served += 1;
+        metrics.connections.request_started(connection);
+        let may_keep_alive = keep_alive_timeout.is_some() && served < config.max_keep_alive_requests;
+        let keep_open = serve_request(&mut reader, peer_addr, &state, handler.as_ref(), may_keep_alive).await;
+        let counts = reader.get_ref();
+        metrics.connections.request_finished(connection, counts.bytes_read(), counts.bytes_written());
+        if !keep_open {
+            break;
+        }
+    }
+
+    let counts = reader.get_ref();
+    metrics.connections.close(connection, counts.bytes_read(), counts.bytes_written());
+    metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Wait up to `timeout` for another request on a kept-alive connection;
+/// `None` on timeout, and `Some(Ok(false))` once the client closed it or the
+/// server started draining, as with the thread pool runtime
+async fn await_next_request(
+    reader: &mut BufReader<CountingStream<TcpStream>>,
+    timeout: Duration,
+    state: &ServerState,
+) -> Option<std::io::Result<bool>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return None;
+        }
+        if state.router.readiness.is_draining() {
+            return Some(Ok(false));
+        }
+        // Cancelling fill_buf loses no data, so waiting in slices is safe
+        if let Ok(ready) = tokio::time::timeout(left.min(DRAIN_POLL), reader.fill_buf()).await {
+            return Some(ready.map(|buf| !buf.is_empty()));
+        }
+    }
+}
+
+/// Parse, handle, and log one request; returns whether the connection should
+/// stay open for another request
+async fn serve_request(
+    reader: &mut BufReader<CountingStream<TcpStream>>,
+    peer_addr: SocketAddr,
+    state: &ServerState,
+    handler: &dyn Handler,
+    may_keep_alive: bool,
+) -> bool {
+    let start_time = Instant::now();
+    let (read_before, written_before) = (reader.get_ref().bytes_read(), reader.get_ref().bytes_written());
+    // Bytes already buffered were read from the socket before this request started
+    let read_before = read_before - reader.buffer().len() as u64;
+    let peer = Some(peer_addr);
+
+    // A body over --max-body-mb, or a large one without a free upload slot, is
+    // refused before any of it is read; the slot is held until the response
+    let mut _upload = None;
+    let request: Result<Arc<HttpRequest>> = async {
+        let mut request = HttpRequest::parse_head_async(reader).await?;
+        request.peer_addr = peer;
+        request.check_body_limit(state.config.max_body_size())?;
+        let length = request.content_length()? as u64;
+        if state.uploads.is_large(length) {
+            _upload = tokio::task::block_in_place(|| state.uploads.admit(length, &state.metrics))?;
+        }
+        request.read_body_async(reader).await?;
+        Ok(Arc::new(request))
+    }
+    .await;
+    let (request_id, span) = match &request {
+        Ok(request) => {
+            let (id, span) = begin_request(request, peer, state);
+            (Some(id), span)
+        }
+        Err(_) => (None, None),
+    };
+
+    // Blocked clients get a 403, or with --deny-action close, nothing at all
+    let blocked = request.as_ref().is_ok_and(|request| blocked_client(request, peer, state).is_some());
+    if blocked && state.config.deny_action == DenyAction::Close {
+        return false;
+    }
+
+    // Reuse the connection only if the client wants to and the server isn't draining
+    let keep_open = may_keep_alive
+        && !blocked
+        && !state.router.readiness.is_draining()
+        && request.as_ref().is_ok_and(|request| request.wants_keep_alive());
+
+    let (request, result) = match request {
+        Ok(request) => {
+            let id = request_id.clone().unwrap_or_default();
+            log::debug!("Request {}: {} {}", id, request.method.as_str(), request.path);
+            let response = if blocked {
+                Ok(HttpResponse::forbidden())
+            } else {
+                REQUEST_ID.scope(id.clone(), handler.call(Arc::clone(&request))).await
+            };
+            let result = match response {
+                Ok(response) => {
+                    let response = finish_response(response, &id, span.as_ref(), &state.config, keep_open);
+                    let status = response.status_code();
+                    write_response(reader.get_mut(), response).await.map(|body_len| (status, body_len))
+                }
+                Err(e) => Err(e),
+            };
+            (Some(request), result)
+        }
+        Err(e) => (None, Err(e)),
+    };
+    let elapsed = start_time.elapsed();
+
+    let (failed, status, bytes, keep_open) = match result {
+        Ok((status, bytes)) => (false, status, bytes, keep_open),
+        // Nobody is left to send an error response to
+        Err(e) if e.is_client_abort() => {
+            client_aborted(&e, peer, &state.metrics);
+            (false, CLIENT_CLOSED_REQUEST, 0, false)
+        }
+        Err(e) => {
+            let response = {
+                let _scope = request_id.as_deref().map(request_id::Scope::enter);
+                error_response(&e, request.as_deref(), peer, request_id.as_deref())
+            };
+            let bytes = response.body_len();
+            let _ = write_response(reader.get_mut(), response).await;
+            (true, e.status_code(), bytes, false)
+        }
+    };
+
+    let stream = reader.get_ref();
+    let read_after = stream.bytes_read() - reader.buffer().len() as u64;
+    let _scope = request_id.as_deref().map(request_id::Scope::enter);
+    record_request(
+        state,
+        RequestOutcome {
+            peer_addr: peer,
+            request: request.as_deref(),
+            request_id: request_id.as_deref(),
+            span,
+            failed,
+            status,
+            bytes,
+            elapsed,
+            bytes_in: read_after - read_before,
+            bytes_out: stream.bytes_written() - written_before,
+        },
+    );
+
+    keep_open
+}
+
+/// Send a response, writing the head and body together without copying the
+/// body; returns the body length
+async fn write_response(stream: &mut CountingStream<TcpStream>, response: HttpResponse) -> Result<usize> {
+    let parts = response.into_parts()?;
+    let mut sent = 0;
+    while sent < parts.len() {
+        let [head, body] = parts.remaining(sent);
+        match stream.write_vectored(&[IoSlice::new(head), IoSlice::new(body)]).await? {
+            0 => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+            n => sent += n,
+        }
+    }
+    stream.flush().await?;
+    Ok(parts.body.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_async_matches_sync_parser() {
+        let raw: &[u8] = b"POST /files/a.txt HTTP/1.1\r\nHost: localhost\r\nContent-Length: 5\r\n\r\nhelloGET / HTTP/1.1\r\n\r\n";
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+
+        let mut reader = BufReader::new(raw);
+        let request = runtime.block_on(HttpRequest::parse_async(&mut reader)).unwrap();
+        assert_eq!(request.request_line(), "POST /files/a.txt HTTP/1.1");
+        assert_eq!(request.get_header("host"), Some("localhost"));
+        assert_eq!(request.body, b"hello");
+
+        // The next pipelined request is left in the buffer
+        let next = runtime.block_on(HttpRequest::parse_async(&mut reader)).unwrap();
+        assert_eq!(next.path, "/");
+    }
+}
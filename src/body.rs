@@ -0,0 +1,114 @@
+//! Request bodies read incrementally instead of buffered up front
+//!
+//! Handlers registered with [`streaming`](crate::router::streaming) get the
+//! body left on the connection: [`HttpRequest::body_reader`] then reads it
+//! straight from the socket, so an upload can be copied to disk in chunks.
+//! For every other handler the body is already in memory and the same reader
+//! reads from there.
+//!
+//! [`HttpRequest::body_reader`]: crate::request::HttpRequest::body_reader
+
+use std::fmt;
+use std::io::{self, Read};
+use std::sync::MutexGuard;
+
+/// A body still on the connection, limited to its declared length
+pub struct BodyStream {
+    inner: Box<dyn Read + Send>,
+    remaining: u64,
+}
+
+impl BodyStream {
+    /// Read `length` bytes from `inner`, which may hold more (pipelined
+    /// requests) or fewer (the client gave up) bytes
+    pub fn new(inner: impl Read + Send + 'static, length: u64) -> Self {
+        BodyStream {
+            inner: Box::new(inner),
+            remaining: length,
+        }
+    }
+
+    /// Bytes of the body not read yet
+    pub fn remaining(&self) -> u64 {
+        self.remaining
+    }
+
+    /// Read and throw away the rest of the body so the connection is
+    /// positioned at the next request
+    pub fn discard(&mut self) -> io::Result<u64> {
+        io::copy(self, &mut io::sink())
+    }
+}
+
+impl Read for BodyStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let limit = buf.len().min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let n = self.inner.read(&mut buf[..limit])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("connection closed with {} bytes of the request body unread", self.remaining),
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+impl fmt::Debug for BodyStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BodyStream").field("remaining", &self.remaining).finish()
+    }
+}
+
+/// Reader over a request body, returned by
+/// [`HttpRequest::body_reader`](crate::request::HttpRequest::body_reader)
+pub enum BodyReader<'a> {
+    /// The body was read into memory with the head
+    Buffered(&'a [u8]),
+    /// The body is read from the connection as the reader is
+    Streaming(MutexGuard<'a, Option<BodyStream>>),
+}
+
+impl Read for BodyReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BodyReader::Buffered(body) => body.read(buf),
+            BodyReader::Streaming(stream) => match stream.as_mut() {
+                Some(stream) => stream.read(buf),
+                None => Ok(0),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_stops_at_declared_length() {
+        let mut stream = BodyStream::new(io::Cursor::new(b"helloGET / HTTP/1.1\r\n".to_vec()), 5);
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello");
+        assert_eq!(stream.remaining(), 0);
+
+        let mut stream = BodyStream::new(io::Cursor::new(b"hello world".to_vec()), 11);
+        let mut start = [0; 5];
+        stream.read_exact(&mut start).unwrap();
+        assert_eq!(stream.discard().unwrap(), 6);
+        assert_eq!(stream.remaining(), 0);
+    }
+
+    #[test]
+    fn test_truncated_stream_is_an_error() {
+        let mut stream = BodyStream::new(io::Cursor::new(b"hel".to_vec()), 5);
+        let e = stream.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(stream.remaining(), 2);
+    }
+}
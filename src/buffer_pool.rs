@@ -0,0 +1,152 @@
+use std::cell::RefCell;
+use std::io::{self, BufRead, Read};
+use std::ops::{Deref, DerefMut};
+
+/// Buffers kept per thread for reuse
+const MAX_POOLED: usize = 32;
+
+/// Buffers that grew beyond this are freed instead of pooled, so one large
+/// response doesn't pin memory on every thread
+const MAX_RETAINED_CAPACITY: usize = 256 * 1024;
+
+thread_local! {
+    static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A byte buffer borrowed from the current thread's pool and returned to it
+/// when dropped
+pub struct PooledBuf(Vec<u8>);
+
+impl PooledBuf {
+    /// Take an empty buffer with at least `capacity` bytes reserved
+    pub fn take(capacity: usize) -> Self {
+        let mut buf = POOL.with(|pool| pool.borrow_mut().pop()).unwrap_or_default();
+        buf.clear();
+        buf.reserve(capacity);
+        PooledBuf(buf)
+    }
+}
+
+impl Deref for PooledBuf {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.0
+    }
+}
+
+impl DerefMut for PooledBuf {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.0
+    }
+}
+
+impl Drop for PooledBuf {
+    fn drop(&mut self) {
+        if self.0.capacity() > MAX_RETAINED_CAPACITY {
+            return;
+        }
+        let buf = std::mem::take(&mut self.0);
+        // The pool may already be gone while the thread is exiting
+        let _ = POOL.try_with(|pool| {
+            let mut pool = pool.borrow_mut();
+            if pool.len() < MAX_POOLED {
+                pool.push(buf);
+            }
+        });
+    }
+}
+
+/// Buffered reader over a connection, backed by a pooled buffer
+///
+/// Works like `std::io::BufReader`, but the buffer goes back to the pool when
+/// the connection closes instead of being freed.
+pub struct PooledReader<R> {
+    inner: R,
+    buf: PooledBuf,
+    pos: usize,
+    filled: usize,
+}
+
+impl<R> PooledReader<R> {
+    pub fn with_capacity(capacity: usize, inner: R) -> Self {
+        let mut buf = PooledBuf::take(capacity);
+        buf.resize(capacity, 0);
+        PooledReader {
+            inner,
+            buf,
+            pos: 0,
+            filled: 0,
+        }
+    }
+
+    /// Bytes read from the connection but not consumed yet
+    pub fn buffer(&self) -> &[u8] {
+        &self.buf[self.pos..self.filled]
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: Read> Read for PooledReader<R> {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        // Skip the buffer for large reads when it's empty, like BufReader
+        if self.pos == self.filled && out.len() >= self.buf.len() {
+            return self.inner.read(out);
+        }
+        let n = self.fill_buf()?.read(out)?;
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl<R: Read> BufRead for PooledReader<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.filled {
+            self.filled = self.inner.read(&mut self.buf)?;
+            self.pos = 0;
+        }
+        Ok(self.buffer())
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.pos = (self.pos + amount).min(self.filled);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffers_are_reused() {
+        let ptr = {
+            let mut buf = PooledBuf::take(1024);
+            buf.extend_from_slice(b"hello");
+            buf.as_ptr()
+        };
+        let buf = PooledBuf::take(16);
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+    }
+
+    #[test]
+    fn test_pooled_reader_lines() {
+        let mut reader = PooledReader::with_capacity(8, &b"first line\nsecond\n"[..]);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "first line\n");
+        // The second fill read past the end of the line
+        assert_eq!(reader.buffer(), b"secon");
+
+        line.clear();
+        reader.read_line(&mut line).unwrap();
+        assert_eq!(line, "second\n");
+    }
+}
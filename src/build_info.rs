@@ -0,0 +1,60 @@
+//! What build of the server is running
+//!
+//! The build script records the git commit, the compiler, and the build time;
+//! they are served at `GET /version` and as the `http_server_build_info`
+//! metric, whose labels carry the values and whose sample is always 1.
+
+use crate::metrics::write_header;
+use chrono::DateTime;
+use serde_json::{json, Value};
+use std::fmt::Write;
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Abbreviated commit hash, or "unknown" when not built from a git checkout
+pub const GIT_COMMIT: &str = env!("HTTP_SERVER_GIT_COMMIT");
+
+/// Output of `rustc --version` for the compiler that built the server
+pub const RUSTC_VERSION: &str = env!("HTTP_SERVER_RUSTC_VERSION");
+
+/// Seconds since the Unix epoch when the build script last ran
+const BUILD_TIMESTAMP: &str = env!("HTTP_SERVER_BUILD_TIMESTAMP");
+
+/// `http-server --version` output
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    " (commit ",
+    env!("HTTP_SERVER_GIT_COMMIT"),
+    ", ",
+    env!("HTTP_SERVER_RUSTC_VERSION"),
+    ")"
+);
+
+/// The build time in RFC 3339, UTC
+pub fn build_time() -> String {
+    let seconds = BUILD_TIMESTAMP.parse().unwrap_or_default();
+    DateTime::from_timestamp(seconds, 0).unwrap_or_default().to_rfc3339()
+}
+
+pub fn to_json() -> Value {
+    json!({
+        "version": VERSION,
+        "git_commit": GIT_COMMIT,
+        "build_time": build_time(),
+        "rustc_version": RUSTC_VERSION,
+    })
+}
+
+/// Append the `http_server_build_info` gauge in Prometheus text format
+pub fn render_prometheus(out: &mut String) {
+    let escape = |value: &str| value.replace('\\', "\\\\").replace('"', "\\\"");
+    write_header(out, "http_server_build_info", "gauge", "Build of the running server, in the labels; always 1");
+    let _ = writeln!(
+        out,
+        "http_server_build_info{{version=\"{}\",git_commit=\"{}\",build_time=\"{}\",rustc_version=\"{}\"}} 1\n",
+        escape(VERSION),
+        escape(GIT_COMMIT),
+        build_time(),
+        escape(RUSTC_VERSION)
+    );
+}
@@ -0,0 +1,215 @@
+use crate::compression::Compression;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// A cached compressed body together with the file version it was built from
+struct CacheEntry {
+    mtime: SystemTime,
+    data: Arc<Vec<u8>>,
+    last_used: u64,
+}
+
+struct CacheState {
+    entries: HashMap<(PathBuf, Compression), CacheEntry>,
+    total_bytes: usize,
+    clock: u64,
+    generation: u64,
+}
+
+/// Size-bounded cache of compressed static file bodies
+///
+/// Entries are keyed by (path, encoding) and remember the file's mtime, so a
+/// lookup with a newer mtime invalidates the stale entry. When the cache grows
+/// past `max_bytes`, the least recently used entries are evicted.
+///
+/// When a file watcher reports changes through [`invalidate`](Self::invalidate),
+/// [`get_current`](Self::get_current) serves entries without the caller
+/// having to stat the file first.
+pub struct CompressedCache {
+    max_bytes: usize,
+    state: Mutex<CacheState>,
+}
+
+impl CompressedCache {
+    /// Create a cache holding at most `max_bytes` of compressed data (0 disables it)
+    pub fn new(max_bytes: usize) -> Self {
+        CompressedCache {
+            max_bytes,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                total_bytes: 0,
+                clock: 0,
+                generation: 0,
+            }),
+        }
+    }
+
+    /// Look up the compressed body for a file version
+    pub fn get(&self, path: &Path, mtime: SystemTime, compression: Compression) -> Option<Arc<Vec<u8>>> {
+        if self.max_bytes == 0 {
+            return None;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let key = (path.to_path_buf(), compression);
+
+        match state.entries.get_mut(&key) {
+            Some(entry) if entry.mtime == mtime => {
+                entry.last_used = clock;
+                Some(Arc::clone(&entry.data))
+            }
+            Some(_) => {
+                // File changed since it was cached
+                if let Some(stale) = state.entries.remove(&key) {
+                    state.total_bytes -= stale.data.len();
+                }
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Look up the compressed body for a file whatever its version, together
+    /// with the mtime it was built from; only correct while every change to
+    /// the file is passed to [`invalidate`](Self::invalidate)
+    pub fn get_current(&self, path: &Path, compression: Compression) -> Option<(Arc<Vec<u8>>, SystemTime)> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let entry = state.entries.get_mut(&(path.to_path_buf(), compression))?;
+        entry.last_used = clock;
+        Some((Arc::clone(&entry.data), entry.mtime))
+    }
+
+    /// Drop the entries for every path `matches` accepts, returning how many
+    /// were removed
+    pub fn invalidate(&self, matches: impl Fn(&Path) -> bool) -> usize {
+        let mut state = self.state.lock().unwrap();
+        state.generation += 1;
+        let before = state.entries.len();
+        let mut freed = 0;
+        state.entries.retain(|(path, _), entry| {
+            let keep = !matches(path);
+            if !keep {
+                freed += entry.data.len();
+            }
+            keep
+        });
+        state.total_bytes -= freed;
+        before - state.entries.len()
+    }
+
+    /// Counter bumped by every [`invalidate`](Self::invalidate); read it before
+    /// reading a file and pass it to [`insert_if_unchanged`](Self::insert_if_unchanged)
+    pub fn generation(&self) -> u64 {
+        self.state.lock().unwrap().generation
+    }
+
+    /// Store the compressed body for a file version, evicting old entries as needed
+    pub fn insert(&self, path: &Path, mtime: SystemTime, compression: Compression, data: Arc<Vec<u8>>) {
+        let generation = self.generation();
+        self.insert_if_unchanged(generation, path, mtime, compression, data);
+    }
+
+    /// Like [`insert`](Self::insert), but skipped if anything was invalidated
+    /// since `generation`, because the body may have been built from a file
+    /// that changed while it was being read
+    pub fn insert_if_unchanged(
+        &self,
+        generation: u64,
+        path: &Path,
+        mtime: SystemTime,
+        compression: Compression,
+        data: Arc<Vec<u8>>,
+    ) {
+        if data.len() > self.max_bytes {
+            return;
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if state.generation != generation {
+            return;
+        }
+        state.clock += 1;
+        let entry = CacheEntry {
+            mtime,
+            data,
+            last_used: state.clock,
+        };
+        state.total_bytes += entry.data.len();
+        if let Some(old) = state.entries.insert((path.to_path_buf(), compression), entry) {
+            state.total_bytes -= old.data.len();
+        }
+
+        while state.total_bytes > self.max_bytes {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone());
+            match oldest.and_then(|key| state.entries.remove(&key)) {
+                Some(evicted) => state.total_bytes -= evicted.data.len(),
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_invalidation_on_mtime_change() {
+        let cache = CompressedCache::new(1024);
+        let path = Path::new("style.css");
+        let t0 = SystemTime::UNIX_EPOCH;
+        let t1 = t0 + Duration::from_secs(1);
+
+        cache.insert(path, t0, Compression::Gzip, Arc::new(vec![1, 2, 3]));
+        assert!(cache.get(path, t0, Compression::Gzip).is_some());
+        assert!(cache.get(path, t0, Compression::Brotli).is_none());
+        assert!(cache.get(path, t1, Compression::Gzip).is_none());
+        assert!(cache.get(path, t0, Compression::Gzip).is_none());
+    }
+
+    #[test]
+    fn test_lru_eviction() {
+        let cache = CompressedCache::new(10);
+        let t = SystemTime::UNIX_EPOCH;
+
+        cache.insert(Path::new("a"), t, Compression::Gzip, Arc::new(vec![0; 4]));
+        cache.insert(Path::new("b"), t, Compression::Gzip, Arc::new(vec![0; 4]));
+        assert!(cache.get(Path::new("a"), t, Compression::Gzip).is_some());
+        cache.insert(Path::new("c"), t, Compression::Gzip, Arc::new(vec![0; 4]));
+
+        assert!(cache.get(Path::new("a"), t, Compression::Gzip).is_some());
+        assert!(cache.get(Path::new("b"), t, Compression::Gzip).is_none());
+        assert!(cache.get(Path::new("c"), t, Compression::Gzip).is_some());
+    }
+
+    #[test]
+    fn test_invalidate_by_path() {
+        let cache = CompressedCache::new(1024);
+        let t = SystemTime::UNIX_EPOCH;
+        cache.insert(Path::new("dir/a.css"), t, Compression::Gzip, Arc::new(vec![0; 4]));
+        cache.insert(Path::new("dir/a.css"), t, Compression::Brotli, Arc::new(vec![0; 4]));
+        cache.insert(Path::new("dir/b.css"), t, Compression::Gzip, Arc::new(vec![0; 4]));
+
+        let generation = cache.generation();
+        assert_eq!(cache.invalidate(|path| path.ends_with("a.css")), 2);
+        assert!(cache.get_current(Path::new("dir/a.css"), Compression::Gzip).is_none());
+        assert!(cache.get_current(Path::new("dir/b.css"), Compression::Gzip).is_some());
+
+        // A body read before the invalidation is not cached
+        cache.insert_if_unchanged(generation, Path::new("dir/a.css"), t, Compression::Gzip, Arc::new(vec![1]));
+        assert!(cache.get_current(Path::new("dir/a.css"), Compression::Gzip).is_none());
+        assert_eq!(cache.invalidate(|_| true), 1);
+        assert_eq!(cache.state.lock().unwrap().total_bytes, 0);
+    }
+}
@@ -0,0 +1,340 @@
+//! CGI/1.1 script execution (RFC 3875)
+//!
+//! A request under the configured prefix runs the script it names with the
+//! standard CGI environment, the request body on stdin, and turns what the
+//! script prints (header lines, a blank line, the body) into the response.
+//! Scripts that run past the timeout are killed and answered with 504.
+
+use crate::error::{Result, ServerError};
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::router::Handler;
+use crate::status::StatusCode;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Name of the catch-all route parameter holding the script path
+pub const PATH_PARAM: &str = "cgi_path";
+
+/// Runs scripts from a directory for requests under a URL prefix
+#[derive(Debug, Clone)]
+pub struct Cgi {
+    pub prefix: String,
+    pub directory: PathBuf,
+    pub timeout: Duration,
+}
+
+impl Cgi {
+    /// Serve scripts in `directory` at `prefix`, e.g. `/cgi-bin`
+    pub fn new(prefix: &str, directory: impl Into<PathBuf>, timeout: Duration) -> Self {
+        Cgi {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            directory: directory.into(),
+            timeout,
+        }
+    }
+
+    /// Route pattern matching every path under the prefix
+    pub fn pattern(&self) -> String {
+        format!("{}/*{}", self.prefix, PATH_PARAM)
+    }
+
+    /// Find the script a request path names: the longest leading run of
+    /// segments that is a file, with the segments after it as PATH_INFO
+    fn resolve(&self, path: &str) -> Result<(PathBuf, String, String)> {
+        let segments: Vec<&str> = path.split('/').collect();
+        if segments.iter().any(|segment| segment.is_empty() || segment.starts_with('.')) {
+            return Err(ServerError::FileNotFound(format!("{}/{}", self.prefix, path)));
+        }
+        let mut script = self.directory.clone();
+        for (i, segment) in segments.iter().enumerate() {
+            script.push(segment);
+            if script.is_file() {
+                let script_name = format!("{}/{}", self.prefix, segments[..=i].join("/"));
+                let path_info = segments[i + 1..].iter().map(|segment| format!("/{}", segment)).collect();
+                return Ok((script, script_name, path_info));
+            }
+            if !script.is_dir() {
+                break;
+            }
+        }
+        Err(ServerError::FileNotFound(format!("{}/{}", self.prefix, path)))
+    }
+
+    /// Run the script with the request body on stdin and collect its output,
+    /// killing it once the timeout passes
+    fn run(&self, request: &HttpRequest, script: &Path, env: Vec<(String, String)>) -> Result<Vec<u8>> {
+        let mut command = Command::new(script);
+        command
+            .env_clear()
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Some(path) = std::env::var_os("PATH") {
+            command.env("PATH", path);
+        }
+        if let Some(dir) = script.parent() {
+            command.current_dir(dir);
+        }
+        let mut child = command
+            .spawn()
+            .map_err(|e| ServerError::BadGateway(format!("Failed to start CGI script {}: {}", script.display(), e)))?;
+        let (stdin, mut stdout, stderr) = (child.stdin.take(), child.stdout.take(), child.stderr.take());
+        let child = Arc::new(Mutex::new(child));
+
+        // The watchdog kills the script unless told it finished in time
+        let (done, finished) = mpsc::channel::<()>();
+        let timed_out = Arc::new(AtomicBool::new(false));
+        let watchdog = {
+            let (child, timed_out, timeout) = (Arc::clone(&child), Arc::clone(&timed_out), self.timeout);
+            thread::spawn(move || {
+                if let Err(mpsc::RecvTimeoutError::Timeout) = finished.recv_timeout(timeout) {
+                    timed_out.store(true, Ordering::Relaxed);
+                    let _ = lock(&child).kill();
+                }
+            })
+        };
+        let output = thread::spawn(move || {
+            let mut output = Vec::new();
+            stdout.as_mut().map_or(Ok(0), |stdout| stdout.read_to_end(&mut output)).map(|_| output)
+        });
+        let script_name = script.display().to_string();
+        if let Some(stderr) = stderr {
+            thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(|line| line.ok()) {
+                    log::warn!("CGI {}: {}", script_name, line);
+                }
+            });
+        }
+
+        // A script may exit without reading its input; that isn't an error
+        if let Some(mut stdin) = stdin {
+            match io::copy(&mut request.body_reader(), &mut stdin) {
+                Err(e) if e.kind() != io::ErrorKind::BrokenPipe => {
+                    let _ = lock(&child).kill();
+                    return Err(e.into());
+                }
+                _ => {}
+            }
+        }
+        let status = loop {
+            if let Some(status) = lock(&child).try_wait()? {
+                break status;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        let _ = done.send(());
+        let _ = watchdog.join();
+
+        // A killed script's own children may still hold stdout open, so its
+        // output isn't waited for
+        if timed_out.load(Ordering::Relaxed) {
+            return Err(ServerError::GatewayTimeout(format!(
+                "CGI script {} did not finish within {}s",
+                script.display(),
+                self.timeout.as_secs()
+            )));
+        }
+        let output = output.join().unwrap_or_else(|_| Err(io::Error::other("CGI output reader panicked")))?;
+        if !status.success() {
+            log::warn!("CGI script {} exited with {}", script.display(), status);
+        }
+        Ok(output)
+    }
+}
+
+impl Handler for Cgi {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let path = request.param(PATH_PARAM).unwrap_or_default();
+        let (script, script_name, path_info) = self.resolve(path)?;
+        if !is_executable(&script) {
+            log::warn!("CGI script {} is not executable", script.display());
+            return Ok(HttpResponse::forbidden());
+        }
+        let env = environment(request, &self.directory, &script, &script_name, &path_info);
+        let output = self.run(request, &script, env)?;
+        parse_output(&output)
+            .map_err(|e| ServerError::BadGateway(format!("CGI script {} {}", script.display(), e)))
+    }
+
+    fn streams_body(&self) -> bool {
+        true
+    }
+}
+
+/// The meta-variables of RFC 3875 section 4.1, plus the common
+/// REQUEST_URI and SCRIPT_FILENAME extensions and a HTTP_* variable per
+/// request header. `document_root` is the directory scripts are found in,
+/// as the script sees it
+pub fn environment(
+    request: &HttpRequest,
+    document_root: &Path,
+    script: &Path,
+    script_name: &str,
+    path_info: &str,
+) -> Vec<(String, String)> {
+    let query = request.path.split_once('?').map_or("", |(_, query)| query);
+    let host = request.get_header("host").unwrap_or("localhost");
+    let (server_name, server_port) = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|b| b.is_ascii_digit()) => (name, port),
+        _ => (host, "80"),
+    };
+    let mut env = vec![
+        ("GATEWAY_INTERFACE", "CGI/1.1".to_string()),
+        ("SERVER_SOFTWARE", format!("http-server/{}", env!("CARGO_PKG_VERSION"))),
+        ("SERVER_PROTOCOL", request.version.clone()),
+        ("SERVER_NAME", server_name.to_string()),
+        ("SERVER_PORT", server_port.to_string()),
+        ("REQUEST_METHOD", request.method.as_str().to_string()),
+        ("REQUEST_URI", request.path.clone()),
+        ("SCRIPT_NAME", script_name.to_string()),
+        ("SCRIPT_FILENAME", script.display().to_string()),
+        ("DOCUMENT_ROOT", document_root.display().to_string()),
+        ("PATH_INFO", path_info.to_string()),
+        ("QUERY_STRING", query.to_string()),
+    ]
+    .into_iter()
+    .map(|(name, value)| (name.to_string(), value))
+    .collect::<Vec<_>>();
+
+    if !path_info.is_empty() {
+        let translated = document_root.join(path_info.trim_start_matches('/'));
+        env.push(("PATH_TRANSLATED".to_string(), translated.display().to_string()));
+    }
+    if let Some(peer) = request.peer_addr {
+        env.push(("REMOTE_ADDR".to_string(), peer.ip().to_string()));
+        env.push(("REMOTE_PORT".to_string(), peer.port().to_string()));
+    }
+    if let Ok(length) = request.content_length() {
+        if length > 0 {
+            env.push(("CONTENT_LENGTH".to_string(), length.to_string()));
+        }
+    }
+    if let Some(content_type) = request.get_header("content-type") {
+        env.push(("CONTENT_TYPE".to_string(), content_type.to_string()));
+    }
+
+    // Credentials stay with the server, and a Proxy header must never
+    // become HTTP_PROXY, which scripts' HTTP clients take as their proxy
+    let names: Vec<String> = request.headers.iter().map(|(name, _)| name.to_ascii_lowercase()).collect();
+    for name in names {
+        if matches!(name.as_str(), "content-length" | "content-type" | "authorization" | "proxy") {
+            continue;
+        }
+        let variable = format!("HTTP_{}", name.to_ascii_uppercase().replace('-', "_"));
+        if env.iter().any(|(existing, _)| *existing == variable) {
+            continue;
+        }
+        if let Some(value) = request.headers.combined(&name) {
+            env.push((variable, value.into_owned()));
+        }
+    }
+    env
+}
+
+fn lock(child: &Mutex<Child>) -> std::sync::MutexGuard<'_, Child> {
+    child.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(unix)]
+fn is_executable(path: &Path) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+    path.metadata().is_ok_and(|metadata| metadata.permissions().mode() & 0o111 != 0)
+}
+
+#[cfg(not(unix))]
+fn is_executable(path: &Path) -> bool {
+    path.is_file()
+}
+
+/// Turn a script's output into a response (RFC 3875 section 6)
+///
+/// Header lines may end in LF or CRLF. `Status` sets the status; without it
+/// a `Location` header means a 302 redirect and anything else a 200.
+pub fn parse_output(output: &[u8]) -> std::result::Result<HttpResponse, String> {
+    let (head, body) = [&b"\r\n\r\n"[..], b"\n\n", b"\n\r\n"]
+        .iter()
+        .filter_map(|separator| {
+            let end = output.windows(separator.len()).position(|w| w == *separator)?;
+            Some((end, end + separator.len()))
+        })
+        .min()
+        .map(|(end, body)| (&output[..end], &output[body..]))
+        .ok_or("printed no header section")?;
+    let head = std::str::from_utf8(head).map_err(|_| "printed headers that aren't UTF-8")?;
+
+    let mut status = None;
+    let mut headers = Vec::new();
+    for line in head.lines() {
+        let (name, value) = line.split_once(':').ok_or_else(|| format!("printed a malformed header line {:?}", line))?;
+        let (name, value) = (name.trim(), value.trim());
+        if name.eq_ignore_ascii_case("status") {
+            let code = value.split_whitespace().next().and_then(|code| code.parse().ok());
+            status = Some(code.and_then(StatusCode::from_u16).ok_or_else(|| format!("printed an invalid Status {:?}", value))?);
+        } else {
+            headers.push((name.to_string(), value.to_string()));
+        }
+    }
+    if status.is_none() && headers.is_empty() {
+        return Err("printed neither Content-Type, Location, nor Status".to_string());
+    }
+    let status = status.unwrap_or_else(|| {
+        if headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("location")) {
+            StatusCode::Found
+        } else {
+            StatusCode::Ok
+        }
+    });
+
+    let response = headers
+        .into_iter()
+        .filter(|(name, _)| !name.eq_ignore_ascii_case("content-length") && !name.eq_ignore_ascii_case("connection"))
+        .fold(HttpResponse::new(status), |response, (name, value)| response.append_header(name, value));
+    Ok(response.body(body.to_vec()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output() {
+        let response = parse_output(b"Content-Type: text/plain\nX-Script: yes\n\nhello\n").unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.get_header("x-script"), Some("yes"));
+        assert_eq!(response.body_len(), 6);
+
+        let response = parse_output(b"Status: 404 Not Found\r\nContent-Type: text/html\r\n\r\n").unwrap();
+        assert_eq!(response.status(), StatusCode::NotFound);
+        let response = parse_output(b"Location: https://example.com/\n\n").unwrap();
+        assert_eq!(response.status(), StatusCode::Found);
+
+        assert!(parse_output(b"hello").is_err());
+        assert!(parse_output(b"\n\nbody").is_err());
+        assert!(parse_output(b"Status: nope\n\n").is_err());
+    }
+
+    #[test]
+    fn test_resolve_splits_path_info() {
+        let dir = std::env::temp_dir().join(format!("http-server-cgi-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("tools")).unwrap();
+        std::fs::write(dir.join("tools/run.sh"), "").unwrap();
+        let cgi = Cgi::new("/cgi-bin/", &dir, Duration::from_secs(1));
+        assert_eq!(cgi.pattern(), "/cgi-bin/*cgi_path");
+
+        let (script, name, info) = cgi.resolve("tools/run.sh/a/b").unwrap();
+        assert_eq!(script, dir.join("tools/run.sh"));
+        assert_eq!((name.as_str(), info.as_str()), ("/cgi-bin/tools/run.sh", "/a/b"));
+        assert!(cgi.resolve("tools").is_err());
+        assert!(cgi.resolve("tools/../tools/run.sh").is_err());
+        assert!(cgi.resolve("missing.sh").is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
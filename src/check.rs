@@ -0,0 +1,177 @@
+//! `http-server check`: validate a configuration file so deployments can lint
+//! it in CI before rollout
+
+use crate::config::Config;
+use crate::{daemon, platform};
+use clap::Args;
+use std::ffi::OsString;
+use std::net::ToSocketAddrs;
+use std::path::{Path, PathBuf};
+
+/// Options for the `check` subcommand
+#[derive(Args, Debug, Clone)]
+pub struct CheckArgs {
+    /// Configuration file to validate
+    #[arg(long)]
+    pub config: PathBuf,
+}
+
+/// Findings for one configuration
+#[derive(Debug, Default)]
+struct Report {
+    errors: Vec<String>,
+    warnings: Vec<String>,
+}
+
+/// Check the file and print every problem found; returns the process exit code
+pub fn run(args: &CheckArgs) -> i32 {
+    let argv: Vec<OsString> = vec!["http-server".into(), "--config".into(), args.config.clone().into()];
+    let config = match Config::load(&argv) {
+        Ok(config) => config,
+        Err(e) => {
+            // Already formatted as "error: ..."
+            eprint!("{}", e.render());
+            return 1;
+        }
+    };
+
+    let report = check(&config);
+    for warning in &report.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    for error in &report.errors {
+        eprintln!("error: {}", error);
+    }
+    if report.errors.is_empty() {
+        println!("{}: OK", args.config.display());
+        0
+    } else {
+        eprintln!("{}: {} problem(s) found", args.config.display(), report.errors.len());
+        1
+    }
+}
+
+/// Validate settings beyond what parsing catches, using the permissions of
+/// the user running the check
+fn check(config: &Config) -> Report {
+    let mut report = Report::default();
+    if let Err(e) = config.validate() {
+        report.errors.push(e);
+    }
+
+    if let Err(e) = config.server_address().to_socket_addrs() {
+        report.errors.push(format!("listen address {} is invalid: {}", config.server_address(), e));
+    }
+    for (flag, addr) in [("--admin-addr", &config.admin_addr), ("--statsd-addr", &config.statsd_addr)] {
+        if let Some(addr) = addr {
+            if let Err(e) = addr.to_socket_addrs() {
+                report.errors.push(format!("{} {} does not resolve: {} (expected host:port)", flag, addr, e));
+            }
+        }
+    }
+
+    check_directory(Path::new(&config.directory), &mut report);
+
+    let outputs = [
+        ("--log-file", config.log_file.as_deref().map(Path::new)),
+        ("--access-log", config.access_log.as_deref().filter(|path| *path != "-").map(Path::new)),
+        ("--audit-log", config.audit_log.as_deref().map(Path::new)),
+        ("--pid-file", config.pid_file.as_deref()),
+    ];
+    for (flag, path) in outputs {
+        if let Some(path) = path {
+            check_output_file(flag, path, &mut report);
+        }
+    }
+
+    if let Err(e) = daemon::check_identity(config.user.as_deref(), config.group.as_deref()) {
+        report.errors.push(format!("--user/--group: {}", e));
+    }
+    report
+}
+
+/// The served directory must be readable, and writable for uploads
+fn check_directory(directory: &Path, report: &mut Report) {
+    match std::fs::metadata(directory) {
+        Ok(metadata) if !metadata.is_dir() => {
+            report.errors.push(format!("--directory {} is not a directory", directory.display()));
+        }
+        Ok(_) => {
+            if let Err(e) = std::fs::read_dir(directory) {
+                report.errors.push(format!("--directory {} is not readable: {}", directory.display(), e));
+            }
+            if !platform::is_writable(directory) {
+                report.warnings.push(format!(
+                    "--directory {} is not writable; file uploads will fail",
+                    directory.display()
+                ));
+            }
+        }
+        Err(_) => {
+            report.warnings.push(format!(
+                "--directory {} does not exist; it is created on the first upload",
+                directory.display()
+            ));
+        }
+    }
+}
+
+/// A file the server writes must be writable, or creatable in its directory
+fn check_output_file(flag: &str, path: &Path, report: &mut Report) {
+    if path.exists() {
+        if path.is_dir() {
+            report.errors.push(format!("{} {} is a directory", flag, path.display()));
+        } else if !platform::is_writable(path) {
+            report.errors.push(format!("{} {} is not writable", flag, path.display()));
+        }
+        return;
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    if !parent.is_dir() {
+        report.errors.push(format!("{} {}: directory {} does not exist", flag, path.display(), parent.display()));
+    } else if !platform::is_writable(parent) {
+        report.errors.push(format!(
+            "{} {}: cannot create files in {}",
+            flag,
+            path.display(),
+            parent.display()
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_reports_actionable_errors() {
+        let dir = std::env::temp_dir().join(format!("http-server-check-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("server.toml");
+        let missing = dir.join("missing");
+        std::fs::write(
+            &path,
+            format!(
+                "directory = {:?}\nlog_file = {:?}\nadmin_addr = \"not-an-address\"\n",
+                dir.display().to_string(),
+                missing.join("server.log").display().to_string()
+            ),
+        )
+        .unwrap();
+
+        let argv: Vec<OsString> = vec!["http-server".into(), "--config".into(), path.clone().into()];
+        let report = check(&Config::load(&argv).unwrap());
+        assert_eq!(report.errors.len(), 2, "{:?}", report.errors);
+        assert!(report.errors[0].starts_with("--admin-addr not-an-address does not resolve"));
+        assert!(report.errors[1].contains("directory") && report.errors[1].contains("does not exist"));
+
+        std::fs::write(&path, format!("directory = {:?}\n", dir.display().to_string())).unwrap();
+        assert!(check(&Config::load(&argv).unwrap()).errors.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+//! Circuit breakers for upstreams
+//!
+//! A [`CircuitBreaker`] watches how requests to one upstream turn out over
+//! the last [`WINDOW_SECS`] seconds. Once at least [`MIN_REQUESTS`] were made
+//! and the threshold percentage of them failed, the circuit opens: requests
+//! are answered with 503 straight away instead of piling up on an upstream
+//! that is down or struggling. After the cool-down the circuit is half-open
+//! and lets a single trial request through; if it succeeds the circuit
+//! closes, and if it fails the circuit opens for another cool-down.
+//! Transitions are logged and counted in the metrics.
+
+use crate::metrics::write_header;
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Length of the rolling window error rates are measured over
+pub const WINDOW_SECS: u64 = 10;
+
+/// Requests in the window needed before the error rate can open the circuit
+pub const MIN_REQUESTS: u64 = 20;
+
+/// Where a circuit stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests go through
+    Closed,
+    /// A trial request is checking whether the upstream recovered
+    HalfOpen,
+    /// Requests fail fast
+    Open,
+}
+
+impl CircuitState {
+    const ALL: [CircuitState; 3] = [CircuitState::Closed, CircuitState::HalfOpen, CircuitState::Open];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CircuitState::Closed => "closed",
+            CircuitState::HalfOpen => "half_open",
+            CircuitState::Open => "open",
+        }
+    }
+}
+
+/// Requests and failures per second of the window, each bucket tagged with
+/// the second it counts
+#[derive(Debug, Default)]
+struct Window {
+    buckets: [(u64, u64, u64); WINDOW_SECS as usize],
+}
+
+impl Window {
+    fn add(&mut self, second: u64, failed: bool) {
+        let bucket = &mut self.buckets[(second % WINDOW_SECS) as usize];
+        if bucket.0 != second {
+            *bucket = (second, 0, 0);
+        }
+        bucket.1 += 1;
+        bucket.2 += u64::from(failed);
+    }
+
+    /// Requests and failures within the window ending at `second`
+    fn totals(&self, second: u64) -> (u64, u64) {
+        self.buckets
+            .iter()
+            .filter(|(tag, _, _)| second.saturating_sub(*tag) < WINDOW_SECS)
+            .fold((0, 0), |(requests, failures), (_, r, f)| (requests + r, failures + f))
+    }
+}
+
+#[derive(Debug)]
+struct Circuit {
+    state: CircuitState,
+    /// When the circuit last opened
+    opened: Instant,
+    /// Whether the half-open circuit's trial request is under way
+    trial: bool,
+    window: Window,
+}
+
+/// Opens when too many requests to an upstream fail, see the module docs
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    upstream: String,
+    /// Failure percentage that opens the circuit
+    threshold: u32,
+    cooldown: Duration,
+    start: Instant,
+    circuit: Mutex<Circuit>,
+    /// Transitions into each state, in [`CircuitState::ALL`] order
+    transitions: [AtomicU64; 3],
+    rejected: AtomicU64,
+}
+
+impl CircuitBreaker {
+    /// A closed circuit for `upstream`, opening at `threshold` percent of
+    /// failures and half-opening `cooldown` after that
+    pub fn new(upstream: &str, threshold: u32, cooldown: Duration) -> Self {
+        let now = Instant::now();
+        CircuitBreaker {
+            upstream: upstream.to_string(),
+            threshold: threshold.clamp(1, 100),
+            cooldown,
+            start: now,
+            circuit: Mutex::new(Circuit {
+                state: CircuitState::Closed,
+                opened: now,
+                trial: false,
+                window: Window::default(),
+            }),
+            transitions: Default::default(),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// A breaker with the same settings for another upstream
+    pub fn for_upstream(&self, upstream: &str) -> Self {
+        CircuitBreaker::new(upstream, self.threshold, self.cooldown)
+    }
+
+    pub fn upstream(&self) -> &str {
+        &self.upstream
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.circuit.lock().unwrap().state
+    }
+
+    /// Whether a request may go to the upstream; each one let through must
+    /// be followed by [`record`](Self::record)
+    pub fn allow(&self) -> bool {
+        let mut circuit = self.circuit.lock().unwrap();
+        let allowed = match circuit.state {
+            CircuitState::Closed => true,
+            CircuitState::Open if circuit.opened.elapsed() >= self.cooldown => {
+                self.transition(&mut circuit, CircuitState::HalfOpen);
+                circuit.trial = true;
+                true
+            }
+            CircuitState::HalfOpen if !circuit.trial => {
+                circuit.trial = true;
+                true
+            }
+            CircuitState::Open | CircuitState::HalfOpen => false,
+        };
+        if !allowed {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+        }
+        allowed
+    }
+
+    /// Count how a request that was let through turned out
+    pub fn record(&self, success: bool) {
+        let second = self.start.elapsed().as_secs();
+        let mut circuit = self.circuit.lock().unwrap();
+        circuit.window.add(second, !success);
+        match circuit.state {
+            CircuitState::HalfOpen => {
+                circuit.trial = false;
+                if success {
+                    circuit.window = Window::default();
+                    self.transition(&mut circuit, CircuitState::Closed);
+                } else {
+                    self.transition(&mut circuit, CircuitState::Open);
+                }
+            }
+            CircuitState::Closed if !success => {
+                let (requests, failures) = circuit.window.totals(second);
+                if requests >= MIN_REQUESTS && failures * 100 >= requests * u64::from(self.threshold) {
+                    self.transition(&mut circuit, CircuitState::Open);
+                }
+            }
+            CircuitState::Closed | CircuitState::Open => {}
+        }
+    }
+
+    fn transition(&self, circuit: &mut Circuit, state: CircuitState) {
+        let from = circuit.state;
+        circuit.state = state;
+        if state == CircuitState::Open {
+            circuit.opened = Instant::now();
+            let (requests, failures) = circuit.window.totals(self.start.elapsed().as_secs());
+            log::warn!(
+                "Circuit for {} is open after {} of {} requests failed, retrying in {}s",
+                self.upstream,
+                failures,
+                requests,
+                self.cooldown.as_secs()
+            );
+        } else {
+            log::info!("Circuit for {} went from {} to {}", self.upstream, from.as_str(), state.as_str());
+        }
+        let index = CircuitState::ALL.iter().position(|s| *s == state).unwrap_or_default();
+        self.transitions[index].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn to_json(&self) -> Value {
+        let circuit = self.circuit.lock().unwrap();
+        let (requests, failures) = circuit.window.totals(self.start.elapsed().as_secs());
+        let transitions: serde_json::Map<String, Value> = CircuitState::ALL
+            .iter()
+            .zip(&self.transitions)
+            .map(|(state, count)| (state.as_str().to_string(), json!(count.load(Ordering::Relaxed))))
+            .collect();
+        json!({
+            "upstream": self.upstream,
+            "state": circuit.state.as_str(),
+            "requests": requests,
+            "failures": failures,
+            "transitions": transitions,
+            "rejected": self.rejected.load(Ordering::Relaxed),
+        })
+    }
+}
+
+/// Append the state of `breakers` in Prometheus text format
+pub fn render_prometheus(breakers: &[Arc<CircuitBreaker>], out: &mut String) {
+    if breakers.is_empty() {
+        return;
+    }
+    write_header(out, "http_upstream_circuit_state", "gauge", "Circuit state (0 = closed, 1 = half-open, 2 = open)");
+    for breaker in breakers {
+        let state = CircuitState::ALL.iter().position(|s| *s == breaker.state()).unwrap_or_default();
+        let _ = writeln!(out, "http_upstream_circuit_state{{upstream=\"{}\"}} {}", breaker.upstream, state);
+    }
+    out.push('\n');
+    write_header(out, "http_upstream_circuit_transitions_total", "counter", "Circuit state changes, by new state");
+    for breaker in breakers {
+        for (state, count) in CircuitState::ALL.iter().zip(&breaker.transitions) {
+            let _ = writeln!(
+                out,
+                "http_upstream_circuit_transitions_total{{upstream=\"{}\",state=\"{}\"}} {}",
+                breaker.upstream,
+                state.as_str(),
+                count.load(Ordering::Relaxed)
+            );
+        }
+    }
+    out.push('\n');
+    write_header(out, "http_upstream_circuit_rejected_total", "counter", "Requests failed fast while the circuit was open");
+    for breaker in breakers {
+        let rejected = breaker.rejected.load(Ordering::Relaxed);
+        let _ = writeln!(out, "http_upstream_circuit_rejected_total{{upstream=\"{}\"}} {}", breaker.upstream, rejected);
+    }
+    out.push('\n');
+}
+
+/// The state of `breakers` for the JSON metrics
+pub fn render_json(breakers: &[Arc<CircuitBreaker>]) -> Value {
+    breakers.iter().map(|breaker| breaker.to_json()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_circuit_opens_on_errors_and_recovers_after_a_trial() {
+        let breaker = CircuitBreaker::new("127.0.0.1:9000", 50, Duration::from_millis(30));
+        // Too few requests to judge
+        for _ in 0..MIN_REQUESTS - 1 {
+            assert!(breaker.allow());
+            breaker.record(false);
+        }
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert!(!breaker.allow());
+
+        // One trial after the cool-down; its failure opens the circuit again
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        assert!(!breaker.allow());
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Open);
+
+        std::thread::sleep(Duration::from_millis(40));
+        assert!(breaker.allow());
+        breaker.record(true);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+        // The old failures no longer count
+        assert!(breaker.allow());
+        breaker.record(false);
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        let mut out = String::new();
+        render_prometheus(&[Arc::new(breaker)], &mut out);
+        assert!(out.contains("http_upstream_circuit_state{upstream=\"127.0.0.1:9000\"} 0\n"), "{}", out);
+        assert!(out.contains("{upstream=\"127.0.0.1:9000\",state=\"open\"} 2\n"));
+        assert!(out.contains("http_upstream_circuit_rejected_total{upstream=\"127.0.0.1:9000\"} 2\n"));
+    }
+}
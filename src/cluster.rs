@@ -0,0 +1,470 @@
+//! Prefork cluster mode
+//!
+//! With `--processes N` the server runs as a supervisor that starts N worker
+//! processes, each a copy of the binary with the same arguments accepting on
+//! the listening sockets the supervisor bound (SO_REUSEPORT sockets where
+//! supported, so the kernel spreads connections across them). A worker that
+//! crashes only takes its own connections down: the supervisor starts a
+//! replacement, after a growing delay if it keeps dying at startup.
+//!
+//! SIGHUP is passed on to every worker, and SIGTERM drains them before the
+//! supervisor exits. With `--admin-addr`, each worker serves its operational
+//! endpoints on a private loopback socket, and the supervisor answers on the
+//! admin address with the metrics of all workers, each series labeled with
+//! its `worker`, and with the health of the cluster.
+
+use crate::config::Config;
+use crate::metrics::write_metric;
+use crate::platform::{self, Signal};
+use crate::request::{HttpMethod, HttpRequest};
+use crate::response::HttpResponse;
+use crate::status::StatusCode;
+use crate::upgrade;
+use serde_json::json;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::Child;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Environment variable giving a worker its number
+const WORKER_ENV: &str = "HTTP_SERVER_WORKER";
+
+/// Environment variable carrying the supervisor's pid
+const SUPERVISOR_ENV: &str = "HTTP_SERVER_SUPERVISOR";
+
+/// Environment variable carrying the fd of a worker's private admin socket
+const ADMIN_FD_ENV: &str = "HTTP_SERVER_WORKER_ADMIN_FD";
+
+/// How often the supervisor checks on its workers
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A worker exiting sooner than this after it started is restarted only after
+/// a delay, doubling up to [`MAX_RESTART_DELAY`] while it keeps doing so
+const MIN_UPTIME: Duration = Duration::from_secs(5);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(30);
+
+/// How long to wait for a worker's metrics
+const SCRAPE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// This process's number, when it was started as a cluster worker
+pub fn worker_index() -> Option<usize> {
+    std::env::var(WORKER_ENV).ok()?.parse().ok()
+}
+
+/// The private admin socket the supervisor handed this worker, if any
+#[cfg(unix)]
+pub fn inherited_admin_listener() -> Option<TcpListener> {
+    let fd = std::env::var(ADMIN_FD_ENV).ok()?.parse().ok()?;
+    std::env::remove_var(ADMIN_FD_ENV);
+    upgrade::inherit_listener(fd)
+}
+
+#[cfg(not(unix))]
+pub fn inherited_admin_listener() -> Option<TcpListener> {
+    None
+}
+
+/// Exit along with the supervisor instead of serving on as an orphan
+#[cfg(target_os = "linux")]
+pub fn follow_supervisor() {
+    let supervisor = std::env::var(SUPERVISOR_ENV).ok().and_then(|pid| pid.parse::<libc::pid_t>().ok());
+    // SAFETY: prctl and getppid have no memory-safety preconditions
+    unsafe {
+        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+        // The supervisor may have died before the request took effect
+        if supervisor.is_some_and(|pid| libc::getppid() != pid) {
+            log::error!("Cluster supervisor exited before the worker started");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Workers notice a lost supervisor only on Linux
+#[cfg(not(target_os = "linux"))]
+pub fn follow_supervisor() {}
+
+/// A worker slot and the process currently filling it
+struct Worker {
+    index: usize,
+    /// Private admin socket, kept across restarts
+    admin: Option<TcpListener>,
+    admin_addr: Option<SocketAddr>,
+    process: Option<Child>,
+    started: Instant,
+    restarts: u64,
+    /// Wait before the next start after a quick exit
+    delay: Duration,
+    restart_at: Option<Instant>,
+}
+
+impl Worker {
+    fn pid(&self) -> Option<u32> {
+        self.process.as_ref().map(Child::id)
+    }
+}
+
+/// Starts, watches, and restarts the worker processes
+pub struct Supervisor {
+    listeners: Vec<TcpListener>,
+    workers: Mutex<Vec<Worker>>,
+    shutdown: AtomicBool,
+}
+
+impl Supervisor {
+    /// A supervisor for `count` workers serving on `listeners`, each with a
+    /// private admin socket if `admin` is set
+    pub fn new(listeners: Vec<TcpListener>, count: usize, admin: bool) -> io::Result<Self> {
+        let workers = (0..count)
+            .map(|index| {
+                let admin = admin.then(|| TcpListener::bind("127.0.0.1:0")).transpose()?;
+                Ok(Worker {
+                    index,
+                    admin_addr: admin.as_ref().map(TcpListener::local_addr).transpose()?,
+                    admin,
+                    process: None,
+                    started: Instant::now(),
+                    restarts: 0,
+                    delay: Duration::ZERO,
+                    restart_at: None,
+                })
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Supervisor {
+            listeners,
+            workers: Mutex::new(workers),
+            shutdown: AtomicBool::new(false),
+        })
+    }
+
+    fn start(&self, worker: &mut Worker) -> io::Result<()> {
+        let mut env = vec![
+            (WORKER_ENV, worker.index.to_string()),
+            (SUPERVISOR_ENV, std::process::id().to_string()),
+        ];
+        #[cfg(unix)]
+        if let Some(admin) = &worker.admin {
+            use std::os::fd::AsRawFd;
+            env.push((ADMIN_FD_ENV, admin.as_raw_fd().to_string()));
+        }
+        let process = upgrade::spawn_inheriting(&self.listeners, &worker.admin.iter().collect::<Vec<_>>(), &env)?;
+        log::info!("Started worker {} (pid {})", worker.index, process.id());
+        worker.process = Some(process);
+        worker.started = Instant::now();
+        worker.restart_at = None;
+        Ok(())
+    }
+
+    /// Start every worker, then keep them running until shutdown
+    pub fn run(&self) -> io::Result<()> {
+        for worker in self.workers.lock().unwrap().iter_mut() {
+            self.start(worker)?;
+        }
+        upgrade::notify_parent();
+        while !self.shutdown.load(Ordering::Relaxed) {
+            std::thread::sleep(POLL_INTERVAL);
+            for worker in self.workers.lock().unwrap().iter_mut() {
+                self.check(worker);
+            }
+        }
+        Ok(())
+    }
+
+    /// Notice a worker that exited and start its replacement when it is due
+    fn check(&self, worker: &mut Worker) {
+        let exited = worker.process.as_mut().and_then(|process| process.try_wait().ok().flatten());
+        if let (Some(status), Some(pid)) = (exited, worker.pid()) {
+            worker.process = None;
+            let uptime = worker.started.elapsed();
+            worker.delay = if uptime < MIN_UPTIME {
+                (worker.delay * 2).clamp(Duration::from_secs(1), MAX_RESTART_DELAY)
+            } else {
+                Duration::ZERO
+            };
+            log::error!(
+                "Worker {} (pid {}) exited after {:?}: {}; restarting in {:?}",
+                worker.index,
+                pid,
+                uptime,
+                status,
+                worker.delay
+            );
+            worker.restart_at = Some(Instant::now() + worker.delay);
+        }
+        if worker.restart_at.is_some_and(|at| Instant::now() >= at) && !self.shutdown.load(Ordering::Relaxed) {
+            worker.restarts += 1;
+            if let Err(e) = self.start(worker) {
+                log::error!("Failed to restart worker {}: {}", worker.index, e);
+                worker.restart_at = Some(Instant::now() + MAX_RESTART_DELAY);
+            }
+        }
+    }
+
+    /// Send `signal` to every running worker
+    #[cfg(unix)]
+    fn signal(&self, signal: libc::c_int) {
+        for pid in self.workers.lock().unwrap().iter().filter_map(Worker::pid) {
+            // SAFETY: kill has no memory-safety preconditions
+            if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+                log::warn!("Failed to signal worker process {}: {}", pid, io::Error::last_os_error());
+            }
+        }
+    }
+
+    /// Pass a configuration reload on to the workers
+    pub fn reload(&self) {
+        #[cfg(unix)]
+        self.signal(libc::SIGHUP);
+    }
+
+    /// Stop restarting workers and ask them to drain; [`run`](Self::run)
+    /// returns soon after
+    pub fn stop(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        #[cfg(unix)]
+        self.signal(libc::SIGTERM);
+    }
+
+    /// Wait up to `timeout` for the workers to exit, then kill the rest
+    pub fn wait(&self, timeout: Duration) {
+        let deadline = Instant::now() + timeout;
+        let processes: Vec<(usize, Child)> = self
+            .workers
+            .lock()
+            .unwrap()
+            .iter_mut()
+            .filter_map(|worker| Some((worker.index, worker.process.take()?)))
+            .collect();
+        for (index, mut process) in processes {
+            while process.try_wait().ok().flatten().is_none() {
+                if Instant::now() >= deadline {
+                    log::warn!("Worker {} (pid {}) did not exit in time, killing it", index, process.id());
+                    let _ = process.kill();
+                    let _ = process.wait();
+                    break;
+                }
+                std::thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Accept loop for the admin address; connections are handled one at a time
+    pub fn serve_admin(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = self.handle_admin(stream) {
+                        log::debug!("Admin connection failed: {}", e);
+                    }
+                }
+                Err(e) => log::error!("Failed to accept admin connection: {}", e),
+            }
+        }
+    }
+
+    fn handle_admin(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+        let mut reader = BufReader::new(stream);
+        let response = match HttpRequest::parse(&mut reader) {
+            Ok(request) => match (&request.method, request.path.split('?').next().unwrap_or_default()) {
+                (HttpMethod::GET, "/metrics") => HttpResponse::ok()
+                    .text(self.render_prometheus())
+                    .header("Content-Type", "text/plain; version=0.0.4"),
+                (HttpMethod::GET, "/health" | "/health/live" | "/health/ready") => self.health(),
+                _ => HttpResponse::not_found(),
+            },
+            Err(e) => e.to_response(),
+        };
+        response.header("Connection", "close").write_to(reader.get_mut())?;
+        Ok(())
+    }
+
+    /// 200 while any worker is running and the cluster isn't shutting down
+    fn health(&self) -> HttpResponse {
+        let workers = self.workers.lock().unwrap();
+        let running = workers.iter().filter(|worker| worker.process.is_some()).count();
+        let healthy = running > 0 && !self.shutdown.load(Ordering::Relaxed);
+        let status = match (healthy, running == workers.len()) {
+            (true, true) => "healthy",
+            (true, false) => "degraded",
+            (false, _) => "unhealthy",
+        };
+        let details: Vec<_> = workers
+            .iter()
+            .map(|worker| {
+                json!({
+                    "worker": worker.index,
+                    "pid": worker.pid(),
+                    "uptime_seconds": worker.pid().map(|_| worker.started.elapsed().as_secs()),
+                    "restarts": worker.restarts,
+                })
+            })
+            .collect();
+        let code = if healthy { StatusCode::Ok } else { StatusCode::ServiceUnavailable };
+        HttpResponse::new(code)
+            .json(&json!({ "status": status, "workers": details }))
+            .unwrap_or_else(|e| e.to_response())
+    }
+
+    /// The cluster's own metrics followed by every worker's, merged
+    pub fn render_prometheus(&self) -> String {
+        let (targets, running, restarts) = {
+            let workers = self.workers.lock().unwrap();
+            let targets: Vec<_> = workers
+                .iter()
+                .filter(|worker| worker.process.is_some())
+                .filter_map(|worker| Some((worker.index, worker.admin_addr?)))
+                .collect();
+            let running = workers.iter().filter(|worker| worker.process.is_some()).count();
+            (targets, running, workers.iter().map(|worker| worker.restarts).sum())
+        };
+        let mut out = String::new();
+        write_metric(&mut out, "http_cluster_workers", "gauge", "Worker processes running", running as u64);
+        write_metric(
+            &mut out,
+            "http_cluster_worker_restarts_total",
+            "counter",
+            "Worker processes restarted after exiting",
+            restarts,
+        );
+        let scrapes: Vec<(usize, String)> = targets
+            .into_iter()
+            .filter_map(|(index, addr)| match scrape(addr) {
+                Ok(text) => Some((index, text)),
+                Err(e) => {
+                    log::warn!("Failed to collect metrics from worker {}: {}", index, e);
+                    None
+                }
+            })
+            .collect();
+        out.push_str(&merge_prometheus(&scrapes));
+        out
+    }
+}
+
+/// Fetch `/metrics` from a worker's admin socket
+fn scrape(addr: SocketAddr) -> io::Result<String> {
+    let mut stream = TcpStream::connect_timeout(&addr, SCRAPE_TIMEOUT)?;
+    stream.set_read_timeout(Some(SCRAPE_TIMEOUT))?;
+    stream.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    match response.split_once("\r\n\r\n") {
+        Some((head, body)) if head.starts_with("HTTP/1.1 200 ") => Ok(body.to_string()),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "unexpected response to /metrics")),
+    }
+}
+
+/// Merge the Prometheus text of several workers into one exposition, adding a
+/// `worker` label to every sample and keeping each family's samples together
+pub fn merge_prometheus(scrapes: &[(usize, String)]) -> String {
+    /// A metric family: its name, HELP and TYPE lines, and samples
+    type Family = (String, Vec<String>, Vec<String>);
+    fn family(name: &str, families: &mut Vec<Family>) -> usize {
+        families.iter().position(|(family, _, _)| family == name).unwrap_or_else(|| {
+            families.push((name.to_string(), Vec::new(), Vec::new()));
+            families.len() - 1
+        })
+    }
+
+    // In order of first appearance
+    let mut families: Vec<Family> = Vec::new();
+
+    for (worker, text) in scrapes {
+        let mut current = None;
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            if let Some(comment) = line.strip_prefix("# ") {
+                let name = comment.split_whitespace().nth(1).unwrap_or_default();
+                let index = family(name, &mut families);
+                if !families[index].1.iter().any(|header| header == line) {
+                    families[index].1.push(line.to_string());
+                }
+                current = Some(index);
+                continue;
+            }
+            let name_end = line.find(['{', ' ']).unwrap_or(line.len());
+            let (name, rest) = line.split_at(name_end);
+            let labeled = match rest.strip_prefix('{') {
+                Some(rest) if rest.starts_with('}') => format!("{}{{worker=\"{}\"{}", name, worker, rest),
+                Some(rest) => format!("{}{{worker=\"{}\",{}", name, worker, rest),
+                None => format!("{}{{worker=\"{}\"}}{}", name, worker, rest),
+            };
+            let index = current.unwrap_or_else(|| family(name, &mut families));
+            families[index].2.push(labeled);
+        }
+    }
+
+    let mut out = String::new();
+    for (_, headers, samples) in families {
+        for line in headers.iter().chain(&samples) {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// Run as the supervisor of `config.processes` workers until SIGTERM, then
+/// drain them
+pub fn supervise(config: &Config, listeners: Vec<TcpListener>, admin: Option<TcpListener>) -> io::Result<()> {
+    let upgrade_listeners = listeners.iter().map(TcpListener::try_clone).collect::<io::Result<Vec<_>>>()?;
+    let supervisor = Arc::new(Supervisor::new(listeners, config.processes, admin.is_some())?);
+
+    let reloading = Arc::clone(&supervisor);
+    platform::on_signal(Signal::Hangup, move || {
+        log::info!("Received SIGHUP, reloading the workers' configuration");
+        reloading.reload();
+    })?;
+    platform::on_signal(Signal::Upgrade, move || {
+        log::info!("Received SIGUSR2, starting a new cluster");
+        if let Err(e) = upgrade::spawn_successor(&upgrade_listeners) {
+            log::error!("Binary upgrade failed, continuing to serve: {}", e);
+        }
+    })?;
+    let stopping = Arc::clone(&supervisor);
+    platform::on_signal(Signal::Terminate, move || {
+        log::info!("Received shutdown signal, draining the workers...");
+        stopping.stop();
+    })?;
+
+    if let Some(admin) = admin {
+        let supervisor = Arc::clone(&supervisor);
+        std::thread::Builder::new()
+            .name("admin-listener".to_string())
+            .spawn(move || supervisor.serve_admin(admin))?;
+    }
+
+    supervisor.run()?;
+    // The workers wait out their drain delay and timeout before exiting
+    let grace = Duration::from_secs(config.drain_delay_secs + config.drain_timeout_secs + 5);
+    supervisor.wait(grace);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_worker_metrics_are_merged_by_family() {
+        let worker = |requests: u64| {
+            format!(
+                "# HELP http_requests_total Total requests\n# TYPE http_requests_total counter\n\
+                 http_requests_total{{method=\"GET\",status=\"2xx\"}} {}\n\n\
+                 # HELP uptime_seconds Uptime\n# TYPE uptime_seconds gauge\nuptime_seconds 9\n\n",
+                requests
+            )
+        };
+        let merged = merge_prometheus(&[(0, worker(3)), (1, worker(5))]);
+        assert_eq!(
+            merged,
+            "# HELP http_requests_total Total requests\n# TYPE http_requests_total counter\n\
+             http_requests_total{worker=\"0\",method=\"GET\",status=\"2xx\"} 3\n\
+             http_requests_total{worker=\"1\",method=\"GET\",status=\"2xx\"} 5\n\n\
+             # HELP uptime_seconds Uptime\n# TYPE uptime_seconds gauge\n\
+             uptime_seconds{worker=\"0\"} 9\nuptime_seconds{worker=\"1\"} 9\n\n"
+        );
+    }
+}
@@ -1,15 +1,21 @@
 use crate::error::{Result, ServerError};
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::router::Next;
 use brotli::enc::BrotliEncoderParams;
+use clap::ValueEnum;
 use flate2::write::{DeflateEncoder, GzEncoder};
 use flate2::Compression as FlateCompression;
 use std::io::Write;
 
 /// Compression algorithms supported by the server
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, ValueEnum)]
 pub enum Compression {
     Gzip,
     Deflate,
+    #[value(name = "br")]
     Brotli,
+    #[value(skip)]
     None,
 }
 
@@ -39,17 +45,27 @@ impl Compression {
 
     /// Compress data using the selected algorithm
     pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        self.compress_level(data, None)
+    }
+
+    /// Compress data at `level`: 0-9 for gzip and deflate, 0-11 for brotli,
+    /// higher values being capped; `None` is each algorithm's default
+    pub fn compress_level(&self, data: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
         match self {
-            Compression::Gzip => Self::gzip_compress(data),
-            Compression::Deflate => Self::deflate_compress(data),
-            Compression::Brotli => Self::brotli_compress(data),
+            Compression::Gzip => Self::gzip_compress(data, level),
+            Compression::Deflate => Self::deflate_compress(data, level),
+            Compression::Brotli => Self::brotli_compress(data, level),
             Compression::None => Ok(data.to_vec()),
         }
     }
 
+    fn flate_level(level: Option<u32>) -> FlateCompression {
+        level.map_or(FlateCompression::default(), |level| FlateCompression::new(level.min(9)))
+    }
+
     /// Compress data using gzip
-    fn gzip_compress(data: &[u8]) -> Result<Vec<u8>> {
-        let mut encoder = GzEncoder::new(Vec::new(), FlateCompression::default());
+    fn gzip_compress(data: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
+        let mut encoder = GzEncoder::new(Vec::new(), Self::flate_level(level));
         encoder
             .write_all(data)
             .map_err(|e| ServerError::CompressionError(format!("Gzip compression failed: {}", e)))?;
@@ -59,8 +75,8 @@ impl Compression {
     }
 
     /// Compress data using deflate
-    fn deflate_compress(data: &[u8]) -> Result<Vec<u8>> {
-        let mut encoder = DeflateEncoder::new(Vec::new(), FlateCompression::default());
+    fn deflate_compress(data: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Self::flate_level(level));
         encoder.write_all(data).map_err(|e| {
             ServerError::CompressionError(format!("Deflate compression failed: {}", e))
         })?;
@@ -70,9 +86,12 @@ impl Compression {
     }
 
     /// Compress data using brotli
-    fn brotli_compress(data: &[u8]) -> Result<Vec<u8>> {
+    fn brotli_compress(data: &[u8], level: Option<u32>) -> Result<Vec<u8>> {
         let mut output = Vec::new();
-        let params = BrotliEncoderParams::default();
+        let mut params = BrotliEncoderParams::default();
+        if let Some(level) = level {
+            params.quality = level.min(11) as i32;
+        }
 
         brotli::BrotliCompress(
             &mut std::io::Cursor::new(data),
@@ -87,27 +106,131 @@ impl Compression {
     }
 }
 
+/// Whether a content type benefits from compression
+pub fn is_compressible(content_type: &str) -> bool {
+    let media_type = content_type.split(';').next().unwrap_or_default().trim();
+    media_type.starts_with("text/")
+        || matches!(media_type, "application/javascript" | "application/json" | "image/svg+xml")
+}
+
+/// Set on a request once a policy has decided how to encode its response,
+/// so policies of enclosing routers leave it alone
+struct PolicyApplied;
+
+/// Which encodings responses may use and how hard to compress them
+///
+/// A router's policy (see [`Router::compression`](crate::router::Router::compression))
+/// applies to its built-in endpoints and, as middleware, to its routes.
+/// Mount a router with its own policy to configure a group of routes
+/// differently; the innermost policy decides.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompressionPolicy {
+    pub enabled: bool,
+    /// Encodings the client may choose from, in its order of preference
+    pub algorithms: Vec<Compression>,
+    /// See [`Compression::compress_level`]
+    pub level: Option<u32>,
+    /// Bodies shorter than this are sent as they are
+    pub min_size: usize,
+}
+
+impl Default for CompressionPolicy {
+    fn default() -> Self {
+        CompressionPolicy {
+            enabled: true,
+            algorithms: vec![Compression::Brotli, Compression::Gzip, Compression::Deflate],
+            level: None,
+            min_size: 0,
+        }
+    }
+}
+
+impl CompressionPolicy {
+    /// Never compress
+    pub fn disabled() -> Self {
+        CompressionPolicy {
+            enabled: false,
+            ..Self::default()
+        }
+    }
+
+    pub fn algorithms(mut self, algorithms: &[Compression]) -> Self {
+        self.algorithms = algorithms.to_vec();
+        self
+    }
+
+    pub fn level(mut self, level: u32) -> Self {
+        self.level = Some(level);
+        self
+    }
+
+    pub fn min_size(mut self, bytes: usize) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// The first encoding in the client's Accept-Encoding list that the
+    /// policy allows
+    pub fn negotiate(&self, accepted: &[String]) -> Compression {
+        if !self.enabled {
+            return Compression::None;
+        }
+        let allowed: Vec<String> = accepted
+            .iter()
+            .filter(|encoding| self.algorithms.iter().any(|algorithm| algorithm.name() == encoding.as_str()))
+            .cloned()
+            .collect();
+        Compression::from_accept_encoding(&allowed)
+    }
+
+    /// Run the handler, then compress its response if nothing closer to
+    /// the handler has decided already and the response is worth it: a
+    /// buffered, unencoded, compressible body of at least `min_size` bytes.
+    /// Responses that already vary on Accept-Encoding, like those of the
+    /// built-in endpoints, were negotiated by their handler.
+    pub fn handle(&self, request: &HttpRequest, next: Next<'_>) -> Result<HttpResponse> {
+        let response = next.run(request)?;
+        if request.extension::<PolicyApplied>().is_some() {
+            return Ok(response);
+        }
+        request.insert_extension(PolicyApplied);
+
+        let eligible = self.enabled
+            && !response.is_streamed()
+            && response.get_header("Content-Encoding").is_none()
+            && !response.get_header("Vary").is_some_and(|vary| vary.to_ascii_lowercase().contains("accept-encoding"))
+            && !matches!(response.status_code(), 204 | 206 | 304)
+            && response.body_len() >= self.min_size.max(1)
+            && is_compressible(response.get_header("Content-Type").unwrap_or_default());
+        if !eligible {
+            return Ok(response);
+        }
+        let compression = self.negotiate(&request.get_accepted_encodings());
+        response.compress_level(compression, self.level)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn test_gzip_compression() {
-        let data = b"Hello, World! This is a test string for compression.";
+        let data = b"Hello, World! This is a test string for compression. Hello, World! This is a test string for compression.";
         let compressed = Compression::Gzip.compress(data).unwrap();
         assert!(compressed.len() < data.len());
     }
 
     #[test]
     fn test_deflate_compression() {
-        let data = b"Hello, World! This is a test string for compression.";
+        let data = b"Hello, World! This is a test string for compression. Hello, World! This is a test string for compression.";
         let compressed = Compression::Deflate.compress(data).unwrap();
         assert!(compressed.len() < data.len());
     }
 
     #[test]
     fn test_brotli_compression() {
-        let data = b"Hello, World! This is a test string for compression.";
+        let data = b"Hello, World! This is a test string for compression. Hello, World! This is a test string for compression.";
         let compressed = Compression::Brotli.compress(data).unwrap();
         assert!(compressed.len() < data.len());
     }
@@ -123,4 +246,22 @@ mod tests {
         let encodings = vec!["identity".to_string()];
         assert_eq!(Compression::from_accept_encoding(&encodings), Compression::None);
     }
+
+    #[test]
+    fn test_policy_negotiation_and_levels() {
+        let accepted = vec!["br".to_string(), "deflate".to_string(), "gzip".to_string()];
+        assert_eq!(CompressionPolicy::default().negotiate(&accepted), Compression::Brotli);
+        let policy = CompressionPolicy::default().algorithms(&[Compression::Gzip, Compression::Deflate]);
+        assert_eq!(policy.negotiate(&accepted), Compression::Deflate);
+        assert_eq!(policy.negotiate(&["br".to_string()]), Compression::None);
+        assert_eq!(CompressionPolicy::disabled().negotiate(&accepted), Compression::None);
+
+        let data = "Hello, World! This is a test string for compression. ".repeat(20);
+        let fastest = Compression::Gzip.compress_level(data.as_bytes(), Some(0)).unwrap();
+        let best = Compression::Gzip.compress_level(data.as_bytes(), Some(9)).unwrap();
+        assert!(best.len() < fastest.len());
+        assert_eq!(Compression::Gzip.compress_level(data.as_bytes(), Some(11)).unwrap(), best);
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(!is_compressible("image/png"));
+    }
 }
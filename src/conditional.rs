@@ -0,0 +1,359 @@
+//! Validators and conditional requests (RFC 9110 section 13)
+//!
+//! Files are served with an `ETag`, computed as `--etag` chooses, and a
+//! `Last-Modified` date. Clients send them back in precondition headers such as
+//! `If-Range`, so a resumed download only gets partial content while the file
+//! is still the version the first part came from, and `If-Match` or
+//! `If-Unmodified-Since`, so an upload or delete based on a stale copy fails
+//! with 412 instead of silently replacing someone else's change.
+
+use crate::compression::Compression;
+use crate::error::{Result, ServerError};
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use chrono::{DateTime, NaiveDateTime, Utc};
+use clap::ValueEnum;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The validator index is emptied when it reaches this many files
+const MAX_ENTRIES: usize = 10_000;
+
+/// HTTP-date formats: the preferred IMF-fixdate, then the obsolete RFC 850
+/// and asctime forms recipients must still accept
+const HTTP_DATE_FORMATS: [&str; 3] = ["%a, %d %b %Y %H:%M:%S GMT", "%A, %d-%b-%y %H:%M:%S GMT", "%a %b %e %H:%M:%S %Y"];
+
+/// How file entity tags are computed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum EtagStrategy {
+    /// Weak tags from the size and modification time; free to compute, but
+    /// never satisfy `If-Match` or an `If-Range` tag, which need strong ones
+    Weak,
+    /// Strong tags from a hash of the contents; the file is read once per
+    /// version, and copies with new mtimes (NFS, container rebuilds) keep their tag
+    Strong,
+    /// No `ETag` headers; `Last-Modified` still allows date preconditions
+    Off,
+}
+
+impl EtagStrategy {
+    /// The tag for a file of `len` bytes modified at `modified`;
+    /// `contents` is only called for strong tags
+    pub fn etag(&self, len: u64, modified: SystemTime, contents: impl FnOnce() -> io::Result<Vec<u8>>) -> io::Result<Option<String>> {
+        Ok(match self {
+            EtagStrategy::Weak => Some(format!("W/\"{:x}-{:x}\"", len, unix_secs(modified))),
+            EtagStrategy::Strong => {
+                let hash = format!("{:x}", Sha256::digest(contents()?));
+                Some(format!("\"{}\"", &hash[..32]))
+            }
+            EtagStrategy::Off => None,
+        })
+    }
+}
+
+/// The validators of one version of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Validators {
+    modified: SystemTime,
+    etag: Option<String>,
+}
+
+impl Validators {
+    /// Validators from a modification time and the unencoded file's tag
+    pub fn new(modified: SystemTime, etag: Option<String>) -> Self {
+        Validators { modified, etag }
+    }
+
+    /// When this version was written
+    pub fn modified(&self) -> SystemTime {
+        self.modified
+    }
+
+    /// Entity tag for the file's bytes, if tags are enabled; a compressed
+    /// representation is a different entity, so it gets its own tag
+    pub fn etag(&self, compression: Compression) -> Option<String> {
+        let etag = self.etag.as_ref()?;
+        match compression {
+            Compression::None => Some(etag.clone()),
+            _ => Some(format!("{}-{}\"", etag.strip_suffix('"').unwrap_or(etag), compression.name())),
+        }
+    }
+
+    /// `Last-Modified` value
+    pub fn last_modified(&self) -> String {
+        http_date(self.modified)
+    }
+
+    /// Add `ETag`, if tags are enabled, and `Last-Modified` to a response
+    /// carrying this version with `compression` applied
+    pub fn apply(&self, response: HttpResponse, compression: Compression) -> HttpResponse {
+        let response = response.header("Last-Modified", self.last_modified());
+        match self.etag(compression) {
+            Some(etag) => response.header("ETag", etag),
+            None => response,
+        }
+    }
+
+    /// Whether an `If-Range` value still identifies the unencoded file, in
+    /// which case the requested ranges may be sent
+    ///
+    /// Entity tags must match exactly (weak tags never do); a date must equal
+    /// the modification time to the second.
+    pub fn if_range_matches(&self, value: &str) -> bool {
+        let value = value.trim();
+        if value.starts_with('"') || value.starts_with("W/") {
+            return strong_match(value, self.etag(Compression::None));
+        }
+        parse_http_date(value).map(unix_secs) == Some(unix_secs(self.modified))
+    }
+
+    /// Whether `tag` is the strong entity tag of any representation of this version
+    fn matches(&self, tag: &str) -> bool {
+        [Compression::None, Compression::Gzip, Compression::Deflate, Compression::Brotli]
+            .into_iter()
+            .any(|compression| strong_match(tag, self.etag(compression)))
+    }
+}
+
+/// Strong comparison (RFC 9110 section 8.8.3.2): both tags strong and equal
+fn strong_match(tag: &str, etag: Option<String>) -> bool {
+    !tag.starts_with("W/") && etag.as_deref() == Some(tag)
+}
+
+/// Validators of the files served so far, computed with one
+/// [`EtagStrategy`] and reused while a file keeps its size and mtime
+///
+/// An [`index`](Self::index)ed directory has every file's validators computed
+/// up front and recomputed by [`reindex`](Self::reindex) as changes come in,
+/// so requests find them ready without hashing.
+pub struct FileValidators {
+    strategy: RwLock<EtagStrategy>,
+    entries: Mutex<HashMap<PathBuf, (u64, Validators)>>,
+    indexed: AtomicBool,
+}
+
+impl FileValidators {
+    pub fn new(strategy: EtagStrategy) -> Self {
+        FileValidators {
+            strategy: RwLock::new(strategy),
+            entries: Mutex::new(HashMap::new()),
+            indexed: AtomicBool::new(false),
+        }
+    }
+
+    pub fn strategy(&self) -> EtagStrategy {
+        *self.strategy.read().unwrap()
+    }
+
+    /// Compute tags with `strategy` from now on
+    pub fn set_strategy(&self, strategy: EtagStrategy) {
+        *self.strategy.write().unwrap() = strategy;
+        self.entries.lock().unwrap().clear();
+    }
+
+    /// Validators for the file at `path` as it is now
+    pub fn get(&self, path: &Path) -> io::Result<Validators> {
+        let metadata = fs::metadata(path)?;
+        let (len, modified) = (metadata.len(), metadata.modified()?);
+        if let Some((cached_len, validators)) = self.entries.lock().unwrap().get(path) {
+            if *cached_len == len && validators.modified == modified {
+                return Ok(validators.clone());
+            }
+        }
+
+        let etag = self.strategy().etag(len, modified, || fs::read(path))?;
+        let validators = Validators::new(modified, etag);
+        let mut entries = self.entries.lock().unwrap();
+        // An index holds one entry per file of the directory, however many
+        if entries.len() >= MAX_ENTRIES && !self.indexed.load(Ordering::Relaxed) {
+            entries.clear();
+        }
+        entries.insert(path.to_path_buf(), (len, validators.clone()));
+        Ok(validators)
+    }
+
+    /// The validators last computed for `path` without looking at the file;
+    /// only correct while every change is passed to [`invalidate`](Self::invalidate)
+    pub fn current(&self, path: &Path) -> Option<Validators> {
+        self.entries.lock().unwrap().get(path).map(|(_, validators)| validators.clone())
+    }
+
+    /// Forget the validators of every path `matches` accepts
+    pub fn invalidate(&self, matches: impl Fn(&Path) -> bool) {
+        self.entries.lock().unwrap().retain(|path, _| !matches(path));
+    }
+
+    /// Compute the validators of every file directly in `directory` ahead
+    /// of requests, and keep them from now on; returns how many files there are
+    pub fn index(&self, directory: &Path) -> io::Result<usize> {
+        self.indexed.store(true, Ordering::Relaxed);
+        let mut count = 0;
+        for entry in fs::read_dir(directory)? {
+            let path = entry?.path();
+            if path.is_file() {
+                self.get(&path)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Recompute the validators of a changed file in an indexed directory,
+    /// or forget them if it is gone
+    pub fn reindex(&self, path: &Path) {
+        if !self.indexed.load(Ordering::Relaxed) {
+            return;
+        }
+        if let Err(e) = self.get(path) {
+            self.entries.lock().unwrap().remove(path);
+            if e.kind() != io::ErrorKind::NotFound {
+                log::warn!("Cannot index {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+/// Evaluate `If-Match` and `If-Unmodified-Since` (RFC 9110 section 13.2.2)
+/// before changing a resource; `current` is `None` if it does not exist yet
+///
+/// `If-Match: *` requires the resource to exist. `If-Unmodified-Since` is
+/// only consulted without `If-Match`, and ignored for a missing resource or
+/// an invalid date.
+pub fn check_preconditions(request: &HttpRequest, current: Option<&Validators>) -> Result<()> {
+    if let Some(value) = request.headers.combined("If-Match") {
+        let matched = value.split(',').map(str::trim).any(|tag| match current {
+            Some(_) if tag == "*" => true,
+            Some(validators) => validators.matches(tag),
+            None => false,
+        });
+        if matched {
+            return Ok(());
+        }
+        let detail = if current.is_some() { "resource has changed (If-Match)" } else { "resource does not exist" };
+        return Err(ServerError::PreconditionFailed(detail.to_string()));
+    }
+
+    let since = request.get_header("If-Unmodified-Since").and_then(parse_http_date);
+    match (current, since) {
+        (Some(validators), Some(since)) if unix_secs(validators.modified) > unix_secs(since) => {
+            Err(ServerError::PreconditionFailed("resource has changed (If-Unmodified-Since)".to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Whole seconds since the epoch, the precision of HTTP-dates
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs()
+}
+
+/// Format a time as an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`
+pub fn http_date(time: SystemTime) -> String {
+    DateTime::<Utc>::from(time).format(HTTP_DATE_FORMATS[0]).to_string()
+}
+
+/// Parse an HTTP-date in any of the three formats RFC 9110 allows
+pub fn parse_http_date(value: &str) -> Option<SystemTime> {
+    let value = value.trim();
+    let parsed = HTTP_DATE_FORMATS.iter().find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())?;
+    let secs = u64::try_from(parsed.and_utc().timestamp()).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_http_dates() {
+        let time = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(http_date(time), "Sun, 06 Nov 1994 08:49:37 GMT");
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(time));
+        assert_eq!(parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT"), Some(time));
+        assert_eq!(parse_http_date("Sun Nov  6 08:49:37 1994"), Some(time));
+        assert_eq!(parse_http_date("yesterday"), None);
+    }
+
+    #[test]
+    fn test_if_range_matches_the_current_version_only() {
+        let modified = UNIX_EPOCH + Duration::new(784111777, 500);
+        let etag = EtagStrategy::Strong.etag(5, modified, || Ok(b"hello".to_vec())).unwrap();
+        let validators = Validators::new(modified, etag);
+        let etag = validators.etag(Compression::None).unwrap();
+        assert_eq!(etag, "\"2cf24dba5fb0a30e26e83b2ac5b9e29e\"");
+        assert_eq!(validators.etag(Compression::Gzip).unwrap(), "\"2cf24dba5fb0a30e26e83b2ac5b9e29e-gzip\"");
+
+        assert!(validators.if_range_matches(&etag));
+        assert!(!validators.if_range_matches(&format!("W/{}", etag)));
+        assert!(!validators.if_range_matches(&validators.etag(Compression::Gzip).unwrap()));
+        assert!(validators.if_range_matches("Sun, 06 Nov 1994 08:49:37 GMT"));
+        assert!(!validators.if_range_matches("Sun, 06 Nov 1994 08:49:38 GMT"));
+        assert!(!validators.if_range_matches("not a date"));
+    }
+
+    #[test]
+    fn test_etag_strategies() {
+        let modified = UNIX_EPOCH + Duration::from_secs(784111777);
+        let unread = || -> io::Result<Vec<u8>> { panic!("contents read") };
+        let weak = EtagStrategy::Weak.etag(5, modified, unread).unwrap();
+        assert_eq!(weak.as_deref(), Some("W/\"5-2ebc98a1\""));
+        assert_eq!(EtagStrategy::Off.etag(5, modified, unread).unwrap(), None);
+
+        // Weak tags never pass strong comparison
+        let validators = Validators::new(modified, weak.clone());
+        assert!(!validators.if_range_matches(weak.as_deref().unwrap()));
+        assert!(!validators.matches(weak.as_deref().unwrap()));
+        assert_eq!(validators.etag(Compression::Brotli).as_deref(), Some("W/\"5-2ebc98a1-br\""));
+        assert_eq!(Validators::new(modified, None).etag(Compression::Gzip), None);
+    }
+
+    #[test]
+    fn test_strong_etags_follow_contents() {
+        let dir = std::env::temp_dir().join(format!("http-server-etags-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        std::fs::write(&a, "same").unwrap();
+        std::fs::write(&b, "same").unwrap();
+        std::fs::File::options().write(true).open(&b).unwrap().set_modified(UNIX_EPOCH).unwrap();
+
+        let validators = FileValidators::new(EtagStrategy::Strong);
+        let tag = validators.get(&a).unwrap().etag(Compression::None);
+        assert_eq!(validators.get(&b).unwrap().etag(Compression::None), tag);
+        assert_eq!(validators.current(&a).unwrap().etag(Compression::None), tag);
+
+        std::fs::write(&a, "changed").unwrap();
+        assert_ne!(validators.get(&a).unwrap().etag(Compression::None), tag);
+        validators.invalidate(|path| path == a);
+        assert!(validators.current(&a).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_index_is_computed_up_front_and_follows_changes() {
+        let dir = std::env::temp_dir().join(format!("http-server-etag-index-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        let (a, b) = (dir.join("a.txt"), dir.join("b.txt"));
+        std::fs::write(&a, "hello").unwrap();
+        std::fs::write(&b, "world").unwrap();
+
+        let validators = FileValidators::new(EtagStrategy::Strong);
+        assert_eq!(validators.index(&dir).unwrap(), 2);
+        let tag = validators.current(&a).unwrap().etag(Compression::None);
+        assert_eq!(tag.as_deref(), Some("\"2cf24dba5fb0a30e26e83b2ac5b9e29e\""));
+        assert!(validators.current(&b).is_some());
+
+        std::fs::write(&a, "changed").unwrap();
+        validators.reindex(&a);
+        assert_ne!(validators.current(&a).unwrap().etag(Compression::None), tag);
+        std::fs::remove_file(&b).unwrap();
+        validators.reindex(&b);
+        assert!(validators.current(&b).is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
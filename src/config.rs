@@ -1,14 +1,54 @@
-use clap::Parser;
-use std::path::PathBuf;
+use crate::access_log::AccessLogFormat;
+use crate::audit::VerifyAuditArgs;
+use crate::check::CheckArgs;
+use crate::compression::{Compression, CompressionPolicy};
+use crate::conditional::EtagStrategy;
+use crate::cors::{Cors, CorsPolicy, CorsRule};
+use crate::digest::DigestAlgorithm;
+use crate::ip_filter::{self, DenyAction, IpNet};
+use crate::load_test::BenchArgs;
+use crate::log_file::{RotatingFile, Rotation, RotationPolicy};
+use crate::net::{SocketOptions, TcpKeepalive};
+use crate::precompress::PrecompressArgs;
+use crate::statsd::StatsdFormat;
+use crate::template::Templates;
+use crate::throttle::{RateLimit, ThrottleStats};
+use crate::timeout::RouteTimeouts;
+use crate::upload_limit::UploadLimiter;
+use clap::error::ErrorKind;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use std::ffi::OsString;
+use std::io::Write;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How connections are accepted and served
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Runtime {
+    /// Blocking I/O on a fixed pool of worker threads
+    Threads,
+    /// Async I/O on a Tokio runtime (requires the `tokio` cargo feature)
+    Tokio,
+    /// One io_uring event loop per acceptor (experimental; Linux with the `io-uring` cargo feature)
+    IoUring,
+}
 
 /// Configuration for the HTTP server
 #[derive(Parser, Debug, Clone)]
 #[command(
     name = "http-server",
     about = "A production-ready HTTP server written in Rust",
-    version = "1.0.0"
+    version = "1.0.0",
+    long_version = crate::build_info::LONG_VERSION,
+    args_override_self = true
 )]
 pub struct Config {
+    /// Read settings from a TOML file (keys are option names, e.g. `port = 8080`)
+    #[arg(long, env = "CONFIG_FILE")]
+    pub config: Option<PathBuf>,
+
     /// Port to bind the server to
     #[arg(short, long, default_value = "4221", env = "HTTP_PORT")]
     pub port: u16,
@@ -21,31 +61,614 @@ pub struct Config {
     #[arg(short, long, default_value = ".", env = "FILE_DIRECTORY")]
     pub directory: String,
 
-    /// Number of worker threads for handling connections
+    /// Serve files for download only; uploads and deletes get 405
+    #[arg(long, default_value = "false", env = "READ_ONLY")]
+    pub read_only: bool,
+
+    /// Turn off the /files/ endpoints entirely, e.g. when only serving an API
+    #[arg(long, default_value = "false", env = "DISABLE_FILES")]
+    pub disable_files: bool,
+
+    /// Run CGI scripts from this directory for requests under --cgi-prefix
+    #[arg(long, env = "CGI_DIR")]
+    pub cgi_dir: Option<String>,
+
+    /// URL prefix for CGI scripts
+    #[arg(long, default_value = "/cgi-bin", env = "CGI_PREFIX")]
+    pub cgi_prefix: String,
+
+    /// Kill CGI scripts still running after this many seconds and answer 504
+    #[arg(long, default_value = "30", env = "CGI_TIMEOUT_SECS")]
+    pub cgi_timeout_secs: u64,
+
+    /// Pass requests under --fastcgi-prefix to the FastCGI application at
+    /// host:port or unix:/path (e.g. php-fpm)
+    #[arg(long, env = "FASTCGI_ADDR")]
+    pub fastcgi_addr: Option<String>,
+
+    /// URL prefix for the FastCGI application
+    #[arg(long, default_value = "/php", env = "FASTCGI_PREFIX")]
+    pub fastcgi_prefix: String,
+
+    /// Script directory as the FastCGI application sees it (defaults to --directory)
+    #[arg(long, env = "FASTCGI_ROOT")]
+    pub fastcgi_root: Option<String>,
+
+    /// Answer 504 when the FastCGI application is silent for this many seconds
+    #[arg(long, default_value = "60", env = "FASTCGI_TIMEOUT_SECS")]
+    pub fastcgi_timeout_secs: u64,
+
+    /// Mirror requests to a second FastCGI application at host:port or
+    /// unix:/path, discarding its responses, e.g. to try a new version
+    #[arg(long, env = "FASTCGI_SHADOW_ADDR")]
+    pub fastcgi_shadow_addr: Option<String>,
+
+    /// Percentage of FastCGI requests mirrored to --fastcgi-shadow-addr
+    #[arg(long, default_value = "100", value_parser = clap::value_parser!(u32).range(0..=100), env = "FASTCGI_SHADOW_PERCENT")]
+    pub fastcgi_shadow_percent: u32,
+
+    /// Send a share of the clients to a second FastCGI application at
+    /// host:port or unix:/path, e.g. a canary release
+    #[arg(long, env = "FASTCGI_CANARY_ADDR")]
+    pub fastcgi_canary_addr: Option<String>,
+
+    /// Percentage of clients sent to --fastcgi-canary-addr
+    #[arg(long, default_value = "5", value_parser = clap::value_parser!(u32).range(0..=100), env = "FASTCGI_CANARY_PERCENT")]
+    pub fastcgi_canary_percent: u32,
+
+    /// Cookie that picks a client's side of the canary split, instead of its
+    /// address (clients without it are split by address)
+    #[arg(long, env = "FASTCGI_CANARY_COOKIE")]
+    pub fastcgi_canary_cookie: Option<String>,
+
+    /// Retry GET and HEAD requests this many times when the FastCGI
+    /// application can't be reached or answers 502 or 503
+    #[arg(long, default_value = "0", env = "FASTCGI_RETRIES")]
+    pub fastcgi_retries: u32,
+
+    /// Answer FastCGI requests with 503 without trying the application while
+    /// this percentage of its recent requests failed (0 = never)
+    #[arg(long, default_value = "0", value_parser = clap::value_parser!(u32).range(0..=100), env = "FASTCGI_BREAKER_PERCENT")]
+    pub fastcgi_breaker_percent: u32,
+
+    /// Seconds an open FastCGI circuit waits before letting a trial request through
+    #[arg(long, default_value = "30", env = "FASTCGI_BREAKER_COOLDOWN_SECS")]
+    pub fastcgi_breaker_cooldown_secs: u64,
+
+    /// Add a header to responses for matching paths, as PATTERN=Name: value, e.g.
+    /// '/assets/*=Access-Control-Allow-Origin: *' (repeatable)
+    #[arg(long, env = "RESPONSE_HEADER")]
+    pub response_header: Vec<String>,
+
+    /// Origins allowed to make cross-origin requests to any path, e.g.
+    /// https://app.example.com; * allows any, https://*.example.com any subdomain
+    #[arg(long, env = "CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Vec<String>,
+
+    /// Methods allowed from --cors-origins
+    #[arg(long, default_value = "GET,HEAD,POST,PUT,PATCH,DELETE", env = "CORS_METHODS", value_delimiter = ',')]
+    pub cors_methods: Vec<String>,
+
+    /// Request headers allowed from --cors-origins; empty allows any the browser asks for
+    #[arg(long, env = "CORS_HEADERS", value_delimiter = ',')]
+    pub cors_headers: Vec<String>,
+
+    /// Response headers scripts from --cors-origins may read
+    #[arg(long, env = "CORS_EXPOSE_HEADERS", value_delimiter = ',')]
+    pub cors_expose_headers: Vec<String>,
+
+    /// Seconds browsers may cache preflight answers (Access-Control-Max-Age)
+    #[arg(long, env = "CORS_MAX_AGE_SECS")]
+    pub cors_max_age_secs: Option<u64>,
+
+    /// Allow cookies and HTTP authentication from --cors-origins, which must be explicit
+    #[arg(long, default_value = "false", env = "CORS_CREDENTIALS")]
+    pub cors_credentials: bool,
+
+    /// CORS policy for matching paths, as PATTERN=SETTINGS, e.g.
+    /// '/api/*=origins=https://app.example.com;methods=GET,POST;max-age=600;credentials';
+    /// checked in order before the --cors-origins policy (repeatable)
+    #[arg(long, env = "CORS_RULE")]
+    pub cors_rule: Vec<String>,
+
+    /// Serve a persistent JSON key-value store kept in this directory under --kv-prefix
+    #[arg(long, env = "KV_DIR")]
+    pub kv_dir: Option<String>,
+
+    /// URL prefix for the key-value store
+    #[arg(long, default_value = "/kv", env = "KV_PREFIX")]
+    pub kv_prefix: String,
+
+    /// Serve the /inspect, /anything, /status/{code}, and /delay/{seconds}
+    /// testing endpoints; off by default, as anyone could use them to tie up
+    /// workers or read back headers and cookies sent by their browser
+    #[arg(long, default_value = "false", env = "DEBUG_ENDPOINTS")]
+    pub debug_endpoints: bool,
+
+    /// Longest wait /delay/{seconds} will serve; longer requests are cut to this
+    #[arg(long, default_value = "10", env = "MAX_DELAY_SECS")]
+    pub max_delay_secs: u64,
+
+    /// Answer 504 when a handler runs longer than this many seconds (0 = no limit)
+    #[arg(long, default_value = "0", env = "HANDLER_TIMEOUT_SECS")]
+    pub handler_timeout_secs: u64,
+
+    /// Handler timeout for paths matching a pattern, as PATTERN=SECS, e.g.
+    /// '/files/*=300'; 0 means no limit (repeatable, first match wins)
+    #[arg(long, env = "ROUTE_TIMEOUT", value_delimiter = ',')]
+    pub route_timeout: Vec<String>,
+
+    /// Handlers allowed to keep running after timing out; beyond that, requests
+    /// with a timeout get 503 until some finish (0 = no limit)
+    #[arg(long, default_value = "64", env = "MAX_ABANDONED_HANDLERS")]
+    pub max_abandoned_handlers: usize,
+
+    /// Directory with index.html, document.html, or error.html templates
+    /// replacing the built-in pages
+    #[arg(long, env = "TEMPLATE_DIR")]
+    pub template_dir: Option<String>,
+
+    /// Serve a route with a WebAssembly plugin exporting `handle`, as
+    /// ROUTE=FILE, e.g. /hello/{name}=plugins/hello.wasm (repeatable)
+    #[arg(long, env = "WASM_HANDLER", value_delimiter = ',')]
+    pub wasm_handler: Vec<String>,
+
+    /// Run a WebAssembly plugin exporting `on_request` around every request (repeatable)
+    #[arg(long, env = "WASM_MIDDLEWARE", value_delimiter = ',')]
+    pub wasm_middleware: Vec<String>,
+
+    /// Largest request body accepted, in megabytes; longer ones get 413 (0 means no limit)
+    #[arg(long, default_value = "0", env = "MAX_BODY_MB")]
+    pub max_body_mb: u64,
+
+    /// Worker threads the pool starts with and shrinks back to (the Tokio
+    /// runtime's fixed thread count); defaults to the number of CPUs
     #[arg(short, long, default_value_t = Config::default_workers(), env = "WORKER_THREADS")]
     pub workers: usize,
 
+    /// Worker threads the pool may grow to while connections queue; never
+    /// fewer than --workers
+    #[arg(long, default_value_t = Config::default_max_workers(), env = "MAX_WORKER_THREADS")]
+    pub max_workers: usize,
+
+    /// Execution mode: a worker thread pool, async I/O on Tokio (--workers sets its thread count), or io_uring
+    #[arg(long, value_enum, default_value = "threads", env = "SERVER_RUNTIME")]
+    pub runtime: Runtime,
+
+    /// Connections allowed to wait for a free worker; beyond that new ones get
+    /// 503 with Retry-After straight away (thread pool runtime, 0 = unbounded)
+    #[arg(long, default_value = "1024", env = "MAX_QUEUE")]
+    pub max_queue: u64,
+
+    /// Workers reserved for /health, /metrics, and /admin/* while the worker
+    /// pool is saturated (thread pool runtime, 0 disables the probe lane)
+    #[arg(long, default_value = "2", env = "PROBE_WORKERS")]
+    pub probe_workers: usize,
+
+    /// Large uploads read at once; more wait for a slot (0 = no limit)
+    #[arg(long, default_value = "0", env = "MAX_UPLOADS")]
+    pub max_uploads: usize,
+
+    /// Request bodies of at least this many kilobytes count as large uploads
+    #[arg(long, default_value = "1024", env = "LARGE_UPLOAD_KB")]
+    pub large_upload_kb: u64,
+
+    /// How long a large upload waits for a slot before getting 503 (0 = no wait)
+    #[arg(long, default_value = "5", env = "UPLOAD_WAIT_SECS")]
+    pub upload_wait_secs: u64,
+
+    /// Request body bandwidth per connection in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0", env = "INGRESS_RATE_KB")]
+    pub ingress_rate_kb: u64,
+
+    /// Request body bandwidth across all connections in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0", env = "INGRESS_RATE_TOTAL_KB")]
+    pub ingress_rate_total_kb: u64,
+
+    /// Response bandwidth per connection in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0", env = "EGRESS_RATE_KB")]
+    pub egress_rate_kb: u64,
+
+    /// Response bandwidth across all connections in KiB/s (0 = unlimited)
+    #[arg(long, default_value = "0", env = "EGRESS_RATE_TOTAL_KB")]
+    pub egress_rate_total_kb: u64,
+
+    /// Maximum size in megabytes of the compressed static file cache (0 disables it)
+    #[arg(long, default_value = "32", env = "COMPRESSION_CACHE_MB")]
+    pub compression_cache_mb: usize,
+
+    /// Encodings responses may be compressed with: br, gzip, deflate
+    #[arg(long, value_enum, default_value = "br,gzip,deflate", env = "COMPRESSION_ALGORITHMS", value_delimiter = ',')]
+    pub compression_algorithms: Vec<Compression>,
+
+    /// Compression level, 0-9 for gzip and deflate and 0-11 for brotli (default: each algorithm's default)
+    #[arg(long, value_parser = clap::value_parser!(u32).range(0..=11), env = "COMPRESSION_LEVEL")]
+    pub compression_level: Option<u32>,
+
+    /// Send every response uncompressed
+    #[arg(long, default_value = "false", env = "DISABLE_COMPRESSION")]
+    pub disable_compression: bool,
+
+    /// How file ETags are computed: weak (size and mtime), strong (content hash), or off
+    #[arg(long, value_enum, default_value = "strong", env = "ETAG")]
+    pub etag: EtagStrategy,
+
+    /// Checksums sent with file downloads: sha-256 (Repr-Digest) and md5 (Content-MD5)
+    #[arg(long, value_enum, env = "FILE_DIGESTS", value_delimiter = ',')]
+    pub file_digests: Vec<DigestAlgorithm>,
+
+    /// Watch the file directory, --config file, and templates for changes
+    /// instead of checking file mtimes per request
+    #[arg(long, default_value = "false", env = "WATCH_FILES")]
+    pub watch_files: bool,
+
+    /// Compute the ETags of every served file at startup and as files
+    /// change, so downloads need no stat or hashing; implies --watch-files
+    #[arg(long, default_value = "false", env = "FILE_INDEX")]
+    pub file_index: bool,
+
+    /// Development mode: --watch-files, plus reloading open pages in the
+    /// browser when a served file changes; turns compression off
+    #[arg(long, default_value = "false", env = "WATCH")]
+    pub watch: bool,
+
+    /// Report /health as degraded when the file directory's filesystem has less free space
+    #[arg(long, default_value = "100", env = "HEALTH_MIN_FREE_MB")]
+    pub health_min_free_mb: u64,
+
+    /// Write an access log line per request to a file, or "-" for stdout
+    #[arg(long, env = "ACCESS_LOG")]
+    pub access_log: Option<String>,
+
+    /// Append a tamper-evident JSON line per file upload and deletion to this file
+    #[arg(long, env = "AUDIT_LOG")]
+    pub audit_log: Option<String>,
+
+    /// Access log line format
+    #[arg(long, value_enum, default_value = "combined", env = "ACCESS_LOG_FORMAT")]
+    pub access_log_format: AccessLogFormat,
+
+    /// Log only one in this many successful requests; errors and slow
+    /// requests (see --slow-request-ms) are always logged
+    #[arg(long, default_value = "1", value_parser = clap::value_parser!(u64).range(1..), env = "ACCESS_LOG_SAMPLE")]
+    pub access_log_sample: u64,
+
+    /// Write the application (error/debug) log to this file instead of stderr
+    #[arg(long, env = "LOG_FILE")]
+    pub log_file: Option<String>,
+
+    /// When to rotate log files written by --log-file and --access-log
+    #[arg(long, value_enum, default_value = "never", env = "LOG_ROTATION")]
+    pub log_rotation: Rotation,
+
+    /// Size in megabytes at which log files are rotated with --log-rotation=size
+    #[arg(long, default_value = "100", env = "LOG_MAX_SIZE_MB")]
+    pub log_max_size_mb: u64,
+
+    /// Number of rotated log archives to keep
+    #[arg(long, default_value = "5", env = "LOG_KEEP")]
+    pub log_keep: usize,
+
+    /// Log requests slower than this many milliseconds at WARN level (0 disables)
+    #[arg(long, default_value = "1000", env = "SLOW_REQUEST_MS")]
+    pub slow_request_ms: u64,
+
+    /// Proxy addresses whose incoming X-Request-ID header is reused (comma-separated)
+    #[arg(long, value_delimiter = ',', env = "TRUSTED_PROXIES")]
+    pub trusted_proxies: Vec<IpAddr>,
+
+    /// Only serve clients in these CIDR blocks, e.g. 10.0.0.0/8 (comma-separated; empty allows all)
+    #[arg(long, value_delimiter = ',', env = "ALLOW_IPS")]
+    pub allow_ips: Vec<IpNet>,
+
+    /// Never serve clients in these CIDR blocks, even if allowed (comma-separated)
+    #[arg(long, value_delimiter = ',', env = "DENY_IPS")]
+    pub deny_ips: Vec<IpNet>,
+
+    /// How clients blocked by --allow-ips/--deny-ips are turned away
+    #[arg(long, value_enum, default_value = "forbidden", env = "DENY_ACTION")]
+    pub deny_action: DenyAction,
+
+    /// Export request spans to this OTLP/HTTP collector (e.g. http://localhost:4318)
+    #[arg(long, env = "OTEL_EXPORTER_OTLP_ENDPOINT")]
+    pub otlp_endpoint: Option<String>,
+
+    /// Service name reported on exported spans
+    #[arg(long, default_value = "http-server", env = "OTEL_SERVICE_NAME")]
+    pub otlp_service_name: String,
+
+    /// Push metrics to this StatsD/DogStatsD address (host:port) over UDP
+    #[arg(long, env = "STATSD_ADDR")]
+    pub statsd_addr: Option<String>,
+
+    /// Prefix for pushed StatsD metric names
+    #[arg(long, default_value = "http_server", env = "STATSD_PREFIX")]
+    pub statsd_prefix: String,
+
+    /// StatsD wire format; dogstatsd adds per-route tags
+    #[arg(long, value_enum, default_value = "statsd", env = "STATSD_FORMAT")]
+    pub statsd_format: StatsdFormat,
+
+    /// Seconds between StatsD flushes
+    #[arg(long, default_value = "10", env = "STATSD_INTERVAL_SECS")]
+    pub statsd_interval_secs: u64,
+
+    /// Serve /metrics, /health, and admin endpoints on this separate address
+    /// (e.g. 127.0.0.1:9090) instead of the public listener
+    #[arg(long, env = "ADMIN_ADDR")]
+    pub admin_addr: Option<String>,
+
+    /// Bearer token required by /admin/* endpoints (admin endpoints are disabled without it)
+    #[arg(long, env = "ADMIN_TOKEN", hide_env_values = true)]
+    pub admin_token: Option<String>,
+
+    /// On shutdown, keep serving this many seconds after readiness turns
+    /// unhealthy so load balancers can stop routing traffic first
+    #[arg(long, default_value = "0", env = "DRAIN_DELAY_SECS")]
+    pub drain_delay_secs: u64,
+
+    /// On shutdown, wait this many seconds for in-flight requests to finish
+    /// after the listener closes before exiting anyway
+    #[arg(long, default_value = "10", env = "DRAIN_TIMEOUT_SECS")]
+    pub drain_timeout_secs: u64,
+
+    /// Seconds an idle keep-alive connection is kept open between requests (0 disables keep-alive)
+    #[arg(long, default_value = "5", env = "KEEP_ALIVE_TIMEOUT_SECS")]
+    pub keep_alive_timeout_secs: u64,
+
+    /// Maximum number of requests served on one keep-alive connection
+    #[arg(long, default_value = "100", env = "MAX_KEEP_ALIVE_REQUESTS")]
+    pub max_keep_alive_requests: usize,
+
+    /// Close connections that send nothing for this many seconds (0 waits forever)
+    #[arg(long, default_value = "30", env = "IDLE_TIMEOUT_SECS")]
+    pub idle_timeout_secs: u64,
+
+    /// Accept loops, each on its own SO_REUSEPORT socket where supported
+    #[arg(long, default_value = "1", env = "ACCEPTORS")]
+    pub acceptors: usize,
+
+    /// Run this many worker processes under a supervisor that restarts them
+    /// when they crash (0 serves from this process)
+    #[arg(long, default_value = "0", env = "PROCESSES")]
+    pub processes: usize,
+
+    /// Maximum number of pending connections queued by the kernel
+    #[arg(long, default_value = "1024", env = "LISTEN_BACKLOG")]
+    pub listen_backlog: i32,
+
+    /// SO_RCVBUF size in bytes for client sockets (OS default if unset)
+    #[arg(long, env = "SOCKET_RECV_BUFFER")]
+    pub socket_recv_buffer: Option<usize>,
+
+    /// SO_SNDBUF size in bytes for client sockets (OS default if unset)
+    #[arg(long, env = "SOCKET_SEND_BUFFER")]
+    pub socket_send_buffer: Option<usize>,
+
+    /// Send TCP keepalive probes after a connection is idle this many seconds (0 disables)
+    #[arg(long, default_value = "0", env = "TCP_KEEPALIVE_SECS")]
+    pub tcp_keepalive_secs: u64,
+
+    /// Seconds between unanswered TCP keepalive probes
+    #[arg(long, default_value = "15", env = "TCP_KEEPALIVE_INTERVAL_SECS")]
+    pub tcp_keepalive_interval_secs: u64,
+
+    /// Unanswered TCP keepalive probes before the connection is dropped
+    #[arg(long, default_value = "4", env = "TCP_KEEPALIVE_RETRIES")]
+    pub tcp_keepalive_retries: u32,
+
+    /// Set SO_LINGER on client sockets so close() waits up to this many seconds for unsent data
+    #[arg(long, env = "SOCKET_LINGER_SECS")]
+    pub socket_linger_secs: Option<u64>,
+
+    /// Detach from the terminal and run in the background
+    #[arg(long, default_value = "false")]
+    pub daemon: bool,
+
+    /// Write the process ID to this file, refusing to start if another instance holds it
+    #[arg(long, env = "PID_FILE")]
+    pub pid_file: Option<PathBuf>,
+
+    /// After binding, switch to this user (name or uid) before accepting connections
+    #[arg(long, env = "SERVER_USER")]
+    pub user: Option<String>,
+
+    /// After binding, switch to this group (name or gid); defaults to the user's primary group
+    #[arg(long, env = "SERVER_GROUP")]
+    pub group: Option<String>,
+
+    /// Log filter in RUST_LOG syntax (e.g. `info,http_server=debug`); overrides --verbose
+    #[arg(long, env = "RUST_LOG")]
+    pub log_level: Option<String>,
+
     /// Enable verbose logging
     #[arg(short, long, default_value = "false")]
     pub verbose: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Tools that run instead of the server
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Load test a server and report throughput and latency percentiles
+    Bench(BenchArgs),
+    /// Validate a configuration file and exit non-zero if it has problems
+    Check(CheckArgs),
+    /// Write .gz and .br copies of compressible files for downloads to serve
+    Precompress(PrecompressArgs),
+    /// Check that an --audit-log file has not been altered
+    VerifyAudit(VerifyAuditArgs),
 }
 
 impl Config {
-    /// Calculate optimal number of worker threads
-    /// For high concurrency, we use: max(num_cpus * 4, 100)
-    /// This ensures we can handle 100+ concurrent requests efficiently
+    /// One worker per CPU while the server is quiet
     fn default_workers() -> usize {
-        let num_cpus = std::thread::available_parallelism()
+        std::thread::available_parallelism()
             .map(|n| n.get())
-            .unwrap_or(4);
-        
+            .unwrap_or(4)
+    }
+
+    /// Calculate the largest worker pool
+    /// For high concurrency, we use: max(num_cpus * 4, 100)
+    /// This ensures we can handle 100+ concurrent connections efficiently
+    fn default_max_workers() -> usize {
         // Use 4x CPU cores, but at least 100 workers for high concurrency
-        std::cmp::max(num_cpus * 4, 100)
+        std::cmp::max(Self::default_workers() * 4, 100)
     }
 
-    /// Parse configuration from command line arguments and environment variables
+    /// Parse configuration from command line arguments, environment variables,
+    /// and the --config file, exiting with a usage error if any are invalid
     pub fn parse_config() -> Self {
-        Config::parse()
+        let args: Vec<OsString> = std::env::args_os().collect();
+        Config::load(&args).unwrap_or_else(|e| e.exit())
+    }
+
+    /// Build the configuration from `args` plus the config file they name
+    ///
+    /// Precedence is command line, then environment, then the file, then defaults.
+    pub fn load(args: &[OsString]) -> Result<Self, clap::Error> {
+        let cli = Config::try_parse_from(args)?;
+        let Some(path) = &cli.config else {
+            return Ok(cli);
+        };
+
+        // File settings become flags placed before the real arguments, which
+        // override them because of args_override_self
+        let mut combined = args[..1].to_vec();
+        combined.extend(Self::file_args(path)?);
+        combined.extend(args[1..].iter().cloned());
+        Config::try_parse_from(combined)
+    }
+
+    /// Translate a TOML config file into the equivalent command line flags
+    fn file_args(path: &Path) -> Result<Vec<OsString>, clap::Error> {
+        let invalid = |message: String| clap::Error::raw(ErrorKind::InvalidValue, message + "\n");
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| clap::Error::raw(ErrorKind::Io, format!("Failed to read {}: {}\n", path.display(), e)))?;
+        let table: toml::Table = text
+            .parse()
+            .map_err(|e| invalid(format!("Invalid config file {}: {}", path.display(), e)))?;
+
+        let command = Config::command();
+        let mut args = Vec::new();
+        for (key, value) in table {
+            let flag = key.replace('_', "-");
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(flag.as_str()) && flag != "config")
+                .ok_or_else(|| invalid(format!("Unknown setting '{}' in {}", key, path.display())))?;
+
+            // Environment variables take precedence over the file
+            if arg.get_env().is_some_and(|env| std::env::var_os(env).is_some()) {
+                continue;
+            }
+
+            let scalar = |value: &toml::Value| match value {
+                toml::Value::String(s) => Ok(s.clone()),
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => Ok(value.to_string()),
+                _ => Err(invalid(format!("Unsupported value for '{}' in {}", key, path.display()))),
+            };
+            match &value {
+                toml::Value::Boolean(true) => args.push(format!("--{}", flag).into()),
+                toml::Value::Boolean(false) => {}
+                toml::Value::Array(items) => {
+                    let items = items.iter().map(scalar).collect::<Result<Vec<_>, _>>()?;
+                    // Values that may contain commas are passed one flag each
+                    match arg.get_value_delimiter() {
+                        Some(delimiter) => args.push(format!("--{}={}", flag, items.join(&delimiter.to_string())).into()),
+                        None => args.extend(items.iter().map(|item| format!("--{}={}", flag, item).into())),
+                    }
+                }
+                other => args.push(format!("--{}={}", flag, scalar(other)?).into()),
+            }
+        }
+        Ok(args)
+    }
+
+    /// Whether --allow-ips and --deny-ips let `ip` in
+    pub fn allows_ip(&self, ip: IpAddr) -> bool {
+        ip_filter::is_allowed(ip, &self.allow_ips, &self.deny_ips)
+    }
+
+    /// Request body limit in bytes set by --max-body-mb, if any
+    pub fn max_body_size(&self) -> Option<usize> {
+        (self.max_body_mb > 0).then(|| usize::try_from(self.max_body_mb * 1024 * 1024).unwrap_or(usize::MAX))
+    }
+
+    /// Request body bandwidth limits, reporting to `stats`
+    pub fn ingress_limit(&self, stats: Arc<ThrottleStats>) -> RateLimit {
+        let (per_connection, total) = self.ingress_rates();
+        RateLimit::new(per_connection, total, stats)
+    }
+
+    /// Request body bandwidth limits in bytes per second, per connection and
+    /// in total
+    pub fn ingress_rates(&self) -> (u64, u64) {
+        (self.ingress_rate_kb.saturating_mul(1024), self.ingress_rate_total_kb.saturating_mul(1024))
+    }
+
+    /// Response bandwidth limits, reporting to `stats`
+    pub fn egress_limit(&self, stats: Arc<ThrottleStats>) -> RateLimit {
+        let (per_connection, total) = self.egress_rates();
+        RateLimit::new(per_connection, total, stats)
+    }
+
+    /// Response bandwidth limits in bytes per second, per connection and in
+    /// total
+    pub fn egress_rates(&self) -> (u64, u64) {
+        (self.egress_rate_kb.saturating_mul(1024), self.egress_rate_total_kb.saturating_mul(1024))
+    }
+
+    /// Admission control for large uploads
+    pub fn upload_limiter(&self) -> UploadLimiter {
+        UploadLimiter::new(
+            self.max_uploads,
+            self.large_upload_kb.saturating_mul(1024),
+            Duration::from_secs(self.upload_wait_secs),
+        )
+    }
+
+    /// Page templates: those in --template-dir over the built-in ones
+    pub fn templates(&self) -> Result<Templates, String> {
+        match &self.template_dir {
+            Some(directory) => Templates::load(Path::new(directory)),
+            None => Ok(Templates::builtin()),
+        }
+    }
+
+    /// The compression policy of the built-in endpoints
+    pub fn compression_policy(&self) -> CompressionPolicy {
+        let policy = CompressionPolicy::default().algorithms(&self.compression_algorithms);
+        let policy = match self.compression_level {
+            Some(level) => policy.level(level),
+            None => policy,
+        };
+        CompressionPolicy {
+            // Live reload edits HTML bodies, which must not be encoded yet
+            enabled: !self.disable_compression && !self.watch,
+            ..policy
+        }
+    }
+
+    /// The CORS rules: each --cors-rule, then the --cors-origins policy for every path
+    pub fn cors(&self) -> Result<Cors, String> {
+        let rules = self.cors_rule.iter().map(|spec| CorsRule::parse(spec)).collect::<Result<_, _>>()?;
+        let cors = Cors { rules };
+        if self.cors_origins.is_empty() {
+            return Ok(cors);
+        }
+        let policy = CorsPolicy {
+            origins: self.cors_origins.clone(),
+            methods: self.cors_methods.iter().map(|method| method.to_ascii_uppercase()).collect(),
+            headers: self.cors_headers.clone(),
+            expose_headers: self.cors_expose_headers.clone(),
+            max_age: self.cors_max_age_secs,
+            credentials: self.cors_credentials,
+        };
+        policy.validate()?;
+        Ok(cors.route("*", policy))
     }
 
     /// Get the full server address (host:port)
@@ -74,19 +697,180 @@ impl Config {
             return Err("Number of workers must be greater than 0".to_string());
         }
 
+        if self.runtime == Runtime::Tokio && !cfg!(feature = "tokio") {
+            return Err("--runtime tokio requires a build with the `tokio` feature".to_string());
+        }
+
+        if self.runtime == Runtime::IoUring && !cfg!(all(target_os = "linux", feature = "io-uring")) {
+            return Err("--runtime io-uring requires Linux and a build with the `io-uring` feature".to_string());
+        }
+
+        if self.acceptors == 0 {
+            return Err("Number of acceptors must be greater than 0".to_string());
+        }
+
+        if self.processes > 0 && !cfg!(unix) {
+            return Err("--processes requires a Unix platform".to_string());
+        }
+
+        if self.listen_backlog <= 0 {
+            return Err("Listen backlog must be greater than 0".to_string());
+        }
+
+        if self.max_keep_alive_requests == 0 {
+            return Err("Max keep-alive requests must be greater than 0".to_string());
+        }
+
+        if let Some(spec) = &self.log_level {
+            crate::log_level::validate(spec)?;
+        }
+
+        let has_plugins = !self.wasm_handler.is_empty() || !self.wasm_middleware.is_empty();
+        if has_plugins && !cfg!(feature = "wasm") {
+            return Err("--wasm-handler and --wasm-middleware require a build with the `wasm` feature".to_string());
+        }
+        if let Some(spec) = self.wasm_handler.iter().find(|spec| !spec.starts_with('/') || !spec.contains('=')) {
+            return Err(format!("--wasm-handler takes ROUTE=FILE, got '{}'", spec));
+        }
+
+        if let Some(address) = &self.fastcgi_addr {
+            crate::fastcgi::FastCgiAddress::parse(address)?;
+        }
+        if let Some(address) = &self.fastcgi_shadow_addr {
+            crate::fastcgi::FastCgiAddress::parse(address)?;
+        }
+        if let Some(address) = &self.fastcgi_canary_addr {
+            crate::fastcgi::FastCgiAddress::parse(address)?;
+        }
+
+        crate::response_headers::ResponseHeaders::parse(&self.response_header)?;
+        self.cors()?;
+        RouteTimeouts::parse(self.handler_timeout_secs, &self.route_timeout)?;
+
+        // The admin listener must not collide with the public one
+        if self.admin_addr.as_deref() == Some(self.server_address().as_str()) {
+            return Err("Admin address must differ from the public listen address".to_string());
+        }
+
         Ok(())
     }
 
+    /// Socket tuning for the public listener and its connections
+    pub fn socket_options(&self) -> SocketOptions {
+        SocketOptions {
+            backlog: self.listen_backlog,
+            recv_buffer: self.socket_recv_buffer,
+            send_buffer: self.socket_send_buffer,
+            keepalive: (self.tcp_keepalive_secs > 0).then(|| TcpKeepalive {
+                idle: Duration::from_secs(self.tcp_keepalive_secs),
+                interval: Duration::from_secs(self.tcp_keepalive_interval_secs),
+                retries: self.tcp_keepalive_retries,
+            }),
+            linger: self.socket_linger_secs.map(Duration::from_secs),
+        }
+    }
+
+    /// Rotation policy applied to every log file the server writes
+    pub fn rotation_policy(&self) -> RotationPolicy {
+        RotationPolicy {
+            rotation: self.log_rotation,
+            max_bytes: self.log_max_size_mb * 1024 * 1024,
+            keep: self.log_keep,
+        }
+    }
+
+    /// Log filter spec to apply: --log-level/RUST_LOG, else based on --verbose
+    pub fn log_filter(&self) -> String {
+        match &self.log_level {
+            Some(spec) => spec.clone(),
+            None if self.verbose => "debug".to_string(),
+            None => "info".to_string(),
+        }
+    }
+
     /// Initialize logger based on configuration
-    pub fn init_logger(&self) {
-        let log_level = if self.verbose {
-            "debug"
-        } else {
-            "info"
-        };
+    pub fn init_logger(&self) -> std::io::Result<()> {
+        // The filter lives in log_level so it can be changed at runtime; the
+        // env_logger output itself lets everything through
+        let spec = self.log_filter();
+        let mut builder = env_logger::Builder::new();
+        builder.filter_level(log::LevelFilter::Trace);
+        builder.format(|buf, record| {
+            let timestamp = buf.timestamp_millis();
+            match crate::request_id::current() {
+                Some(id) => writeln!(
+                    buf,
+                    "[{} {:<5} {}] [{}] {}",
+                    timestamp,
+                    record.level(),
+                    record.target(),
+                    id,
+                    record.args()
+                ),
+                None => writeln!(
+                    buf,
+                    "[{} {:<5} {}] {}",
+                    timestamp,
+                    record.level(),
+                    record.target(),
+                    record.args()
+                ),
+            }
+        });
+
+        if let Some(path) = &self.log_file {
+            let file = RotatingFile::open(path, self.rotation_policy())?;
+            builder.target(env_logger::Target::Pipe(Box::new(file)));
+        }
+
+        crate::log_level::install(builder.build(), &spec).map_err(std::io::Error::other)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<OsString> {
+        args.iter().map(OsString::from).collect()
+    }
+
+    #[test]
+    fn test_config_file_is_overridden_by_flags() {
+        let path = std::env::temp_dir().join(format!("http-server-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "port = 8000\ndirectory = \"/srv/files\"\nverbose = true\nstatsd_format = \"dogstatsd\"\n",
+        )
+        .unwrap();
+        let path_arg = path.to_str().unwrap();
+
+        let config = Config::load(&args(&["http-server", "--config", path_arg, "--port", "9000"])).unwrap();
+        assert_eq!(config.port, 9000);
+        assert_eq!(config.directory, "/srv/files");
+        assert!(config.verbose);
+        assert_eq!(config.statsd_format, StatsdFormat::Dogstatsd);
+
+        std::fs::write(&path, "prot = 8000\n").unwrap();
+        let error = Config::load(&args(&["http-server", "--config", path_arg])).unwrap_err();
+        assert!(error.to_string().contains("Unknown setting 'prot'"));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_config_file_lists_keep_commas_in_values() {
+        let path = std::env::temp_dir().join(format!("http-server-config-lists-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "response_header = [\"/assets/*=Cache-Control: public, max-age=60\", \"*=X-Frame-Options: DENY\"]\n\
+             file_digests = [\"sha-256\", \"md5\"]\n",
+        )
+        .unwrap();
 
-        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(log_level))
-            .format_timestamp_millis()
-            .init();
+        let config = Config::load(&args(&["http-server", "--config", path.to_str().unwrap()])).unwrap();
+        assert_eq!(config.response_header, ["/assets/*=Cache-Control: public, max-age=60", "*=X-Frame-Options: DENY"]);
+        assert_eq!(config.file_digests, [DigestAlgorithm::Sha256, DigestAlgorithm::Md5]);
+        let _ = std::fs::remove_file(&path);
     }
 }
@@ -0,0 +1,234 @@
+//! Per-connection accounting
+//!
+//! Every runtime registers its connections in a [`ConnectionRegistry`] when
+//! they are accepted and reports each request served on them. Open
+//! connections are listed at `GET /admin/connections`; when one closes, its
+//! duration, bytes in each direction, and request count go into histograms
+//! exported with the other metrics.
+
+use crate::metrics::{write_header, ValueHistogram};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Upper bounds (in seconds) of the connection duration buckets
+const DURATION_BUCKETS: [f64; 10] = [0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 900.0, 3600.0];
+
+/// Upper bounds of the per-connection byte count buckets, 256 B to 256 MiB
+const BYTES_BUCKETS: [f64; 11] = [
+    256.0,
+    1024.0,
+    4096.0,
+    16384.0,
+    65536.0,
+    262144.0,
+    1048576.0,
+    4194304.0,
+    16777216.0,
+    67108864.0,
+    268435456.0,
+];
+
+/// Upper bounds of the requests-per-connection buckets
+const REQUESTS_BUCKETS: [f64; 10] = [1.0, 2.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0, 1000.0];
+
+/// What an open connection is doing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Waiting for the first or next request
+    Idle,
+    /// Reading a request or sending its response
+    Active,
+}
+
+impl ConnectionState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConnectionState::Idle => "idle",
+            ConnectionState::Active => "active",
+        }
+    }
+}
+
+/// An open connection, as of its last completed request
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer_addr: Option<SocketAddr>,
+    pub opened: Instant,
+    pub state: ConnectionState,
+    pub requests: u64,
+    pub bytes_received: u64,
+    pub bytes_sent: u64,
+}
+
+/// Histograms of closed connections
+#[derive(Debug, Clone)]
+struct Closed {
+    duration: ValueHistogram,
+    received: ValueHistogram,
+    sent: ValueHistogram,
+    requests: ValueHistogram,
+}
+
+/// Open connections by id, and statistics of the closed ones
+#[derive(Debug)]
+pub struct ConnectionRegistry {
+    next_id: AtomicU64,
+    open: Mutex<BTreeMap<u64, ConnectionInfo>>,
+    closed: Mutex<Closed>,
+}
+
+impl ConnectionRegistry {
+    pub fn new() -> Self {
+        ConnectionRegistry {
+            next_id: AtomicU64::new(1),
+            open: Mutex::new(BTreeMap::new()),
+            closed: Mutex::new(Closed {
+                duration: ValueHistogram::new(&DURATION_BUCKETS),
+                received: ValueHistogram::new(&BYTES_BUCKETS),
+                sent: ValueHistogram::new(&BYTES_BUCKETS),
+                requests: ValueHistogram::new(&REQUESTS_BUCKETS),
+            }),
+        }
+    }
+
+    /// Register a newly accepted connection; returns its id
+    pub fn open(&self, peer_addr: Option<SocketAddr>) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = ConnectionInfo {
+            peer_addr,
+            opened: Instant::now(),
+            state: ConnectionState::Idle,
+            requests: 0,
+            bytes_received: 0,
+            bytes_sent: 0,
+        };
+        self.open.lock().unwrap().insert(id, info);
+        id
+    }
+
+    /// A request started arriving on connection `id`
+    pub fn request_started(&self, id: u64) {
+        if let Some(info) = self.open.lock().unwrap().get_mut(&id) {
+            info.state = ConnectionState::Active;
+            info.requests += 1;
+        }
+    }
+
+    /// Connection `id` finished a response; the byte counts are totals for
+    /// the connection so far
+    pub fn request_finished(&self, id: u64, bytes_received: u64, bytes_sent: u64) {
+        if let Some(info) = self.open.lock().unwrap().get_mut(&id) {
+            info.state = ConnectionState::Idle;
+            info.bytes_received = bytes_received;
+            info.bytes_sent = bytes_sent;
+        }
+    }
+
+    /// Connection `id` closed after transferring these totals
+    pub fn close(&self, id: u64, bytes_received: u64, bytes_sent: u64) {
+        let Some(info) = self.open.lock().unwrap().remove(&id) else {
+            return;
+        };
+        let mut closed = self.closed.lock().unwrap();
+        closed.duration.observe(info.opened.elapsed().as_secs_f64());
+        closed.received.observe(bytes_received as f64);
+        closed.sent.observe(bytes_sent as f64);
+        closed.requests.observe(info.requests as f64);
+    }
+
+    /// A snapshot of the open connections, oldest first
+    pub fn snapshot(&self) -> Vec<(u64, ConnectionInfo)> {
+        self.open.lock().unwrap().iter().map(|(id, info)| (*id, info.clone())).collect()
+    }
+
+    /// `GET /admin/connections`: the open connections
+    pub fn list_json(&self) -> Value {
+        let connections: Vec<Value> = self
+            .snapshot()
+            .into_iter()
+            .map(|(id, info)| {
+                json!({
+                    "id": id,
+                    "peer_addr": info.peer_addr.map(|addr| addr.to_string()),
+                    "state": info.state.as_str(),
+                    "age_seconds": info.opened.elapsed().as_secs_f64(),
+                    "requests": info.requests,
+                    "bytes_received": info.bytes_received,
+                    "bytes_sent": info.bytes_sent,
+                })
+            })
+            .collect();
+        json!({ "count": connections.len(), "connections": connections })
+    }
+
+    /// Histograms of closed connections for the JSON metrics document
+    pub fn to_json(&self) -> Value {
+        let closed = self.closed.lock().unwrap();
+        json!({
+            "count": closed.duration.count(),
+            "duration_seconds": closed.duration.to_json(),
+            "received_bytes": closed.received.to_json(),
+            "sent_bytes": closed.sent.to_json(),
+            "requests": closed.requests.to_json(),
+        })
+    }
+
+    /// Append the closed-connection histograms in Prometheus text format
+    pub fn render_prometheus(&self, out: &mut String) {
+        let closed = self.closed.lock().unwrap().clone();
+        for (name, help, histogram) in [
+            ("http_connection_duration_seconds", "How long closed connections were open", &closed.duration),
+            ("http_connection_received_bytes", "Bytes read from each closed connection", &closed.received),
+            ("http_connection_sent_bytes", "Bytes written to each closed connection", &closed.sent),
+            ("http_connection_requests", "Requests served on each closed connection", &closed.requests),
+        ] {
+            write_header(out, name, "histogram", help);
+            histogram.render(out, name, "");
+            out.push('\n');
+        }
+    }
+}
+
+impl Default for ConnectionRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_connection_lifecycle() {
+        let registry = ConnectionRegistry::new();
+        let peer: SocketAddr = "192.0.2.7:50123".parse().unwrap();
+        let id = registry.open(Some(peer));
+        registry.request_started(id);
+        assert_eq!(registry.list_json()["connections"][0]["state"], "active");
+        registry.request_finished(id, 80, 2000);
+        registry.request_started(id);
+        registry.request_finished(id, 160, 4000);
+
+        let listing = registry.list_json();
+        assert_eq!(listing["count"], 1);
+        assert_eq!(listing["connections"][0]["peer_addr"], "192.0.2.7:50123");
+        assert_eq!(listing["connections"][0]["state"], "idle");
+        assert_eq!(listing["connections"][0]["requests"], 2);
+        assert_eq!(listing["connections"][0]["bytes_sent"], 4000);
+
+        registry.close(id, 160, 4000);
+        assert_eq!(registry.list_json()["count"], 0);
+        let mut out = String::new();
+        registry.render_prometheus(&mut out);
+        assert!(out.contains("http_connection_requests_bucket{le=\"2\"} 1\n"));
+        assert!(out.contains("http_connection_requests_bucket{le=\"1\"} 0\n"));
+        assert!(out.contains("http_connection_sent_bytes_bucket{le=\"4096\"} 1\n"));
+        assert!(out.contains("http_connection_received_bytes_sum 160\n"));
+        assert_eq!(registry.to_json()["count"], 1);
+    }
+}
@@ -0,0 +1,287 @@
+//! Cross-origin resource sharing (CORS)
+//!
+//! A [`Cors`] middleware holds policies by path pattern (an exact path, or a
+//! prefix followed by `*`); the first rule matching a request's path decides.
+//! Preflight requests (`OPTIONS` with `Access-Control-Request-Method`) are
+//! answered by the middleware itself, with `Access-Control-Max-Age` so
+//! browsers can cache the answer; other requests go to their handler and get
+//! `Access-Control-Allow-Origin` and `Access-Control-Expose-Headers` added.
+//!
+//! The allowed origin is echoed back rather than sent as `*` whenever the
+//! answer depends on it, with `Vary: Origin` so caches keep the answers
+//! apart. Credentialed policies always echo, as browsers reject `*` there.
+
+use crate::error::Result;
+use crate::problem::Problem;
+use crate::request::{HttpMethod, HttpRequest};
+use crate::response::HttpResponse;
+use crate::response_headers::path_matches;
+use crate::router::Next;
+use crate::status::StatusCode;
+
+/// What cross-origin requests a path allows
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsPolicy {
+    /// Allowed origins such as `https://app.example.com`; `*` allows any,
+    /// and `https://*.example.com` any subdomain
+    pub origins: Vec<String>,
+    pub methods: Vec<String>,
+    /// Request headers allowed in preflights; empty allows whatever the
+    /// browser asks for
+    pub headers: Vec<String>,
+    /// Response headers scripts may read besides the safelisted ones
+    pub expose_headers: Vec<String>,
+    /// Seconds browsers may cache a preflight answer
+    pub max_age: Option<u64>,
+    /// Allow cookies and HTTP authentication; needs explicit origins
+    pub credentials: bool,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        CorsPolicy {
+            origins: Vec::new(),
+            methods: ["GET", "HEAD", "POST", "PUT", "PATCH", "DELETE"].map(String::from).to_vec(),
+            headers: Vec::new(),
+            expose_headers: Vec::new(),
+            max_age: None,
+            credentials: false,
+        }
+    }
+}
+
+impl CorsPolicy {
+    /// A policy allowing `origins` with the default methods
+    pub fn new(origins: &[&str]) -> Self {
+        CorsPolicy {
+            origins: origins.iter().map(|origin| origin.to_string()).collect(),
+            ..Self::default()
+        }
+    }
+
+    pub fn methods(mut self, methods: &[&str]) -> Self {
+        self.methods = methods.iter().map(|method| method.to_ascii_uppercase()).collect();
+        self
+    }
+
+    pub fn headers(mut self, headers: &[&str]) -> Self {
+        self.headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    pub fn expose_headers(mut self, headers: &[&str]) -> Self {
+        self.expose_headers = headers.iter().map(|header| header.to_string()).collect();
+        self
+    }
+
+    pub fn max_age(mut self, seconds: u64) -> Self {
+        self.max_age = Some(seconds);
+        self
+    }
+
+    pub fn credentials(mut self, credentials: bool) -> Self {
+        self.credentials = credentials;
+        self
+    }
+
+    /// Parse `origins=A,B;methods=GET,POST;headers=...;expose=...;max-age=N;credentials`
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let list = |value: &str| value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect();
+        let mut policy = CorsPolicy::default();
+        for setting in spec.split(';').map(str::trim).filter(|setting| !setting.is_empty()) {
+            match setting.split_once('=') {
+                Some(("origins", value)) => policy.origins = list(value),
+                Some(("methods", value)) => policy.methods = list(&value.to_ascii_uppercase()),
+                Some(("headers", value)) => policy.headers = list(value),
+                Some(("expose", value)) => policy.expose_headers = list(value),
+                Some(("max-age", value)) => {
+                    let seconds = value.trim().parse().map_err(|_| format!("Invalid CORS max-age '{}'", value))?;
+                    policy.max_age = Some(seconds);
+                }
+                None if setting == "credentials" => policy.credentials = true,
+                _ => return Err(format!("Unknown CORS setting '{}'", setting)),
+            }
+        }
+        policy.validate()?;
+        Ok(policy)
+    }
+
+    /// Reject policies browsers would refuse or that would be unsafe
+    pub fn validate(&self) -> std::result::Result<(), String> {
+        if self.origins.is_empty() {
+            return Err("A CORS policy needs at least one origin".to_string());
+        }
+        // Echoing every origin with credentials would let any site act as the user
+        if self.credentials && self.origins.iter().any(|origin| origin == "*") {
+            return Err("Credentialed CORS needs explicit origins, not '*'".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.origins.iter().any(|allowed| match allowed.split_once("://*.") {
+            _ if allowed == "*" => true,
+            Some((scheme, domain)) => origin
+                .strip_prefix(scheme)
+                .and_then(|rest| rest.strip_prefix("://"))
+                .and_then(|host| host.strip_suffix(domain)?.strip_suffix('.'))
+                .is_some_and(|subdomain| !subdomain.is_empty() && !subdomain.contains(['/', ':'])),
+            None => allowed.eq_ignore_ascii_case(origin),
+        })
+    }
+
+    /// Whether responses differ by origin, and so need `Vary: Origin`
+    fn echoes_origin(&self) -> bool {
+        self.credentials || self.origins.iter().all(|origin| origin != "*")
+    }
+
+    fn allow_origin(&self, response: HttpResponse, origin: &str) -> HttpResponse {
+        let response = match self.echoes_origin() {
+            true => response.header("Access-Control-Allow-Origin", origin),
+            false => response.header("Access-Control-Allow-Origin", "*"),
+        };
+        match self.credentials {
+            true => response.header("Access-Control-Allow-Credentials", "true"),
+            false => response,
+        }
+    }
+
+    /// Answer a preflight from `origin`
+    fn preflight(&self, request: &HttpRequest, origin: &str, method: &str) -> HttpResponse {
+        let requested_headers: Vec<&str> = request
+            .get_header("Access-Control-Request-Headers")
+            .map(|headers| headers.split(',').map(str::trim).filter(|header| !header.is_empty()).collect())
+            .unwrap_or_default();
+        let rejection = if !self.allows_origin(origin) {
+            Some(format!("Origin {} is not allowed", origin))
+        } else if !self.methods.iter().any(|allowed| allowed.eq_ignore_ascii_case(method)) {
+            Some(format!("Method {} is not allowed from other origins", method))
+        } else if self.headers.is_empty() {
+            None
+        } else {
+            requested_headers
+                .iter()
+                .find(|header| !self.headers.iter().any(|allowed| allowed.eq_ignore_ascii_case(header)))
+                .map(|header| format!("Header {} is not allowed from other origins", header))
+        };
+        let response = match rejection {
+            Some(detail) => Problem::new(StatusCode::Forbidden, "cors_rejected", detail)
+                .instance(&request.path)
+                .to_response(Some(request)),
+            None => {
+                let allowed_headers = match self.headers.is_empty() {
+                    true => requested_headers.join(", "),
+                    false => self.headers.join(", "),
+                };
+                let response = self
+                    .allow_origin(HttpResponse::no_content(), origin)
+                    .header("Access-Control-Allow-Methods", self.methods.join(", "));
+                let response = match allowed_headers.is_empty() {
+                    true => response,
+                    false => response.header("Access-Control-Allow-Headers", allowed_headers),
+                };
+                match self.max_age {
+                    Some(seconds) => response.header("Access-Control-Max-Age", seconds.to_string()),
+                    None => response,
+                }
+            }
+        };
+        response.vary("Origin").vary("Access-Control-Request-Method").vary("Access-Control-Request-Headers")
+    }
+}
+
+/// A policy and the paths it applies to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CorsRule {
+    pub pattern: String,
+    pub policy: CorsPolicy,
+}
+
+impl CorsRule {
+    /// Parse `PATTERN=SETTINGS`, see [`CorsPolicy::parse`]
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let invalid = |e: String| format!("Invalid CORS rule '{}': {}", spec, e);
+        let (pattern, settings) = spec.split_once('=').ok_or_else(|| invalid("expected PATTERN=SETTINGS".to_string()))?;
+        if !(pattern.starts_with('/') || pattern == "*") {
+            return Err(invalid("the pattern must be a path or *".to_string()));
+        }
+        Ok(CorsRule {
+            pattern: pattern.to_string(),
+            policy: CorsPolicy::parse(settings).map_err(invalid)?,
+        })
+    }
+}
+
+/// Middleware applying the first matching rule's policy
+#[derive(Debug, Clone, Default)]
+pub struct Cors {
+    pub rules: Vec<CorsRule>,
+}
+
+impl Cors {
+    /// Apply `policy` to paths matching `pattern`, after the rules so far
+    pub fn route(mut self, pattern: &str, policy: CorsPolicy) -> Self {
+        self.rules.push(CorsRule {
+            pattern: pattern.to_string(),
+            policy,
+        });
+        self
+    }
+
+    pub fn policy_for(&self, path: &str) -> Option<&CorsPolicy> {
+        self.rules.iter().find(|rule| path_matches(&rule.pattern, path)).map(|rule| &rule.policy)
+    }
+
+    pub fn handle(&self, request: &HttpRequest, next: Next<'_>) -> Result<HttpResponse> {
+        let path = request.path.split('?').next().unwrap_or_default();
+        let Some(policy) = self.policy_for(path) else {
+            return next.run(request);
+        };
+        let origin = request.get_header("Origin");
+        let preflight_method = request.get_header("Access-Control-Request-Method");
+        if let (HttpMethod::OPTIONS, Some(origin), Some(method)) = (&request.method, origin, preflight_method) {
+            return Ok(policy.preflight(request, origin, method));
+        }
+
+        let response = next.run(request)?;
+        let response = match policy.echoes_origin() {
+            true => response.vary("Origin"),
+            false => response,
+        };
+        match origin.filter(|origin| policy.allows_origin(origin)) {
+            Some(origin) => {
+                let response = policy.allow_origin(response, origin);
+                match policy.expose_headers.is_empty() {
+                    true => Ok(response),
+                    false => Ok(response.header("Access-Control-Expose-Headers", policy.expose_headers.join(", "))),
+                }
+            }
+            None => Ok(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policies_and_match_origins() {
+        let spec = "/api/*=origins=https://app.example.com,https://*.example.org;methods=get,post;max-age=600;credentials";
+        let rule = CorsRule::parse(spec).unwrap();
+        assert_eq!(rule.pattern, "/api/*");
+        assert_eq!(rule.policy.methods, ["GET", "POST"]);
+        assert_eq!((rule.policy.max_age, rule.policy.credentials), (Some(600), true));
+        assert!(rule.policy.allows_origin("https://app.example.com"));
+        assert!(rule.policy.allows_origin("https://admin.example.org"));
+        assert!(!rule.policy.allows_origin("https://example.org"));
+        assert!(!rule.policy.allows_origin("http://admin.example.org"));
+        assert!(!rule.policy.allows_origin("https://evil.com/.example.org"));
+        assert!(CorsPolicy::new(&["*"]).allows_origin("https://anywhere.test"));
+
+        let invalid = ["/api", "api=origins=*", "/api=methods=GET", "/api=origins=*;credentials", "/api=origins=*;max-age=x"];
+        for spec in invalid.into_iter().chain(["/api=origins=*;colour=red"]) {
+            assert!(CorsRule::parse(spec).is_err(), "{}", spec);
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// An exclusively locked PID file, removed again when dropped
+pub struct PidFile {
+    file: File,
+    path: PathBuf,
+}
+
+impl PidFile {
+    /// Lock the PID file, failing if another running instance holds it
+    pub fn lock(path: &Path) -> io::Result<Self> {
+        Self::acquire(path, false)
+    }
+
+    /// Lock the PID file, waiting for the current holder to exit
+    pub fn wait_for_lock(path: &Path) -> io::Result<Self> {
+        Self::acquire(path, true)
+    }
+
+    fn acquire(path: &Path, wait: bool) -> io::Result<Self> {
+        loop {
+            let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+            if let Err(e) = lock_file(&file, wait) {
+                if e.kind() == io::ErrorKind::WouldBlock {
+                    let holder = std::fs::read_to_string(path).unwrap_or_default();
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is locked by another instance (pid {})", path.display(), holder.trim()),
+                    ));
+                }
+                return Err(e);
+            }
+
+            // The previous holder removes the file before unlocking it, so make
+            // sure the lock we got is on the file that's still at `path`
+            if same_file(&file, path) {
+                return Ok(PidFile {
+                    file,
+                    path: path.to_path_buf(),
+                });
+            }
+        }
+    }
+
+    /// Record the current process ID in the file
+    pub fn write_pid(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        let mut file = &self.file;
+        writeln!(file, "{}", std::process::id())?;
+        file.flush()
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // Remove while still holding the lock; it is released when `file` closes
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(unix)]
+fn lock_file(file: &File, wait: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let operation = if wait { libc::LOCK_EX } else { libc::LOCK_EX | libc::LOCK_NB };
+    // SAFETY: flock on a valid, owned descriptor
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(unix))]
+fn lock_file(_file: &File, _wait: bool) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(unix)]
+fn same_file(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(_file: &File, _path: &Path) -> bool {
+    true
+}
+
+/// Detach from the terminal and continue in a background process
+///
+/// Must be called before any threads are started. The working directory is
+/// kept so relative paths in the configuration still resolve.
+#[cfg(unix)]
+pub fn daemonize() -> io::Result<()> {
+    // SAFETY: the process is still single-threaded, so fork only duplicates
+    // this thread; the parents exit immediately without running destructors
+    unsafe {
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+        if libc::setsid() == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        // Fork again so the daemon is not a session leader and can never
+        // reacquire a controlling terminal
+        match libc::fork() {
+            -1 => return Err(io::Error::last_os_error()),
+            0 => {}
+            _ => libc::_exit(0),
+        }
+    }
+
+    // Point stdio at /dev/null; logs go to --log-file / --access-log instead
+    let null = OpenOptions::new().read(true).write(true).open("/dev/null")?;
+    {
+        use std::os::fd::AsRawFd;
+        for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            // SAFETY: dup2 onto the standard descriptors
+            if unsafe { libc::dup2(null.as_raw_fd(), fd) } == -1 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize() -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--daemon requires a Unix platform"))
+}
+
+/// Switch to an unprivileged user and/or group after binding privileged ports
+///
+/// The group is changed first, since changing the user gives up the right to
+/// do so. Without `group`, the user's primary group is used. Succeeds without
+/// changes when already running as the target (e.g. after a binary upgrade).
+#[cfg(unix)]
+pub fn drop_privileges(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    let user = user.map(lookup_user).transpose()?;
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => user.as_ref().map(|(_, gid)| *gid),
+    };
+
+    // SAFETY: plain credential syscalls with no pointer arguments beyond `groups`
+    unsafe {
+        if let Some(gid) = gid {
+            if libc::getegid() != gid || libc::getgid() != gid {
+                let groups = [gid];
+                if libc::setgroups(1, groups.as_ptr()) != 0 || libc::setgid(gid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        if let Some((uid, _)) = user {
+            if libc::geteuid() != uid || libc::getuid() != uid {
+                if libc::setuid(uid) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                // Make sure root can't be regained
+                if uid != 0 && libc::setuid(0) == 0 {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied, "privileges could be regained after setuid"));
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_user: Option<&str>, _group: Option<&str>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "--user/--group require a Unix platform"))
+}
+
+/// Check that the --user and --group names resolve, without switching to them
+#[cfg(unix)]
+pub fn check_identity(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    user.map(lookup_user).transpose()?;
+    group.map(lookup_group).transpose()?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check_identity(user: Option<&str>, group: Option<&str>) -> io::Result<()> {
+    if user.is_none() && group.is_none() {
+        return Ok(());
+    }
+    drop_privileges(user, group)
+}
+
+/// Resolve a user name or numeric ID to (uid, primary gid)
+#[cfg(unix)]
+fn lookup_user(name: &str) -> io::Result<(libc::uid_t, libc::gid_t)> {
+    let c_name = std::ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut entry = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+
+    // SAFETY: all pointers reference live buffers of the stated sizes
+    let found = unsafe {
+        libc::getpwnam_r(c_name.as_ptr(), entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result) == 0
+            && !result.is_null()
+    };
+    if found {
+        // SAFETY: getpwnam_r initialized the entry when it returned a result
+        let entry = unsafe { entry.assume_init() };
+        return Ok((entry.pw_uid, entry.pw_gid));
+    }
+    match name.parse() {
+        Ok(uid) => Ok((uid, lookup_primary_gid(uid).unwrap_or(uid))),
+        Err(_) => Err(io::Error::new(io::ErrorKind::NotFound, format!("unknown user '{}'", name))),
+    }
+}
+
+/// Primary group of a numeric user ID, if it has a passwd entry
+#[cfg(unix)]
+fn lookup_primary_gid(uid: libc::uid_t) -> Option<libc::gid_t> {
+    let mut entry = std::mem::MaybeUninit::<libc::passwd>::uninit();
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+
+    // SAFETY: all pointers reference live buffers of the stated sizes
+    unsafe {
+        (libc::getpwuid_r(uid, entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result) == 0 && !result.is_null())
+            .then(|| entry.assume_init().pw_gid)
+    }
+}
+
+/// Resolve a group name or numeric ID
+#[cfg(unix)]
+fn lookup_group(name: &str) -> io::Result<libc::gid_t> {
+    let c_name = std::ffi::CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut entry = std::mem::MaybeUninit::<libc::group>::uninit();
+    let mut result = std::ptr::null_mut();
+    let mut buf = vec![0 as libc::c_char; 16 * 1024];
+
+    // SAFETY: all pointers reference live buffers of the stated sizes
+    let found = unsafe {
+        libc::getgrnam_r(c_name.as_ptr(), entry.as_mut_ptr(), buf.as_mut_ptr(), buf.len(), &mut result) == 0
+            && !result.is_null()
+    };
+    if found {
+        // SAFETY: getgrnam_r initialized the entry when it returned a result
+        return Ok(unsafe { entry.assume_init() }.gr_gid);
+    }
+    name.parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::NotFound, format!("unknown group '{}'", name)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_pid_file_is_exclusive() {
+        let path = std::env::temp_dir().join(format!("http-server-{}.pid", std::process::id()));
+
+        let mut pid_file = PidFile::lock(&path).unwrap();
+        pid_file.write_pid().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().trim(), std::process::id().to_string());
+
+        let err = PidFile::lock(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+
+        drop(pid_file);
+        assert!(!path.exists());
+        drop(PidFile::lock(&path).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_lookup_root() {
+        assert_eq!(lookup_user("root").unwrap(), (0, 0));
+        assert_eq!(lookup_group("0").unwrap(), 0);
+        assert!(lookup_user("no-such-user-here").is_err());
+    }
+}
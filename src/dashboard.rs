@@ -0,0 +1,191 @@
+//! Operator dashboard
+//!
+//! `GET /dashboard` renders the live metrics as a small HTML page that
+//! reloads itself, for operators without a Grafana setup: request and error
+//! rates over the last minute, the latency distribution, connections and
+//! workers, and the most recent slow requests. Like `/admin/*`, it needs the
+//! admin token; browsers can give it as the password of a Basic login.
+//!
+//! `GET /admin/metrics/stream` sends the same snapshot as an event stream
+//! (SSE) every few seconds, for tools drawing live graphs. A stream ends after
+//! [`STREAM_DURATION`] and the client reconnects, so it never holds a worker
+//! for long.
+
+use crate::build_info;
+use crate::metrics::ServerMetrics;
+use crate::response::HttpResponse;
+use crate::template;
+use serde_json::{json, Value};
+use std::io::{self, Read};
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often the page reloads, and the default interval between streamed
+/// snapshots
+pub const REFRESH_SECS: u64 = 5;
+
+/// Longest interval a stream may ask for
+pub const MAX_STREAM_INTERVAL_SECS: u64 = 60;
+
+/// How long a metrics stream lasts before the client has to reconnect
+pub const STREAM_DURATION: Duration = Duration::from_secs(60);
+
+/// Round to two decimals for display
+fn round2(value: f64) -> f64 {
+    (value * 100.0).round() / 100.0
+}
+
+/// A latency bucket bound for people: "≤ 5 ms", "≤ 2.5 s"
+fn bound_label(seconds: f64) -> String {
+    if seconds.is_infinite() {
+        "more".to_string()
+    } else if seconds < 1.0 {
+        format!("≤ {} ms", seconds * 1000.0)
+    } else {
+        format!("≤ {} s", seconds)
+    }
+}
+
+/// The current state of the server, as shown on the dashboard
+pub fn snapshot(metrics: &ServerMetrics) -> Value {
+    let (request_rate, error_rate) = metrics.recent_rates();
+    let latency = metrics.overall_latency();
+    let distribution = latency.distribution();
+    let busiest = distribution.iter().map(|(_, count)| *count).max().unwrap_or_default().max(1);
+    let buckets: Vec<Value> = distribution
+        .into_iter()
+        .map(|(bound, count)| {
+            json!({
+                "label": bound_label(bound),
+                "count": count,
+                "percent": round2(count as f64 * 100.0 / busiest as f64),
+            })
+        })
+        .collect();
+    let requests = metrics.request_count.load(Ordering::Relaxed);
+    let errors = metrics.error_count.load(Ordering::Relaxed);
+    let slow: Vec<Value> = metrics.recent_slow_requests().iter().map(|slow| slow.to_json()).collect();
+
+    json!({
+        "timestamp": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        "uptime_seconds": metrics.uptime_seconds(),
+        "requests": requests,
+        "errors": errors,
+        "request_rate": round2(request_rate),
+        "error_rate": round2(error_rate),
+        "error_percent": if requests > 0 { round2(errors as f64 * 100.0 / requests as f64) } else { 0.0 },
+        "avg_latency_ms": if latency.count() > 0 { round2(latency.sum() * 1000.0 / latency.count() as f64) } else { 0.0 },
+        "latency": buckets,
+        "active_connections": metrics.active_connections.load(Ordering::Relaxed),
+        "worker_pool": {
+            "size": metrics.worker_pool_size.load(Ordering::Relaxed),
+            "active": metrics.workers_busy.load(Ordering::Relaxed),
+            "queued": metrics.jobs_queued.load(Ordering::Relaxed),
+        },
+        "slow_requests": slow,
+    })
+}
+
+/// The dashboard page
+pub fn render(metrics: &ServerMetrics) -> String {
+    let mut context = snapshot(metrics);
+    context["refresh_secs"] = json!(REFRESH_SECS);
+    context["version"] = json!(build_info::VERSION);
+    template::current().dashboard.render(&context)
+}
+
+/// An event stream of snapshots of `metrics`, one straight away and then one
+/// every `interval`
+pub fn stream(metrics: Arc<ServerMetrics>, interval: Duration) -> HttpResponse {
+    let stream = MetricsStream {
+        metrics,
+        interval,
+        next: Instant::now(),
+        end: Instant::now() + STREAM_DURATION,
+        pending: b"retry: 1000\n\n".to_vec(),
+    };
+    HttpResponse::ok()
+        .header("Content-Type", "text/event-stream")
+        .header("Cache-Control", "no-store")
+        .stream(stream, None)
+}
+
+/// The body of a metrics stream: a snapshot event at each tick until the end
+struct MetricsStream {
+    metrics: Arc<ServerMetrics>,
+    interval: Duration,
+    next: Instant,
+    end: Instant,
+    pending: Vec<u8>,
+}
+
+impl Read for MetricsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && self.next < self.end {
+            std::thread::sleep(self.next.saturating_duration_since(Instant::now()));
+            self.next += self.interval;
+            self.pending = format!("data: {}\n\n", snapshot(&self.metrics)).into_bytes();
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::SlowRequest;
+    use std::time::Duration;
+
+    #[test]
+    fn test_dashboard_shows_latency_and_slow_requests() {
+        let metrics = ServerMetrics::new();
+        metrics.request_count.fetch_add(3, Ordering::Relaxed);
+        metrics.record_request("GET", "/", 200, Duration::from_millis(3));
+        metrics.record_request("GET", "/", 200, Duration::from_millis(4));
+        metrics.record_request("GET", "/delay/:seconds", 200, Duration::from_secs(2));
+        metrics.record_slow_request(SlowRequest {
+            time: "2026-10-16T12:00:00+00:00".to_string(),
+            request_line: "GET /delay/2 HTTP/1.1".to_string(),
+            client: None,
+            status: 200,
+            duration: Duration::from_secs(2),
+        });
+
+        let snapshot = snapshot(&metrics);
+        assert_eq!(snapshot["latency"][0], json!({"label": "≤ 5 ms", "count": 2, "percent": 100.0}));
+        assert_eq!(snapshot["latency"][8], json!({"label": "≤ 2.5 s", "count": 1, "percent": 50.0}));
+        assert_eq!(snapshot["latency"][11]["label"], "more");
+        assert_eq!(snapshot["slow_requests"][0]["duration_ms"], 2000);
+
+        let page = render(&metrics);
+        assert!(page.contains("http-equiv=\"refresh\" content=\"5\""), "{}", page);
+        assert!(page.contains("GET /delay/2 HTTP/1.1"));
+    }
+
+    #[test]
+    fn test_stream_sends_a_snapshot_each_interval_until_the_end() {
+        let metrics = Arc::new(ServerMetrics::new());
+        metrics.request_count.fetch_add(7, Ordering::Relaxed);
+        let start = Instant::now();
+        let mut stream = MetricsStream {
+            metrics,
+            interval: Duration::from_millis(20),
+            next: start,
+            end: start + Duration::from_millis(50),
+            pending: b"retry: 1000\n\n".to_vec(),
+        };
+        let mut body = String::new();
+        stream.read_to_string(&mut body).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(40), "{:?}", start.elapsed());
+
+        let events: Vec<&str> = body.split("\n\n").filter(|event| !event.is_empty()).collect();
+        assert_eq!(events.len(), 4, "{}", body);
+        assert_eq!(events[0], "retry: 1000");
+        let first: Value = serde_json::from_str(events[1].strip_prefix("data: ").unwrap()).unwrap();
+        assert_eq!(first["requests"], 7);
+    }
+}
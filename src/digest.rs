@@ -0,0 +1,147 @@
+//! Integrity digests for file downloads
+//!
+//! With `--file-digests sha-256,md5`, file responses carry
+//! `Repr-Digest: sha-256=:…:` (RFC 9530) and `Content-MD5` so clients can
+//! check what they received. Digests cover the representation as sent, so a
+//! gzip download has a different digest from the plain file. Hashing a large
+//! file is not free, so digests are kept in an index keyed by file version
+//! and only computed the first time that version is served.
+
+use crate::compression::Compression;
+use crate::response::HttpResponse;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::ValueEnum;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+/// The index is emptied when it reaches this many entries
+const MAX_ENTRIES: usize = 10_000;
+
+/// A checksum sent with file downloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DigestAlgorithm {
+    /// SHA-256 in a `Repr-Digest` header
+    #[value(name = "sha-256")]
+    Sha256,
+    /// MD5 in a `Content-MD5` header, for older clients
+    Md5,
+}
+
+/// Digest header fields for one representation of a file version
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Digests {
+    pub headers: Vec<(&'static str, String)>,
+}
+
+impl Digests {
+    pub fn compute(algorithms: &[DigestAlgorithm], data: &[u8]) -> Self {
+        let headers = algorithms
+            .iter()
+            .map(|algorithm| match algorithm {
+                DigestAlgorithm::Sha256 => ("Repr-Digest", format!("sha-256=:{}:", BASE64.encode(Sha256::digest(data)))),
+                DigestAlgorithm::Md5 => ("Content-MD5", BASE64.encode(Md5::digest(data))),
+            })
+            .collect();
+        Digests { headers }
+    }
+
+    /// Add the headers to a response carrying the whole representation
+    pub fn apply(&self, response: HttpResponse) -> HttpResponse {
+        self.headers.iter().fold(response, |response, (name, value)| response.header(*name, value.clone()))
+    }
+
+    /// Add the headers that still hold for a 206 response: `Repr-Digest`
+    /// describes the whole representation, while `Content-MD5` would have
+    /// to cover just the bytes sent
+    pub fn apply_partial(&self, response: HttpResponse) -> HttpResponse {
+        self.headers
+            .iter()
+            .filter(|(name, _)| *name == "Repr-Digest")
+            .fold(response, |response, (name, value)| response.header(*name, value.clone()))
+    }
+}
+
+/// Digests together with the file version they were computed for
+struct IndexEntry {
+    mtime: SystemTime,
+    digests: Arc<Digests>,
+}
+
+/// Digests of the files served so far, keyed by path and encoding
+pub struct DigestIndex {
+    algorithms: Vec<DigestAlgorithm>,
+    entries: Mutex<HashMap<(PathBuf, Compression), IndexEntry>>,
+}
+
+impl DigestIndex {
+    pub fn new(algorithms: &[DigestAlgorithm]) -> Self {
+        let mut unique = Vec::new();
+        for algorithm in algorithms {
+            if !unique.contains(algorithm) {
+                unique.push(*algorithm);
+            }
+        }
+        DigestIndex {
+            algorithms: unique,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The digests of `data`, the body of `path` as of `mtime` with
+    /// `compression` applied, computed only if this version is new
+    pub fn get(&self, path: &Path, mtime: SystemTime, compression: Compression, data: &[u8]) -> Arc<Digests> {
+        let key = (path.to_path_buf(), compression);
+        if let Some(entry) = self.entries.lock().unwrap().get(&key) {
+            if entry.mtime == mtime {
+                return Arc::clone(&entry.digests);
+            }
+        }
+
+        // Hash outside the lock so other downloads are not held up
+        let digests = Arc::new(Digests::compute(&self.algorithms, data));
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= MAX_ENTRIES {
+            entries.clear();
+        }
+        entries.insert(key, IndexEntry { mtime, digests: Arc::clone(&digests) });
+        digests
+    }
+
+    /// Forget the digests of every path `matches` accepts
+    pub fn invalidate(&self, matches: impl Fn(&Path) -> bool) {
+        self.entries.lock().unwrap().retain(|(path, _), _| !matches(path));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_digests_are_cached_per_version() {
+        let index = DigestIndex::new(&[DigestAlgorithm::Sha256, DigestAlgorithm::Md5]);
+        let path = Path::new("hello.txt");
+        let t0 = SystemTime::UNIX_EPOCH;
+
+        let digests = index.get(path, t0, Compression::None, b"hello");
+        assert_eq!(
+            digests.headers,
+            vec![
+                ("Repr-Digest", "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:".to_string()),
+                ("Content-MD5", "XUFAKrxLKna5cZ2REBfFkg==".to_string()),
+            ]
+        );
+        // Same version: the stored digests, whatever the data
+        assert_eq!(index.get(path, t0, Compression::None, b"other"), digests);
+        assert_ne!(index.get(path, t0 + Duration::from_secs(1), Compression::None, b"other"), digests);
+
+        index.invalidate(|_| true);
+        assert_ne!(index.get(path, t0, Compression::None, b"other"), digests);
+    }
+}
@@ -1,3 +1,7 @@
+use crate::problem::Problem;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::status::StatusCode;
 use std::io;
 use thiserror::Error;
 
@@ -22,6 +26,27 @@ pub enum ServerError {
     #[error("Parse error: {0}")]
     ParseError(String),
 
+    #[error("Content too large: {0}")]
+    ContentTooLarge(String),
+
+    #[error("Unsupported media type: {0}")]
+    UnsupportedMediaType(String),
+
+    #[error("Unprocessable content: {0}")]
+    UnprocessableContent(String),
+
+    #[error("Precondition failed: {0}")]
+    PreconditionFailed(String),
+
+    #[error("Bad gateway: {0}")]
+    BadGateway(String),
+
+    #[error("Gateway timeout: {0}")]
+    GatewayTimeout(String),
+
+    #[error("Overloaded: {0}")]
+    Overloaded(String),
+
     #[error("Configuration error: {0}")]
     ConfigError(String),
 
@@ -32,32 +57,72 @@ pub enum ServerError {
 pub type Result<T> = std::result::Result<T, ServerError>;
 
 impl ServerError {
-    /// Convert error to HTTP status code
-    pub fn status_code(&self) -> u16 {
+    /// Convert error to HTTP status
+    pub fn status(&self) -> StatusCode {
         match self {
-            ServerError::FileNotFound(_) => 404,
-            ServerError::InvalidRequest(_) | ServerError::InvalidMethod(_) => 400,
-            ServerError::ParseError(_) => 400,
-            _ => 500,
+            ServerError::FileNotFound(_) => StatusCode::NotFound,
+            ServerError::InvalidRequest(_) | ServerError::InvalidMethod(_) => StatusCode::BadRequest,
+            ServerError::ParseError(_) => StatusCode::BadRequest,
+            ServerError::ContentTooLarge(_) => StatusCode::ContentTooLarge,
+            ServerError::UnsupportedMediaType(_) => StatusCode::UnsupportedMediaType,
+            ServerError::UnprocessableContent(_) => StatusCode::UnprocessableContent,
+            ServerError::PreconditionFailed(_) => StatusCode::PreconditionFailed,
+            ServerError::BadGateway(_) => StatusCode::BadGateway,
+            ServerError::GatewayTimeout(_) => StatusCode::GatewayTimeout,
+            ServerError::Overloaded(_) => StatusCode::ServiceUnavailable,
+            _ => StatusCode::InternalServerError,
         }
     }
 
-    /// Convert error to HTTP response
-    pub fn to_response(&self) -> String {
-        let status_code = self.status_code();
-        let status_text = match status_code {
-            400 => "Bad Request",
-            404 => "Not Found",
-            500 => "Internal Server Error",
-            _ => "Error",
-        };
-
-        format!(
-            "HTTP/1.1 {} {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
-            status_code,
-            status_text,
-            self.to_string().len(),
-            self.to_string()
+    /// Whether the client went away (broken pipe, connection reset, a body
+    /// cut short) rather than something failing on the server's side
+    pub fn is_client_abort(&self) -> bool {
+        matches!(
+            self,
+            ServerError::Io(e) if matches!(
+                e.kind(),
+                io::ErrorKind::BrokenPipe
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::UnexpectedEof
+            )
         )
     }
+
+    /// Convert error to a numeric HTTP status code
+    pub fn status_code(&self) -> u16 {
+        self.status().as_u16()
+    }
+
+    /// Stable machine-readable code for problem details
+    pub fn code(&self) -> &'static str {
+        match self {
+            ServerError::Io(_) => "io_error",
+            ServerError::InvalidRequest(_) => "invalid_request",
+            ServerError::InvalidMethod(_) => "invalid_method",
+            ServerError::FileNotFound(_) => "file_not_found",
+            ServerError::CompressionError(_) => "compression_error",
+            ServerError::ParseError(_) => "parse_error",
+            ServerError::ContentTooLarge(_) => "content_too_large",
+            ServerError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ServerError::UnprocessableContent(_) => "unprocessable_content",
+            ServerError::PreconditionFailed(_) => "precondition_failed",
+            ServerError::BadGateway(_) => "bad_gateway",
+            ServerError::GatewayTimeout(_) => "gateway_timeout",
+            ServerError::Overloaded(_) => "overloaded",
+            ServerError::ConfigError(_) => "config_error",
+            ServerError::InternalError(_) => "internal_error",
+        }
+    }
+
+    /// Convert error to an `application/problem+json` response
+    pub fn to_response(&self) -> HttpResponse {
+        Problem::from(self).to_response(None)
+    }
+
+    /// Convert error to a problem response for `request`, in the format its
+    /// Accept header prefers
+    pub fn to_response_for(&self, request: &HttpRequest) -> HttpResponse {
+        Problem::from(self).instance(&request.path).to_response(Some(request))
+    }
 }
@@ -0,0 +1,93 @@
+//! A map holding at most one value of each type
+//!
+//! Used for application state shared through the router, so handlers can
+//! reach database pools, caches, and clients without global statics, and for
+//! per-request values middleware passes on to handlers, such as the
+//! authenticated user or the client address resolved from proxy headers.
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::Arc;
+
+/// Values keyed by their type
+///
+/// Values are reference counted, so cloning the map is cheap.
+#[derive(Clone, Default)]
+pub struct Extensions {
+    map: HashMap<TypeId, Arc<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Store `value`, replacing any earlier value of the same type
+    pub fn insert<T: Send + Sync + 'static>(&mut self, value: T) {
+        self.map.insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    /// The value of type `T`, if one was stored
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    /// A shared handle to the value of type `T`, for holding on to it after
+    /// the map is gone or unlocked
+    pub fn get_arc<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        Arc::clone(self.map.get(&TypeId::of::<T>())?).downcast().ok()
+    }
+
+    pub fn contains<T: Send + Sync + 'static>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Add the values of `other` whose types this map doesn't hold yet
+    pub fn extend(&mut self, other: &Extensions) {
+        for (type_id, value) in &other.map {
+            self.map.entry(*type_id).or_insert_with(|| Arc::clone(value));
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+}
+
+impl fmt::Debug for Extensions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Extensions").field("len", &self.map.len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_values_are_keyed_by_type() {
+        let mut extensions = Extensions::new();
+        extensions.insert(42u32);
+        extensions.insert(String::from("pool"));
+        extensions.insert(7u32);
+
+        assert_eq!(extensions.len(), 2);
+        assert_eq!(extensions.get::<u32>(), Some(&7));
+        assert_eq!(extensions.get::<String>().map(String::as_str), Some("pool"));
+        assert_eq!(extensions.get::<u64>(), None);
+        assert_eq!(extensions.get_arc::<u32>().as_deref(), Some(&7));
+
+        let mut other = Extensions::new();
+        other.insert(1u32);
+        other.insert(1.5f64);
+        other.extend(&extensions);
+        assert_eq!(other.get::<u32>(), Some(&1));
+        assert!(other.contains::<String>());
+        assert_eq!(other.len(), 3);
+    }
+}
@@ -0,0 +1,383 @@
+//! Typed extractors for route handlers
+//!
+//! A handler can take its inputs as arguments instead of digging them out of
+//! the request, and [`handler`] adapts it for the router:
+//!
+//! ```
+//! use http_server::extract::{self, Json, Path};
+//! use http_server::response::HttpResponse;
+//! use http_server::router::Router;
+//! use serde::Deserialize;
+//!
+//! #[derive(Deserialize)]
+//! struct Rename {
+//!     name: String,
+//! }
+//!
+//! fn rename(Path(id): Path<u64>, Json(body): Json<Rename>) -> http_server::error::Result<HttpResponse> {
+//!     Ok(HttpResponse::ok().text(format!("user {} is now {}", id, body.name)))
+//! }
+//!
+//! let router = Router::empty().put("/users/{id}", extract::handler(rename));
+//! ```
+//!
+//! When an argument can't be extracted the handler isn't called and the
+//! client gets a problem response instead: 400 for malformed input, 415 for
+//! a body that isn't JSON, and 422 for JSON of the wrong shape.
+
+use crate::error::{Result, ServerError};
+use crate::openapi;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::router::Handler;
+use serde::de::value::{Error as ValueError, MapDeserializer, SeqDeserializer, StrDeserializer};
+use serde::de::{self, DeserializeOwned, Error as _, IntoDeserializer, Visitor};
+use serde_json::{json, Value};
+use std::marker::PhantomData;
+
+/// A value that can be built from a request, for use as a handler argument
+pub trait FromRequest: Sized {
+    fn from_request(request: &HttpRequest) -> Result<Self>;
+
+    /// Add what the argument needs from requests, and the errors it can give,
+    /// to the handler's OpenAPI operation
+    fn describe(_operation: &mut Value) {}
+}
+
+/// The matched route's `{name}` parameters
+///
+/// `T` is a single value for routes with one parameter, a tuple for several
+/// in order, or a struct with fields named like the parameters.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Path<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Path<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self> {
+        let params = request.route.get().map_or(&[][..], |route| &route.params[..]);
+        T::deserialize(PathDeserializer { params })
+            .map(Path)
+            .map_err(|e| ServerError::InvalidRequest(format!("Invalid path parameters: {}", e)))
+    }
+
+    fn describe(operation: &mut Value) {
+        openapi::error_response(operation, 400, "Invalid path parameters");
+    }
+}
+
+/// The query string, deserialized from `application/x-www-form-urlencoded`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Query<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self> {
+        let query = request.path.split_once('?').map_or("", |(_, query)| query);
+        serde_urlencoded::from_str(query)
+            .map(Query)
+            .map_err(|e| ServerError::InvalidRequest(format!("Invalid query string: {}", e)))
+    }
+
+    fn describe(operation: &mut Value) {
+        openapi::error_response(operation, 400, "Invalid query string");
+    }
+}
+
+/// A JSON request body; the Content-Type must be `application/json` or a
+/// `+json` type
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+impl<T: DeserializeOwned> FromRequest for Json<T> {
+    fn from_request(request: &HttpRequest) -> Result<Self> {
+        let content_type = request.get_header("content-type").unwrap_or_default();
+        let media_type = content_type.split(';').next().unwrap_or_default().trim().to_ascii_lowercase();
+        if media_type != "application/json" && !media_type.ends_with("+json") {
+            return Err(ServerError::UnsupportedMediaType(format!(
+                "Expected a JSON body, got Content-Type {:?}",
+                content_type
+            )));
+        }
+        serde_json::from_slice(&request.body).map(Json).map_err(|e| match e.classify() {
+            serde_json::error::Category::Data => ServerError::UnprocessableContent(e.to_string()),
+            _ => ServerError::InvalidRequest(format!("Malformed JSON body: {}", e)),
+        })
+    }
+
+    fn describe(operation: &mut Value) {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": { "type": "object" } } },
+        });
+        openapi::error_response(operation, 400, "Malformed JSON body");
+        openapi::error_response(operation, 415, "The body isn't JSON");
+        openapi::error_response(operation, 422, "The JSON doesn't have the expected shape");
+    }
+}
+
+/// A function whose arguments are all extractors
+///
+/// Implemented for functions of one to four [`FromRequest`] arguments
+/// returning `Result<HttpResponse>`.
+pub trait ExtractHandler<Args>: Send + Sync + 'static {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse>;
+
+    /// Describe the arguments in the OpenAPI operation
+    fn describe(operation: &mut Value);
+}
+
+macro_rules! extract_handler {
+    ($($arg:ident),+) => {
+        impl<F, $($arg),+> ExtractHandler<($($arg,)+)> for F
+        where
+            F: Fn($($arg),+) -> Result<HttpResponse> + Send + Sync + 'static,
+            $($arg: FromRequest,)+
+        {
+            #[allow(non_snake_case)]
+            fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+                $(let $arg = $arg::from_request(request)?;)+
+                self($($arg),+)
+            }
+
+            fn describe(operation: &mut Value) {
+                $($arg::describe(operation);)+
+            }
+        }
+    };
+}
+
+extract_handler!(A);
+extract_handler!(A, B);
+extract_handler!(A, B, C);
+extract_handler!(A, B, C, D);
+
+/// Adapt a function taking extractors into a [`Handler`]
+pub fn handler<Args: 'static>(function: impl ExtractHandler<Args>) -> impl Handler {
+    Extracted {
+        function,
+        args: PhantomData,
+    }
+}
+
+struct Extracted<F, Args> {
+    function: F,
+    args: PhantomData<fn() -> Args>,
+}
+
+impl<F: ExtractHandler<Args>, Args: 'static> Handler for Extracted<F, Args> {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        self.function.call(request)
+    }
+
+    fn describe(&self, operation: &mut Value) {
+        F::describe(operation);
+    }
+}
+
+/// Deserializes route parameters as a map, a sequence, or a single value
+struct PathDeserializer<'a> {
+    params: &'a [(String, String)],
+}
+
+impl<'a> PathDeserializer<'a> {
+    fn single(&self) -> std::result::Result<ParamDeserializer<'a>, ValueError> {
+        match self.params {
+            [(_, value)] => Ok(ParamDeserializer(value)),
+            params => Err(ValueError::custom(format!("expected 1 parameter, found {}", params.len()))),
+        }
+    }
+}
+
+macro_rules! forward_to_single {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, ValueError> {
+                self.single()?.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for PathDeserializer<'_> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, ValueError> {
+        let entries = self.params.iter().map(|(name, value)| (name.as_str(), ParamDeserializer(value)));
+        visitor.visit_map(MapDeserializer::new(entries))
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, ValueError> {
+        visitor.visit_seq(SeqDeserializer::new(self.params.iter().map(|(_, value)| ParamDeserializer(value))))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(self, _len: usize, visitor: V) -> std::result::Result<V::Value, ValueError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _len: usize,
+        visitor: V,
+    ) -> std::result::Result<V::Value, ValueError> {
+        self.deserialize_seq(visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> std::result::Result<V::Value, ValueError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> std::result::Result<V::Value, ValueError> {
+        self.single()?.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, ValueError> {
+        visitor.visit_unit()
+    }
+
+    forward_to_single! {
+        deserialize_bool deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64 deserialize_f32 deserialize_f64
+        deserialize_char deserialize_str deserialize_string deserialize_bytes deserialize_byte_buf
+        deserialize_option
+    }
+
+    serde::forward_to_deserialize_any! {
+        unit_struct map struct identifier ignored_any
+    }
+}
+
+/// Deserializes one parameter, parsing it for numeric and boolean types
+struct ParamDeserializer<'a>(&'a str);
+
+macro_rules! parse_param {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, ValueError> {
+                match self.0.parse() {
+                    Ok(value) => visitor.$visit(value),
+                    Err(_) => Err(ValueError::custom(format!("cannot parse {:?}", self.0))),
+                }
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for ParamDeserializer<'_> {
+    type Error = ValueError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, ValueError> {
+        visitor.visit_str(self.0)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> std::result::Result<V::Value, ValueError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> std::result::Result<V::Value, ValueError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> std::result::Result<V::Value, ValueError> {
+        let value: StrDeserializer<'_, ValueError> = self.0.into_deserializer();
+        value.deserialize_enum(name, variants, visitor)
+    }
+
+    parse_param! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    serde::forward_to_deserialize_any! {
+        str string bytes byte_buf unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+impl<'de, 'a> IntoDeserializer<'de, ValueError> for ParamDeserializer<'a> {
+    type Deserializer = ParamDeserializer<'a>;
+
+    fn into_deserializer(self) -> Self::Deserializer {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::router::RouteMatch;
+    use serde::Deserialize;
+
+    fn routed(path: &str, params: &[(&str, &str)]) -> HttpRequest {
+        let request = HttpRequest::parse(&mut format!("GET {} HTTP/1.1\r\n\r\n", path).as_bytes()).unwrap();
+        let params = params.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        request.route.set(RouteMatch { pattern: "/test", params }).unwrap();
+        request
+    }
+
+    #[test]
+    fn test_path_parameters_deserialize_by_shape() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Ids {
+            org: String,
+            id: u32,
+        }
+        #[derive(Debug, Deserialize, PartialEq)]
+        #[serde(rename_all = "lowercase")]
+        enum Kind {
+            Team,
+            User,
+        }
+
+        let request = routed("/orgs/acme/users/7", &[("org", "acme"), ("id", "7")]);
+        assert_eq!(Path::<Ids>::from_request(&request).unwrap().0, Ids { org: "acme".into(), id: 7 });
+        assert_eq!(Path::<(String, u8)>::from_request(&request).unwrap().0, ("acme".to_string(), 7));
+        assert!(Path::<u32>::from_request(&request).is_err());
+
+        let request = routed("/kinds/user", &[("kind", "user")]);
+        assert_eq!(Path::<Kind>::from_request(&request).unwrap().0, Kind::User);
+        assert_eq!(Path::<String>::from_request(&request).unwrap().0, "user");
+        let e = Path::<u64>::from_request(&request).unwrap_err();
+        assert_eq!(e.status_code(), 400);
+        assert!(e.to_string().contains("cannot parse \"user\""), "{}", e);
+    }
+
+    #[test]
+    fn test_query_parameters_are_decoded() {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Search {
+            q: String,
+            limit: Option<u32>,
+        }
+
+        let request = routed("/search?q=rust%20http&limit=5", &[]);
+        assert_eq!(Query::<Search>::from_request(&request).unwrap().0, Search { q: "rust http".into(), limit: Some(5) });
+        let request = routed("/search?limit=many", &[]);
+        assert_eq!(Query::<Search>::from_request(&request).unwrap_err().status_code(), 400);
+    }
+}
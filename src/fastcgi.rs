@@ -0,0 +1,728 @@
+//! FastCGI client for php-fpm and other FastCGI applications
+//!
+//! Requests under the configured prefix are sent to the application as
+//! FastCGI records: the CGI meta-variables as PARAMS, the request body as
+//! STDIN. The application's STDOUT is a CGI response and is parsed the same
+//! way [`cgi`](crate::cgi) parses a script's output. Connections are kept
+//! open between requests and reused.
+//!
+//! A share of the requests can also be mirrored to a shadow application, such
+//! as a new version under test: the shadow gets the same request in the
+//! background, and its response is discarded.
+//!
+//! GET and HEAD requests can be retried when the application can't be reached
+//! or answers 502 or 503, on a fresh connection after a jittered, growing
+//! delay. Other methods are never retried, as the application may already have
+//! acted on them. The response is only sent once complete, so a retry never
+//! follows bytes already sent to the client.
+//!
+//! With a [`CircuitBreaker`], requests fail fast with 503 while too many of
+//! the recent ones to the application failed, and mirroring pauses while the
+//! shadow's own circuit is open.
+
+use crate::cgi::{environment, parse_output};
+use crate::circuit_breaker::CircuitBreaker;
+use crate::error::{Result, ServerError};
+use crate::request::{HttpMethod, HttpRequest};
+use crate::response::HttpResponse;
+use crate::router::Handler;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// Name of the catch-all route parameter holding the script path
+pub const PATH_PARAM: &str = "fastcgi_path";
+
+const VERSION: u8 = 1;
+const BEGIN_REQUEST: u8 = 1;
+const END_REQUEST: u8 = 3;
+const PARAMS: u8 = 4;
+const STDIN: u8 = 5;
+const STDOUT: u8 = 6;
+const STDERR: u8 = 7;
+const RESPONDER: u16 = 1;
+const KEEP_CONN: u8 = 1;
+const REQUEST_COMPLETE: u8 = 0;
+
+/// Largest record body; longer streams are split into several records
+const MAX_RECORD: usize = 65535;
+/// Idle connections kept for reuse
+const MAX_IDLE: usize = 8;
+/// Requests with longer bodies are not mirrored, as the body would have to
+/// be held in memory for the shadow
+const MAX_SHADOW_BODY: usize = 1024 * 1024;
+/// Mirrored requests still waiting for the shadow; more are skipped, so a
+/// slow shadow cannot pile up threads
+const MAX_SHADOW_IN_FLIGHT: usize = 64;
+/// Delay before the first retry, doubled for each one after it up to
+/// [`MAX_RETRY_DELAY`], of which a random half to all is waited
+const RETRY_DELAY: Duration = Duration::from_millis(50);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Where the FastCGI application listens
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FastCgiAddress {
+    /// `host:port`
+    Tcp(String),
+    /// `unix:/path/to/socket`
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl FastCgiAddress {
+    /// Parse `host:port` or `unix:/path`
+    pub fn parse(address: &str) -> std::result::Result<Self, String> {
+        if let Some(path) = address.strip_prefix("unix:") {
+            #[cfg(unix)]
+            return Ok(FastCgiAddress::Unix(PathBuf::from(path)));
+            #[cfg(not(unix))]
+            return Err(format!("Unix socket {} is not supported on this platform", path));
+        }
+        match address.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                Ok(FastCgiAddress::Tcp(address.to_string()))
+            }
+            _ => Err(format!("FastCGI address must be host:port or unix:/path, got '{}'", address)),
+        }
+    }
+
+    fn connect(&self, timeout: Duration) -> io::Result<Connection> {
+        let connection = match self {
+            FastCgiAddress::Tcp(address) => {
+                let stream = TcpStream::connect(address)?;
+                stream.set_nodelay(true)?;
+                Connection::Tcp(stream)
+            }
+            #[cfg(unix)]
+            FastCgiAddress::Unix(path) => Connection::Unix(UnixStream::connect(path)?),
+        };
+        connection.set_timeout(timeout)?;
+        Ok(connection)
+    }
+}
+
+impl std::fmt::Display for FastCgiAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FastCgiAddress::Tcp(address) => write!(f, "{}", address),
+            #[cfg(unix)]
+            FastCgiAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// A socket to the application
+enum Connection {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Connection {
+    fn set_timeout(&self, timeout: Duration) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            }
+            #[cfg(unix)]
+            Connection::Unix(stream) => {
+                stream.set_read_timeout(Some(timeout))?;
+                stream.set_write_timeout(Some(timeout))
+            }
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Connection::Unix(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Why an exchange with the application failed
+enum ExchangeError {
+    /// Nothing came back; on a reused connection the application has most
+    /// likely closed it while it was idle
+    NoResponse(io::Error),
+    Failed(io::Error),
+}
+
+/// Proxies requests under a URL prefix to a FastCGI application
+#[derive(Clone)]
+pub struct FastCgi {
+    pub prefix: String,
+    pub address: FastCgiAddress,
+    /// Directory the application finds scripts in, as the application sees
+    /// it; sent as DOCUMENT_ROOT and the base of SCRIPT_FILENAME
+    pub root: PathBuf,
+    /// Script run for paths naming a directory
+    pub index: String,
+    /// Extension ending the script part of a path; the rest is PATH_INFO
+    pub extension: String,
+    pub timeout: Duration,
+    /// Times a GET or HEAD request is retried after a failure or a 502/503
+    pub retries: u32,
+    idle: Arc<Mutex<Vec<Connection>>>,
+    shadow: Option<Arc<Shadow>>,
+    breaker: Option<Arc<CircuitBreaker>>,
+}
+
+/// Where and how often requests are mirrored
+struct Shadow {
+    fastcgi: FastCgi,
+    percent: u32,
+    requests: AtomicU64,
+    in_flight: AtomicUsize,
+}
+
+impl Shadow {
+    /// Pick `percent` of the requests, spread evenly
+    fn sample(&self) -> bool {
+        let n = self.requests.fetch_add(1, Ordering::Relaxed);
+        let percent = u64::from(self.percent);
+        (n + 1) * percent / 100 > n * percent / 100
+    }
+}
+
+impl FastCgi {
+    /// Send requests under `prefix` to the application at `address`, whose
+    /// scripts live in `root`
+    pub fn new(prefix: &str, address: FastCgiAddress, root: impl Into<PathBuf>, timeout: Duration) -> Self {
+        FastCgi {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            address,
+            root: root.into(),
+            index: "index.php".to_string(),
+            extension: ".php".to_string(),
+            timeout,
+            retries: 0,
+            idle: Arc::new(Mutex::new(Vec::new())),
+            shadow: None,
+            breaker: None,
+        }
+    }
+
+    /// Retry GET and HEAD requests up to `retries` times when the application
+    /// can't be reached, fails mid-response, or answers 502 or 503
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Fail fast with 503 while `threshold` percent or more of the recent
+    /// requests to the application failed, trying it again after `cooldown`;
+    /// the shadow, if any, gets a circuit of its own
+    pub fn breaker(mut self, threshold: u32, cooldown: Duration) -> Self {
+        let breaker = CircuitBreaker::new(&self.address.to_string(), threshold, cooldown);
+        if let Some(shadow) = self.shadow.as_mut().and_then(Arc::get_mut) {
+            shadow.fastcgi.breaker = Some(Arc::new(breaker.for_upstream(&shadow.fastcgi.address.to_string())));
+        }
+        self.breaker = Some(Arc::new(breaker));
+        self
+    }
+
+    /// The circuit breakers of the application and the shadow, for the metrics
+    pub fn circuit_breakers(&self) -> Vec<Arc<CircuitBreaker>> {
+        let shadow = self.shadow.as_ref().and_then(|shadow| shadow.fastcgi.breaker.clone());
+        self.breaker.iter().cloned().chain(shadow).collect()
+    }
+
+    /// How long to wait before retry number `attempt`, counting from 1
+    fn retry_delay(attempt: u32) -> Duration {
+        let delay = RETRY_DELAY.saturating_mul(1 << attempt.saturating_sub(1).min(16)).min(MAX_RETRY_DELAY);
+        let jitter = (uuid::Uuid::new_v4().as_u128() % 1000) as u32;
+        delay / 2 + delay / 2 * jitter / 1000
+    }
+
+    /// Mirror `percent` of the requests to the application at `address`,
+    /// which is sent the same variables, so it should find its scripts in
+    /// the same root; its responses are discarded and its errors logged
+    pub fn shadow(mut self, address: FastCgiAddress, percent: u32) -> Self {
+        let breaker = self.breaker.as_ref().map(|breaker| Arc::new(breaker.for_upstream(&address.to_string())));
+        let fastcgi = FastCgi {
+            address,
+            idle: Arc::default(),
+            shadow: None,
+            breaker,
+            ..self.clone()
+        };
+        self.shadow = Some(Arc::new(Shadow {
+            fastcgi,
+            percent: percent.min(100),
+            requests: AtomicU64::new(0),
+            in_flight: AtomicUsize::new(0),
+        }));
+        self
+    }
+
+    /// Route pattern matching every path under the prefix
+    pub fn pattern(&self) -> String {
+        format!("{}/*{}", self.prefix, PATH_PARAM)
+    }
+
+    /// Split a request path into SCRIPT_NAME and PATH_INFO at the first
+    /// segment ending in the script extension
+    fn split(&self, path: &str) -> Result<(String, String)> {
+        let segments: Vec<&str> = path.split('/').collect();
+        if segments.iter().any(|segment| segment.starts_with('.')) {
+            return Err(ServerError::FileNotFound(format!("{}/{}", self.prefix, path)));
+        }
+        let end = segments
+            .iter()
+            .position(|segment| segment.ends_with(&self.extension))
+            .map_or(segments.len(), |i| i + 1);
+        let mut script = segments[..end].join("/");
+        if script.is_empty() || script.ends_with('/') {
+            script.push_str(&self.index);
+        }
+        let path_info = segments[end..].iter().map(|segment| format!("/{}", segment)).collect();
+        Ok((script, path_info))
+    }
+
+    fn connection(&self) -> io::Result<(Connection, bool)> {
+        if let Some(connection) = self.idle.lock().unwrap().pop() {
+            return Ok((connection, true));
+        }
+        Ok((self.address.connect(self.timeout)?, false))
+    }
+
+    /// Send one request over `connection` and read the application's output
+    fn exchange(
+        &self,
+        connection: &mut Connection,
+        params: &[u8],
+        body: impl Read,
+    ) -> std::result::Result<(Vec<u8>, bool), ExchangeError> {
+        let mut begin = [0; 8];
+        begin[..2].copy_from_slice(&RESPONDER.to_be_bytes());
+        begin[2] = KEEP_CONN;
+        let sent = write_record(connection, BEGIN_REQUEST, &begin)
+            .and_then(|_| write_stream(connection, PARAMS, params))
+            .and_then(|_| write_stream(connection, STDIN, body));
+        let mut stdout = Vec::new();
+        let mut received = false;
+        let result = sent.and_then(|_| loop {
+            let (kind, content) = read_record(connection)?;
+            received = true;
+            match kind {
+                STDOUT => stdout.extend_from_slice(&content),
+                STDERR => {
+                    for line in String::from_utf8_lossy(&content).lines().filter(|line| !line.is_empty()) {
+                        log::warn!("FastCGI {}: {}", self.address, line);
+                    }
+                }
+                END_REQUEST => {
+                    let protocol_status = content.get(4).copied().unwrap_or(REQUEST_COMPLETE);
+                    break Ok(protocol_status == REQUEST_COMPLETE);
+                }
+                _ => {}
+            }
+        });
+        match result {
+            Ok(complete) if complete || !stdout.is_empty() => Ok((stdout, complete)),
+            Ok(_) => Err(ExchangeError::Failed(io::Error::other("application rejected the request"))),
+            Err(e) if !received => Err(ExchangeError::NoResponse(e)),
+            Err(e) => Err(ExchangeError::Failed(e)),
+        }
+    }
+
+    /// Send a request to the application and return its output
+    ///
+    /// `body` gives a reader over the request body, called again if a stale
+    /// pooled connection makes a retry necessary and `replayable` says the
+    /// body can still be read in full.
+    fn forward<'a>(
+        &self,
+        params: &[u8],
+        body: impl Fn() -> Box<dyn Read + 'a>,
+        replayable: impl Fn() -> bool,
+    ) -> Result<Vec<u8>> {
+        let gateway_error = |e: io::Error| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => ServerError::GatewayTimeout(format!(
+                "FastCGI application at {} did not answer within {}s",
+                self.address,
+                self.timeout.as_secs()
+            )),
+            _ => ServerError::BadGateway(format!("FastCGI application at {}: {}", self.address, e)),
+        };
+        let (mut connection, reused) = self.connection().map_err(gateway_error)?;
+        let mut outcome = self.exchange(&mut connection, params, body());
+        // A stale pooled connection is retried once on a fresh one, as long
+        // as no streamed body bytes were consumed on the way
+        if let Err(ExchangeError::NoResponse(_)) = &outcome {
+            if reused && replayable() {
+                connection = self.address.connect(self.timeout).map_err(gateway_error)?;
+                outcome = self.exchange(&mut connection, params, body());
+            }
+        }
+        let (output, complete) = match outcome {
+            Ok(output) => output,
+            Err(ExchangeError::NoResponse(e) | ExchangeError::Failed(e)) => return Err(gateway_error(e)),
+        };
+        if complete {
+            let mut idle = self.idle.lock().unwrap();
+            if idle.len() < MAX_IDLE {
+                idle.push(connection);
+            }
+        }
+        Ok(output)
+    }
+
+    /// The response in the application's output
+    fn parse(&self, output: &[u8]) -> Result<HttpResponse> {
+        parse_output(output).map_err(|e| ServerError::BadGateway(format!("FastCGI application at {} {}", self.address, e)))
+    }
+
+    /// The body of a request picked for mirroring, unless it is too long
+    /// to hold or the shadow is already busy enough
+    fn shadow_body(&self, request: &HttpRequest) -> Option<(Arc<Shadow>, Vec<u8>)> {
+        let shadow = self.shadow.as_ref().filter(|shadow| shadow.sample())?;
+        let length = request.content_length().ok().filter(|length| *length <= MAX_SHADOW_BODY)?;
+        if shadow.in_flight.fetch_add(1, Ordering::Relaxed) >= MAX_SHADOW_IN_FLIGHT {
+            shadow.in_flight.fetch_sub(1, Ordering::Relaxed);
+            log::debug!("Not mirroring {} to {}: too many requests in flight", request.path, shadow.fastcgi.address);
+            return None;
+        }
+        let mut body = Vec::with_capacity(length);
+        if let Err(e) = request.body_reader().read_to_end(&mut body) {
+            shadow.in_flight.fetch_sub(1, Ordering::Relaxed);
+            log::debug!("Not mirroring {}: {}", request.path, e);
+            return None;
+        }
+        if !shadow.fastcgi.breaker.as_ref().map_or(true, |breaker| breaker.allow()) {
+            shadow.in_flight.fetch_sub(1, Ordering::Relaxed);
+            log::debug!("Not mirroring {}: the circuit for {} is open", request.path, shadow.fastcgi.address);
+            return None;
+        }
+        Some((Arc::clone(shadow), body))
+    }
+}
+
+impl Handler for FastCgi {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let path = request.param(PATH_PARAM).unwrap_or_default();
+        let (script_name, path_info) = self.split(path)?;
+        let script = self.root.join(&script_name);
+        let script_name = format!("{}/{}", self.prefix, script_name);
+        let mut params = Vec::new();
+        for (name, value) in environment(request, &self.root, &script, &script_name, &path_info) {
+            encode_param(&mut params, name.as_bytes(), value.as_bytes());
+        }
+
+        let mirrored = self.shadow_body(request).map(|(shadow, body)| {
+            let body = Arc::new(body);
+            let (shadow_params, shadow_body) = (params.clone(), Arc::clone(&body));
+            thread::spawn(move || {
+                let body = || Box::new(&shadow_body[..]) as Box<dyn Read>;
+                let output = shadow.fastcgi.forward(&shadow_params, body, || true);
+                if let Err(e) = &output {
+                    log::warn!("Shadow {}", e);
+                }
+                if let Some(breaker) = &shadow.fastcgi.breaker {
+                    breaker.record(is_healthy(&output.and_then(|output| shadow.fastcgi.parse(&output))));
+                }
+                shadow.in_flight.fetch_sub(1, Ordering::Relaxed);
+            });
+            body
+        });
+        let replayable = || {
+            mirrored.is_some()
+                || request.body_stream.lock().unwrap().as_ref().map_or(true, |stream| {
+                    request.content_length().is_ok_and(|length| stream.remaining() == length as u64)
+                })
+        };
+        let idempotent = matches!(request.method, HttpMethod::GET | HttpMethod::HEAD);
+
+        let mut attempt = 0;
+        loop {
+            if !self.breaker.as_ref().map_or(true, |breaker| breaker.allow()) {
+                return Err(ServerError::Overloaded(format!(
+                    "FastCGI application at {} is failing, its circuit is open",
+                    self.address
+                )));
+            }
+            let output = match &mirrored {
+                Some(body) => self.forward(&params, || Box::new(&body[..]), || true),
+                None => self.forward(&params, || Box::new(request.body_reader()), replayable),
+            };
+            // Only failures to get an answer and answers saying the application
+            // is unavailable are worth another try; timeouts and malformed
+            // output are not
+            let (response, failure) = match output {
+                Ok(output) => match self.parse(&output) {
+                    Ok(response) if matches!(response.status_code(), 502 | 503) => {
+                        let failure = format!("FastCGI application at {} answered {}", self.address, response.status_code());
+                        (Ok(response), Some(failure))
+                    }
+                    response => (response, None),
+                },
+                Err(ServerError::BadGateway(message)) => (Err(ServerError::BadGateway(message.clone())), Some(message)),
+                Err(e) => (Err(e), None),
+            };
+            if let Some(breaker) = &self.breaker {
+                breaker.record(is_healthy(&response));
+            }
+            let Some(failure) = failure else {
+                return response;
+            };
+            if attempt >= self.retries || !idempotent || !replayable() {
+                return response;
+            }
+            attempt += 1;
+            let delay = Self::retry_delay(attempt);
+            log::warn!("{}; retrying {} in {:?} ({} of {})", failure, request.path, delay, attempt, self.retries);
+            thread::sleep(delay);
+        }
+    }
+
+    fn streams_body(&self) -> bool {
+        true
+    }
+}
+
+/// Whether a response shows the application working, for the circuit breaker
+fn is_healthy(response: &Result<HttpResponse>) -> bool {
+    response.as_ref().is_ok_and(|response| !matches!(response.status_code(), 502..=504))
+}
+
+/// Append a name-value pair in the FastCGI length-prefixed encoding
+fn encode_param(out: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    for len in [name.len(), value.len()] {
+        if len < 128 {
+            out.push(len as u8);
+        } else {
+            out.extend_from_slice(&(len as u32 | 0x8000_0000).to_be_bytes());
+        }
+    }
+    out.extend_from_slice(name);
+    out.extend_from_slice(value);
+}
+
+fn write_record(out: &mut impl Write, kind: u8, content: &[u8]) -> io::Result<()> {
+    let padding = (8 - content.len() % 8) % 8;
+    let len = content.len() as u16;
+    let [len_hi, len_lo] = len.to_be_bytes();
+    out.write_all(&[VERSION, kind, 0, 1, len_hi, len_lo, padding as u8, 0])?;
+    out.write_all(content)?;
+    out.write_all(&[0; 8][..padding])
+}
+
+/// Send `reader` as a stream of records ended by an empty one
+fn write_stream(out: &mut impl Write, kind: u8, mut reader: impl Read) -> io::Result<()> {
+    let mut chunk = vec![0; MAX_RECORD];
+    loop {
+        let n = reader.read(&mut chunk)?;
+        write_record(out, kind, &chunk[..n])?;
+        if n == 0 {
+            return out.flush();
+        }
+    }
+}
+
+/// Read one record's type and content, skipping its padding
+fn read_record(input: &mut impl Read) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0; 8];
+    input.read_exact(&mut header)?;
+    if header[0] != VERSION {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unsupported record version {}", header[0])));
+    }
+    let len = u16::from_be_bytes([header[4], header[5]]) as usize;
+    let mut content = vec![0; len + header[6] as usize];
+    input.read_exact(&mut content)?;
+    content.truncate(len);
+    Ok((header[1], content))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_records_round_trip() {
+        let mut params = Vec::new();
+        encode_param(&mut params, b"SCRIPT_NAME", b"/php/index.php");
+        encode_param(&mut params, b"HTTP_X_LONG", &[b'a'; 200]);
+        assert_eq!(&params[..2], &[11, 14]);
+        assert_eq!(&params[27..35], &[11, 0x80, 0, 0, 200, b'H', b'T', b'T']);
+
+        let mut wire = Vec::new();
+        write_stream(&mut wire, PARAMS, &params[..]).unwrap();
+        assert_eq!(wire.len() % 8, 0);
+        let mut wire = &wire[..];
+        assert_eq!(read_record(&mut wire).unwrap(), (PARAMS, params));
+        assert_eq!(read_record(&mut wire).unwrap(), (PARAMS, Vec::new()));
+        assert!(wire.is_empty());
+    }
+
+    /// Answer a request with each of `statuses` in turn on one connection,
+    /// echoing SCRIPT_FILENAME, PATH_INFO, and the body back
+    fn fake_application(listener: std::net::TcpListener, statuses: &[&str]) {
+        let (mut stream, _) = listener.accept().unwrap();
+        for status in statuses {
+            let (mut params, mut stdin) = (Vec::new(), Vec::new());
+            loop {
+                let (kind, content) = read_record(&mut stream).unwrap();
+                match kind {
+                    BEGIN_REQUEST => assert_eq!(content[2], KEEP_CONN),
+                    PARAMS => params.extend(content),
+                    STDIN if content.is_empty() => break,
+                    STDIN => stdin.extend(content),
+                    _ => panic!("unexpected record {}", kind),
+                }
+            }
+            let mut env = std::collections::HashMap::new();
+            let mut rest = &params[..];
+            while !rest.is_empty() {
+                let (name_len, value_len) = (rest[0] as usize, rest[1] as usize);
+                let pair = &rest[2..2 + name_len + value_len];
+                env.insert(String::from_utf8(pair[..name_len].to_vec()).unwrap(), String::from_utf8(pair[name_len..].to_vec()).unwrap());
+                rest = &rest[2 + name_len + value_len..];
+            }
+            let output = format!(
+                "Status: {}\r\nContent-Type: text/plain\r\n\r\n{}|{}|{}",
+                status,
+                env["SCRIPT_FILENAME"],
+                env["PATH_INFO"],
+                String::from_utf8_lossy(&stdin)
+            );
+            write_stream(&mut stream, STDOUT, output.as_bytes()).unwrap();
+            write_record(&mut stream, STDERR, b"notice").unwrap();
+            write_record(&mut stream, END_REQUEST, &[0; 8]).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_requests_reuse_the_connection() {
+        use crate::router::Router;
+        use crate::testing::TestRequest;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = FastCgiAddress::parse(&listener.local_addr().unwrap().to_string()).unwrap();
+        let application = std::thread::spawn(move || fake_application(listener, &["201 Created"; 2]));
+        let router = Router::empty().fastcgi(FastCgi::new("/php", address, "/srv/www", Duration::from_secs(5)));
+
+        let response = TestRequest::post("/php/form.php/step/2").body("name=a").send(&router);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.text(), "/srv/www/form.php|/step/2|name=a");
+        let response = TestRequest::get("/php/").send(&router);
+        assert_eq!(response.text(), "/srv/www/index.php||");
+        application.join().unwrap();
+
+        // Nothing listens any more
+        assert_eq!(TestRequest::get("/php/").send(&router).status, 502);
+    }
+
+    #[test]
+    fn test_shadow_gets_a_share_of_the_requests() {
+        use crate::router::Router;
+        use crate::testing::TestRequest;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = FastCgiAddress::parse(&listener.local_addr().unwrap().to_string()).unwrap();
+        let application = std::thread::spawn(move || fake_application(listener, &["201 Created"; 4]));
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let shadow_address = FastCgiAddress::parse(&listener.local_addr().unwrap().to_string()).unwrap();
+        let shadow = std::thread::spawn(move || fake_application(listener, &["201 Created"; 2]));
+        let fastcgi = FastCgi::new("/php", address, "/srv/www", Duration::from_secs(5)).shadow(shadow_address, 50);
+        let mirror = Arc::clone(fastcgi.shadow.as_ref().unwrap());
+        let router = Router::empty().fastcgi(fastcgi);
+
+        for i in 0..4 {
+            let response = TestRequest::post("/php/form.php").body(format!("n={}", i)).send(&router);
+            assert_eq!(response.text(), format!("/srv/www/form.php||n={}", i));
+            // One mirrored request at a time, as the fake shadow has one connection
+            while mirror.in_flight.load(Ordering::Relaxed) > 0 {
+                std::thread::sleep(Duration::from_millis(5));
+            }
+        }
+        application.join().unwrap();
+        shadow.join().unwrap();
+    }
+
+    #[test]
+    fn test_only_idempotent_requests_are_retried() {
+        use crate::router::Router;
+        use crate::testing::TestRequest;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = FastCgiAddress::parse(&listener.local_addr().unwrap().to_string()).unwrap();
+        let statuses = ["503 Service Unavailable", "200 OK", "503 Service Unavailable"];
+        let application = std::thread::spawn(move || fake_application(listener, &statuses));
+        let fastcgi = FastCgi::new("/php", address, "/srv/www", Duration::from_secs(5)).retries(2);
+        let router = Router::empty().fastcgi(fastcgi);
+
+        let response = TestRequest::get("/php/").send(&router);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "/srv/www/index.php||");
+        assert_eq!(TestRequest::post("/php/form.php").body("n=1").send(&router).status, 503);
+        application.join().unwrap();
+
+        // Nothing listens any more: every attempt fails, after growing delays
+        let start = std::time::Instant::now();
+        assert_eq!(TestRequest::get("/php/").send(&router).status, 502);
+        assert!(start.elapsed() >= Duration::from_millis(75), "{:?}", start.elapsed());
+    }
+
+    #[test]
+    fn test_open_circuit_fails_fast() {
+        use crate::circuit_breaker::{CircuitState, MIN_REQUESTS};
+        use crate::router::Router;
+        use crate::testing::TestRequest;
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = FastCgiAddress::parse(&listener.local_addr().unwrap().to_string()).unwrap();
+        drop(listener);
+        let fastcgi = FastCgi::new("/php", address, "/srv/www", Duration::from_secs(5)).breaker(50, Duration::from_secs(60));
+        let breaker = Arc::clone(&fastcgi.circuit_breakers()[0]);
+        let router = Router::empty().fastcgi(fastcgi);
+
+        for _ in 0..MIN_REQUESTS {
+            assert_eq!(TestRequest::get("/php/").send(&router).status, 502);
+        }
+        assert_eq!(breaker.state(), CircuitState::Open);
+        assert_eq!(TestRequest::get("/php/").send(&router).status, 503);
+    }
+
+    #[test]
+    fn test_split_finds_the_script() {
+        let fastcgi = FastCgi::new("/php", FastCgiAddress::parse("127.0.0.1:9000").unwrap(), "/srv/www", Duration::from_secs(1));
+        let split = |path| fastcgi.split(path).unwrap();
+        assert_eq!(split("blog/post.php/2024/title"), ("blog/post.php".to_string(), "/2024/title".to_string()));
+        assert_eq!(split("status"), ("status".to_string(), String::new()));
+        assert_eq!(split(""), ("index.php".to_string(), String::new()));
+        assert_eq!(split("admin/"), ("admin/index.php".to_string(), String::new()));
+        assert!(fastcgi.split("../etc/passwd").is_err());
+
+        assert!(FastCgiAddress::parse("localhost").is_err());
+        assert!(FastCgiAddress::parse(":9000").is_err());
+    }
+}
@@ -0,0 +1,158 @@
+//! Request header storage that keeps every field line in arrival order
+//!
+//! A field may legitimately appear more than once (`X-Forwarded-For` from
+//! several proxies, split `Cookie` lines), so nothing is replaced on insert.
+//! [`Headers::get`] returns the first value, [`Headers::get_all`] every value,
+//! and [`Headers::combined`] joins them the way RFC 9110 section 5.3 allows.
+
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use std::borrow::Cow;
+
+/// Ordered multimap of header names (lowercased) to values
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Headers {
+    entries: Vec<(String, String)>,
+}
+
+impl Headers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a field line, keeping any earlier values for the same name
+    pub fn append(&mut self, name: &str, value: impl Into<String>) {
+        self.entries.push((name.to_ascii_lowercase(), value.into()));
+    }
+
+    /// Replace every value for `name` with a single one
+    pub fn insert(&mut self, name: &str, value: impl Into<String>) {
+        self.remove(name);
+        self.append(name, value);
+    }
+
+    /// Remove every value for `name`
+    pub fn remove(&mut self, name: &str) {
+        self.entries.retain(|(key, _)| !key.eq_ignore_ascii_case(name));
+    }
+
+    /// First value for `name` (case-insensitive)
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Every value for `name`, in the order they were received
+    pub fn get_all<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.entries
+            .iter()
+            .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// All values for `name` as one field value: comma-separated, or
+    /// `; `-separated for `Cookie` (RFC 6265 section 5.4)
+    pub fn combined(&self, name: &str) -> Option<Cow<'_, str>> {
+        let separator = if name.eq_ignore_ascii_case("cookie") { "; " } else { ", " };
+        let mut combined: Option<Cow<'_, str>> = None;
+        for (key, value) in &self.entries {
+            if !key.eq_ignore_ascii_case(name) {
+                continue;
+            }
+            combined = Some(match combined {
+                None => Cow::Borrowed(value),
+                Some(joined) => Cow::Owned(format!("{}{}{}", joined, separator, value)),
+            });
+        }
+        combined
+    }
+
+    /// Number of field lines carrying `name`
+    pub fn count(&self, name: &str) -> usize {
+        self.get_all(name).count()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.get(name).is_some()
+    }
+
+    /// Every field line as `(name, value)`, in arrival order
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries.iter().map(|(name, value)| (name.as_str(), value.as_str()))
+    }
+
+    /// Number of field lines
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<N: AsRef<str>, V: Into<String>> FromIterator<(N, V)> for Headers {
+    fn from_iter<I: IntoIterator<Item = (N, V)>>(iter: I) -> Self {
+        let mut headers = Headers::new();
+        for (name, value) in iter {
+            headers.append(name.as_ref(), value);
+        }
+        headers
+    }
+}
+
+/// Serialized as an object with one combined value per name, in order of
+/// first appearance
+impl Serialize for Headers {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut names: Vec<&str> = Vec::new();
+        for (name, _) in &self.entries {
+            if !names.contains(&name.as_str()) {
+                names.push(name);
+            }
+        }
+        let mut map = serializer.serialize_map(Some(names.len()))?;
+        for name in names {
+            map.serialize_entry(name, &self.combined(name))?;
+        }
+        map.end()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repeated_fields_are_kept_in_order() {
+        let headers: Headers = [
+            ("X-Forwarded-For", "10.0.0.1"),
+            ("Host", "localhost"),
+            ("x-forwarded-for", "10.0.0.2"),
+            ("Cookie", "a=1"),
+            ("Cookie", "b=2"),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(headers.len(), 5);
+        assert_eq!(headers.get("X-FORWARDED-FOR"), Some("10.0.0.1"));
+        assert_eq!(headers.get_all("x-forwarded-for").collect::<Vec<_>>(), ["10.0.0.1", "10.0.0.2"]);
+        assert_eq!(headers.combined("x-forwarded-for").as_deref(), Some("10.0.0.1, 10.0.0.2"));
+        assert_eq!(headers.combined("cookie").as_deref(), Some("a=1; b=2"));
+        assert_eq!(headers.combined("host"), Some(Cow::Borrowed("localhost")));
+        assert_eq!(headers.combined("accept"), None);
+
+        assert_eq!(
+            serde_json::to_string(&headers).unwrap(),
+            r#"{"x-forwarded-for":"10.0.0.1, 10.0.0.2","host":"localhost","cookie":"a=1; b=2"}"#
+        );
+
+        let mut headers = headers;
+        headers.insert("x-forwarded-for", "10.0.0.3");
+        assert_eq!(headers.get_all("x-forwarded-for").collect::<Vec<_>>(), ["10.0.0.3"]);
+        headers.remove("cookie");
+        assert!(!headers.contains("cookie"));
+    }
+}
@@ -0,0 +1,157 @@
+use crate::platform;
+use serde_json::{json, Map, Value};
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Lifecycle flags that decide whether the server should receive new traffic
+#[derive(Debug, Default)]
+pub struct Readiness {
+    listening: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl Readiness {
+    /// Mark the public listener as bound and accepting connections
+    pub fn set_listening(&self) {
+        self.listening.store(true, Ordering::Relaxed);
+    }
+
+    /// Stop advertising readiness so load balancers move traffic elsewhere
+    pub fn start_draining(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Run every readiness check against the served directory; returns
+    /// whether all passed and a per-check report
+    pub fn check(&self, directory: &Path) -> (bool, Value) {
+        let checks = [
+            ("listener", self.listening.load(Ordering::Relaxed).then_some(()).ok_or("not bound")),
+            ("draining", (!self.is_draining()).then_some(()).ok_or("draining")),
+            ("disk", check_writable(directory).map_err(|_| "not writable")),
+        ];
+
+        let ready = checks.iter().all(|(_, result)| result.is_ok());
+        let report: Map<String, Value> = checks
+            .iter()
+            .map(|(name, result)| (name.to_string(), json!(result.err().unwrap_or("ok"))))
+            .collect();
+        (ready, Value::Object(report))
+    }
+}
+
+/// Overall state reported by `/health`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HealthStatus {
+    Healthy,
+    /// Serving, but something needs attention, e.g. uploads would fail
+    Degraded,
+    /// Unable to serve files
+    Unhealthy,
+}
+
+impl HealthStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            HealthStatus::Healthy => "healthy",
+            HealthStatus::Degraded => "degraded",
+            HealthStatus::Unhealthy => "unhealthy",
+        }
+    }
+}
+
+/// Check the served directory for `/health`: that it exists and is readable,
+/// has at least `min_free_bytes` available, and, if uploads are enabled,
+/// is writable; returns the resulting status and a per-check report
+pub fn check_directory(directory: &Path, min_free_bytes: u64, uploads: bool) -> (HealthStatus, Value) {
+    use HealthStatus::*;
+
+    if !directory.is_dir() {
+        let report = json!({ "exists": "missing", "readable": "skipped", "free_space": "skipped", "writable": "skipped" });
+        return (Unhealthy, report);
+    }
+    let readable = match fs::read_dir(directory) {
+        Ok(_) => (Healthy, "ok".to_string()),
+        Err(e) => (Unhealthy, format!("not readable: {}", e)),
+    };
+    let free_bytes = platform::available_space(directory);
+    let free_space = match free_bytes {
+        Some(free) if free < min_free_bytes => (Degraded, format!("low: {} bytes free, {} required", free, min_free_bytes)),
+        Some(_) => (Healthy, "ok".to_string()),
+        None => (Healthy, "unknown".to_string()),
+    };
+    let writable = match (uploads, check_writable(directory)) {
+        (false, _) => (Healthy, "not required".to_string()),
+        (true, Ok(())) => (Healthy, "ok".to_string()),
+        // Downloads still work
+        (true, Err(e)) => (Degraded, format!("not writable: {}", e)),
+    };
+
+    let status = readable.0.max(free_space.0).max(writable.0);
+    let report = json!({
+        "exists": "ok",
+        "readable": readable.1,
+        "free_space": free_space.1,
+        "free_bytes": free_bytes,
+        "writable": writable.1,
+    });
+    (status, report)
+}
+
+/// Verify files can be created in `directory` by writing and removing a probe file
+fn check_writable(directory: &Path) -> std::io::Result<()> {
+    fs::create_dir_all(directory)?;
+    let probe = directory.join(format!(".ready-check-{}", std::process::id()));
+    fs::write(&probe, b"")?;
+    fs::remove_file(&probe)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_readiness_checks() {
+        let dir = std::env::temp_dir().join(format!("http-server-ready-{}", std::process::id()));
+        let directory = dir.as_path();
+        let readiness = Readiness::default();
+
+        let (ready, report) = readiness.check(directory);
+        assert!(!ready);
+        assert_eq!(report["listener"], "not bound");
+
+        readiness.set_listening();
+        let (ready, report) = readiness.check(directory);
+        assert!(ready);
+        assert_eq!(report["disk"], "ok");
+
+        readiness.start_draining();
+        let (ready, report) = readiness.check(directory);
+        assert!(!ready);
+        assert_eq!(report["draining"], "draining");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_directory_checks() {
+        let dir = std::env::temp_dir().join(format!("http-server-health-{}", std::process::id()));
+        let (status, report) = check_directory(&dir, 0, true);
+        assert_eq!((status, report["exists"].as_str()), (HealthStatus::Unhealthy, Some("missing")));
+
+        fs::create_dir_all(&dir).unwrap();
+        let (status, report) = check_directory(&dir, 0, true);
+        assert_eq!((status, report["writable"].as_str()), (HealthStatus::Healthy, Some("ok")));
+        assert!(report["free_bytes"].as_u64().is_some());
+
+        let (status, report) = check_directory(&dir, u64::MAX, false);
+        assert_eq!(status, HealthStatus::Degraded);
+        assert!(report["free_space"].as_str().unwrap().starts_with("low: "));
+        assert_eq!(report["writable"], "not required");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
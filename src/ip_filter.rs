@@ -0,0 +1,163 @@
+//! CIDR allow and deny lists for client addresses
+//!
+//! Connections are checked right after accept, and each request is checked
+//! again against the client address a trusted proxy forwarded for, since the
+//! proxy's own address says nothing about who is asking.
+
+use clap::ValueEnum;
+use std::fmt;
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// An address block such as `10.0.0.0/8` or `2001:db8::/32`; a bare address
+/// is a block of one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    /// Whether `ip` is in the block; IPv4-mapped IPv6 addresses match IPv4 blocks
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix_len) = match s.split_once('/') {
+            Some((address, prefix_len)) => (address, Some(prefix_len)),
+            None => (s, None),
+        };
+        let network: IpAddr = address
+            .trim()
+            .parse()
+            .map_err(|_| format!("invalid IP address '{}'", address))?;
+        let network = network.to_canonical();
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(len) => len
+                .trim()
+                .parse()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("invalid prefix length '{}' (expected 0-{})", len, max_len))?,
+            None => max_len,
+        };
+        Ok(IpNet { network, prefix_len })
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// How clients blocked by the IP lists are turned away
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DenyAction {
+    /// Answer each request with 403 Forbidden
+    Forbidden,
+    /// Close the connection without a response
+    Close,
+}
+
+/// Whether the lists let `ip` in: a deny match always blocks, and a
+/// non-empty allow list blocks everything it doesn't match
+pub fn is_allowed(ip: IpAddr, allow: &[IpNet], deny: &[IpNet]) -> bool {
+    !deny.iter().any(|net| net.contains(ip)) && (allow.is_empty() || allow.iter().any(|net| net.contains(ip)))
+}
+
+/// The client a request is from: the peer, or if the peer is a trusted proxy,
+/// the nearest untrusted address in `X-Forwarded-For`
+///
+/// Entries are read right to left, as each proxy appends the address it
+/// received the request from; anything left of the first untrusted entry
+/// could have been made up by the client.
+pub fn client_ip(peer: IpAddr, forwarded_for: Option<&str>, trusted_proxies: &[IpAddr]) -> IpAddr {
+    let is_trusted = |ip: IpAddr| trusted_proxies.iter().any(|proxy| proxy.to_canonical() == ip.to_canonical());
+    if !is_trusted(peer) {
+        return peer;
+    }
+    let mut client = peer;
+    for hop in forwarded_for.unwrap_or_default().rsplit(',') {
+        match hop.trim().parse() {
+            Ok(ip) => {
+                client = ip;
+                if !is_trusted(ip) {
+                    break;
+                }
+            }
+            // A malformed entry can't be trusted or checked; stop at the last good one
+            Err(_) => break,
+        }
+    }
+    client
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn nets(specs: &[&str]) -> Vec<IpNet> {
+        specs.iter().map(|spec| spec.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_blocks_match_by_prefix() {
+        let net: IpNet = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.255.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.0.9")));
+        assert!(!net.contains(ip("2001:db8::1")));
+        assert_eq!(net.to_string(), "10.1.0.0/16");
+
+        assert!("0.0.0.0/0".parse::<IpNet>().unwrap().contains(ip("203.0.113.7")));
+        assert!("2001:db8::/32".parse::<IpNet>().unwrap().contains(ip("2001:db8:ffff::1")));
+        assert_eq!("192.0.2.1".parse::<IpNet>().unwrap().to_string(), "192.0.2.1/32");
+        assert!("10.0.0.0/33".parse::<IpNet>().is_err());
+        assert!("10.0.0/8".parse::<IpNet>().is_err());
+    }
+
+    #[test]
+    fn test_deny_wins_over_allow() {
+        let allow = nets(&["10.0.0.0/8"]);
+        let deny = nets(&["10.6.6.0/24"]);
+        assert!(is_allowed(ip("10.1.2.3"), &allow, &deny));
+        assert!(!is_allowed(ip("10.6.6.6"), &allow, &deny));
+        assert!(!is_allowed(ip("192.0.2.1"), &allow, &deny));
+        assert!(is_allowed(ip("192.0.2.1"), &[], &deny));
+        assert!(is_allowed(ip("192.0.2.1"), &[], &[]));
+    }
+
+    #[test]
+    fn test_client_ip_trusts_only_configured_proxies() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let forwarded = Some("198.51.100.9, 203.0.113.5, 10.0.0.2");
+
+        // The nearest untrusted hop, not the client-supplied leftmost entry
+        assert_eq!(client_ip(ip("10.0.0.1"), forwarded, &proxies), ip("203.0.113.5"));
+        // Untrusted peers can't claim another address
+        assert_eq!(client_ip(ip("192.0.2.1"), forwarded, &proxies), ip("192.0.2.1"));
+        assert_eq!(client_ip(ip("10.0.0.1"), None, &proxies), ip("10.0.0.1"));
+        assert_eq!(client_ip(ip("10.0.0.1"), Some("garbage, 10.0.0.2"), &proxies), ip("10.0.0.2"));
+    }
+}
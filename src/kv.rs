@@ -0,0 +1,264 @@
+//! A JSON key-value store served over REST
+//!
+//! With `--kv-dir`, `PUT /kv/{key}` stores a JSON document, `GET /kv/{key}`
+//! returns it, `DELETE /kv/{key}` removes it, and `GET /kv` lists the keys
+//! (`?prefix=users/` narrows the list), which makes the server a quick mock
+//! backend for frontend work. Keys may contain slashes.
+//!
+//! Entries live in memory, and every change is appended to `kv.log` in the
+//! directory as a line of JSON, so the store survives restarts. Once most of
+//! the log is superseded it is rewritten with just the live entries.
+
+use crate::error::Result;
+use crate::extract::{FromRequest, Json, Query};
+use crate::problem::Problem;
+use crate::request::{HttpMethod, HttpRequest};
+use crate::response::HttpResponse;
+use crate::router::Handler;
+use crate::status::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// Name of the catch-all route parameter holding the key
+pub const KEY_PARAM: &str = "kv_key";
+
+/// The log is only compacted once it has at least this many lines
+const COMPACT_MIN_RECORDS: usize = 1000;
+
+/// A line of the log
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum Record {
+    Put { key: String, value: Value },
+    Delete { key: String },
+}
+
+struct State {
+    entries: BTreeMap<String, Value>,
+    log: File,
+    /// Lines in the log, live or not
+    records: usize,
+}
+
+/// JSON documents by key, persisted in an append-only log
+pub struct KvStore {
+    path: PathBuf,
+    state: RwLock<State>,
+}
+
+impl KvStore {
+    /// Open the store in `directory`, creating it if needed, and replay its log
+    pub fn open(directory: impl AsRef<Path>) -> io::Result<Self> {
+        fs::create_dir_all(&directory)?;
+        let path = directory.as_ref().join("kv.log");
+        let mut entries = BTreeMap::new();
+        let mut records = 0;
+        let mut damaged = false;
+        if let Ok(file) = File::open(&path) {
+            for line in BufReader::new(file).lines() {
+                let line = line?;
+                match serde_json::from_str(&line) {
+                    Ok(Record::Put { key, value }) => {
+                        entries.insert(key, value);
+                    }
+                    Ok(Record::Delete { key }) => {
+                        entries.remove(&key);
+                    }
+                    // Most likely a write cut short by a crash
+                    Err(e) => {
+                        log::warn!("Skipping damaged line {} of {}: {}", records + 1, path.display(), e);
+                        damaged = true;
+                    }
+                }
+                records += 1;
+            }
+        }
+
+        let log = File::options().create(true).append(true).open(&path)?;
+        let store = KvStore {
+            path,
+            state: RwLock::new(State { entries, log, records }),
+        };
+        if damaged {
+            // Rewrite so new lines don't follow a partial one
+            store.compact(&mut store.state.write().unwrap())?;
+        }
+        Ok(store)
+    }
+
+    pub fn get(&self, key: &str) -> Option<Value> {
+        self.state.read().unwrap().entries.get(key).cloned()
+    }
+
+    /// Keys starting with `prefix`, in order
+    pub fn keys(&self, prefix: &str) -> Vec<String> {
+        let state = self.state.read().unwrap();
+        let keys = state.entries.range(prefix.to_string()..).map(|(key, _)| key);
+        keys.take_while(|key| key.starts_with(prefix)).cloned().collect()
+    }
+
+    /// Store `value` under `key`; true if the key is new
+    pub fn put(&self, key: &str, value: Value) -> io::Result<bool> {
+        let mut state = self.state.write().unwrap();
+        Self::append(&mut state, &Record::Put { key: key.to_string(), value: value.clone() })?;
+        let created = state.entries.insert(key.to_string(), value).is_none();
+        self.compact_if_stale(&mut state);
+        Ok(created)
+    }
+
+    /// Remove `key`; false if it was not there
+    pub fn delete(&self, key: &str) -> io::Result<bool> {
+        let mut state = self.state.write().unwrap();
+        if !state.entries.contains_key(key) {
+            return Ok(false);
+        }
+        Self::append(&mut state, &Record::Delete { key: key.to_string() })?;
+        state.entries.remove(key);
+        self.compact_if_stale(&mut state);
+        Ok(true)
+    }
+
+    /// Log a change before it is applied, so a failed write changes nothing
+    fn append(state: &mut State, record: &Record) -> io::Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        state.log.write_all(&line)?;
+        state.records += 1;
+        Ok(())
+    }
+
+    /// Compact once more than half the log is superseded; the change is
+    /// already logged, so a failure here is only worth a warning
+    fn compact_if_stale(&self, state: &mut State) {
+        if state.records >= COMPACT_MIN_RECORDS && state.records > 2 * state.entries.len() {
+            if let Err(e) = self.compact(state) {
+                log::warn!("Failed to compact {}: {}", self.path.display(), e);
+            }
+        }
+    }
+
+    /// Replace the log with one line per live entry
+    fn compact(&self, state: &mut State) -> io::Result<()> {
+        let temporary = self.path.with_extension("log.tmp");
+        let mut file = io::BufWriter::new(File::create(&temporary)?);
+        for (key, value) in &state.entries {
+            serde_json::to_writer(&mut file, &Record::Put { key: key.clone(), value: value.clone() })?;
+            file.write_all(b"\n")?;
+        }
+        file.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temporary, &self.path)?;
+        state.log = File::options().append(true).open(&self.path)?;
+        state.records = state.entries.len();
+        Ok(())
+    }
+}
+
+/// Serves a [`KvStore`] under a URL prefix
+#[derive(Clone)]
+pub struct Kv {
+    pub prefix: String,
+    pub store: Arc<KvStore>,
+}
+
+impl Kv {
+    /// Serve the store in `directory` at `prefix`, e.g. `/kv`
+    pub fn open(prefix: &str, directory: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Kv {
+            prefix: prefix.trim_end_matches('/').to_string(),
+            store: Arc::new(KvStore::open(directory)?),
+        })
+    }
+
+    /// Route pattern matching every key under the prefix
+    pub fn pattern(&self) -> String {
+        format!("{}/*{}", self.prefix, KEY_PARAM)
+    }
+
+    fn not_found(request: &HttpRequest, key: &str) -> HttpResponse {
+        Problem::new(StatusCode::NotFound, "key_not_found", format!("No value stored under '{}'", key))
+            .instance(&request.path)
+            .to_response(Some(request))
+    }
+
+    /// `GET {prefix}?prefix=...`: the keys as a JSON array
+    fn list(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        #[derive(Deserialize)]
+        struct ListQuery {
+            #[serde(default)]
+            prefix: String,
+        }
+        let Query(query) = Query::<ListQuery>::from_request(request)?;
+        HttpResponse::ok().json(&json!({ "keys": self.store.keys(&query.prefix) }))
+    }
+}
+
+impl Handler for Kv {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let Some(key) = request.param(KEY_PARAM) else {
+            return self.list(request);
+        };
+        match request.method {
+            HttpMethod::GET => match self.store.get(key) {
+                Some(value) => HttpResponse::ok().json(&value),
+                None => Ok(Self::not_found(request, key)),
+            },
+            HttpMethod::PUT => {
+                let Json(value) = Json::<Value>::from_request(request)?;
+                let response = match self.store.put(key, value.clone())? {
+                    true => HttpResponse::created().header("Location", request.path.split('?').next().unwrap_or_default()),
+                    false => HttpResponse::ok(),
+                };
+                response.json(&value)
+            }
+            HttpMethod::DELETE => match self.store.delete(key)? {
+                true => Ok(HttpResponse::no_content()),
+                false => Ok(Self::not_found(request, key)),
+            },
+            _ => Ok(HttpResponse::method_not_allowed().header("Allow", "GET, PUT, DELETE")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_survives_reopening_and_compacts() {
+        let dir = std::env::temp_dir().join(format!("http-server-kv-{}", std::process::id()));
+        let store = KvStore::open(&dir).unwrap();
+        assert!(store.put("users/1", json!({"name": "Ada"})).unwrap());
+        assert!(!store.put("users/1", json!({"name": "Grace"})).unwrap());
+        assert!(store.put("users/2", json!({"name": "Alan"})).unwrap());
+        assert!(store.put("posts/1", json!("hello")).unwrap());
+        assert!(store.delete("posts/1").unwrap());
+        assert!(!store.delete("posts/1").unwrap());
+        assert_eq!(store.keys("users/"), vec!["users/1", "users/2"]);
+        drop(store);
+
+        // A write cut short by a crash is skipped
+        let mut log = File::options().append(true).open(dir.join("kv.log")).unwrap();
+        log.write_all(br#"{"op":"put","key":"users/3","val"#).unwrap();
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get("users/1"), Some(json!({"name": "Grace"})));
+        assert_eq!(store.get("posts/1"), None);
+        assert_eq!(store.keys(""), vec!["users/1", "users/2"]);
+        assert!(store.put("users/3", json!(3)).unwrap());
+
+        for i in 0..COMPACT_MIN_RECORDS {
+            store.put("counter", json!(i)).unwrap();
+        }
+        let lines = fs::read_to_string(dir.join("kv.log")).unwrap().lines().count();
+        assert!(lines < COMPACT_MIN_RECORDS, "{}", lines);
+        drop(store);
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.get("counter"), Some(json!(COMPACT_MIN_RECORDS - 1)));
+        assert_eq!(store.keys("").len(), 4);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,70 @@
+//! A production-ready HTTP/1.1 server
+//!
+//! The `http-server` binary is a thin wrapper around this crate. The router,
+//! request and response types, and the [`testing`] helpers are usable on
+//! their own, e.g. to exercise handlers without opening sockets.
+
+pub mod access_log;
+#[cfg(feature = "alloc-stats")]
+mod alloc_stats;
+#[cfg(feature = "tokio")]
+pub mod async_server;
+pub mod audit;
+pub mod body;
+pub mod build_info;
+mod buffer_pool;
+pub mod cache;
+pub mod cgi;
+pub mod check;
+pub mod circuit_breaker;
+pub mod cluster;
+pub mod compression;
+pub mod conditional;
+pub mod config;
+pub mod connections;
+pub mod cors;
+pub mod daemon;
+pub mod dashboard;
+pub mod digest;
+pub mod error;
+pub mod extensions;
+pub mod extract;
+pub mod fastcgi;
+pub mod headers;
+pub mod health;
+pub mod ip_filter;
+pub mod kv;
+pub mod live_reload;
+pub mod load_test;
+pub mod log_file;
+pub mod log_level;
+pub mod metrics;
+pub mod net;
+pub mod openapi;
+pub mod platform;
+pub mod precompress;
+pub mod problem;
+pub mod range;
+pub mod request;
+pub mod request_id;
+pub mod response;
+pub mod response_cache;
+pub mod response_headers;
+pub mod router;
+pub mod schema;
+pub mod server;
+pub mod statsd;
+pub mod status;
+pub mod telemetry;
+pub mod template;
+pub mod testing;
+pub mod throttle;
+pub mod timeout;
+pub mod upgrade;
+pub mod upload_limit;
+#[cfg(all(target_os = "linux", feature = "io-uring"))]
+pub mod uring_server;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod watch;
+pub mod worker_pool;
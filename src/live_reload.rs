@@ -0,0 +1,185 @@
+//! Browser live reload for `--watch` development mode
+//!
+//! HTML responses get a small script that opens an event stream (SSE) at
+//! [`EVENTS_PATH`]. When the file watcher reports a change under the served
+//! directory, every open stream sends a `reload` event and the pages refresh.
+//!
+//! A stream ends after its event, or after [`POLL_TIMEOUT`] without one, and
+//! the browser reconnects with the `Last-Event-ID` it saw; a change made while
+//! it was reconnecting is sent straight away. Short-lived streams also keep
+//! working on runtimes that buffer streamed bodies, as long polls.
+
+use crate::error::Result;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::router::Next;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Path of the reload event stream
+pub const EVENTS_PATH: &str = "/__livereload";
+
+/// How long a stream waits for a change before ending
+pub const POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Editors often save in several steps; changes closer together than this
+/// are sent as one reload
+const SETTLE_DELAY: Duration = Duration::from_millis(100);
+
+/// The script added to HTML pages
+pub const SCRIPT: &str = "<script>(function () { var events = new EventSource(\"/__livereload\"); \
+events.onmessage = function (event) { if (event.data === \"reload\") location.reload(); }; })();</script>";
+
+/// Counts changes and wakes the event streams waiting for one
+#[derive(Debug, Default)]
+pub struct LiveReload {
+    generation: Mutex<u64>,
+    changed: Condvar,
+}
+
+impl LiveReload {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The number of changes reported so far
+    pub fn generation(&self) -> u64 {
+        *self.generation.lock().unwrap()
+    }
+
+    /// Report a change; called from the file watcher
+    pub fn notify(&self) {
+        *self.generation.lock().unwrap() += 1;
+        self.changed.notify_all();
+    }
+
+    /// Wait up to `timeout` for a change after generation `seen`; returns
+    /// the newer generation, or `None` on timeout
+    pub fn wait_for_change(&self, seen: u64, timeout: Duration) -> Option<u64> {
+        let deadline = Instant::now() + timeout;
+        let mut generation = self.generation.lock().unwrap();
+        while *generation <= seen {
+            let left = deadline.saturating_duration_since(Instant::now());
+            if left.is_zero() {
+                return None;
+            }
+            generation = self.changed.wait_timeout(generation, left).unwrap().0;
+        }
+        Some(*generation)
+    }
+
+    /// `GET /__livereload`: an event stream sending `reload` once something
+    /// changed since the client's `Last-Event-ID`
+    pub fn events(self: &Arc<Self>, request: &HttpRequest) -> HttpResponse {
+        let current = self.generation();
+        // A client that saw a newer generation talked to a previous process
+        let seen = request
+            .get_header("Last-Event-ID")
+            .and_then(|id| id.trim().parse().ok())
+            .filter(|&id| id <= current)
+            .unwrap_or(current);
+        let stream = EventStream {
+            live_reload: Arc::clone(self),
+            seen,
+            pending: format!("retry: 1000\nid: {}\n\n", seen).into_bytes(),
+            done: false,
+        };
+        HttpResponse::ok()
+            .header("Content-Type", "text/event-stream")
+            .header("Cache-Control", "no-store")
+            .stream(stream, None)
+    }
+
+    /// Middleware adding [`SCRIPT`] to HTML responses
+    pub fn handle(&self, request: &HttpRequest, next: Next<'_>) -> Result<HttpResponse> {
+        let response = next.run(request)?;
+        let html = response.get_header("Content-Type").is_some_and(|content_type| content_type.starts_with("text/html"));
+        // Encoded, streamed, and partial bodies can't be edited
+        if !html
+            || response.is_streamed()
+            || response.get_header("Content-Encoding").is_some()
+            || matches!(response.status_code(), 204 | 206 | 304)
+        {
+            return Ok(response);
+        }
+        let body = inject_script(response.body_bytes());
+        Ok(response.body(body))
+    }
+}
+
+/// `html` with [`SCRIPT`] before its closing body tag, or at the end if it
+/// has none
+pub fn inject_script(html: &[u8]) -> Vec<u8> {
+    let at = html
+        .windows(7)
+        .rposition(|window| window.eq_ignore_ascii_case(b"</body>"))
+        .unwrap_or(html.len());
+    let mut injected = Vec::with_capacity(html.len() + SCRIPT.len());
+    injected.extend_from_slice(&html[..at]);
+    injected.extend_from_slice(SCRIPT.as_bytes());
+    injected.extend_from_slice(&html[at..]);
+    injected
+}
+
+/// The body of a reload stream: the last seen generation, then at most one
+/// reload event
+struct EventStream {
+    live_reload: Arc<LiveReload>,
+    seen: u64,
+    pending: Vec<u8>,
+    done: bool,
+}
+
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pending.is_empty() && !self.done {
+            self.done = true;
+            if let Some(mut generation) = self.live_reload.wait_for_change(self.seen, POLL_TIMEOUT) {
+                while let Some(newer) = self.live_reload.wait_for_change(generation, SETTLE_DELAY) {
+                    generation = newer;
+                }
+                self.pending = format!("id: {}\ndata: reload\n\n", generation).into_bytes();
+            }
+        }
+        let n = buf.len().min(self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_inject_script() {
+        let page = inject_script(b"<html><body><p>Hi</p></BODY></html>");
+        assert_eq!(page, format!("<html><body><p>Hi</p>{}</BODY></html>", SCRIPT).into_bytes());
+        assert_eq!(inject_script(b"<p>Hi</p>"), format!("<p>Hi</p>{}", SCRIPT).into_bytes());
+    }
+
+    #[test]
+    fn test_stream_sends_one_reload_per_burst_of_changes() {
+        let live_reload = Arc::new(LiveReload::new());
+        let notifier = Arc::clone(&live_reload);
+        let changes = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            notifier.notify();
+            notifier.notify();
+        });
+        let mut events = EventStream {
+            live_reload: Arc::clone(&live_reload),
+            seen: 0,
+            pending: Vec::new(),
+            done: false,
+        };
+        let mut body = String::new();
+        events.read_to_string(&mut body).unwrap();
+        changes.join().unwrap();
+        assert_eq!(body, "id: 2\ndata: reload\n\n");
+        assert_eq!(live_reload.wait_for_change(2, Duration::from_millis(10)), None);
+    }
+}
@@ -0,0 +1,368 @@
+//! `http-server bench`: a small closed-loop load generator for checking
+//! configuration and tuning changes without installing wrk
+
+use clap::Args;
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Options for the `bench` subcommand
+#[derive(Args, Debug, Clone)]
+pub struct BenchArgs {
+    /// URL to request, e.g. http://127.0.0.1:4221/echo/hello (plain HTTP only)
+    #[arg(long)]
+    pub url: String,
+
+    /// Concurrent keep-alive connections, each with one request in flight
+    #[arg(short, long, default_value = "10")]
+    pub connections: usize,
+
+    /// How long to run, e.g. 30s, 500ms, or 2m
+    #[arg(long, default_value = "10s", value_parser = parse_duration)]
+    pub duration: Duration,
+
+    /// Give up on a request after this long
+    #[arg(long, default_value = "5s", value_parser = parse_duration)]
+    pub timeout: Duration,
+}
+
+/// Where to send requests, parsed from `--url`
+#[derive(Debug, PartialEq)]
+struct Target {
+    host: String,
+    port: u16,
+    path: String,
+}
+
+/// What one connection observed
+#[derive(Default)]
+struct Results {
+    /// Latency of each completed request in microseconds
+    latencies: Vec<u64>,
+    /// Completed requests per status class (1xx..5xx)
+    statuses: [u64; 5],
+    errors: u64,
+    bytes: u64,
+}
+
+impl Results {
+    fn merge(&mut self, other: Results) {
+        self.latencies.extend(other.latencies);
+        for (total, count) in self.statuses.iter_mut().zip(other.statuses) {
+            *total += count;
+        }
+        self.errors += other.errors;
+        self.bytes += other.bytes;
+    }
+}
+
+/// Run the load test and print a report; returns the process exit code
+pub fn run(args: &BenchArgs) -> i32 {
+    let target = match parse_url(&args.url) {
+        Ok(target) => target,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 2;
+        }
+    };
+    let addr = match (target.host.as_str(), target.port).to_socket_addrs().map(|mut addrs| addrs.next()) {
+        Ok(Some(addr)) => addr,
+        Ok(None) | Err(_) => {
+            eprintln!("error: could not resolve {}", target.host);
+            return 2;
+        }
+    };
+    if args.connections == 0 {
+        eprintln!("error: --connections must be at least 1");
+        return 2;
+    }
+
+    println!("Running {:?} test @ {}", args.duration, args.url);
+    println!("  {} connections", args.connections);
+
+    let host = if target.host.contains(':') { format!("[{}]", target.host) } else { target.host.clone() };
+    let host = if target.port == 80 { host } else { format!("{}:{}", host, target.port) };
+    let request: Arc<[u8]> = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: http-server-bench\r\nAccept-Encoding: identity\r\n\r\n",
+        target.path, host
+    )
+    .into_bytes()
+    .into();
+    let start = Instant::now();
+    let deadline = start + args.duration;
+    let threads: Vec<_> = (0..args.connections)
+        .map(|_| {
+            let request = Arc::clone(&request);
+            let timeout = args.timeout;
+            std::thread::spawn(move || drive(addr, &request, deadline, timeout))
+        })
+        .collect();
+
+    let mut results = Results::default();
+    for thread in threads {
+        results.merge(thread.join().unwrap_or_default());
+    }
+    report(&mut results, start.elapsed());
+
+    if results.latencies.is_empty() {
+        1
+    } else {
+        0
+    }
+}
+
+/// Issue requests back to back on one connection until `deadline`,
+/// reconnecting whenever the server closes it
+fn drive(addr: SocketAddr, request: &[u8], deadline: Instant, timeout: Duration) -> Results {
+    let mut results = Results::default();
+    let mut body = Vec::new();
+    while Instant::now() < deadline {
+        let stream = match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => stream,
+            Err(_) => {
+                results.errors += 1;
+                // Don't spin if the server is down
+                std::thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+        };
+        let _ = stream.set_nodelay(true);
+        let _ = stream.set_read_timeout(Some(timeout));
+        let Ok(mut writer) = stream.try_clone() else {
+            results.errors += 1;
+            continue;
+        };
+        let mut reader = BufReader::new(stream);
+
+        while Instant::now() < deadline {
+            let sent = Instant::now();
+            let response = writer
+                .write_all(request)
+                .and_then(|_| read_response(&mut reader, &mut body));
+            match response {
+                Ok((status, keep_alive)) => {
+                    results.latencies.push(sent.elapsed().as_micros() as u64);
+                    results.statuses[(status / 100).clamp(1, 5) as usize - 1] += 1;
+                    results.bytes += body.len() as u64;
+                    if !keep_alive {
+                        break;
+                    }
+                }
+                Err(_) => {
+                    results.errors += 1;
+                    break;
+                }
+            }
+        }
+    }
+    results
+}
+
+/// Read one response into `body`; returns the status code and whether the
+/// connection can be reused
+fn read_response(reader: &mut impl BufRead, body: &mut Vec<u8>) -> io::Result<(u16, bool)> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut line = String::new();
+    if reader.read_line(&mut line)? == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    let status: u16 = line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse().ok())
+        .ok_or_else(|| invalid("malformed status line"))?;
+
+    let (mut content_length, mut chunked, mut keep_alive) = (None, false, true);
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        let Some((name, value)) = header.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if name.eq_ignore_ascii_case("content-length") {
+            content_length = Some(value.parse::<usize>().map_err(|_| invalid("bad Content-Length"))?);
+        } else if name.eq_ignore_ascii_case("transfer-encoding") {
+            chunked = value.eq_ignore_ascii_case("chunked");
+        } else if name.eq_ignore_ascii_case("connection") {
+            keep_alive = !value.eq_ignore_ascii_case("close");
+        }
+    }
+
+    body.clear();
+    if chunked {
+        loop {
+            line.clear();
+            reader.read_line(&mut line)?;
+            let size_field = line.trim_end().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size_field, 16).map_err(|_| invalid("bad chunk size"))?;
+            if size == 0 {
+                // Skip trailers up to the blank line
+                loop {
+                    line.clear();
+                    if reader.read_line(&mut line)? == 0 || line.trim_end().is_empty() {
+                        break;
+                    }
+                }
+                break;
+            }
+            let start = body.len();
+            body.resize(start + size, 0);
+            reader.read_exact(&mut body[start..])?;
+            line.clear();
+            reader.read_line(&mut line)?;
+        }
+    } else if let Some(length) = content_length {
+        body.resize(length, 0);
+        reader.read_exact(body)?;
+    } else if status >= 200 && status != 204 && status != 304 {
+        // No framing: the body runs until the server closes the connection
+        reader.read_to_end(body)?;
+        keep_alive = false;
+    }
+    Ok((status, keep_alive))
+}
+
+fn report(results: &mut Results, elapsed: Duration) {
+    let seconds = elapsed.as_secs_f64();
+    let completed = results.latencies.len();
+    results.latencies.sort_unstable();
+
+    println!();
+    println!("Requests:   {:>12} ({:.1}/s)", completed, completed as f64 / seconds);
+    println!(
+        "Transfer:   {:>12} ({}/s)",
+        format_bytes(results.bytes as f64),
+        format_bytes(results.bytes as f64 / seconds)
+    );
+    if completed > 0 {
+        let average = results.latencies.iter().sum::<u64>() / completed as u64;
+        println!("Latency:    avg {}  max {}", format_micros(average), format_micros(results.latencies[completed - 1]));
+        for (label, quantile) in [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p99.9", 0.999)] {
+            println!("  {:<6} {:>10}", label, format_micros(percentile(&results.latencies, quantile)));
+        }
+    }
+    let [s1, s2, s3, s4, s5] = results.statuses;
+    println!("Responses:  1xx={} 2xx={} 3xx={} 4xx={} 5xx={}", s1, s2, s3, s4, s5);
+    println!("Errors:     {}", results.errors);
+}
+
+/// Nearest-rank percentile of sorted samples
+fn percentile(sorted: &[u64], quantile: f64) -> u64 {
+    if sorted.is_empty() {
+        return 0;
+    }
+    let rank = (quantile * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn format_micros(micros: u64) -> String {
+    match micros {
+        0..=999 => format!("{}us", micros),
+        1_000..=999_999 => format!("{:.2}ms", micros as f64 / 1_000.0),
+        _ => format!("{:.2}s", micros as f64 / 1_000_000.0),
+    }
+}
+
+fn format_bytes(bytes: f64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.2} {}", value, UNITS[unit])
+}
+
+/// Parse `http://host[:port][/path]`
+fn parse_url(url: &str) -> Result<Target, String> {
+    let rest = match url.split_once("://") {
+        Some(("http", rest)) => rest,
+        Some((scheme, _)) => return Err(format!("unsupported scheme '{}' (only http is supported)", scheme)),
+        None => url,
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.rsplit_once(':') {
+        // Bracketed IPv6 addresses contain colons of their own
+        Some((host, port)) if !port.contains(']') => {
+            let port = port.parse().map_err(|_| format!("invalid port in '{}'", url))?;
+            (host, port)
+        }
+        _ => (authority, 80),
+    };
+    if host.is_empty() {
+        return Err(format!("missing host in '{}'", url));
+    }
+    Ok(Target {
+        host: host.trim_start_matches('[').trim_end_matches(']').to_string(),
+        port,
+        path: path.to_string(),
+    })
+}
+
+/// Parse a duration such as `30s`, `500ms`, `2m`, or a bare number of seconds
+fn parse_duration(value: &str) -> Result<Duration, String> {
+    let value = value.trim();
+    let split = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(split);
+    let number: f64 = number.parse().map_err(|_| format!("invalid duration '{}'", value))?;
+    let seconds = match unit {
+        "ms" => number / 1000.0,
+        "" | "s" => number,
+        "m" => number * 60.0,
+        "h" => number * 3600.0,
+        _ => return Err(format!("invalid duration unit '{}' (use ms, s, m, or h)", unit)),
+    };
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_url_and_duration() {
+        let target = parse_url("http://localhost:4221/echo/hi").unwrap();
+        assert_eq!((target.host.as_str(), target.port, target.path.as_str()), ("localhost", 4221, "/echo/hi"));
+        let target = parse_url("http://[::1]").unwrap();
+        assert_eq!((target.host.as_str(), target.port, target.path.as_str()), ("::1", 80, "/"));
+        assert!(parse_url("https://example.com/").is_err());
+
+        assert_eq!(parse_duration("30s"), Ok(Duration::from_secs(30)));
+        assert_eq!(parse_duration("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_duration("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_duration("5"), Ok(Duration::from_secs(5)));
+        assert!(parse_duration("5 days").is_err());
+    }
+
+    #[test]
+    fn test_read_response_framing() {
+        let mut body = Vec::new();
+        let raw: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhelloHTTP/1.1 404 Not Found\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n3\r\nabc\r\n2\r\nde\r\n0\r\n\r\n";
+        let mut reader = BufReader::new(raw);
+        assert_eq!(read_response(&mut reader, &mut body).unwrap(), (200, true));
+        assert_eq!(body, b"hello");
+        assert_eq!(read_response(&mut reader, &mut body).unwrap(), (404, false));
+        assert_eq!(body, b"abcde");
+    }
+
+    #[test]
+    fn test_percentile() {
+        let samples: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&samples, 0.5), 50);
+        assert_eq!(percentile(&samples, 0.99), 99);
+        assert_eq!(percentile(&samples, 0.999), 100);
+        assert_eq!(percentile(&[], 0.5), 0);
+    }
+}
@@ -0,0 +1,146 @@
+use chrono::{Local, NaiveDate};
+use clap::ValueEnum;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// When a log file is rotated
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Rotation {
+    /// Never rotate; the file grows without bound
+    Never,
+    /// Rotate once the file reaches the configured size
+    Size,
+    /// Rotate on the first write after local midnight
+    Daily,
+}
+
+/// Rotation settings shared by every rotating log file
+#[derive(Debug, Clone, Copy)]
+pub struct RotationPolicy {
+    pub rotation: Rotation,
+    pub max_bytes: u64,
+    pub keep: usize,
+}
+
+/// An append-only log file that rotates itself according to a policy
+///
+/// Archives are named `<path>.1` (newest) through `<path>.<keep>` (oldest);
+/// anything older is deleted on rotation.
+pub struct RotatingFile {
+    path: PathBuf,
+    policy: RotationPolicy,
+    file: File,
+    size: u64,
+    opened_on: NaiveDate,
+}
+
+impl RotatingFile {
+    /// Open (or create) the log file for appending
+    pub fn open(path: impl AsRef<Path>, policy: RotationPolicy) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        let opened_on = metadata
+            .modified()
+            .map(|t| chrono::DateTime::<Local>::from(t).date_naive())
+            .unwrap_or_else(|_| Local::now().date_naive());
+
+        Ok(RotatingFile {
+            path,
+            policy,
+            file,
+            size: metadata.len(),
+            opened_on,
+        })
+    }
+
+    /// Whether the file must be rotated before writing `incoming` more bytes
+    fn needs_rotation(&self, incoming: usize) -> bool {
+        match self.policy.rotation {
+            Rotation::Never => false,
+            Rotation::Size => self.size > 0 && self.size + incoming as u64 > self.policy.max_bytes,
+            Rotation::Daily => Local::now().date_naive() != self.opened_on,
+        }
+    }
+
+    /// Path of the n-th archive
+    fn archive_path(&self, n: usize) -> PathBuf {
+        let mut name = self.path.clone().into_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    }
+
+    /// Shift archives up by one and start a fresh log file
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+
+        if self.policy.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.archive_path(self.policy.keep));
+            for n in (1..self.policy.keep).rev() {
+                let from = self.archive_path(n);
+                if from.exists() {
+                    fs::rename(&from, self.archive_path(n + 1))?;
+                }
+            }
+            fs::rename(&self.path, self.archive_path(1))?;
+        }
+
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        self.opened_on = Local::now().date_naive();
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.needs_rotation(buf.len()) {
+            self.rotate()?;
+        }
+
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_size_rotation_keeps_n_archives() {
+        let dir = std::env::temp_dir().join(format!("http-server-log-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("server.log");
+
+        let policy = RotationPolicy {
+            rotation: Rotation::Size,
+            max_bytes: 10,
+            keep: 2,
+        };
+        let mut file = RotatingFile::open(&path, policy).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "fourth\n");
+        assert_eq!(fs::read_to_string(dir.join("server.log.1")).unwrap(), "third\n");
+        assert_eq!(fs::read_to_string(dir.join("server.log.2")).unwrap(), "second\n");
+        assert!(!dir.join("server.log.3").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+use log::{Log, Metadata, Record};
+use std::sync::{OnceLock, RwLock};
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+/// Wraps the env_logger output with a filter that can be swapped at runtime
+struct ReloadableLogger {
+    output: env_logger::Logger,
+    filter: RwLock<(String, env_filter::Filter)>,
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.filter.read().unwrap().1.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.filter.read().unwrap().1.matches(record) {
+            self.output.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.output.flush();
+    }
+}
+
+/// Parse a `RUST_LOG`-style filter spec such as `info,http_server=debug`
+fn parse(spec: &str) -> Result<env_filter::Filter, String> {
+    let mut builder = env_filter::Builder::new();
+    builder
+        .try_parse(spec)
+        .map_err(|e| format!("Invalid log filter '{}': {}", spec, e))?;
+    Ok(builder.build())
+}
+
+/// Check that a filter spec is valid without applying it
+pub fn validate(spec: &str) -> Result<(), String> {
+    parse(spec).map(|_| ())
+}
+
+/// Install `output` as the global logger, filtered by `spec`
+///
+/// `output` should be built with the most permissive level; filtering happens here.
+pub fn install(output: env_logger::Logger, spec: &str) -> Result<(), String> {
+    let filter = parse(spec)?;
+    let max_level = filter.filter();
+    let logger = LOGGER.get_or_init(|| ReloadableLogger {
+        output,
+        filter: RwLock::new((spec.to_string(), filter)),
+    });
+    log::set_logger(logger).map_err(|e| e.to_string())?;
+    log::set_max_level(max_level);
+    Ok(())
+}
+
+/// The filter spec currently in effect
+pub fn current() -> Option<String> {
+    LOGGER.get().map(|logger| logger.filter.read().unwrap().0.clone())
+}
+
+/// Replace the active filter, returning the previous spec
+pub fn set(spec: &str) -> Result<String, String> {
+    let logger = LOGGER.get().ok_or("Logger is not installed")?;
+    let filter = parse(spec)?;
+    let max_level = filter.filter();
+    let previous = std::mem::replace(&mut *logger.filter.write().unwrap(), (spec.to_string(), filter));
+    log::set_max_level(max_level);
+    Ok(previous.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter_spec() {
+        assert_eq!(parse("debug").unwrap().filter(), log::LevelFilter::Debug);
+        assert_eq!(parse("warn,http_server=trace").unwrap().filter(), log::LevelFilter::Trace);
+        assert!(parse("http_server=loud").is_err());
+    }
+}
@@ -1,236 +1,361 @@
-mod compression;
-mod config;
-mod error;
-mod request;
-mod response;
-mod router;
-
-use config::Config;
-use error::ServerError;
-use request::HttpRequest;
-use router::Router;
-use std::io::BufReader;
-use std::net::{TcpListener, TcpStream};
-use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::Arc;
-use std::time::Instant;
-use threadpool::ThreadPool;
-
-#[cfg(unix)]
-fn set_socket_options(listener: &TcpListener) -> anyhow::Result<()> {
-    use std::os::fd::AsRawFd;
-    
-    let fd = listener.as_raw_fd();
-    
-    // Enable SO_REUSEADDR for quick restarts
-    unsafe {
-        let optval: libc::c_int = 1;
-        libc::setsockopt(
-            fd,
-            libc::SOL_SOCKET,
-            libc::SO_REUSEADDR,
-            &optval as *const _ as *const libc::c_void,
-            std::mem::size_of_val(&optval) as libc::socklen_t,
-        );
-    }
-    
-    // Enable SO_REUSEPORT for better load distribution across threads (Linux/BSD)
-    #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
-    unsafe {
-        let optval: libc::c_int = 1;
-        libc::setsockopt(
-            fd,
-            libc::SOL_SOCKET,
-            libc::SO_REUSEPORT,
-            &optval as *const _ as *const libc::c_void,
-            std::mem::size_of_val(&optval) as libc::socklen_t,
-        );
-    }
-    
-    Ok(())
-}
+use http_server::access_log::AccessLogger;
+use http_server::audit::{self, AuditLog};
+use http_server::config::{Command, Config, Runtime};
+use http_server::daemon::{self, PidFile};
+use http_server::metrics::ServerMetrics;
+use http_server::platform::{self, Signal};
+use http_server::router::Router;
+use http_server::server::{reload_config, run_acceptors, serve_admin, wait_for_drain, ServerState};
+use http_server::telemetry::Tracer;
+use http_server::{check, cluster, load_test, net, precompress, statsd, template, upgrade, watch};
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
-#[cfg(not(unix))]
-fn set_socket_options(_listener: &TcpListener) -> anyhow::Result<()> {
-    // Windows doesn't need these optimizations
-    Ok(())
-}
+fn main() -> anyhow::Result<()> {
+    // Pick up the listening sockets handed over by a process being upgraded
+    let inherited_listeners = upgrade::inherited_listeners();
 
-/// Server metrics for monitoring
-pub struct ServerMetrics {
-    pub request_count: AtomicU64,
-    pub error_count: AtomicU64,
-    pub total_response_time_ms: AtomicU64,
-    pub active_connections: AtomicU64,
-    pub start_time: Instant,
-}
+    // Parse configuration
+    let config = Config::parse_config();
 
-impl ServerMetrics {
-    pub fn new() -> Self {
-        Self {
-            request_count: AtomicU64::new(0),
-            error_count: AtomicU64::new(0),
-            total_response_time_ms: AtomicU64::new(0),
-            active_connections: AtomicU64::new(0),
-            start_time: Instant::now(),
-        }
+    // Subcommands run instead of the server
+    match &config.command {
+        Some(Command::Bench(args)) => std::process::exit(load_test::run(args)),
+        Some(Command::Check(args)) => std::process::exit(check::run(args)),
+        Some(Command::Precompress(args)) => std::process::exit(precompress::run(args)),
+        Some(Command::VerifyAudit(args)) => std::process::exit(audit::run(args)),
+        None => {}
     }
 
-    pub fn uptime_seconds(&self) -> u64 {
-        self.start_time.elapsed().as_secs()
+    // Initialize logger
+    if let Err(e) = config.init_logger() {
+        eprintln!("Failed to initialize logging: {}", e);
+        std::process::exit(1);
     }
-}
-
-/// Handle a single client connection
-fn handle_client(stream: TcpStream, router: Arc<Router>, metrics: Arc<ServerMetrics>) {
-    use std::io::Write;
-
-    let peer_addr = stream.peer_addr().ok();
-    let stream_clone = stream.try_clone();
-
-    // Enable TCP_NODELAY to disable Nagle's algorithm for lower latency
-    let _ = stream.set_nodelay(true);
 
-    // Track active connection
-    metrics.active_connections.fetch_add(1, Ordering::Relaxed);
-    let start_time = Instant::now();
-
-    let result = (|| -> Result<(), ServerError> {
-        let mut reader = BufReader::with_capacity(8192, stream);
-
-        // Parse the HTTP request
-        let request = HttpRequest::parse(&mut reader)?;
+    // Validate configuration
+    if let Err(e) = config.validate() {
+        log::error!("Configuration error: {}", e);
+        std::process::exit(1);
+    }
 
-        // Generate request ID for tracking
-        let request_id = metrics.request_count.fetch_add(1, Ordering::Relaxed);
-        
-        log::debug!("Request #{}: {} {}", request_id, request.method.as_str(), request.path);
+    // A cluster worker leaves the PID file, daemonizing, and upgrades to its
+    // supervisor, and serves on the sockets it was handed
+    let worker = cluster::worker_index();
+    if worker.is_some() {
+        cluster::follow_supervisor();
+    }
 
-        // Route the request and generate response
-        let response_bytes = router.route(request, &metrics)?;
+    match config.templates() {
+        Ok(templates) => template::install(templates),
+        Err(e) => {
+            log::error!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    }
 
-        // Write response back to client
-        let mut stream = reader.into_inner();
-        stream.write_all(&response_bytes)?;
-        stream.flush()?;
+    // Lock the PID file before detaching so a second instance fails visibly;
+    // an upgraded process instead takes the file over once its parent exits
+    let upgrading = !inherited_listeners.is_empty() && worker.is_none();
+    let mut pid_file = match (&config.pid_file, upgrading || worker.is_some()) {
+        (Some(path), false) => match PidFile::lock(path) {
+            Ok(pid_file) => Some(pid_file),
+            Err(e) => {
+                log::error!("Refusing to start: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => None,
+    };
+
+    // Daemonize before any threads start; an upgraded process is already detached
+    if config.daemon && !upgrading && worker.is_none() {
+        if config.log_file.is_none() {
+            log::warn!("Running as a daemon without --log-file; the application log will be discarded");
+        }
+        if let Err(e) = daemon::daemonize() {
+            log::error!("Failed to daemonize: {}", e);
+            std::process::exit(1);
+        }
+    }
+    if let Some(pid_file) = &mut pid_file {
+        if let Err(e) = pid_file.write_pid() {
+            log::error!("Failed to write PID file: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let pid_file = Arc::new(Mutex::new(pid_file));
+
+    // In cluster mode this process only binds the sockets and supervises the
+    // workers serving on them
+    if config.processes > 0 && worker.is_none() {
+        let listeners = net::bind_listeners(
+            &config.server_address(),
+            &config.socket_options(),
+            inherited_listeners,
+            config.acceptors,
+        )?;
+        let admin_listener = bind_admin_listener(&config);
+        drop_privileges(&config);
+        if upgrading {
+            take_over_pid_file(&config, &pid_file);
+        }
+        log::info!("Starting {} worker processes on http://{}", config.processes, config.server_address());
+        if let Some(admin_addr) = &config.admin_addr {
+            log::info!("Cluster metrics: http://{}/metrics", admin_addr);
+        }
+        if let Err(e) = cluster::supervise(&config, listeners, admin_listener) {
+            log::error!("Cluster supervisor failed: {}", e);
+            std::process::exit(1);
+        }
+        drop(pid_file.lock().unwrap().take());
+        log::info!("Cluster shutdown complete");
+        return Ok(());
+    }
 
-        Ok(())
-    })();
+    // Open the access log, if enabled
+    let access_log = match &config.access_log {
+        Some(target) => match AccessLogger::open(target, config.access_log_format, config.rotation_policy()) {
+            Ok(logger) => {
+                let slow = (config.slow_request_ms > 0).then(|| std::time::Duration::from_millis(config.slow_request_ms));
+                Some(logger.sampling(config.access_log_sample, slow))
+            }
+            Err(e) => {
+                log::error!("Failed to open access log '{}': {}", target, e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Start the OTLP span exporter, if tracing is enabled
+    let tracer = match &config.otlp_endpoint {
+        Some(endpoint) => match Tracer::new(endpoint, &config.otlp_service_name) {
+            Ok(tracer) => Some(tracer),
+            Err(e) => {
+                log::error!("Configuration error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        None => None,
+    };
+
+    // Load WebAssembly plugins before serving so a bad module stops startup
+    #[cfg(feature = "wasm")]
+    let router = match http_server::wasm::load_plugins(Router::new(&config), &config) {
+        Ok(router) => router,
+        Err(e) => {
+            log::error!("Configuration error: {}", e);
+            std::process::exit(1);
+        }
+    };
+    #[cfg(not(feature = "wasm"))]
+    let router = Router::new(&config);
+
+    // Open the audit log, if enabled; changes must not go unrecorded
+    let router = match &config.audit_log {
+        Some(path) => match AuditLog::open(path, &config.trusted_proxies) {
+            Ok(log) => router.audit_log(log),
+            Err(e) => {
+                log::error!("Failed to open audit log '{}': {}", path, e);
+                std::process::exit(1);
+            }
+        },
+        None => router,
+    };
 
-    // Record metrics
-    let response_time_ms = start_time.elapsed().as_millis() as u64;
-    metrics.total_response_time_ms.fetch_add(response_time_ms, Ordering::Relaxed);
-    metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+    // Create router, metrics, and the shared server state
+    let metrics = Arc::new(ServerMetrics::new());
+    let state = Arc::new(ServerState {
+        router,
+        uploads: config.upload_limiter(),
+        ingress: config.ingress_limit(Arc::clone(&metrics.ingress)),
+        egress: config.egress_limit(Arc::clone(&metrics.egress)),
+        metrics,
+        access_log,
+        tracer,
+        config,
+    });
+    let config = &state.config;
+    let metrics = &state.metrics;
+
+    // Reload the served directory and log filter on SIGHUP
+    let reload_state = Arc::clone(&state);
+    platform::on_signal(Signal::Hangup, move || {
+        log::info!("Received SIGHUP, reloading configuration");
+        reload_config(&reload_state);
+    })?;
 
-    if result.is_err() {
-        metrics.error_count.fetch_add(1, Ordering::Relaxed);
+    // Reload the same way when the --config file or a page template is edited
+    let mut watched_files: Vec<PathBuf> = config.config.iter().cloned().collect();
+    if let Some(directory) = &config.template_dir {
+        watched_files.extend(["index.html", "document.html", "error.html"].map(|name| Path::new(directory).join(name)));
     }
-
-    // Log errors if any
-    if let Err(e) = result {
-        log::error!(
-            "Error handling request from {:?}: {}",
-            peer_addr.unwrap_or_else(|| "unknown".parse().unwrap()),
-            e
-        );
-
-        // Try to send error response using cloned stream
-        if let Ok(mut stream_for_error) = stream_clone {
-            let error_response = e.to_response();
-            let _ = stream_for_error.write_all(error_response.as_bytes());
-            let _ = stream_for_error.flush();
+    let reload_state = Arc::clone(&state);
+    let _config_watcher = if (config.watch_files || config.watch) && !watched_files.is_empty() {
+        let delay = std::time::Duration::from_millis(200);
+        watch::on_file_change(&watched_files, delay, move || {
+            log::info!("Configuration files changed, reloading configuration");
+            reload_config(&reload_state);
+        })
+        .map_err(|e| log::warn!("Not watching the configuration for changes: {}", e))
+        .ok()
+    } else {
+        None
+    };
+
+    // Push metrics to StatsD, if configured
+    if let Some(addr) = &config.statsd_addr {
+        if let Err(e) = statsd::StatsdExporter::spawn(
+            addr,
+            &config.statsd_prefix,
+            config.statsd_format,
+            std::time::Duration::from_secs(config.statsd_interval_secs.max(1)),
+            Arc::clone(metrics),
+        ) {
+            log::error!("Failed to start StatsD exporter for {}: {}", addr, e);
+            std::process::exit(1);
         }
     }
-}
 
-fn main() -> anyhow::Result<()> {
-    // Parse configuration
-    let config = Config::parse_config();
+    // Threads in the worker pool, or in the Tokio runtime
+    metrics
+        .worker_pool_size
+        .store(config.workers as u64, Ordering::Relaxed);
 
-    // Initialize logger
-    config.init_logger();
-
-    // Validate configuration
-    if let Err(e) = config.validate() {
-        log::error!("Configuration error: {}", e);
-        std::process::exit(1);
+    // Bind one socket per acceptor, reusing any a previous process handed us
+    if upgrading {
+        log::info!("Inherited {} listening socket(s) from the previous process", inherited_listeners.len());
     }
+    let listeners = net::bind_listeners(
+        &config.server_address(),
+        &config.socket_options(),
+        inherited_listeners,
+        config.acceptors,
+    )?;
 
-    // Create router and metrics
-    let router = Arc::new(Router::new(config.directory.clone()));
-    let metrics = Arc::new(ServerMetrics::new());
+    // Set non-blocking mode for shutdown handling
+    for listener in &listeners {
+        listener.set_nonblocking(true)?;
+    }
+    state.router.readiness.set_listening();
+
+    // On SIGUSR2, start the (possibly updated) binary on the same sockets; it
+    // asks this process to drain once it is accepting connections
+    let upgrade_listeners = listeners.iter().map(TcpListener::try_clone).collect::<Result<Vec<_>, _>>()?;
+    if worker.is_none() {
+        platform::on_signal(Signal::Upgrade, move || {
+            log::info!("Received SIGUSR2, starting a new server process");
+            match upgrade::spawn_successor(&upgrade_listeners) {
+                Ok(mut child) => {
+                    log::info!("Started process {}, waiting for it to take over", child.id());
+                    std::thread::spawn(move || {
+                        if let Ok(status) = child.wait() {
+                            log::warn!("Upgraded process exited before taking over: {}", status);
+                        }
+                    });
+                }
+                Err(e) => log::error!("Binary upgrade failed, continuing to serve: {}", e),
+            }
+        })?;
+    }
 
-    // Setup graceful shutdown
+    // Setup graceful shutdown: fail readiness first, then stop accepting
     let shutdown = Arc::new(AtomicBool::new(false));
     let shutdown_clone = Arc::clone(&shutdown);
-    
-    ctrlc::set_handler(move || {
+    let signal_state = Arc::clone(&state);
+
+    platform::on_signal(Signal::Terminate, move || {
         log::info!("Received shutdown signal, gracefully shutting down...");
+        signal_state.router.readiness.start_draining();
+        let delay = signal_state.config.drain_delay_secs;
+        if delay > 0 {
+            log::info!("Readiness now failing, draining for {}s before closing the listener", delay);
+            std::thread::sleep(std::time::Duration::from_secs(delay));
+        }
         shutdown_clone.store(true, Ordering::Relaxed);
     })?;
-
-    // Create thread pool for handling connections
-    let pool = ThreadPool::new(config.workers);
-
-    // Bind to address
-    let listener = TcpListener::bind(config.server_address())?;
-    
-    // Set socket options for better performance
-    set_socket_options(&listener)?;
-    
-    // Set non-blocking mode for shutdown handling
-    listener.set_nonblocking(false)?;
     
+    // Operational endpoints get their own listener and thread, so they stay
+    // reachable when the worker pool is saturated; a cluster worker has a
+    // private one the supervisor collects metrics from
+    let admin_listener = match worker {
+        Some(_) => cluster::inherited_admin_listener(),
+        None => bind_admin_listener(config),
+    };
+    drop_privileges(config);
+
+    if let Some(admin_listener) = admin_listener {
+        let admin_addr = admin_listener.local_addr()?;
+        let state = Arc::clone(&state);
+        std::thread::Builder::new()
+            .name("admin-listener".to_string())
+            .spawn(move || serve_admin(admin_listener, state))?;
+        log::info!("Admin endpoints: http://{}/metrics", admin_addr);
+    }
+
     log::info!("Server starting...");
     log::info!("Serving files from: {}", config.directory);
-    log::info!("Worker threads: {}", config.workers);
+    match config.runtime {
+        Runtime::Threads => log::info!("Worker threads: {}-{} (adaptive)", config.workers, config.max_workers.max(config.workers)),
+        runtime => log::info!("Worker threads: {} ({:?} runtime)", config.workers, runtime),
+    }
+    log::info!(
+        "Acceptors: {}{}",
+        listeners.len(),
+        if listeners.len() > 1 && net::REUSEPORT_SHARDING { " (SO_REUSEPORT sharded)" } else { "" }
+    );
     log::info!("Listening on: http://{}", config.server_address());
-    log::info!("Optimizations: TCP_NODELAY=on, SO_REUSEADDR=on, Buffer=8KB");
+    log::info!(
+        "Optimizations: TCP_NODELAY=on, SO_REUSEADDR=on, Backlog={}, Buffer=8KB",
+        config.listen_backlog
+    );
     log::info!("Features: Graceful shutdown, Metrics tracking, Request ID tracing");
-    log::info!("Metrics endpoint: http://{}/metrics", config.server_address());
+    if config.admin_addr.is_none() {
+        log::info!("Metrics endpoint: http://{}/metrics", config.server_address());
+    }
     log::info!("Server is ready to handle 100+ concurrent requests per second!");
 
-    // Accept connections
-    for stream in listener.incoming() {
-        // Check for shutdown signal
-        if shutdown.load(Ordering::Relaxed) {
-            log::info!("Shutdown initiated, no longer accepting new connections");
-            break;
-        }
+    if let Some(worker) = worker {
+        log::info!("Serving as cluster worker {}", worker);
+    } else {
+        upgrade::notify_parent();
+    }
+    if upgrading {
+        take_over_pid_file(config, &pid_file);
+    }
 
-        match stream {
-            Ok(stream) => {
-                let router = Arc::clone(&router);
-                let metrics_clone = Arc::clone(&metrics);
-                pool.execute(move || {
-                    handle_client(stream, router, metrics_clone);
-                });
-            }
-            Err(e) => {
-                log::error!("Failed to accept connection: {}", e);
+    let drain_timeout = std::time::Duration::from_secs(config.drain_timeout_secs);
+    match config.runtime {
+        Runtime::Threads => {
+            run_acceptors(listeners, &state, &shutdown)?;
+            wait_for_drain(metrics, drain_timeout);
+        }
+        #[cfg(feature = "tokio")]
+        Runtime::Tokio => {
+            // Connection tasks keep running on the runtime's threads while draining
+            let runtime = http_server::async_server::run(listeners, Arc::clone(&state), Arc::clone(&shutdown))?;
+            wait_for_drain(metrics, drain_timeout);
+            runtime.shutdown_timeout(std::time::Duration::from_secs(1));
+        }
+        #[cfg(not(feature = "tokio"))]
+        Runtime::Tokio => unreachable!("rejected by Config::validate"),
+        #[cfg(all(target_os = "linux", feature = "io-uring"))]
+        Runtime::IoUring => {
+            if let Err(e) = http_server::uring_server::run(listeners, Arc::clone(&state), Arc::clone(&shutdown)) {
+                log::error!("Failed to start io_uring event loops: {}", e);
+                std::process::exit(1);
             }
+            wait_for_drain(metrics, drain_timeout);
         }
+        #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+        Runtime::IoUring => unreachable!("rejected by Config::validate"),
     }
 
-    // Wait for active connections to finish
-    log::info!("Waiting for {} active connections to finish...", 
-        metrics.active_connections.load(Ordering::Relaxed));
-    
-    drop(listener);
-    
-    // Give threads time to finish (with timeout)
-    let shutdown_timeout = std::time::Duration::from_secs(10);
-    let shutdown_start = Instant::now();
-    
-    while metrics.active_connections.load(Ordering::Relaxed) > 0 
-        && shutdown_start.elapsed() < shutdown_timeout {
-        std::thread::sleep(std::time::Duration::from_millis(100));
-    }
-    
-    let remaining = metrics.active_connections.load(Ordering::Relaxed);
-    if remaining > 0 {
-        log::warn!("Shutdown timeout reached with {} connections still active", remaining);
-    }
+    // Removes the PID file
+    drop(pid_file.lock().unwrap().take());
 
     log::info!("Server shutdown complete");
     log::info!("Final stats - Requests: {}, Errors: {}, Uptime: {}s",
@@ -241,6 +366,51 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Bind the `--admin-addr` listener, if configured, exiting on failure
+fn bind_admin_listener(config: &Config) -> Option<TcpListener> {
+    config.admin_addr.as_ref().map(|admin_addr| match TcpListener::bind(admin_addr) {
+        Ok(listener) => listener,
+        Err(e) => {
+            log::error!("Failed to bind admin listener {}: {}", admin_addr, e);
+            std::process::exit(1);
+        }
+    })
+}
+
+/// Every socket is bound; give up root before touching any client traffic
+fn drop_privileges(config: &Config) {
+    if config.user.is_none() && config.group.is_none() {
+        return;
+    }
+    if let Err(e) = daemon::drop_privileges(config.user.as_deref(), config.group.as_deref()) {
+        log::error!("Failed to drop privileges: {}", e);
+        std::process::exit(1);
+    }
+    log::info!(
+        "Dropped privileges to user {} / group {}",
+        config.user.as_deref().unwrap_or("(unchanged)"),
+        config.group.as_deref().unwrap_or("(user's primary)")
+    );
+}
+
+/// After a binary upgrade, take the PID file over once the previous process
+/// has exited and released it
+fn take_over_pid_file(config: &Config, pid_file: &Arc<Mutex<Option<PidFile>>>) {
+    let Some(path) = config.pid_file.clone() else {
+        return;
+    };
+    let pid_file = Arc::clone(pid_file);
+    std::thread::spawn(move || match PidFile::wait_for_lock(&path) {
+        Ok(mut locked) => {
+            if let Err(e) = locked.write_pid() {
+                log::error!("Failed to write PID file: {}", e);
+            }
+            *pid_file.lock().unwrap() = Some(locked);
+        }
+        Err(e) => log::error!("Failed to take over PID file {}: {}", path.display(), e),
+    });
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -248,11 +418,114 @@ mod tests {
     #[test]
     fn test_server_configuration() {
         let config = Config {
+            config: None,
             port: 8080,
             host: "127.0.0.1".to_string(),
             directory: ".".to_string(),
+            read_only: false,
+            disable_files: false,
+            max_body_mb: 0,
+            cgi_dir: None,
+            cgi_prefix: "/cgi-bin".to_string(),
+            cgi_timeout_secs: 30,
+            fastcgi_addr: None,
+            fastcgi_prefix: "/php".to_string(),
+            fastcgi_root: None,
+            fastcgi_timeout_secs: 60,
+            fastcgi_shadow_addr: None,
+            fastcgi_shadow_percent: 100,
+            fastcgi_canary_addr: None,
+            fastcgi_canary_percent: 5,
+            fastcgi_canary_cookie: None,
+            fastcgi_retries: 0,
+            fastcgi_breaker_percent: 0,
+            fastcgi_breaker_cooldown_secs: 30,
+            response_header: Vec::new(),
+            cors_origins: Vec::new(),
+            cors_methods: vec!["GET".to_string()],
+            cors_headers: Vec::new(),
+            cors_expose_headers: Vec::new(),
+            cors_max_age_secs: None,
+            cors_credentials: false,
+            cors_rule: Vec::new(),
+            kv_dir: None,
+            kv_prefix: "/kv".to_string(),
+            debug_endpoints: false,
+            max_delay_secs: 10,
+            handler_timeout_secs: 0,
+            route_timeout: Vec::new(),
+            max_abandoned_handlers: 64,
+            template_dir: None,
+            wasm_handler: Vec::new(),
+            wasm_middleware: Vec::new(),
             workers: 4,
+            max_workers: 100,
+            runtime: Runtime::Threads,
+            max_queue: 1024,
+            probe_workers: 2,
+            max_uploads: 0,
+            large_upload_kb: 1024,
+            upload_wait_secs: 5,
+            ingress_rate_kb: 0,
+            ingress_rate_total_kb: 0,
+            egress_rate_kb: 0,
+            egress_rate_total_kb: 0,
+            compression_cache_mb: 32,
+            compression_algorithms: vec![
+                http_server::compression::Compression::Brotli,
+                http_server::compression::Compression::Gzip,
+                http_server::compression::Compression::Deflate,
+            ],
+            compression_level: None,
+            disable_compression: false,
+            etag: http_server::conditional::EtagStrategy::Strong,
+            file_digests: Vec::new(),
+            watch_files: false,
+            file_index: false,
+            watch: false,
+            health_min_free_mb: 100,
+            access_log: None,
+            audit_log: None,
+            access_log_format: http_server::access_log::AccessLogFormat::Combined,
+            access_log_sample: 1,
+            log_file: None,
+            log_rotation: http_server::log_file::Rotation::Never,
+            log_max_size_mb: 100,
+            log_keep: 5,
+            slow_request_ms: 1000,
+            trusted_proxies: Vec::new(),
+            allow_ips: Vec::new(),
+            deny_ips: Vec::new(),
+            deny_action: http_server::ip_filter::DenyAction::Forbidden,
+            otlp_endpoint: None,
+            otlp_service_name: "http-server".to_string(),
+            statsd_addr: None,
+            statsd_prefix: "http_server".to_string(),
+            statsd_format: statsd::StatsdFormat::Statsd,
+            statsd_interval_secs: 10,
+            admin_addr: None,
+            admin_token: None,
+            drain_delay_secs: 0,
+            drain_timeout_secs: 10,
+            keep_alive_timeout_secs: 5,
+            max_keep_alive_requests: 100,
+            idle_timeout_secs: 30,
+            daemon: false,
+            pid_file: None,
+            user: None,
+            group: None,
+            acceptors: 1,
+            processes: 0,
+            listen_backlog: 1024,
+            socket_recv_buffer: None,
+            socket_send_buffer: None,
+            tcp_keepalive_secs: 0,
+            tcp_keepalive_interval_secs: 15,
+            tcp_keepalive_retries: 4,
+            socket_linger_secs: None,
+            log_level: None,
             verbose: false,
+            command: None,
         };
 
         assert_eq!(config.server_address(), "127.0.0.1:8080");
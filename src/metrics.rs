@@ -0,0 +1,1016 @@
+use crate::build_info;
+use crate::connections::ConnectionRegistry;
+use crate::platform;
+use crate::throttle::{Throttle, ThrottleStats};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Upper bounds (in seconds) of the latency histogram buckets
+const LATENCY_BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// How many slow requests are kept for the dashboard
+const RECENT_SLOW_REQUESTS: usize = 20;
+
+/// Window over which the recent request and error rates are computed
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Label set for per-route series: (method, route pattern, status class)
+pub type RouteKey = (&'static str, &'static str, &'static str);
+
+/// Fixed-bucket histogram of durations, rendered as a Prometheus histogram
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    /// Record one observation
+    pub fn observe(&mut self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bucket, bound) in self.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+
+    /// Number of observations
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Sum of all observations in seconds
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// Fold another histogram's observations into this one
+    pub fn merge(&mut self, other: &Histogram) {
+        for (bucket, theirs) in self.buckets.iter_mut().zip(other.buckets) {
+            *bucket += theirs;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+
+    /// Remove an earlier snapshot's observations, leaving the delta since then
+    pub fn subtract(&mut self, earlier: &Histogram) {
+        for (bucket, theirs) in self.buckets.iter_mut().zip(earlier.buckets) {
+            *bucket -= theirs;
+        }
+        self.count -= earlier.count;
+        self.sum -= earlier.sum;
+    }
+
+    /// Write the _bucket, _sum, and _count samples for one label set
+    pub fn render(&self, out: &mut String, name: &str, labels: &str) {
+        write_buckets(out, name, labels, &LATENCY_BUCKETS, &self.buckets, self.count, self.sum);
+    }
+
+    /// Observations per bucket, not cumulative, by upper bound in seconds;
+    /// the last bound is infinite
+    pub fn distribution(&self) -> Vec<(f64, u64)> {
+        let cumulative = self.buckets.iter().copied().chain([self.count]);
+        let bounds = LATENCY_BUCKETS.into_iter().chain([f64::INFINITY]);
+        let mut below = 0;
+        bounds
+            .zip(cumulative)
+            .map(|(bound, count)| (bound, count - std::mem::replace(&mut below, count)))
+            .collect()
+    }
+
+    /// JSON form with cumulative bucket counts keyed by upper bound
+    pub fn to_json(self) -> Value {
+        let mut buckets = serde_json::Map::new();
+        for (bucket, bound) in self.buckets.iter().zip(LATENCY_BUCKETS) {
+            buckets.insert(bound.to_string(), json!(bucket));
+        }
+        buckets.insert("+Inf".to_string(), json!(self.count));
+        json!({ "count": self.count, "sum_seconds": self.sum, "buckets": buckets })
+    }
+}
+
+/// Fixed-bucket histogram of sizes or counts, with upper bounds chosen by
+/// the caller, rendered as a Prometheus histogram
+#[derive(Debug, Clone)]
+pub struct ValueHistogram {
+    bounds: &'static [f64],
+    buckets: Vec<u64>,
+    count: u64,
+    sum: f64,
+}
+
+impl ValueHistogram {
+    pub fn new(bounds: &'static [f64]) -> Self {
+        ValueHistogram {
+            bounds,
+            buckets: vec![0; bounds.len()],
+            count: 0,
+            sum: 0.0,
+        }
+    }
+
+    /// Record one observation
+    pub fn observe(&mut self, value: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(self.bounds) {
+            if value <= *bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+
+    /// Number of observations
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Write the _bucket, _sum, and _count samples for one label set
+    pub fn render(&self, out: &mut String, name: &str, labels: &str) {
+        write_buckets(out, name, labels, self.bounds, &self.buckets, self.count, self.sum);
+    }
+
+    /// JSON form with cumulative bucket counts keyed by upper bound
+    pub fn to_json(&self) -> Value {
+        let mut buckets = serde_json::Map::new();
+        for (bucket, bound) in self.buckets.iter().zip(self.bounds) {
+            buckets.insert(bound.to_string(), json!(bucket));
+        }
+        buckets.insert("+Inf".to_string(), json!(self.count));
+        json!({ "count": self.count, "sum": self.sum, "buckets": buckets })
+    }
+}
+
+/// Write the samples of one histogram series; `buckets` are cumulative counts
+/// for `bounds`
+fn write_buckets(out: &mut String, name: &str, labels: &str, bounds: &[f64], buckets: &[u64], count: u64, sum: f64) {
+    let sep = if labels.is_empty() { "" } else { "," };
+    for (bucket, bound) in buckets.iter().zip(bounds) {
+        let _ = writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}", name, labels, sep, bound, bucket);
+    }
+    let _ = writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}", name, labels, sep, count);
+    let braces = |l: &str| if l.is_empty() { String::new() } else { format!("{{{}}}", l) };
+    let _ = writeln!(out, "{}_sum{} {}", name, braces(labels), sum);
+    let _ = writeln!(out, "{}_count{} {}", name, braces(labels), count);
+}
+
+/// Server metrics for monitoring
+pub struct ServerMetrics {
+    pub request_count: AtomicU64,
+    pub error_count: AtomicU64,
+    pub slow_request_count: AtomicU64,
+    pub client_aborts: AtomicU64,
+    pub active_connections: AtomicU64,
+    pub ip_rejections: AtomicU64,
+    pub worker_pool_size: AtomicU64,
+    pub workers_busy: AtomicU64,
+    pub jobs_queued: AtomicU64,
+    /// Connections turned away because the worker queue was full
+    pub jobs_shed: AtomicU64,
+    /// Connections served on the probe lane while the worker pool was saturated
+    pub probe_lane: AtomicU64,
+    /// Large uploads being read or handled
+    pub uploads_active: AtomicU64,
+    /// Large uploads that had to wait for a slot
+    pub uploads_waited: AtomicU64,
+    /// Large uploads refused with 503 after waiting
+    pub uploads_rejected: AtomicU64,
+    /// Request body bandwidth limits and how much they held back
+    pub ingress: Arc<ThrottleStats>,
+    /// Response bandwidth limits and how much they held back
+    pub egress: Arc<ThrottleStats>,
+    /// Open connections, and histograms of the closed ones
+    pub connections: ConnectionRegistry,
+    pub start_time: Instant,
+    queue_wait: Mutex<Histogram>,
+    routes: Mutex<BTreeMap<RouteKey, Histogram>>,
+    methods: Mutex<BTreeMap<&'static str, u64>>,
+    status_codes: Mutex<BTreeMap<u16, u64>>,
+    transfer: Mutex<BTreeMap<&'static str, (u64, u64)>>,
+    handler_timeouts: Mutex<BTreeMap<&'static str, u64>>,
+    slow_requests: Mutex<VecDeque<SlowRequest>>,
+    /// (time, requests, errors) samples over the last [`RATE_WINDOW`]
+    rate_samples: Mutex<VecDeque<(Instant, u64, u64)>>,
+}
+
+/// A request that took at least --slow-request-ms
+#[derive(Debug, Clone)]
+pub struct SlowRequest {
+    /// When it finished, in RFC 3339
+    pub time: String,
+    pub request_line: String,
+    pub client: Option<IpAddr>,
+    pub status: u16,
+    pub duration: Duration,
+}
+
+impl SlowRequest {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "time": self.time,
+            "request": self.request_line,
+            "client": self.client.map(|ip| ip.to_string()),
+            "status": self.status,
+            "duration_ms": self.duration.as_millis() as u64,
+        })
+    }
+}
+
+impl ServerMetrics {
+    pub fn new() -> Self {
+        Self {
+            request_count: AtomicU64::new(0),
+            error_count: AtomicU64::new(0),
+            slow_request_count: AtomicU64::new(0),
+            client_aborts: AtomicU64::new(0),
+            active_connections: AtomicU64::new(0),
+            ip_rejections: AtomicU64::new(0),
+            worker_pool_size: AtomicU64::new(0),
+            workers_busy: AtomicU64::new(0),
+            jobs_queued: AtomicU64::new(0),
+            jobs_shed: AtomicU64::new(0),
+            probe_lane: AtomicU64::new(0),
+            uploads_active: AtomicU64::new(0),
+            uploads_waited: AtomicU64::new(0),
+            uploads_rejected: AtomicU64::new(0),
+            ingress: Arc::default(),
+            egress: Arc::default(),
+            connections: ConnectionRegistry::new(),
+            start_time: Instant::now(),
+            queue_wait: Mutex::new(Histogram::default()),
+            routes: Mutex::new(BTreeMap::new()),
+            methods: Mutex::new(BTreeMap::new()),
+            status_codes: Mutex::new(BTreeMap::new()),
+            transfer: Mutex::new(BTreeMap::new()),
+            handler_timeouts: Mutex::new(BTreeMap::new()),
+            slow_requests: Mutex::new(VecDeque::new()),
+            rate_samples: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn uptime_seconds(&self) -> u64 {
+        self.start_time.elapsed().as_secs()
+    }
+
+    /// Note a connection handed to the worker pool
+    pub fn job_queued(&self) {
+        self.jobs_queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note a worker picking up a queued connection after waiting `waited`
+    pub fn job_started(&self, waited: Duration) {
+        self.jobs_queued.fetch_sub(1, Ordering::Relaxed);
+        self.workers_busy.fetch_add(1, Ordering::Relaxed);
+        self.queue_wait.lock().unwrap().observe(waited);
+    }
+
+    /// Note a worker finishing a connection
+    pub fn job_finished(&self) {
+        self.workers_busy.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// Record a completed request against its (method, route, status class) series
+    pub fn record_request(&self, method: &'static str, route: &'static str, status: u16, duration: Duration) {
+        let mut routes = self.routes.lock().unwrap();
+        routes
+            .entry((method, route, status_class(status)))
+            .or_default()
+            .observe(duration);
+        drop(routes);
+
+        *self.methods.lock().unwrap().entry(method).or_default() += 1;
+    }
+
+    /// Count a response by its exact status code, including responses to unparseable requests
+    pub fn record_status(&self, status: u16) {
+        *self.status_codes.lock().unwrap().entry(status).or_default() += 1;
+    }
+
+    /// Add bytes read from and written to the client for a route
+    pub fn record_transfer(&self, route: &'static str, bytes_in: u64, bytes_out: u64) {
+        let mut transfer = self.transfer.lock().unwrap();
+        let totals = transfer.entry(route).or_default();
+        totals.0 += bytes_in;
+        totals.1 += bytes_out;
+    }
+
+    /// Keep a slow request for the dashboard, forgetting the oldest once
+    /// [`RECENT_SLOW_REQUESTS`] are kept
+    pub fn record_slow_request(&self, slow: SlowRequest) {
+        let mut slow_requests = self.slow_requests.lock().unwrap();
+        if slow_requests.len() == RECENT_SLOW_REQUESTS {
+            slow_requests.pop_front();
+        }
+        slow_requests.push_back(slow);
+    }
+
+    /// The most recent slow requests, newest first
+    pub fn recent_slow_requests(&self) -> Vec<SlowRequest> {
+        self.slow_requests.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// Requests and errors per second over about the last minute
+    ///
+    /// The counters are sampled at most once a second, when this is called,
+    /// so the rates are exact for a caller polling regularly and averaged
+    /// since startup on the first call.
+    pub fn recent_rates(&self) -> (f64, f64) {
+        let now = Instant::now();
+        let requests = self.request_count.load(Ordering::Relaxed);
+        let errors = self.error_count.load(Ordering::Relaxed);
+        let mut samples = self.rate_samples.lock().unwrap();
+        if samples.is_empty() {
+            samples.push_back((self.start_time, 0, 0));
+        }
+        // Keep the newest sample at least a window old as the baseline
+        while samples.len() > 1 && now.duration_since(samples[1].0) >= RATE_WINDOW {
+            samples.pop_front();
+        }
+        let (since, base_requests, base_errors) = samples[0];
+        if samples.back().is_some_and(|(time, ..)| now.duration_since(*time) >= Duration::from_secs(1)) {
+            samples.push_back((now, requests, errors));
+        }
+        let elapsed = now.duration_since(since).as_secs_f64().max(1.0);
+        ((requests - base_requests) as f64 / elapsed, (errors - base_errors) as f64 / elapsed)
+    }
+
+    /// Count a handler abandoned for running past its timeout
+    pub fn record_handler_timeout(&self, route: &'static str) {
+        *self.handler_timeouts.lock().unwrap().entry(route).or_insert(0) += 1;
+    }
+
+    /// Total bytes (read, written) across every route
+    pub fn total_transfer(&self) -> (u64, u64) {
+        let transfer = self.transfer.lock().unwrap();
+        transfer
+            .values()
+            .fold((0, 0), |(read, written), (r, w)| (read + r, written + w))
+    }
+
+    /// Response counts keyed by status code
+    pub fn status_code_counts(&self) -> BTreeMap<String, u64> {
+        let status_codes = self.status_codes.lock().unwrap();
+        status_codes.iter().map(|(code, count)| (code.to_string(), *count)).collect()
+    }
+
+    /// Snapshot of the per-route latency histograms
+    pub fn route_latencies(&self) -> BTreeMap<RouteKey, Histogram> {
+        self.routes.lock().unwrap().clone()
+    }
+
+    /// Latency histogram aggregated over every route
+    pub fn overall_latency(&self) -> Histogram {
+        let routes = self.routes.lock().unwrap();
+        routes.values().fold(Histogram::default(), |mut total, h| {
+            total.merge(h);
+            total
+        })
+    }
+
+    /// Render all metrics in the Prometheus text exposition format
+    pub fn render_prometheus(&self) -> String {
+        let routes = self.routes.lock().unwrap().clone();
+        let mut out = String::new();
+
+        write_header(&mut out, "http_requests_total", "counter", "The total number of HTTP requests");
+        for ((method, route, status), histogram) in &routes {
+            let _ = writeln!(
+                out,
+                "http_requests_total{{method=\"{}\",route=\"{}\",status=\"{}\"}} {}",
+                method,
+                route,
+                status,
+                histogram.count()
+            );
+        }
+        out.push('\n');
+
+        write_header(
+            &mut out,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time spent handling HTTP requests",
+        );
+        for ((method, route, status), histogram) in &routes {
+            let labels = format!("method=\"{}\",route=\"{}\",status=\"{}\"", method, route, status);
+            histogram.render(&mut out, "http_request_duration_seconds", &labels);
+        }
+        out.push('\n');
+
+        write_header(
+            &mut out,
+            "http_requests_by_method_total",
+            "counter",
+            "The total number of HTTP requests by method",
+        );
+        for (method, count) in self.methods.lock().unwrap().iter() {
+            let _ = writeln!(out, "http_requests_by_method_total{{method=\"{}\"}} {}", method, count);
+        }
+        out.push('\n');
+
+        write_header(
+            &mut out,
+            "http_responses_total",
+            "counter",
+            "The total number of HTTP responses by status code",
+        );
+        for (code, count) in self.status_codes.lock().unwrap().iter() {
+            let _ = writeln!(out, "http_responses_total{{code=\"{}\"}} {}", code, count);
+        }
+        out.push('\n');
+
+        let transfer = self.transfer.lock().unwrap().clone();
+        for (name, help, pick) in [
+            (
+                "http_request_bytes_total",
+                "Bytes read from clients, including request lines and headers",
+                (|t: &(u64, u64)| t.0) as fn(&(u64, u64)) -> u64,
+            ),
+            (
+                "http_response_bytes_total",
+                "Bytes written to clients, including status lines and headers",
+                |t: &(u64, u64)| t.1,
+            ),
+        ] {
+            write_header(&mut out, name, "counter", help);
+            for (route, totals) in &transfer {
+                let _ = writeln!(out, "{}{{route=\"{}\"}} {}", name, route, pick(totals));
+            }
+            out.push('\n');
+        }
+
+        write_header(
+            &mut out,
+            "http_handler_timeouts_total",
+            "counter",
+            "Requests answered with 504 because their handler ran past its timeout",
+        );
+        for (route, count) in self.handler_timeouts.lock().unwrap().iter() {
+            let _ = writeln!(out, "http_handler_timeouts_total{{route=\"{}\"}} {}", route, count);
+        }
+        out.push('\n');
+
+        write_metric(
+            &mut out,
+            "http_errors_total",
+            "counter",
+            "The total number of HTTP errors",
+            self.error_count.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_slow_requests_total",
+            "counter",
+            "The total number of requests slower than the slow request threshold",
+            self.slow_request_count.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_client_aborts_total",
+            "counter",
+            "The total number of requests abandoned by the client before the response was sent",
+            self.client_aborts.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_active_connections",
+            "gauge",
+            "Current number of active connections",
+            self.active_connections.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_ip_rejections_total",
+            "counter",
+            "The total number of connections and requests rejected by the IP allow/deny lists",
+            self.ip_rejections.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_worker_pool_size",
+            "gauge",
+            "Number of worker threads in the pool",
+            self.worker_pool_size.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_worker_pool_active",
+            "gauge",
+            "Number of workers currently handling a connection",
+            self.workers_busy.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_worker_pool_queued",
+            "gauge",
+            "Number of connections waiting for a free worker",
+            self.jobs_queued.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_load_shed_total",
+            "counter",
+            "Connections answered with 503 because the worker queue was full",
+            self.jobs_shed.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_probe_lane_connections_total",
+            "counter",
+            "Connections to operational endpoints served on the probe lane while the worker pool was saturated",
+            self.probe_lane.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_large_uploads_active",
+            "gauge",
+            "Request bodies over --large-upload-kb being read or handled",
+            self.uploads_active.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_large_uploads_waited_total",
+            "counter",
+            "Large uploads that waited because --max-uploads were in progress",
+            self.uploads_waited.load(Ordering::Relaxed),
+        );
+        write_metric(
+            &mut out,
+            "http_large_uploads_rejected_total",
+            "counter",
+            "Large uploads answered with 503 because no upload slot freed up in time",
+            self.uploads_rejected.load(Ordering::Relaxed),
+        );
+        self.ingress.render_prometheus(&mut out, "ingress");
+        self.egress.render_prometheus(&mut out, "egress");
+
+        write_header(
+            &mut out,
+            "http_requests_queued_seconds",
+            "histogram",
+            "Time connections spent waiting for a free worker",
+        );
+        self.queue_wait
+            .lock()
+            .unwrap()
+            .render(&mut out, "http_requests_queued_seconds", "");
+        out.push('\n');
+
+        write_metric(
+            &mut out,
+            "http_server_uptime_seconds",
+            "counter",
+            "Server uptime in seconds",
+            self.uptime_seconds(),
+        );
+
+        let process = platform::process_stats();
+        if let Some(bytes) = process.resident_memory_bytes {
+            write_metric(&mut out, "process_resident_memory_bytes", "gauge", "Resident memory size in bytes", bytes);
+        }
+        if let Some(fds) = process.open_fds {
+            write_metric(&mut out, "process_open_fds", "gauge", "Number of open file descriptors", fds);
+        }
+        if let Some(threads) = process.threads {
+            write_metric(&mut out, "process_threads", "gauge", "Number of OS threads in the process", threads);
+        }
+        if let Some(cpu) = process.cpu_seconds {
+            write_header(
+                &mut out,
+                "process_cpu_seconds_total",
+                "counter",
+                "Total user and system CPU time spent in seconds",
+            );
+            let _ = writeln!(out, "process_cpu_seconds_total {}\n", cpu);
+        }
+        #[cfg(feature = "alloc-stats")]
+        write_metric(
+            &mut out,
+            "process_heap_allocations_total",
+            "counter",
+            "Heap allocations made by the process",
+            crate::alloc_stats::allocations(),
+        );
+        self.connections.render_prometheus(&mut out);
+        build_info::render_prometheus(&mut out);
+
+        out
+    }
+}
+
+impl ServerMetrics {
+    /// Render all metrics as a structured JSON document
+    pub fn render_json(&self) -> Value {
+        let routes: Vec<Value> = self
+            .route_latencies()
+            .iter()
+            .map(|((method, route, status), histogram)| {
+                json!({
+                    "method": method,
+                    "route": route,
+                    "status": status,
+                    "duration_seconds": histogram.to_json(),
+                })
+            })
+            .collect();
+        let methods = self.methods.lock().unwrap().clone();
+        let transfer: BTreeMap<&str, Value> = self
+            .transfer
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(route, (read, written))| (*route, json!({ "received": read, "sent": written })))
+            .collect();
+        let (received, sent) = self.total_transfer();
+        let process = platform::process_stats();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        json!({
+            "build": build_info::to_json(),
+            "uptime_seconds": self.uptime_seconds(),
+            "requests": {
+                "total": load(&self.request_count),
+                "errors": load(&self.error_count),
+                "slow": load(&self.slow_request_count),
+                "client_aborts": load(&self.client_aborts),
+                "handler_timeouts": self.handler_timeouts.lock().unwrap().clone(),
+                "by_method": methods,
+                "by_status": self.status_code_counts(),
+                "routes": routes,
+            },
+            "connections": {
+                "active": load(&self.active_connections),
+                "ip_rejections": load(&self.ip_rejections),
+                "closed": self.connections.to_json(),
+            },
+            "worker_pool": {
+                "size": load(&self.worker_pool_size),
+                "active": load(&self.workers_busy),
+                "queued": load(&self.jobs_queued),
+                "shed": load(&self.jobs_shed),
+                "probe_lane": load(&self.probe_lane),
+                "queue_wait_seconds": self.queue_wait.lock().unwrap().to_json(),
+            },
+            "ingress": self.ingress.to_json(),
+            "egress": self.egress.to_json(),
+            "large_uploads": {
+                "active": load(&self.uploads_active),
+                "waited": load(&self.uploads_waited),
+                "rejected": load(&self.uploads_rejected),
+            },
+            "bytes": { "received": received, "sent": sent, "by_route": transfer },
+            "process": {
+                "resident_memory_bytes": process.resident_memory_bytes,
+                "open_fds": process.open_fds,
+                "threads": process.threads,
+                "cpu_seconds": process.cpu_seconds,
+            },
+        })
+    }
+}
+
+impl Default for ServerMetrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Wraps a client connection and counts the bytes read from and written to it,
+/// pacing them when the connection is rate limited
+pub struct CountingStream<S> {
+    inner: S,
+    bytes_read: u64,
+    bytes_written: u64,
+    ingress: Option<Throttle>,
+    egress: Option<Throttle>,
+}
+
+impl<S> CountingStream<S> {
+    pub fn new(inner: S) -> Self {
+        CountingStream {
+            inner,
+            bytes_read: 0,
+            bytes_written: 0,
+            ingress: None,
+            egress: None,
+        }
+    }
+
+    /// Pace reads with `ingress`, if any
+    pub fn throttle_reads(mut self, ingress: Option<Throttle>) -> Self {
+        self.ingress = ingress;
+        self
+    }
+
+    /// A handle on the read throttle, for reading the connection through a
+    /// second handle at the same pace
+    pub fn read_throttle(&self) -> Option<Throttle> {
+        self.ingress.clone()
+    }
+
+    /// Pace writes with `egress`, if any
+    pub fn throttle_writes(mut self, egress: Option<Throttle>) -> Self {
+        self.egress = egress;
+        self
+    }
+
+    /// The wrapped connection
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Bytes read from the connection so far
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    /// Bytes written to the connection so far
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+}
+
+impl<S: Read> Read for CountingStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = match self.ingress.as_mut() {
+            Some(throttle) => {
+                throttle.wait();
+                let len = throttle.limit(buf.len());
+                let n = self.inner.read(&mut buf[..len])?;
+                throttle.charge(n);
+                n
+            }
+            None => self.inner.read(buf)?,
+        };
+        self.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for CountingStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = match self.egress.as_mut() {
+            Some(throttle) => {
+                throttle.wait();
+                let n = self.inner.write(&buf[..throttle.limit(buf.len())])?;
+                throttle.charge(n);
+                n
+            }
+            None => self.inner.write(buf)?,
+        };
+        self.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn write_vectored(&mut self, bufs: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        // A throttled write is one chunk, so one buffer at a time will do
+        if self.egress.is_some() {
+            let buf = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &**buf);
+            return self.write(buf);
+        }
+        let n = self.inner.write_vectored(bufs)?;
+        self.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: tokio::io::AsyncRead + Unpin> tokio::io::AsyncRead for CountingStream<S> {
+    fn poll_read(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        let this = &mut *self;
+        let Some(throttle) = this.ingress.as_mut() else {
+            let before = buf.filled().len();
+            let result = std::pin::Pin::new(&mut this.inner).poll_read(cx, buf);
+            this.bytes_read += (buf.filled().len() - before) as u64;
+            return result;
+        };
+        std::task::ready!(throttle.poll_wait(cx));
+        let len = throttle.limit(buf.remaining());
+        let mut limited = tokio::io::ReadBuf::new(buf.initialize_unfilled_to(len));
+        let result = std::pin::Pin::new(&mut this.inner).poll_read(cx, &mut limited);
+        let n = limited.filled().len();
+        buf.advance(n);
+        throttle.charge(n);
+        this.bytes_read += n as u64;
+        result
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: tokio::io::AsyncWrite + Unpin> tokio::io::AsyncWrite for CountingStream<S> {
+    fn poll_write(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<io::Result<usize>> {
+        let this = &mut *self;
+        let result = match this.egress.as_mut() {
+            Some(throttle) => {
+                std::task::ready!(throttle.poll_wait(cx));
+                let len = throttle.limit(buf.len());
+                let result = std::pin::Pin::new(&mut this.inner).poll_write(cx, &buf[..len]);
+                if let std::task::Poll::Ready(Ok(n)) = result {
+                    throttle.charge(n);
+                }
+                result
+            }
+            None => std::pin::Pin::new(&mut this.inner).poll_write(cx, buf),
+        };
+        if let std::task::Poll::Ready(Ok(n)) = result {
+            this.bytes_written += n as u64;
+        }
+        result
+    }
+
+    fn poll_write_vectored(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> std::task::Poll<io::Result<usize>> {
+        if self.egress.is_some() {
+            let buf = bufs.iter().find(|buf| !buf.is_empty()).map_or(&[][..], |buf| &**buf);
+            return tokio::io::AsyncWrite::poll_write(self, cx, buf);
+        }
+        let result = std::pin::Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        if let std::task::Poll::Ready(Ok(n)) = result {
+            self.bytes_written += n as u64;
+        }
+        result
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.egress.is_none() && self.inner.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: std::pin::Pin<&mut Self>, cx: &mut std::task::Context<'_>) -> std::task::Poll<io::Result<()>> {
+        std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Collapse a status code into its class ("2xx", "4xx", ...) to bound label cardinality
+fn status_class(status: u16) -> &'static str {
+    match status {
+        100..=199 => "1xx",
+        200..=299 => "2xx",
+        300..=399 => "3xx",
+        400..=499 => "4xx",
+        _ => "5xx",
+    }
+}
+
+/// Write the HELP and TYPE lines for a metric family
+pub fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// Write an unlabeled metric family with a single sample
+pub fn write_metric(out: &mut String, name: &str, kind: &str, help: &str, value: u64) {
+    write_header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}\n", name, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_labeled_request_series() {
+        let metrics = ServerMetrics::new();
+        metrics.record_request("GET", "/files/:name", 200, Duration::from_millis(250));
+        metrics.record_request("GET", "/files/:name", 204, Duration::from_millis(250));
+        metrics.record_request("GET", "/files/:name", 404, Duration::from_millis(10));
+
+        let output = metrics.render_prometheus();
+        assert!(output.contains("http_requests_total{method=\"GET\",route=\"/files/:name\",status=\"2xx\"} 2\n"));
+        assert!(output.contains("http_requests_total{method=\"GET\",route=\"/files/:name\",status=\"4xx\"} 1\n"));
+        assert!(output.contains(
+            "http_request_duration_seconds_sum{method=\"GET\",route=\"/files/:name\",status=\"2xx\"} 0.5\n"
+        ));
+    }
+
+    #[test]
+    fn test_build_info_series() {
+        let output = ServerMetrics::new().render_prometheus();
+        let series = format!(
+            "http_server_build_info{{version=\"{}\",git_commit=\"{}\"",
+            build_info::VERSION,
+            build_info::GIT_COMMIT
+        );
+        assert!(output.contains(&series), "{}", output);
+        assert!(output.contains(&format!("rustc_version=\"{}\"}} 1\n", build_info::RUSTC_VERSION)));
+        assert_eq!(ServerMetrics::new().render_json()["build"]["version"], build_info::VERSION);
+    }
+
+    #[test]
+    fn test_status_and_method_counters() {
+        let metrics = ServerMetrics::new();
+        metrics.record_request("GET", "/", 200, Duration::ZERO);
+        metrics.record_request("POST", "/files/:name", 201, Duration::ZERO);
+        metrics.record_request("GET", "unmatched", 404, Duration::ZERO);
+        for status in [200, 201, 404, 400] {
+            metrics.record_status(status);
+        }
+
+        let output = metrics.render_prometheus();
+        assert!(output.contains("http_requests_by_method_total{method=\"GET\"} 2\n"));
+        assert!(output.contains("http_requests_by_method_total{method=\"POST\"} 1\n"));
+        assert!(output.contains("http_responses_total{code=\"400\"} 1\n"));
+        assert!(output.contains("http_responses_total{code=\"404\"} 1\n"));
+    }
+
+    #[test]
+    fn test_transfer_counters() {
+        let metrics = ServerMetrics::new();
+        let mut stream = CountingStream::new(io::Cursor::new(b"GET / HTTP/1.1\r\n\r\n".to_vec()));
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).unwrap();
+        stream.write_all(b"HTTP/1.1 200 OK\r\n\r\n").unwrap();
+        assert_eq!((stream.bytes_read(), stream.bytes_written()), (18, 19));
+
+        metrics.record_transfer("/", stream.bytes_read(), stream.bytes_written());
+        metrics.record_transfer("/files/:name", 100, 5);
+        assert_eq!(metrics.total_transfer(), (118, 24));
+
+        let output = metrics.render_prometheus();
+        assert!(output.contains("http_request_bytes_total{route=\"/files/:name\"} 100\n"));
+        assert!(output.contains("http_response_bytes_total{route=\"/\"} 19\n"));
+    }
+
+    #[test]
+    fn test_worker_pool_gauges() {
+        let metrics = ServerMetrics::new();
+        metrics.job_queued();
+        metrics.job_queued();
+        metrics.job_started(Duration::from_millis(20));
+
+        let output = metrics.render_prometheus();
+        assert!(output.contains("http_worker_pool_active 1\n"));
+        assert!(output.contains("http_worker_pool_queued 1\n"));
+        assert!(output.contains("http_requests_queued_seconds_bucket{le=\"0.025\"} 1\n"));
+        assert!(output.contains("http_requests_queued_seconds_count 1\n"));
+
+        metrics.job_finished();
+        assert_eq!(metrics.workers_busy.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn test_json_document() {
+        let metrics = ServerMetrics::new();
+        metrics.request_count.fetch_add(2, Ordering::Relaxed);
+        metrics.record_request("GET", "/", 200, Duration::from_millis(20));
+        metrics.record_request("POST", "/files/:name", 201, Duration::from_millis(30));
+        metrics.record_status(200);
+        metrics.record_transfer("/", 18, 19);
+        metrics.client_aborts.fetch_add(1, Ordering::Relaxed);
+
+        let doc = metrics.render_json();
+        assert_eq!(doc["requests"]["total"], 2);
+        assert_eq!(doc["requests"]["client_aborts"], 1);
+        assert_eq!(doc["requests"]["by_method"]["POST"], 1);
+        assert_eq!(doc["requests"]["by_status"]["200"], 1);
+        assert_eq!(doc["requests"]["routes"][0]["route"], "/");
+        assert_eq!(doc["requests"]["routes"][0]["duration_seconds"]["buckets"]["0.025"], 1);
+        assert_eq!(doc["bytes"]["by_route"]["/"]["sent"], 19);
+        assert_eq!(doc["bytes"]["sent"], 19);
+    }
+
+    #[test]
+    fn test_histogram_buckets_are_cumulative() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(3));
+        histogram.observe(Duration::from_millis(70));
+        histogram.observe(Duration::from_secs(30));
+
+        let mut out = String::new();
+        histogram.render(&mut out, "latency", "route=\"/\"");
+        assert!(out.contains("latency_bucket{route=\"/\",le=\"0.005\"} 1\n"));
+        assert!(out.contains("latency_bucket{route=\"/\",le=\"0.1\"} 2\n"));
+        assert!(out.contains("latency_bucket{route=\"/\",le=\"10\"} 2\n"));
+        assert!(out.contains("latency_bucket{route=\"/\",le=\"+Inf\"} 3\n"));
+        assert!(out.contains("latency_count{route=\"/\"} 3\n"));
+    }
+}
@@ -0,0 +1,266 @@
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// TCP keepalive probing for idle connections
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    pub idle: Duration,
+    pub interval: Duration,
+    pub retries: u32,
+}
+
+/// Socket tuning applied to the listener and to accepted connections
+#[derive(Debug, Clone)]
+pub struct SocketOptions {
+    pub backlog: i32,
+    pub recv_buffer: Option<usize>,
+    pub send_buffer: Option<usize>,
+    pub keepalive: Option<TcpKeepalive>,
+    pub linger: Option<Duration>,
+}
+
+/// Create a listening socket with SO_REUSEADDR/SO_REUSEPORT, buffer sizes, and
+/// backlog applied before it starts listening
+#[cfg(unix)]
+pub fn bind_listener(addr: &str, options: &SocketOptions) -> io::Result<TcpListener> {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    let addr = resolve(addr)?;
+    let domain = if addr.is_ipv4() { libc::AF_INET } else { libc::AF_INET6 };
+
+    // SAFETY: socket() returns a new descriptor we immediately take ownership of
+    let fd = unsafe { libc::socket(domain, libc::SOCK_STREAM, 0) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+    set_cloexec(fd)?;
+
+    // Allow quick restarts and let several sockets share the port
+    set_int(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR, 1)?;
+    #[cfg(any(target_os = "linux", target_os = "freebsd", target_os = "netbsd", target_os = "openbsd"))]
+    set_int(fd, libc::SOL_SOCKET, libc::SO_REUSEPORT, 1)?;
+
+    // Buffer sizes must be set before listen() to affect the advertised window;
+    // accepted sockets inherit them
+    if let Some(size) = options.recv_buffer {
+        set_int(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, size as libc::c_int)?;
+    }
+    if let Some(size) = options.send_buffer {
+        set_int(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, size as libc::c_int)?;
+    }
+
+    let (storage, len) = sockaddr(&addr);
+    // SAFETY: storage holds a valid sockaddr of `len` bytes
+    unsafe {
+        if libc::bind(fd, &storage as *const _ as *const libc::sockaddr, len) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::listen(fd, options.backlog) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(TcpListener::from(socket))
+}
+
+#[cfg(not(unix))]
+pub fn bind_listener(addr: &str, _options: &SocketOptions) -> io::Result<TcpListener> {
+    // Tuning needs raw socket access; fall back to the platform defaults
+    log::warn!("Socket tuning options are not supported on this platform and are ignored");
+    TcpListener::bind(resolve(addr)?)
+}
+
+/// Whether the kernel load-balances connections across SO_REUSEPORT sockets
+/// bound to the same address
+pub const REUSEPORT_SHARDING: bool = cfg!(target_os = "linux");
+
+/// Make sure there are `count` listeners for `addr`, binding any that are missing
+///
+/// `listeners` holds sockets already open (e.g. inherited during an upgrade).
+/// With SO_REUSEPORT sharding each additional listener is a separate socket with
+/// its own accept queue; elsewhere they are clones of one socket.
+pub fn bind_listeners(
+    addr: &str,
+    options: &SocketOptions,
+    mut listeners: Vec<TcpListener>,
+    count: usize,
+) -> io::Result<Vec<TcpListener>> {
+    if listeners.is_empty() {
+        listeners.push(bind_listener(addr, options)?);
+    }
+    // Bind the extra sockets to the port actually chosen, in case `addr` used port 0
+    let local_addr = listeners[0].local_addr()?.to_string();
+    while listeners.len() < count {
+        let listener = if REUSEPORT_SHARDING {
+            bind_listener(&local_addr, options)?
+        } else {
+            listeners[0].try_clone()?
+        };
+        listeners.push(listener);
+    }
+    Ok(listeners)
+}
+
+/// Apply per-connection options to an accepted socket
+pub fn configure_stream(stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+    // Disable Nagle's algorithm for lower latency
+    stream.set_nodelay(true)?;
+    configure_raw(stream, options)
+}
+
+#[cfg(unix)]
+fn configure_raw(stream: &TcpStream, options: &SocketOptions) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = stream.as_raw_fd();
+    if let Some(keepalive) = options.keepalive {
+        set_int(fd, libc::SOL_SOCKET, libc::SO_KEEPALIVE, 1)?;
+        #[cfg(any(target_os = "macos", target_os = "ios"))]
+        let idle_option = libc::TCP_KEEPALIVE;
+        #[cfg(not(any(target_os = "macos", target_os = "ios")))]
+        let idle_option = libc::TCP_KEEPIDLE;
+        set_int(fd, libc::IPPROTO_TCP, idle_option, keepalive.idle.as_secs().max(1) as libc::c_int)?;
+        set_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPINTVL, keepalive.interval.as_secs().max(1) as libc::c_int)?;
+        set_int(fd, libc::IPPROTO_TCP, libc::TCP_KEEPCNT, keepalive.retries.max(1) as libc::c_int)?;
+    }
+    if let Some(linger) = options.linger {
+        let value = libc::linger {
+            l_onoff: 1,
+            l_linger: linger.as_secs() as libc::c_int,
+        };
+        set_option(fd, libc::SOL_SOCKET, libc::SO_LINGER, &value)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn configure_raw(_stream: &TcpStream, _options: &SocketOptions) -> io::Result<()> {
+    Ok(())
+}
+
+/// Resolve `host:port` to the first matching socket address
+fn resolve(addr: &str) -> io::Result<SocketAddr> {
+    addr.to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("{} did not resolve to an address", addr)))
+}
+
+#[cfg(unix)]
+fn set_int(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    set_option(fd, level, name, &value)
+}
+
+#[cfg(unix)]
+fn set_option<T>(fd: libc::c_int, level: libc::c_int, name: libc::c_int, value: &T) -> io::Result<()> {
+    // SAFETY: `value` points to a live T of the size passed
+    let result = unsafe {
+        libc::setsockopt(
+            fd,
+            level,
+            name,
+            value as *const T as *const libc::c_void,
+            std::mem::size_of::<T>() as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn set_cloexec(fd: libc::c_int) -> io::Result<()> {
+    // SAFETY: fcntl on a descriptor we own
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Convert a socket address into its C representation
+#[cfg(unix)]
+fn sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+    // SAFETY: an all-zero sockaddr_storage is a valid value
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(v4) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for sockaddr_in
+            let sin = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in) };
+            sin.sin_family = libc::AF_INET as libc::sa_family_t;
+            sin.sin_port = v4.port().to_be();
+            sin.sin_addr = libc::in_addr {
+                s_addr: u32::from_ne_bytes(v4.ip().octets()),
+            };
+            std::mem::size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v6) => {
+            // SAFETY: sockaddr_storage is large and aligned enough for sockaddr_in6
+            let sin6 = unsafe { &mut *(&mut storage as *mut _ as *mut libc::sockaddr_in6) };
+            sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+            sin6.sin6_port = v6.port().to_be();
+            sin6.sin6_addr = libc::in6_addr { s6_addr: v6.ip().octets() };
+            sin6.sin6_flowinfo = v6.flowinfo();
+            sin6.sin6_scope_id = v6.scope_id();
+            std::mem::size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (storage, len as libc::socklen_t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    fn get_int(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        unsafe { libc::getsockopt(fd, level, name, &mut value as *mut _ as *mut libc::c_void, &mut len) };
+        value
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_tuned_listener_and_stream() {
+        use std::os::fd::AsRawFd;
+
+        let options = SocketOptions {
+            backlog: 16,
+            recv_buffer: Some(64 * 1024),
+            send_buffer: None,
+            keepalive: Some(TcpKeepalive {
+                idle: Duration::from_secs(30),
+                interval: Duration::from_secs(5),
+                retries: 3,
+            }),
+            linger: Some(Duration::from_secs(2)),
+        };
+        let listener = bind_listener("127.0.0.1:0", &options).unwrap();
+        let fd = listener.as_raw_fd();
+        assert_eq!(get_int(fd, libc::SOL_SOCKET, libc::SO_REUSEADDR), 1);
+        assert!(get_int(fd, libc::SOL_SOCKET, libc::SO_RCVBUF) >= 64 * 1024);
+
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        configure_stream(&stream, &options).unwrap();
+        assert_eq!(get_int(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_KEEPALIVE), 1);
+        assert_eq!(get_int(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_KEEPCNT), 3);
+        drop(client);
+    }
+
+    #[test]
+    fn test_bind_listeners_share_port() {
+        let options = SocketOptions {
+            backlog: 16,
+            recv_buffer: None,
+            send_buffer: None,
+            keepalive: None,
+            linger: None,
+        };
+        let listeners = bind_listeners("127.0.0.1:0", &options, Vec::new(), 3).unwrap();
+        assert_eq!(listeners.len(), 3);
+        let port = listeners[0].local_addr().unwrap().port();
+        assert!(listeners.iter().all(|l| l.local_addr().unwrap().port() == port));
+    }
+}
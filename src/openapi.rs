@@ -0,0 +1,146 @@
+//! OpenAPI documents for registered routes
+//!
+//! `GET /openapi.json` describes the routes registered on the router as an
+//! OpenAPI 3 document, for client generators and API explorers. Paths,
+//! methods, and path parameters come from the route patterns, a parameter's
+//! regex constraint becoming its `pattern`. Handlers made with
+//! [`extract::handler`](crate::extract::handler) add what their arguments
+//! tell: a JSON request body for [`Json`](crate::extract::Json), and the
+//! errors extraction can answer with. Anything else, such as summaries and
+//! response types, is added by wrapping the handler in [`describe`].
+
+use crate::error::Result;
+use crate::request::{HttpMethod, HttpRequest};
+use crate::response::HttpResponse;
+use crate::router::Handler;
+use serde_json::{json, Map, Value};
+
+/// Version of the specification the documents follow
+pub const OPENAPI_VERSION: &str = "3.0.3";
+
+/// Add the fields of `operation`, such as a `summary` or `responses`, to the
+/// OpenAPI operation of the routes `handler` is registered for
+///
+/// Objects are merged into what the handler describes itself with; other
+/// values replace it.
+pub fn describe(handler: impl Handler, operation: Value) -> impl Handler {
+    Described { handler, operation }
+}
+
+struct Described<H> {
+    handler: H,
+    operation: Value,
+}
+
+impl<H: Handler> Handler for Described<H> {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        self.handler.call(request)
+    }
+
+    fn streams_body(&self) -> bool {
+        self.handler.streams_body()
+    }
+
+    fn describe(&self, operation: &mut Value) {
+        self.handler.describe(operation);
+        merge(operation, &self.operation);
+    }
+}
+
+/// Merge `patch` into `target`, object by object
+fn merge(target: &mut Value, patch: &Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(target.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (target, patch) => *target = patch.clone(),
+    }
+}
+
+/// Add an error response to `operation`, described as a problem document
+pub fn error_response(operation: &mut Value, status: u16, description: &str) {
+    let response = json!({
+        "description": description,
+        "content": { "application/problem+json": { "schema": { "type": "object" } } },
+    });
+    operation["responses"][status.to_string()] = response;
+}
+
+/// A route pattern as an OpenAPI path, with its parameters: `{name:regex}`
+/// and `*name` become `{name}`
+fn path_template(pattern: &str) -> (String, Vec<Value>) {
+    let mut parameters = Vec::new();
+    let segments: Vec<String> = pattern
+        .split('/')
+        .map(|segment| {
+            let (name, schema) = if let Some(param) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+                match param.split_once(':') {
+                    Some((name, regex)) => (name, json!({ "type": "string", "pattern": format!("^(?:{})$", regex) })),
+                    None => (param, json!({ "type": "string" })),
+                }
+            } else if let Some(name) = segment.strip_prefix('*') {
+                (name, json!({ "type": "string", "description": "The rest of the path" }))
+            } else {
+                return segment.to_string();
+            };
+            parameters.push(json!({ "name": name, "in": "path", "required": true, "schema": schema }));
+            format!("{{{}}}", name)
+        })
+        .collect();
+    (segments.join("/"), parameters)
+}
+
+/// An operation id unique to the method and path, e.g. `get_users_id`
+fn operation_id(method: &HttpMethod, path: &str) -> String {
+    let words = path.split(|c: char| !c.is_ascii_alphanumeric()).filter(|word| !word.is_empty());
+    std::iter::once(method.as_str().to_ascii_lowercase()).chain(words.map(str::to_string)).collect::<Vec<_>>().join("_")
+}
+
+/// The OpenAPI document for routes, each a method, a pattern, and its
+/// handler; the first route registered for a method and path describes it
+pub fn document<'a>(title: &str, routes: impl IntoIterator<Item = (&'a HttpMethod, &'a str, &'a dyn Handler)>) -> Value {
+    let mut paths = Map::new();
+    for (method, pattern, handler) in routes {
+        let (path, parameters) = path_template(pattern);
+        let item = paths.entry(path.clone()).or_insert_with(|| json!({}));
+        let key = method.as_str().to_ascii_lowercase();
+        if item.get(&key).is_some() {
+            continue;
+        }
+        let mut operation = json!({
+            "operationId": operation_id(method, &path),
+            "responses": { "default": { "description": "The handler's response" } },
+        });
+        if !parameters.is_empty() {
+            operation["parameters"] = Value::Array(parameters);
+        }
+        handler.describe(&mut operation);
+        item[key] = operation;
+    }
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": { "title": title, "version": crate::build_info::VERSION },
+        "paths": paths,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_patterns_become_path_templates() {
+        let (path, parameters) = path_template("/users/{id:[0-9]+}/files/*path");
+        assert_eq!(path, "/users/{id}/files/{path}");
+        assert_eq!(parameters[0]["schema"]["pattern"], "^(?:[0-9]+)$");
+        assert_eq!(parameters[1]["name"], "path");
+        assert_eq!(operation_id(&HttpMethod::DELETE, &path), "delete_users_id_files_path");
+
+        let mut operation = json!({ "responses": { "default": { "description": "x" } } });
+        merge(&mut operation, &json!({ "summary": "Get", "responses": { "200": { "description": "ok" } } }));
+        assert_eq!(operation["summary"], "Get");
+        assert_eq!(operation["responses"].as_object().unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,256 @@
+/// Resource usage of the server process; fields are `None` where the platform can't report them
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ProcessStats {
+    pub resident_memory_bytes: Option<u64>,
+    pub open_fds: Option<u64>,
+    pub threads: Option<u64>,
+    pub cpu_seconds: Option<f64>,
+}
+
+/// Collect resource usage for the current process
+pub fn process_stats() -> ProcessStats {
+    ProcessStats {
+        resident_memory_bytes: resident_memory_bytes(),
+        open_fds: open_fds(),
+        threads: threads(),
+        cpu_seconds: cpu_seconds(),
+    }
+}
+
+/// Read a `Key:   value` field from /proc/self/status
+#[cfg(target_os = "linux")]
+fn proc_status_field(key: &str) -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix(key)?.strip_prefix(':'))
+        .and_then(|value| value.split_whitespace().next()?.parse().ok())
+}
+
+#[cfg(target_os = "linux")]
+fn resident_memory_bytes() -> Option<u64> {
+    proc_status_field("VmRSS").map(|kb| kb * 1024)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_memory_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn threads() -> Option<u64> {
+    proc_status_field("Threads")
+}
+
+#[cfg(not(target_os = "linux"))]
+fn threads() -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn open_fds() -> Option<u64> {
+    // Both Linux (/proc/self/fd) and the BSDs/macOS (/dev/fd) list open descriptors;
+    // the count includes the descriptor used to read the directory itself
+    let dir = if cfg!(target_os = "linux") { "/proc/self/fd" } else { "/dev/fd" };
+    let count = std::fs::read_dir(dir).ok()?.count() as u64;
+    Some(count.saturating_sub(1))
+}
+
+#[cfg(not(unix))]
+fn open_fds() -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn cpu_seconds() -> Option<f64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::uninit();
+    // SAFETY: getrusage fully initializes the struct when it returns 0
+    let usage = unsafe {
+        if libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) != 0 {
+            return None;
+        }
+        usage.assume_init()
+    };
+    let seconds = |tv: libc::timeval| tv.tv_sec as f64 + tv.tv_usec as f64 / 1_000_000.0;
+    Some(seconds(usage.ru_utime) + seconds(usage.ru_stime))
+}
+
+#[cfg(not(unix))]
+fn cpu_seconds() -> Option<f64> {
+    None
+}
+
+/// Signals the server reacts to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    /// SIGINT, SIGTERM, or SIGQUIT (Ctrl-C elsewhere): shut down gracefully
+    Terminate,
+    /// SIGHUP: reload configuration
+    Hangup,
+    /// SIGUSR2: hand the listener to a freshly started binary
+    Upgrade,
+}
+
+#[cfg(unix)]
+mod signals {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    pub static RECEIVED: [AtomicBool; 3] = [AtomicBool::new(false), AtomicBool::new(false), AtomicBool::new(false)];
+
+    // Only async-signal-safe work happens in the handlers themselves
+    pub extern "C" fn on_hangup(_signal: libc::c_int) {
+        RECEIVED[0].store(true, Ordering::SeqCst);
+    }
+
+    pub extern "C" fn on_upgrade(_signal: libc::c_int) {
+        RECEIVED[1].store(true, Ordering::SeqCst);
+    }
+
+    pub extern "C" fn on_terminate(_signal: libc::c_int) {
+        RECEIVED[2].store(true, Ordering::SeqCst);
+    }
+}
+
+/// Run `callback` on a background thread each time the process receives `signal`
+#[cfg(unix)]
+pub fn on_signal(signal: Signal, callback: impl Fn() + Send + 'static) -> std::io::Result<()> {
+    use std::sync::atomic::Ordering;
+
+    let (numbers, index, handler): (&[_], _, extern "C" fn(libc::c_int)) = match signal {
+        Signal::Terminate => (&[libc::SIGINT, libc::SIGTERM, libc::SIGQUIT], 2, signals::on_terminate),
+        Signal::Hangup => (&[libc::SIGHUP], 0, signals::on_hangup),
+        Signal::Upgrade => (&[libc::SIGUSR2], 1, signals::on_upgrade),
+    };
+
+    for &number in numbers {
+        // SAFETY: the handler only stores to an atomic
+        if unsafe { libc::signal(number, handler as libc::sighandler_t) } == libc::SIG_ERR {
+            return Err(std::io::Error::last_os_error());
+        }
+    }
+
+    std::thread::Builder::new()
+        .name(format!("signal-{:?}", signal).to_lowercase())
+        .spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_millis(250));
+            if signals::RECEIVED[index].swap(false, Ordering::SeqCst) {
+                callback();
+            }
+        })?;
+    Ok(())
+}
+
+/// Only Ctrl-C is delivered on other platforms
+#[cfg(not(unix))]
+pub fn on_signal(signal: Signal, callback: impl Fn() + Send + 'static) -> std::io::Result<()> {
+    match signal {
+        Signal::Terminate => ctrlc::set_handler(callback).map_err(std::io::Error::other),
+        Signal::Hangup | Signal::Upgrade => Ok(()),
+    }
+}
+
+/// Wait up to `timeout` for a connection to be ready to accept; returns false on timeout
+#[cfg(unix)]
+pub fn wait_for_connection(listener: &std::net::TcpListener, timeout: std::time::Duration) -> std::io::Result<bool> {
+    use std::os::fd::AsRawFd;
+
+    let mut pollfd = libc::pollfd {
+        fd: listener.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: pollfd is a valid, initialized array of length 1
+    match unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int) } {
+        -1 => {
+            let err = std::io::Error::last_os_error();
+            // A signal arriving mid-poll is not an error; just report a timeout
+            if err.kind() == std::io::ErrorKind::Interrupted {
+                Ok(false)
+            } else {
+                Err(err)
+            }
+        }
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn wait_for_connection(_listener: &std::net::TcpListener, timeout: std::time::Duration) -> std::io::Result<bool> {
+    // Without poll, back off briefly and let the non-blocking accept decide
+    std::thread::sleep(timeout.min(std::time::Duration::from_millis(10)));
+    Ok(true)
+}
+
+/// Whether the current user may write to `path`
+#[cfg(unix)]
+pub fn is_writable(path: &std::path::Path) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // SAFETY: c_path is a valid NUL-terminated string
+    unsafe { libc::access(c_path.as_ptr(), libc::W_OK) == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_writable(path: &std::path::Path) -> bool {
+    std::fs::metadata(path).is_ok_and(|metadata| !metadata.permissions().readonly())
+}
+
+/// Bytes available to unprivileged users on the filesystem holding `path`
+#[cfg(unix)]
+pub fn available_space(path: &std::path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stats = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: c_path is a valid NUL-terminated string, and statvfs fully
+    // initializes the struct when it returns 0
+    let stats = unsafe {
+        if libc::statvfs(c_path.as_ptr(), stats.as_mut_ptr()) != 0 {
+            return None;
+        }
+        stats.assume_init()
+    };
+    #[allow(clippy::unnecessary_cast)]
+    Some(stats.f_bavail as u64 * stats.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &std::path::Path) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_process_stats_on_linux() {
+        let stats = process_stats();
+        assert!(stats.resident_memory_bytes.unwrap() > 0);
+        assert!(stats.threads.unwrap() >= 1);
+        assert!(stats.open_fds.unwrap() >= 3);
+        assert!(stats.cpu_seconds.unwrap() >= 0.0);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_available_space() {
+        assert!(available_space(std::path::Path::new(".")).unwrap() > 0);
+        assert_eq!(available_space(std::path::Path::new("/no/such/directory")), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_sigquit_terminates_like_sigterm() {
+        let (received, receiver) = std::sync::mpsc::channel();
+        on_signal(Signal::Terminate, move || received.send(()).unwrap()).unwrap();
+        // SAFETY: the handler installed above only stores to an atomic
+        unsafe { libc::raise(libc::SIGQUIT) };
+        receiver.recv_timeout(std::time::Duration::from_secs(5)).unwrap();
+    }
+}
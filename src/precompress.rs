@@ -0,0 +1,200 @@
+//! `http-server precompress`: write compressed copies of static assets ahead
+//! of time
+//!
+//! Each compressible file gets sidecars such as `app.js.gz` and `app.js.br`
+//! at the highest compression level, stamped with the file's modification
+//! time. File downloads serve a sidecar whose time still matches instead of
+//! compressing the file, so even the first request costs no CPU and gets the
+//! smallest body. Sidecars that would not be smaller than the file are not
+//! written.
+
+use crate::compression::{self, Compression};
+use crate::router::Router;
+use clap::Args;
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Options for the `precompress` subcommand
+#[derive(Args, Debug, Clone)]
+pub struct PrecompressArgs {
+    /// Directory to walk, including its subdirectories
+    #[arg(long, short, default_value = ".")]
+    pub directory: PathBuf,
+
+    /// Sidecars to write: gzip (.gz) and br (.br)
+    #[arg(long, value_enum, default_value = "gzip,br", value_delimiter = ',')]
+    pub algorithms: Vec<Compression>,
+
+    /// Skip files smaller than this many bytes, which are not worth compressing
+    #[arg(long, default_value = "256")]
+    pub min_size: u64,
+
+    /// Rewrite sidecars that are already up to date
+    #[arg(long, default_value = "false")]
+    pub force: bool,
+}
+
+/// What a run did
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub written: usize,
+    pub up_to_date: usize,
+    /// Sidecars left out because compression did not make the file smaller
+    pub not_smaller: usize,
+}
+
+/// The file extension of an algorithm's sidecars; deflate has no common one
+pub fn sidecar_extension(compression: Compression) -> Option<&'static str> {
+    match compression {
+        Compression::Gzip => Some("gz"),
+        Compression::Brotli => Some("br"),
+        Compression::Deflate | Compression::None => None,
+    }
+}
+
+/// `path` with the sidecar extension of `compression` appended
+pub fn sidecar_path(path: &Path, compression: Compression) -> Option<PathBuf> {
+    let extension = sidecar_extension(compression)?;
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".");
+    sidecar.push(extension);
+    Some(PathBuf::from(sidecar))
+}
+
+/// The sidecar of `path` for `compression`, if one was written for the
+/// version of the file modified at `modified`
+pub fn read_sidecar(path: &Path, modified: SystemTime, compression: Compression) -> Option<Vec<u8>> {
+    let sidecar = sidecar_path(path, compression)?;
+    let file = File::open(&sidecar).ok()?;
+    if file.metadata().and_then(|metadata| metadata.modified()).ok()? != modified {
+        return None;
+    }
+    fs::read(&sidecar).ok()
+}
+
+/// Compress the files under `args.directory`; returns the process exit code
+pub fn run(args: &PrecompressArgs) -> i32 {
+    if let Some(algorithm) = args.algorithms.iter().find(|algorithm| sidecar_extension(**algorithm).is_none()) {
+        eprintln!("error: {} has no sidecar format; use gzip or br", algorithm.name());
+        return 2;
+    }
+    match precompress(args) {
+        Ok(summary) => {
+            println!(
+                "{}: {} sidecar(s) written, {} up to date, {} skipped as not smaller",
+                args.directory.display(),
+                summary.written,
+                summary.up_to_date,
+                summary.not_smaller
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            1
+        }
+    }
+}
+
+pub fn precompress(args: &PrecompressArgs) -> io::Result<Summary> {
+    let mut summary = Summary::default();
+    let mut files = Vec::new();
+    collect_files(&args.directory, &mut files)?;
+    for path in files {
+        let name = path.file_name().and_then(|name| name.to_str()).unwrap_or_default();
+        let is_sidecar = name.ends_with(".gz") || name.ends_with(".br");
+        if is_sidecar || !compression::is_compressible(Router::guess_content_type(name)) {
+            continue;
+        }
+        let metadata = fs::metadata(&path)?;
+        if metadata.len() < args.min_size {
+            continue;
+        }
+        let modified = metadata.modified()?;
+
+        let mut content = None;
+        for &algorithm in &args.algorithms {
+            let Some(sidecar) = sidecar_path(&path, algorithm) else { continue };
+            let current = fs::metadata(&sidecar).and_then(|metadata| metadata.modified()).ok() == Some(modified);
+            if current && !args.force {
+                summary.up_to_date += 1;
+                continue;
+            }
+            if content.is_none() {
+                content = Some(fs::read(&path)?);
+            }
+            let content = content.as_deref().unwrap_or_default();
+            let level = match algorithm {
+                Compression::Brotli => 11,
+                _ => 9,
+            };
+            let compressed = algorithm.compress_level(content, Some(level)).map_err(io::Error::other)?;
+            if compressed.len() >= content.len() {
+                // A stale sidecar would not be served anyway, but is confusing
+                let _ = fs::remove_file(&sidecar);
+                summary.not_smaller += 1;
+                continue;
+            }
+            fs::write(&sidecar, &compressed)?;
+            File::options().write(true).open(&sidecar)?.set_modified(modified)?;
+            summary.written += 1;
+        }
+    }
+    Ok(summary)
+}
+
+/// Every regular file under `directory`, skipping hidden entries
+fn collect_files(directory: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(directory)? {
+        let entry = entry?;
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            collect_files(&entry.path(), files)?;
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sidecars_are_written_once_per_version() {
+        let dir = std::env::temp_dir().join(format!("http-server-precompress-{}", std::process::id()));
+        fs::create_dir_all(dir.join("css")).unwrap();
+        fs::write(dir.join("app.js"), "console.log('hello');\n".repeat(50)).unwrap();
+        fs::write(dir.join("css/site.css"), "body { color: red }\n".repeat(50)).unwrap();
+        fs::write(dir.join("tiny.txt"), "hi").unwrap();
+        fs::write(dir.join("logo.png"), vec![0; 1000]).unwrap();
+        let args = PrecompressArgs {
+            directory: dir.clone(),
+            algorithms: vec![Compression::Gzip, Compression::Brotli],
+            min_size: 256,
+            force: false,
+        };
+
+        assert_eq!(precompress(&args).unwrap(), Summary { written: 4, up_to_date: 0, not_smaller: 0 });
+        assert!(dir.join("css/site.css.br").exists());
+        assert!(!dir.join("tiny.txt.gz").exists() && !dir.join("logo.png.gz").exists());
+        let modified = fs::metadata(dir.join("app.js")).unwrap().modified().unwrap();
+        let gzipped = read_sidecar(&dir.join("app.js"), modified, Compression::Gzip).unwrap();
+        let expected = Compression::Gzip.compress_level("console.log('hello');\n".repeat(50).as_bytes(), Some(9));
+        assert_eq!(gzipped, expected.unwrap());
+
+        assert_eq!(precompress(&args).unwrap(), Summary { written: 0, up_to_date: 4, not_smaller: 0 });
+        // A changed file no longer matches its sidecars until they are rewritten
+        let later = modified + std::time::Duration::from_secs(10);
+        File::options().write(true).open(dir.join("app.js")).unwrap().set_modified(later).unwrap();
+        assert_eq!(read_sidecar(&dir.join("app.js"), later, Compression::Gzip), None);
+        assert_eq!(precompress(&args).unwrap(), Summary { written: 2, up_to_date: 2, not_smaller: 0 });
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+//! RFC 9457 (formerly RFC 7807) problem details for error responses
+//!
+//! Errors go out as `application/problem+json` by default. Clients that only
+//! accept `application/json`, HTML, or plain text get the same fields in that
+//! form instead, so a browser shows a readable page and curl a readable line.
+
+use crate::error::ServerError;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::status::StatusCode;
+use crate::template;
+use serde::Serialize;
+
+/// Media types an error can be rendered as, in order of preference
+const OFFERED: [&str; 4] = ["application/problem+json", "application/json", "text/html", "text/plain"];
+
+/// Problem details for one failed request
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    /// URI identifying the problem type; `about:blank` means the status
+    /// code says it all
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    /// The request path the problem occurred on
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    /// Stable machine-readable error code, e.g. `invalid_request`
+    pub code: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// The fields of the request that caused the problem
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldError>,
+}
+
+/// One invalid field of a request body
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FieldError {
+    /// JSON pointer to the field, e.g. `/items/0/name`
+    pub pointer: String,
+    pub detail: String,
+}
+
+impl Problem {
+    pub fn new(status: StatusCode, code: impl Into<String>, detail: impl Into<String>) -> Self {
+        Problem {
+            problem_type: "about:blank".to_string(),
+            title: status.reason_phrase().to_string(),
+            status: status.as_u16(),
+            detail: detail.into(),
+            instance: None,
+            code: code.into(),
+            request_id: None,
+            errors: Vec::new(),
+        }
+    }
+
+    /// Set the path the problem occurred on
+    pub fn instance(mut self, instance: impl Into<String>) -> Self {
+        self.instance = Some(instance.into());
+        self
+    }
+
+    /// Tag the problem with the request's ID
+    pub fn request_id(mut self, request_id: impl Into<String>) -> Self {
+        self.request_id = Some(request_id.into());
+        self
+    }
+
+    /// List the fields that caused the problem
+    pub fn errors(mut self, errors: Vec<FieldError>) -> Self {
+        self.errors = errors;
+        self
+    }
+
+    /// Render in the format the request's Accept header prefers; requests
+    /// that could not be parsed, or accept none of the formats, get
+    /// problem+json and plain text respectively
+    pub fn to_response(&self, request: Option<&HttpRequest>) -> HttpResponse {
+        let media_type = match request {
+            Some(request) => request.negotiate(&OFFERED).unwrap_or("text/plain"),
+            None => OFFERED[0],
+        };
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::InternalServerError);
+        let response = HttpResponse::new(status).vary("Accept");
+
+        match media_type {
+            "text/html" => response.html(self.to_html()),
+            "text/plain" => response.text(self.to_text()),
+            json_type => {
+                let body = serde_json::to_vec(self).expect("problem details always serialize");
+                response.header("Content-Type", json_type).body(body)
+            }
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut text = format!("{} {}: {}\n", self.status, self.title, self.detail);
+        for error in &self.errors {
+            text.push_str(&format!("  {}: {}\n", error.pointer, error.detail));
+        }
+        if let Some(request_id) = &self.request_id {
+            text.push_str(&format!("Request ID: {}\n", request_id));
+        }
+        text
+    }
+
+    fn to_html(&self) -> String {
+        let context = serde_json::to_value(self).expect("problem details always serialize");
+        template::current().error.render(&context)
+    }
+}
+
+impl From<&ServerError> for Problem {
+    fn from(e: &ServerError) -> Self {
+        Problem::new(e.status(), e.code(), e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(accept: Option<&str>) -> HttpRequest {
+        let mut raw = "GET /files/missing.txt HTTP/1.1\r\n".to_string();
+        if let Some(accept) = accept {
+            raw.push_str(&format!("Accept: {}\r\n", accept));
+        }
+        raw.push_str("\r\n");
+        HttpRequest::parse(&mut raw.as_bytes()).unwrap()
+    }
+
+    fn render(accept: Option<&str>) -> (Option<String>, String) {
+        let problem = Problem::from(&ServerError::FileNotFound("missing.txt".to_string()))
+            .instance("/files/missing.txt")
+            .request_id("req-1");
+        let response = problem.to_response(Some(&request(accept)));
+        assert_eq!(response.status(), StatusCode::NotFound);
+        let content_type = response.get_header("Content-Type").map(str::to_string);
+        let mut raw = Vec::new();
+        response.write_to(&mut raw).unwrap();
+        let raw = String::from_utf8(raw).unwrap();
+        (content_type, raw.split_once("\r\n\r\n").unwrap().1.to_string())
+    }
+
+    #[test]
+    fn test_problem_formats_follow_accept() {
+        let (content_type, body) = render(None);
+        assert_eq!(content_type.as_deref(), Some("application/problem+json"));
+        let json: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(json["type"], "about:blank");
+        assert_eq!(json["title"], "Not Found");
+        assert_eq!(json["status"], 404);
+        assert_eq!(json["detail"], "File not found: missing.txt");
+        assert_eq!(json["instance"], "/files/missing.txt");
+        assert_eq!(json["code"], "file_not_found");
+        assert_eq!(json["request_id"], "req-1");
+
+        assert_eq!(render(Some("application/json")).0.as_deref(), Some("application/json"));
+
+        let (content_type, body) = render(Some("text/html,application/xhtml+xml;q=0.9,*/*;q=0.8"));
+        assert_eq!(content_type.as_deref(), Some("text/html"));
+        assert!(body.contains("<h1>404 Not Found</h1>"));
+
+        let (content_type, body) = render(Some("image/png"));
+        assert_eq!(content_type.as_deref(), Some("text/plain"));
+        assert_eq!(body, "404 Not Found: File not found: missing.txt\nRequest ID: req-1\n");
+    }
+
+    #[test]
+    fn test_html_is_escaped() {
+        let problem = Problem::new(StatusCode::BadRequest, "invalid_request", "<script>alert('x')</script>");
+        assert!(problem.to_html().contains("&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;"));
+    }
+}
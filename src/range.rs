@@ -0,0 +1,174 @@
+//! Byte range requests (RFC 9110 section 14)
+//!
+//! A `Range: bytes=0-499` request gets `206 Partial Content` with just those
+//! bytes; several ranges, e.g. `bytes=0-99,1000-1099`, come back as a
+//! `multipart/byteranges` body with one part per range. Ranges apply to the
+//! unencoded file, so a range response is never compressed.
+
+use crate::response::HttpResponse;
+use crate::status::StatusCode;
+
+/// More ranges than this in one request are ignored and the whole file is
+/// sent, so a request cannot split a response into countless tiny parts
+pub const MAX_RANGES: usize = 32;
+
+/// An inclusive range of byte offsets within a representation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub start: u64,
+    pub end: u64,
+}
+
+impl ByteRange {
+    /// The `Content-Range` value for this range of a `total`-byte representation
+    pub fn content_range(&self, total: u64) -> String {
+        format!("bytes {}-{}/{}", self.start, self.end, total)
+    }
+}
+
+/// What a `Range` header asks of a representation
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RangeRequest {
+    /// Send these ranges, in the order requested
+    Ranges(Vec<ByteRange>),
+    /// None of the ranges overlap the representation: 416
+    Unsatisfiable,
+}
+
+/// Resolve a `Range` header against a representation of `len` bytes
+///
+/// Returns `None` when the header should be ignored and the full
+/// representation sent: a unit other than `bytes`, invalid syntax, more
+/// than [`MAX_RANGES`] ranges, or ranges that add up to more bytes than the
+/// representation has. The last keeps overlapping ranges such as
+/// `bytes=0-,0-` from multiplying the size of the response (RFC 9110 section
+/// 14.3 lets a server ignore them).
+pub fn parse(header: &str, len: u64) -> Option<RangeRequest> {
+    let (unit, specs) = header.split_once('=')?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return None;
+    }
+
+    let mut ranges = Vec::new();
+    let mut count = 0;
+    for spec in specs.split(',').map(str::trim).filter(|spec| !spec.is_empty()) {
+        count += 1;
+        if count > MAX_RANGES {
+            return None;
+        }
+        let (first, last) = spec.split_once('-')?;
+        let number = |s: &str| -> Option<Option<u64>> {
+            match s.trim() {
+                "" => Some(None),
+                s if s.bytes().all(|b| b.is_ascii_digit()) => s.parse().ok().map(Some),
+                _ => None,
+            }
+        };
+        let range = match (number(first)?, number(last)?) {
+            // bytes=500-999, clamped to the end
+            (Some(start), Some(end)) if start <= end => (start < len).then(|| ByteRange {
+                start,
+                end: end.min(len - 1),
+            }),
+            (Some(_), Some(_)) => return None,
+            // bytes=500-
+            (Some(start), None) => (start < len).then(|| ByteRange { start, end: len - 1 }),
+            // bytes=-500, the last 500 bytes
+            (None, Some(suffix)) => (suffix > 0 && len > 0).then(|| ByteRange {
+                start: len.saturating_sub(suffix),
+                end: len - 1,
+            }),
+            (None, None) => return None,
+        };
+        ranges.extend(range);
+    }
+
+    let requested = ranges.iter().fold(0u64, |sum, range| sum.saturating_add(range.end - range.start + 1));
+    match count {
+        0 => None,
+        _ if requested > len => None,
+        _ if ranges.is_empty() => Some(RangeRequest::Unsatisfiable),
+        _ => Some(RangeRequest::Ranges(ranges)),
+    }
+}
+
+/// A 206 response carrying `ranges` of `content`: the bytes themselves for
+/// one range, or a `multipart/byteranges` body for several
+pub fn partial_response(content: &[u8], content_type: &str, ranges: &[ByteRange]) -> HttpResponse {
+    let total = content.len() as u64;
+    let slice = |range: &ByteRange| &content[range.start as usize..=range.end as usize];
+    if let [range] = ranges {
+        return HttpResponse::new(StatusCode::PartialContent)
+            .header("Content-Type", content_type)
+            .header("Content-Range", range.content_range(total))
+            .body(slice(range));
+    }
+
+    let boundary = uuid::Uuid::new_v4().simple().to_string();
+    let mut body = Vec::new();
+    for (i, range) in ranges.iter().enumerate() {
+        let delimiter = if i == 0 { "" } else { "\r\n" };
+        body.extend_from_slice(
+            format!(
+                "{}--{}\r\nContent-Type: {}\r\nContent-Range: {}\r\n\r\n",
+                delimiter,
+                boundary,
+                content_type,
+                range.content_range(total)
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(slice(range));
+    }
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    HttpResponse::new(StatusCode::PartialContent)
+        .header("Content-Type", format!("multipart/byteranges; boundary={}", boundary))
+        .body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ranges(header: &str, len: u64) -> Option<Vec<(u64, u64)>> {
+        match parse(header, len)? {
+            RangeRequest::Ranges(ranges) => Some(ranges.iter().map(|r| (r.start, r.end)).collect()),
+            RangeRequest::Unsatisfiable => Some(Vec::new()),
+        }
+    }
+
+    #[test]
+    fn test_parse_range_forms() {
+        assert_eq!(ranges("bytes=0-499", 1000), Some(vec![(0, 499)]));
+        assert_eq!(ranges("bytes=500-", 1000), Some(vec![(500, 999)]));
+        assert_eq!(ranges("bytes=-200", 1000), Some(vec![(800, 999)]));
+        assert_eq!(ranges("bytes=-2000", 1000), Some(vec![(0, 999)]));
+        assert_eq!(ranges("bytes=900-1200", 1000), Some(vec![(900, 999)]));
+        assert_eq!(ranges("Bytes = 0-0, 10-19 ,-1", 1000), Some(vec![(0, 0), (10, 19), (999, 999)]));
+        // Unsatisfiable ranges are dropped; none left means 416
+        assert_eq!(ranges("bytes=0-9,2000-", 1000), Some(vec![(0, 9)]));
+        assert_eq!(parse("bytes=1000-", 1000), Some(RangeRequest::Unsatisfiable));
+        assert_eq!(parse("bytes=-0", 1000), Some(RangeRequest::Unsatisfiable));
+        assert_eq!(parse("bytes=0-", 0), Some(RangeRequest::Unsatisfiable));
+    }
+
+    #[test]
+    fn test_invalid_ranges_are_ignored() {
+        for header in ["items=0-9", "bytes=9-0", "bytes=a-b", "bytes=-", "bytes=+1-2", "bytes", "bytes="] {
+            assert_eq!(parse(header, 1000), None, "{}", header);
+        }
+        let many = format!("bytes={}", vec!["0-0"; MAX_RANGES + 1].join(","));
+        assert_eq!(parse(&many, 1000), None);
+    }
+
+    #[test]
+    fn test_ranges_adding_up_past_the_length_are_ignored() {
+        let repeated = format!("bytes={}", vec!["0-"; MAX_RANGES].join(","));
+        assert_eq!(parse(&repeated, 1000), None);
+        assert_eq!(parse("bytes=0-599,400-", 1000), None);
+        // Overlaps are fine while the response stays within the file's size
+        assert_eq!(ranges("bytes=0-99,50-149", 1000), Some(vec![(0, 99), (50, 149)]));
+        assert_eq!(ranges("bytes=0-499,-500", 1000), Some(vec![(0, 499), (500, 999)]));
+    }
+}
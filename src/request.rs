@@ -1,6 +1,11 @@
+use crate::body::{BodyReader, BodyStream};
 use crate::error::{Result, ServerError};
-use std::collections::HashMap;
-use std::io::{BufRead, BufReader, Read};
+use crate::extensions::Extensions;
+use crate::headers::Headers;
+use crate::router::RouteMatch;
+use std::io::{BufRead, Read};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, OnceLock};
 
 /// HTTP methods supported by the server
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -14,8 +19,10 @@ pub enum HttpMethod {
     OPTIONS,
 }
 
-impl HttpMethod {
-    pub fn from_str(s: &str) -> Result<Self> {
+impl std::str::FromStr for HttpMethod {
+    type Err = ServerError;
+
+    fn from_str(s: &str) -> Result<Self> {
         match s.to_uppercase().as_str() {
             "GET" => Ok(HttpMethod::GET),
             "POST" => Ok(HttpMethod::POST),
@@ -27,8 +34,10 @@ impl HttpMethod {
             _ => Err(ServerError::InvalidMethod(s.to_string())),
         }
     }
+}
 
-    pub fn as_str(&self) -> &str {
+impl HttpMethod {
+    pub fn as_str(&self) -> &'static str {
         match self {
             HttpMethod::GET => "GET",
             HttpMethod::POST => "POST",
@@ -47,80 +56,374 @@ pub struct HttpRequest {
     pub method: HttpMethod,
     pub path: String,
     pub version: String,
-    pub headers: HashMap<String, String>,
+    pub headers: Headers,
     pub body: Vec<u8>,
+    /// The body left on the connection for a streaming handler, in which case
+    /// [`body`](Self::body) is empty; see [`body_reader`](Self::body_reader)
+    pub body_stream: Mutex<Option<BodyStream>>,
+    /// Address of the connection the request came in on, if known
+    pub peer_addr: Option<SocketAddr>,
+    /// The registered route the request matched, once routed
+    pub route: OnceLock<RouteMatch>,
+    /// Application state of the router that handled the request
+    pub state: OnceLock<Arc<Extensions>>,
+    /// Values attached while the request is handled, see
+    /// [`insert_extension`](Self::insert_extension)
+    pub extensions: Mutex<Extensions>,
 }
 
+/// Longest request head (request line and headers) the parser accepts
+pub const MAX_HEAD_SIZE: usize = 64 * 1024;
+
 impl HttpRequest {
     /// Parse an HTTP request from a TCP stream
-    pub fn parse<R: Read>(reader: &mut BufReader<R>) -> Result<Self> {
-        // Parse request line
-        let mut request_line = String::new();
-        reader
-            .read_line(&mut request_line)
-            .map_err(|e| ServerError::InvalidRequest(format!("Failed to read request line: {}", e)))?;
-
-        let parts: Vec<&str> = request_line.trim().split_whitespace().collect();
-        if parts.len() < 3 {
-            return Err(ServerError::InvalidRequest(
-                "Invalid request line format".to_string(),
-            ));
+    pub fn parse<R: BufRead>(reader: &mut R) -> Result<Self> {
+        let mut request = Self::parse_head(reader)?;
+        request.read_body(reader)?;
+        Ok(request)
+    }
+
+    /// Parse the request line and headers, leaving the body unread so the
+    /// caller can check its length first or stream it to the handler
+    pub fn parse_head<R: BufRead>(reader: &mut R) -> Result<Self> {
+        // Read the head line by line into one buffer, then parse it as bytes
+        let mut head = Vec::new();
+        let mut start = 0;
+        loop {
+            let line_start = head.len();
+            let limit = MAX_HEAD_SIZE.saturating_sub(line_start) as u64;
+            reader
+                .by_ref()
+                .take(limit)
+                .read_until(b'\n', &mut head)
+                .map_err(|e| ServerError::InvalidRequest(format!("Failed to read request head: {}", e)))?;
+            if Self::head_complete(&head, line_start, &mut start)? {
+                break;
+            }
         }
+        Self::from_head(&head[start..])
+    }
 
-        let method = HttpMethod::from_str(parts[0])?;
-        let path = parts[1].to_string();
-        let version = parts[2].to_string();
+    /// Read the body declared by the head into [`body`](Self::body)
+    pub fn read_body<R: Read>(&mut self, reader: &mut R) -> Result<()> {
+        // The buffer grows as data arrives rather than trusting the declared
+        // length up front
+        let length = self.content_length()?;
+        let mut body = Vec::new();
+        if length > 0 {
+            reader
+                .by_ref()
+                .take(length as u64)
+                .read_to_end(&mut body)
+                .map_err(|e| ServerError::InvalidRequest(format!("Failed to read request body: {}", e)))?;
+        }
+        self.body = Self::check_body(body, length)?;
+        Ok(())
+    }
 
-        // Parse headers
-        let mut headers = HashMap::new();
-        let mut content_length = 0usize;
+    /// Parse an HTTP request from an async stream
+    #[cfg(feature = "tokio")]
+    pub async fn parse_async<R: tokio::io::AsyncBufRead + Unpin>(reader: &mut R) -> Result<Self> {
+        let mut request = Self::parse_head_async(reader).await?;
+        request.read_body_async(reader).await?;
+        Ok(request)
+    }
 
-        for line in reader.by_ref().lines() {
-            let line = line.map_err(|e| {
-                ServerError::InvalidRequest(format!("Failed to read header line: {}", e))
-            })?;
+    /// Async counterpart of [`parse_head`](Self::parse_head)
+    #[cfg(feature = "tokio")]
+    pub async fn parse_head_async<R: tokio::io::AsyncBufRead + Unpin>(reader: &mut R) -> Result<Self> {
+        use tokio::io::{AsyncBufReadExt, AsyncReadExt};
 
-            if line.is_empty() {
+        let mut head = Vec::new();
+        let mut start = 0;
+        loop {
+            let line_start = head.len();
+            let limit = MAX_HEAD_SIZE.saturating_sub(line_start) as u64;
+            (&mut *reader)
+                .take(limit)
+                .read_until(b'\n', &mut head)
+                .await
+                .map_err(|e| ServerError::InvalidRequest(format!("Failed to read request head: {}", e)))?;
+            if Self::head_complete(&head, line_start, &mut start)? {
                 break;
             }
+        }
+        Self::from_head(&head[start..])
+    }
 
-            if let Some((key, value)) = line.split_once(':') {
-                let key = key.trim().to_lowercase();
-                let value = value.trim().to_string();
+    /// Async counterpart of [`read_body`](Self::read_body)
+    #[cfg(feature = "tokio")]
+    pub async fn read_body_async<R: tokio::io::AsyncRead + Unpin>(&mut self, reader: &mut R) -> Result<()> {
+        use tokio::io::AsyncReadExt;
 
-                if key == "content-length" {
-                    content_length = value.parse().unwrap_or(0);
-                }
+        let length = self.content_length()?;
+        let mut body = Vec::new();
+        if length > 0 {
+            (&mut *reader)
+                .take(length as u64)
+                .read_to_end(&mut body)
+                .await
+                .map_err(|e| ServerError::InvalidRequest(format!("Failed to read request body: {}", e)))?;
+        }
+        self.body = Self::check_body(body, length)?;
+        Ok(())
+    }
 
-                headers.insert(key, value);
+    /// Check the line just read into `head` (from `line_start`) and report
+    /// whether it was the blank line ending the head
+    ///
+    /// Empty lines before the request line are skipped by moving `start` past
+    /// them, as RFC 9112 section 2.2 suggests; they still count toward
+    /// [`MAX_HEAD_SIZE`].
+    fn head_complete(head: &[u8], line_start: usize, start: &mut usize) -> Result<bool> {
+        let line = &head[line_start..];
+        if !line.ends_with(b"\n") {
+            return Err(ServerError::InvalidRequest(if head.len() >= MAX_HEAD_SIZE {
+                format!("Request head exceeds {} bytes", MAX_HEAD_SIZE)
+            } else if head.len() == *start {
+                "Connection closed before the request line".to_string()
+            } else {
+                "Connection closed in the middle of the request head".to_string()
+            }));
+        }
+        if !line.ends_with(b"\r\n") {
+            return Err(ServerError::InvalidRequest("Request line or header not terminated by CRLF".to_string()));
+        }
+        if line == b"\r\n" {
+            if line_start == *start {
+                *start = head.len();
+                return Ok(false);
             }
+            return Ok(true);
         }
+        Ok(false)
+    }
 
-        // Read body if present
-        let mut body = vec![0u8; content_length];
-        if content_length > 0 {
-            reader.read_exact(&mut body).map_err(|e| {
-                ServerError::InvalidRequest(format!("Failed to read request body: {}", e))
-            })?;
+    /// Parse a complete head: CRLF-terminated lines ending with an empty one
+    fn from_head(head: &[u8]) -> Result<Self> {
+        let mut lines = head
+            .split_inclusive(|&b| b == b'\n')
+            .map(|line| &line[..line.len() - 2])
+            .take_while(|line| !line.is_empty());
+
+        let mut request = Self::from_request_line(lines.next().unwrap_or_default())?;
+        for line in lines {
+            request.add_header_line(line)?;
         }
 
+        // Fields that frame or address the request must not be repeated
+        // (RFC 9112 sections 3.2 and 6.3); which one is meant can't be guessed
+        for name in ["content-length", "host"] {
+            if request.headers.count(name) > 1 {
+                return Err(ServerError::InvalidRequest(format!("Duplicate {} header", name)));
+            }
+        }
+        Ok(request)
+    }
+
+    /// Start a request from its request line, e.g. `GET /index.html HTTP/1.1`
+    fn from_request_line(line: &[u8]) -> Result<Self> {
+        let mut parts = line.split(|&b| b == b' ');
+        let (Some(method), Some(target), Some(version), None) = (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(ServerError::InvalidRequest(
+                "Invalid request line format: expected `METHOD target HTTP/1.x` separated by single spaces".to_string(),
+            ));
+        };
+
+        if !is_token(method) {
+            return Err(ServerError::InvalidRequest(format!(
+                "Invalid method token {:?}",
+                String::from_utf8_lossy(method)
+            )));
+        }
+        if target.is_empty() || !target.iter().all(u8::is_ascii_graphic) {
+            return Err(ServerError::InvalidRequest(format!(
+                "Invalid request target {:?}",
+                String::from_utf8_lossy(target)
+            )));
+        }
+        if !matches!(version, [b'H', b'T', b'T', b'P', b'/', b'1', b'.', minor] if minor.is_ascii_digit()) {
+            return Err(ServerError::InvalidRequest(format!(
+                "Unsupported HTTP version {:?}",
+                String::from_utf8_lossy(version)
+            )));
+        }
+
+        // Each part is ASCII after the checks above
+        let text = |bytes: &[u8]| String::from_utf8_lossy(bytes).into_owned();
         Ok(HttpRequest {
-            method,
-            path,
-            version,
-            headers,
-            body,
+            method: text(method).parse()?,
+            path: text(target),
+            version: text(version),
+            headers: Headers::new(),
+            body: Vec::new(),
+            body_stream: Mutex::default(),
+            peer_addr: None,
+            route: OnceLock::new(),
+            state: OnceLock::new(),
+            extensions: Mutex::default(),
         })
     }
 
-    /// Get a header value (case-insensitive)
-    pub fn get_header(&self, key: &str) -> Option<&String> {
-        self.headers.get(&key.to_lowercase())
+    /// Record one `Name: value` header line
+    ///
+    /// Folded continuation lines, whitespace before the colon, and control
+    /// characters in the value are rejected rather than guessed at.
+    fn add_header_line(&mut self, line: &[u8]) -> Result<()> {
+        if line.starts_with(b" ") || line.starts_with(b"\t") {
+            return Err(ServerError::InvalidRequest("Folded header lines are not supported".to_string()));
+        }
+        let Some(colon) = line.iter().position(|&b| b == b':') else {
+            return Err(ServerError::InvalidRequest(format!(
+                "Header line without a colon: {:?}",
+                String::from_utf8_lossy(line)
+            )));
+        };
+
+        let (name, value) = (&line[..colon], trim_whitespace(&line[colon + 1..]));
+        if !is_token(name) {
+            return Err(ServerError::InvalidRequest(format!(
+                "Invalid header name {:?}",
+                String::from_utf8_lossy(name)
+            )));
+        }
+        if value.iter().any(|&b| b.is_ascii_control() && b != b'\t') {
+            return Err(ServerError::InvalidRequest(format!(
+                "Invalid character in {} header value",
+                String::from_utf8_lossy(name)
+            )));
+        }
+
+        // Non-ASCII bytes in values are legal but not text; keep them readable
+        self.headers.append(&String::from_utf8_lossy(name), String::from_utf8_lossy(value));
+        Ok(())
+    }
+
+    /// Declared body length; a missing header means no body
+    ///
+    /// Chunked bodies are not supported, so Transfer-Encoding is refused rather
+    /// than leaving the body to be read as the next request.
+    pub fn content_length(&self) -> Result<usize> {
+        if self.get_header("transfer-encoding").is_some() {
+            return Err(ServerError::InvalidRequest("Transfer-Encoding is not supported".to_string()));
+        }
+        match self.get_header("content-length") {
+            None => Ok(0),
+            Some(value) if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) => value
+                .parse()
+                .map_err(|_| ServerError::InvalidRequest(format!("Content-Length {} is too large", value))),
+            Some(value) => Err(ServerError::InvalidRequest(format!("Invalid Content-Length {:?}", value))),
+        }
+    }
+
+    /// Refuse a declared body longer than `max` bytes before any of it is read
+    pub fn check_body_limit(&self, max: Option<usize>) -> Result<()> {
+        let length = self.content_length()?;
+        match max {
+            Some(max) if length > max => Err(ServerError::ContentTooLarge(format!(
+                "Request body of {} bytes exceeds the {} byte limit",
+                length, max
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    /// Leave the body on the connection for the handler to read through
+    /// [`body_reader`](Self::body_reader); `reader` must yield the bytes
+    /// following the head
+    pub fn stream_body(&mut self, reader: impl Read + Send + 'static) -> Result<()> {
+        let length = self.content_length()?;
+        *self.body_stream.get_mut().unwrap_or_else(|e| e.into_inner()) = Some(BodyStream::new(reader, length as u64));
+        Ok(())
+    }
+
+    /// Read the request body, whether it was buffered with the head or left
+    /// on the connection for a [streaming](crate::router::streaming) handler
+    ///
+    /// A streamed body can only be read once; bytes the handler leaves
+    /// unread are discarded by the server afterwards.
+    pub fn body_reader(&self) -> BodyReader<'_> {
+        let stream = self.body_stream.lock().unwrap_or_else(|e| e.into_inner());
+        if stream.is_some() {
+            BodyReader::Streaming(stream)
+        } else {
+            BodyReader::Buffered(&self.body)
+        }
+    }
+
+    /// The body must be as long as declared
+    fn check_body(body: Vec<u8>, length: usize) -> Result<Vec<u8>> {
+        if body.len() < length {
+            return Err(ServerError::InvalidRequest(format!(
+                "Failed to read request body: connection closed after {} of {} bytes",
+                body.len(),
+                length
+            )));
+        }
+        Ok(body)
+    }
+
+    /// Value of a `{name}` segment in the route the request matched
+    pub fn param(&self, name: &str) -> Option<&str> {
+        let route = self.route.get()?;
+        route.params.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Reconstruct the request line as sent by the client
+    pub fn request_line(&self) -> String {
+        format!("{} {} {}", self.method.as_str(), self.path, self.version)
+    }
+
+    /// Application state of type `T` shared with [`Router::with_state`]; `None`
+    /// if no such state was added or the request hasn't been routed
+    ///
+    /// [`Router::with_state`]: crate::router::Router::with_state
+    pub fn state<T: Send + Sync + 'static>(&self) -> Option<&T> {
+        self.state.get()?.get()
+    }
+
+    /// Attach a value for later middleware and the handler, replacing any
+    /// earlier value of the same type
+    ///
+    /// Middleware uses this to pass on what it worked out, e.g. the
+    /// authenticated user; wrap values in newtypes so they don't collide.
+    pub fn insert_extension<T: Send + Sync + 'static>(&self, value: T) {
+        self.extensions.lock().unwrap_or_else(|e| e.into_inner()).insert(value);
+    }
+
+    /// The value of type `T` attached with
+    /// [`insert_extension`](Self::insert_extension)
+    pub fn extension<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        self.extensions.lock().unwrap_or_else(|e| e.into_inner()).get_arc()
+    }
+
+    /// Whether the client wants the connection kept open after this request;
+    /// HTTP/1.1 defaults to persistent connections, HTTP/1.0 must opt in
+    pub fn wants_keep_alive(&self) -> bool {
+        let connection = self.headers.combined("connection").map(|value| value.to_ascii_lowercase());
+        let has_token = |token: &str| {
+            connection
+                .as_deref()
+                .is_some_and(|value| value.split(',').any(|t| t.trim() == token))
+        };
+        if self.version == "HTTP/1.0" {
+            has_token("keep-alive")
+        } else {
+            !has_token("close")
+        }
+    }
+
+    /// Get a header value (case-insensitive); the first one if the header was
+    /// repeated, see [`Headers::get_all`] and [`Headers::combined`]
+    pub fn get_header(&self, key: &str) -> Option<&str> {
+        self.headers.get(key)
     }
 
     /// Get accepted encoding from Accept-Encoding header
     pub fn get_accepted_encodings(&self) -> Vec<String> {
-        self.get_header("accept-encoding")
+        self.headers
+            .combined("accept-encoding")
             .map(|value| {
                 value
                     .split(',')
@@ -130,6 +433,61 @@ impl HttpRequest {
             .unwrap_or_default()
     }
 
+    /// Pick the best of the offered media types according to the Accept header
+    ///
+    /// Honors q-values and `type/*` / `*/*` wildcards; a missing Accept header
+    /// accepts anything, so the first offered type wins.
+    pub fn negotiate<'a>(&self, offered: &[&'a str]) -> Option<&'a str> {
+        let accept = match self.headers.combined("accept") {
+            Some(accept) if !accept.trim().is_empty() => accept,
+            _ => return offered.first().copied(),
+        };
+
+        // Parse "type/subtype;q=0.8" ranges into (range, quality)
+        let ranges: Vec<(String, f32)> = accept
+            .split(',')
+            .filter_map(|part| {
+                let mut params = part.split(';');
+                let range = params.next()?.trim().to_lowercase();
+                let quality = params
+                    .filter_map(|p| p.trim().strip_prefix("q="))
+                    .find_map(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (!range.is_empty()).then_some((range, quality))
+            })
+            .collect();
+
+        // Most specific matching range decides each offer's quality
+        let quality_of = |media_type: &str| -> f32 {
+            let (main_type, _) = media_type.split_once('/').unwrap_or((media_type, ""));
+            let mut best: Option<(u8, f32)> = None;
+            for (range, quality) in &ranges {
+                let specificity = if range == media_type {
+                    2
+                } else if range.strip_suffix("/*") == Some(main_type) {
+                    1
+                } else if range == "*/*" {
+                    0
+                } else {
+                    continue;
+                };
+                if best.map_or(true, |(s, _)| specificity > s) {
+                    best = Some((specificity, *quality));
+                }
+            }
+            best.map_or(0.0, |(_, quality)| quality)
+        };
+
+        let mut choice: Option<(&'a str, f32)> = None;
+        for media_type in offered {
+            let quality = quality_of(media_type);
+            if quality > 0.0 && choice.map_or(true, |(_, q)| quality > q) {
+                choice = Some((media_type, quality));
+            }
+        }
+        choice.map(|(media_type, _)| media_type)
+    }
+
     /// Get request body as string
     pub fn body_as_string(&self) -> Result<String> {
         String::from_utf8(self.body.clone())
@@ -143,3 +501,158 @@ impl HttpRequest {
             .any(|e| e == encoding || e == "*")
     }
 }
+
+/// `tchar` from RFC 9110 section 5.6.2; methods and header names are tokens
+fn is_token(bytes: &[u8]) -> bool {
+    !bytes.is_empty()
+        && bytes
+            .iter()
+            .all(|&b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Strip optional whitespace (spaces and tabs) from both ends
+fn trim_whitespace(mut bytes: &[u8]) -> &[u8] {
+    while let [b' ' | b'\t', rest @ ..] = bytes {
+        bytes = rest;
+    }
+    while let [rest @ .., b' ' | b'\t'] = bytes {
+        bytes = rest;
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(version: &str, header: Option<(&str, &str)>) -> HttpRequest {
+        let headers = header.into_iter().collect();
+        HttpRequest {
+            method: HttpMethod::GET,
+            path: "/".to_string(),
+            version: version.to_string(),
+            headers,
+            body: Vec::new(),
+            body_stream: Mutex::default(),
+            peer_addr: None,
+            route: OnceLock::new(),
+            state: OnceLock::new(),
+            extensions: Mutex::default(),
+        }
+    }
+
+    fn with_accept(accept: Option<&str>) -> HttpRequest {
+        request("HTTP/1.1", accept.map(|accept| ("accept", accept)))
+    }
+
+    fn parse(raw: &[u8]) -> Result<HttpRequest> {
+        HttpRequest::parse(&mut &raw[..])
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let raw = b"\r\nPOST /files/a.txt HTTP/1.1\r\nHost: localhost\r\nX-Name:\t caf\xe9 \r\nContent-Length: 5\r\n\r\nhello";
+        let request = parse(raw).unwrap();
+        assert_eq!(request.request_line(), "POST /files/a.txt HTTP/1.1");
+        assert_eq!(request.get_header("Host"), Some("localhost"));
+        assert_eq!(request.get_header("x-name"), Some("caf\u{fffd}"));
+        assert_eq!(request.body, b"hello");
+    }
+
+    #[test]
+    fn test_repeated_headers_are_kept() {
+        let raw = b"GET / HTTP/1.1\r\nX-Forwarded-For: 10.0.0.1\r\nAccept-Encoding: br\r\nX-Forwarded-For: 10.0.0.2\r\nAccept-Encoding: gzip\r\nConnection: keep-alive\r\nConnection: close\r\n\r\n";
+        let request = parse(raw).unwrap();
+        assert_eq!(request.get_header("x-forwarded-for"), Some("10.0.0.1"));
+        assert_eq!(request.headers.get_all("x-forwarded-for").collect::<Vec<_>>(), ["10.0.0.1", "10.0.0.2"]);
+        // List-valued fields are read as one combined value
+        assert_eq!(request.get_accepted_encodings(), ["br", "gzip"]);
+        assert!(!request.wants_keep_alive());
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_requests() {
+        let malformed: &[(&[u8], &str)] = &[
+            (b"", "closed before the request line"),
+            (b"GET / HTTP/1.1", "middle of the request head"),
+            (b"GET / HTTP/1.1\r\nHost: localhost\r\n", "middle of the request head"),
+            (b"GET / HTTP/1.1\nHost: localhost\n\n", "not terminated by CRLF"),
+            (b"GET / HTTP/1.1\r\nHost: local\rhost\r\n\r\n", "Invalid character in Host"),
+            (b"GET  / HTTP/1.1\r\n\r\n", "Invalid request line format"),
+            (b"GET\t/ HTTP/1.1\r\n\r\n", "Invalid request line format"),
+            (b"GET /\r\n\r\n", "Invalid request line format"),
+            (b"G(T / HTTP/1.1\r\n\r\n", "Invalid method token"),
+            (b"GET /a\x00b HTTP/1.1\r\n\r\n", "Invalid request target"),
+            (b"GET /caf\xc3\xa9 HTTP/1.1\r\n\r\n", "Invalid request target"),
+            (b"GET / HTTP/2.0\r\n\r\n", "Unsupported HTTP version"),
+            (b"GET / http/1.1\r\n\r\n", "Unsupported HTTP version"),
+            (b"GET / HTTP/1.1\r\nX-Long: first\r\n second\r\n\r\n", "Folded header lines"),
+            (b"GET / HTTP/1.1\r\nHost : localhost\r\n\r\n", "Invalid header name"),
+            (b"GET / HTTP/1.1\r\n: value\r\n\r\n", "Invalid header name"),
+            (b"GET / HTTP/1.1\r\nHost localhost\r\n\r\n", "without a colon"),
+            (b"GET / HTTP/1.1\r\nX-Test: a\x00b\r\n\r\n", "Invalid character"),
+            (b"POST / HTTP/1.1\r\nContent-Length: -1\r\n\r\n", "Invalid Content-Length"),
+            (b"POST / HTTP/1.1\r\nContent-Length: 99999999999999999999999\r\n\r\n", "too large"),
+            (b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nshort", "after 5 of 10 bytes"),
+            (b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n0\r\n\r\n", "Transfer-Encoding"),
+            (b"POST / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\nhello", "Duplicate content-length"),
+            (b"GET / HTTP/1.1\r\nHost: a\r\nHost: b\r\n\r\n", "Duplicate host"),
+        ];
+        for (raw, expected) in malformed {
+            match parse(raw) {
+                Err(ServerError::InvalidRequest(message)) => {
+                    assert!(message.contains(expected), "{:?}: {}", String::from_utf8_lossy(raw), message)
+                }
+                other => panic!("{:?} was not rejected: {:?}", String::from_utf8_lossy(raw), other),
+            }
+        }
+
+        let oversized = [b"GET / HTTP/1.1\r\nX-Big: ".as_slice(), &vec![b'a'; MAX_HEAD_SIZE]].concat();
+        assert!(parse(&oversized).unwrap_err().to_string().contains("exceeds"));
+    }
+
+    #[test]
+    fn test_body_limit_and_streaming() {
+        let raw = b"POST /upload HTTP/1.1\r\nContent-Length: 11\r\n\r\nhello worldGET / HTTP/1.1\r\n\r\n";
+        let mut reader = &raw[..];
+        let mut request = HttpRequest::parse_head(&mut reader).unwrap();
+        assert!(request.check_body_limit(None).is_ok());
+        assert!(request.check_body_limit(Some(11)).is_ok());
+        assert_eq!(request.check_body_limit(Some(10)).unwrap_err().status_code(), 413);
+
+        request.stream_body(std::io::Cursor::new(reader.to_vec())).unwrap();
+        let mut body = String::new();
+        request.body_reader().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello world");
+        assert!(request.body.is_empty());
+        // A streamed body is gone once read
+        assert_eq!(request.body_reader().read(&mut [0; 8]).unwrap(), 0);
+
+        let request = parse(raw).unwrap();
+        let mut body = Vec::new();
+        request.body_reader().read_to_end(&mut body).unwrap();
+        assert_eq!(body, b"hello world");
+    }
+
+    #[test]
+    fn test_wants_keep_alive() {
+        assert!(request("HTTP/1.1", None).wants_keep_alive());
+        assert!(!request("HTTP/1.1", Some(("connection", "Close"))).wants_keep_alive());
+        assert!(!request("HTTP/1.0", None).wants_keep_alive());
+        assert!(request("HTTP/1.0", Some(("connection", "Keep-Alive"))).wants_keep_alive());
+    }
+
+    #[test]
+    fn test_negotiate() {
+        let offered = ["text/plain", "application/json"];
+        let negotiate = |accept| with_accept(accept).negotiate(&offered);
+
+        assert_eq!(negotiate(None), Some("text/plain"));
+        assert_eq!(negotiate(Some("application/json")), Some("application/json"));
+        assert_eq!(negotiate(Some("*/*")), Some("text/plain"));
+        assert_eq!(negotiate(Some("text/*;q=0.5, application/json")), Some("application/json"));
+        assert_eq!(negotiate(Some("application/*, text/plain;q=0.9")), Some("application/json"));
+        assert_eq!(negotiate(Some("*/*;q=0.1, text/plain;q=0")), Some("application/json"));
+        assert_eq!(negotiate(Some("image/png")), None);
+    }
+}
@@ -0,0 +1,76 @@
+use std::cell::RefCell;
+use std::net::IpAddr;
+
+/// Header used to receive and return request IDs
+pub const HEADER: &str = "X-Request-ID";
+
+thread_local! {
+    static CURRENT: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Generate a fresh request ID
+pub fn generate() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// Pick the ID for a request: reuse a well-formed incoming ID when the peer is
+/// a trusted proxy, otherwise generate a new one
+pub fn resolve(incoming: Option<&str>, peer: Option<IpAddr>, trusted_proxies: &[IpAddr]) -> String {
+    let trusted = peer.is_some_and(|ip| trusted_proxies.contains(&ip));
+    match incoming {
+        Some(id) if trusted && is_valid(id) => id.to_string(),
+        _ => generate(),
+    }
+}
+
+/// Incoming IDs must be short, visible ASCII so they are safe to log and echo
+fn is_valid(id: &str) -> bool {
+    !id.is_empty() && id.len() <= 200 && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+/// Get the request ID being handled on this thread, if any
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Marks a request ID as current on this thread until dropped
+pub struct Scope;
+
+impl Scope {
+    pub fn enter(id: &str) -> Self {
+        CURRENT.with(|current| *current.borrow_mut() = Some(id.to_string()));
+        Scope
+    }
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        CURRENT.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve_trusts_only_configured_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "192.0.2.7".parse().unwrap();
+
+        assert_eq!(resolve(Some("abc-123"), Some(proxy), &[proxy]), "abc-123");
+        assert_ne!(resolve(Some("abc-123"), Some(client), &[proxy]), "abc-123");
+        assert_ne!(resolve(Some("bad id"), Some(proxy), &[proxy]), "bad id");
+        assert_eq!(resolve(None, Some(proxy), &[proxy]).len(), 36);
+    }
+
+    #[test]
+    fn test_scope_sets_current_id() {
+        assert_eq!(current(), None);
+        {
+            let _scope = Scope::enter("req-1");
+            assert_eq!(current().as_deref(), Some("req-1"));
+        }
+        assert_eq!(current(), None);
+    }
+}
@@ -1,55 +1,175 @@
+use crate::buffer_pool::PooledBuf;
 use crate::compression::Compression;
 use crate::error::Result;
-use std::collections::HashMap;
+use crate::problem::Problem;
+use crate::request::HttpRequest;
+use crate::status::StatusCode;
+use std::fmt;
+use std::io::{self, IoSlice, Read, Write};
 
 /// HTTP response builder
 #[derive(Debug)]
 pub struct HttpResponse {
-    status_code: u16,
-    status_text: String,
-    headers: HashMap<String, String>,
+    status: StatusCode,
+    headers: Vec<(String, String)>,
     body: Vec<u8>,
+    stream: Option<StreamBody>,
 }
 
+/// A body read from a reader as it is sent, see [`HttpResponse::stream`]
+struct StreamBody {
+    reader: Box<dyn Read + Send>,
+    len: Option<u64>,
+}
+
+impl fmt::Debug for StreamBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamBody").field("len", &self.len).finish()
+    }
+}
+
+/// Bytes copied from a streamed body per write
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
 impl HttpResponse {
     /// Create a new response with status code
-    pub fn new(status_code: u16) -> Self {
-        let status_text = Self::status_text(status_code);
+    pub fn new(status: StatusCode) -> Self {
         HttpResponse {
-            status_code,
-            status_text,
-            headers: HashMap::new(),
+            status,
+            headers: Vec::new(),
             body: Vec::new(),
+            stream: None,
+        }
+    }
+
+    /// Get the response status
+    pub fn status(&self) -> StatusCode {
+        self.status
+    }
+
+    /// Get the numeric response status code
+    pub fn status_code(&self) -> u16 {
+        self.status.as_u16()
+    }
+
+    /// Get the length of the response body in bytes; for a streamed body,
+    /// its declared length, or 0 if unknown until sent
+    pub fn body_len(&self) -> usize {
+        match &self.stream {
+            Some(stream) => stream.len.map_or(0, |len| usize::try_from(len).unwrap_or(usize::MAX)),
+            None => self.body.len(),
         }
     }
 
-    /// Get standard status text for a status code
-    fn status_text(code: u16) -> String {
-        match code {
-            200 => "OK",
-            201 => "Created",
-            204 => "No Content",
-            400 => "Bad Request",
-            404 => "Not Found",
-            405 => "Method Not Allowed",
-            500 => "Internal Server Error",
-            _ => "Unknown",
+    /// The body held in memory; empty for a streamed body
+    pub fn body_bytes(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// Whether the body is read from a reader as it is sent
+    pub fn is_streamed(&self) -> bool {
+        self.stream.is_some()
+    }
+
+    /// A copy of the response; `None` for a streamed body, which can only be
+    /// read once
+    pub fn try_clone(&self) -> Option<Self> {
+        if self.stream.is_some() {
+            return None;
         }
-        .to_string()
+        Some(HttpResponse {
+            status: self.status,
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            stream: None,
+        })
     }
 
-    /// Set a header
+    /// Set a header, replacing any existing values with the same name
     pub fn header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
-        self.headers.insert(key.into(), value.into());
+        let key = key.into();
+        self.headers.retain(|(k, _)| !k.eq_ignore_ascii_case(&key));
+        self.headers.push((key, value.into()));
         self
     }
 
+    /// Append a header, keeping any existing values with the same name
+    pub fn append_header(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((key.into(), value.into()));
+        self
+    }
+
+    /// Add a request header name to the Vary header if not already listed
+    pub fn vary(mut self, field: &str) -> Self {
+        let already_listed = self
+            .header_values("Vary")
+            .flat_map(|v| v.split(','))
+            .any(|f| f.trim().eq_ignore_ascii_case(field) || f.trim() == "*");
+        if !already_listed {
+            self.headers.push(("Vary".to_string(), field.to_string()));
+        }
+        self
+    }
+
+    /// Get the first value of a header (case-insensitive)
+    pub fn get_header<'a>(&'a self, key: &'a str) -> Option<&'a str> {
+        self.header_values(key).next()
+    }
+
+    /// Iterate over all values of a header (case-insensitive)
+    pub fn header_values<'a>(&'a self, key: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.headers
+            .iter()
+            .filter(move |(k, _)| k.eq_ignore_ascii_case(key))
+            .map(|(_, v)| v.as_str())
+    }
+
     /// Set the response body
     pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
         self.body = body.into();
+        self.stream = None;
+        self
+    }
+
+    /// Send the body from a reader such as a file, a pipe, or a generator,
+    /// copying it in chunks instead of holding it in memory
+    ///
+    /// With a known `len` the response gets a Content-Length and exactly that
+    /// many bytes are sent; a reader that ends early fails the response. With
+    /// `None` the body is sent with chunked transfer coding until the reader
+    /// ends. Streamed bodies are never compressed.
+    pub fn stream(mut self, reader: impl Read + Send + 'static, len: Option<u64>) -> Self {
+        self.body = Vec::new();
+        self.stream = Some(StreamBody {
+            reader: Box::new(reader),
+            len,
+        });
         self
     }
 
+    /// Read a streamed body into memory, for runtimes that send the body
+    /// from a buffer; other responses are returned as they are
+    pub fn buffered(mut self) -> io::Result<Self> {
+        let Some(stream) = self.stream.take() else {
+            return Ok(self);
+        };
+        let mut body = Vec::new();
+        match stream.len {
+            Some(len) => {
+                stream.reader.take(len).read_to_end(&mut body)?;
+                if (body.len() as u64) < len {
+                    return Err(short_body(body.len() as u64, len));
+                }
+            }
+            None => {
+                let mut reader = stream.reader;
+                reader.read_to_end(&mut body)?;
+            }
+        }
+        self.body = body;
+        Ok(self)
+    }
+
     /// Set the response body as text
     pub fn text(self, text: impl Into<String>) -> Self {
         self.header("Content-Type", "text/plain")
@@ -71,72 +191,448 @@ impl HttpResponse {
             .body(html.into().into_bytes())
     }
 
-    /// Apply compression to the response body
-    pub fn compress(mut self, compression: Compression) -> Result<Self> {
-        if self.body.is_empty() {
-            return Ok(self);
+    /// Respond in the representation the client prefers
+    ///
+    /// `render` builds the response for the media type chosen from `offered`
+    /// by the Accept header (the first one when there is no preference). A
+    /// request that accepts none of them gets 406 Not Acceptable listing what
+    /// is available. Either way the response carries `Vary: Accept`.
+    pub fn negotiated<'a>(
+        request: &HttpRequest,
+        offered: &[&'a str],
+        render: impl FnOnce(&'a str) -> Result<HttpResponse>,
+    ) -> Result<HttpResponse> {
+        match request.negotiate(offered) {
+            Some(media_type) => Ok(render(media_type)?.vary("Accept")),
+            None => Ok(Problem::new(
+                StatusCode::NotAcceptable,
+                "not_acceptable",
+                format!("Available representations: {}", offered.join(", ")),
+            )
+            .instance(&request.path)
+            .to_response(Some(request))),
         }
+    }
 
-        let compressed = compression.compress(&self.body)?;
-        self.body = compressed;
-        self.headers
-            .insert("Content-Encoding".to_string(), compression.name().to_string());
-        Ok(self)
+    /// Apply negotiated compression to the response body
+    ///
+    /// The response always gets `Vary: Accept-Encoding`, since its encoding
+    /// depends on the request even when no compression ends up being applied.
+    pub fn compress(self, compression: Compression) -> Result<Self> {
+        self.compress_level(compression, None)
     }
 
-    /// Build the HTTP response as bytes
-    pub fn build(mut self) -> Vec<u8> {
-        // Set Content-Length if not already set
-        if !self.headers.contains_key("Content-Length") {
-            self.headers
-                .insert("Content-Length".to_string(), self.body.len().to_string());
+    /// [`compress`](Self::compress) at a level, see [`Compression::compress_level`]
+    pub fn compress_level(self, compression: Compression, level: Option<u32>) -> Result<Self> {
+        let mut response = self.vary("Accept-Encoding");
+        if response.body.is_empty() || response.stream.is_some() || compression == Compression::None {
+            return Ok(response);
         }
 
-        // Build response
-        let mut response = format!(
-            "HTTP/1.1 {} {}\r\n",
-            self.status_code, self.status_text
-        );
+        response.body = compression.compress_level(&response.body, level)?;
+        Ok(response.encoded(compression))
+    }
+
+    /// Mark the body as already compressed with the given algorithm
+    pub fn encoded(self, compression: Compression) -> Self {
+        let response = self.vary("Accept-Encoding");
+        if compression == Compression::None {
+            return response;
+        }
+        response.header("Content-Encoding", compression.name())
+    }
+
+    /// Serialize the status line and headers, keeping the body separate so
+    /// the two can go out in one vectored write without copying the body
+    ///
+    /// A streamed body is read into memory first, see
+    /// [`buffered`](Self::buffered).
+    pub fn into_parts(self) -> io::Result<ResponseParts> {
+        let mut response = self.buffered()?;
+        let head = response.head();
+        Ok(ResponseParts { head, body: response.body })
+    }
+
+    /// Serialize the status line and headers, framing the body with
+    /// Content-Length, or chunked transfer coding for a stream of unknown length
+    fn head(&mut self) -> PooledBuf {
+        if self.get_header("Content-Length").is_none() && self.get_header("Transfer-Encoding").is_none() {
+            let framing = match &self.stream {
+                Some(StreamBody { len: None, .. }) => ("Transfer-Encoding", "chunked".to_string()),
+                Some(StreamBody { len: Some(len), .. }) => ("Content-Length", len.to_string()),
+                None => ("Content-Length", self.body.len().to_string()),
+            };
+            self.headers.push((framing.0.to_string(), framing.1));
+        }
+
+        let mut head = PooledBuf::take(256);
+        head.extend_from_slice(b"HTTP/1.1 ");
+        head.extend_from_slice(self.status.as_u16().to_string().as_bytes());
+        head.push(b' ');
+        head.extend_from_slice(self.status.reason_phrase().as_bytes());
+        head.extend_from_slice(b"\r\n");
 
         for (key, value) in &self.headers {
-            response.push_str(&format!("{}: {}\r\n", key, value));
+            head.extend_from_slice(key.as_bytes());
+            head.extend_from_slice(b": ");
+            head.extend_from_slice(value.as_bytes());
+            head.extend_from_slice(b"\r\n");
+        }
+        head.extend_from_slice(b"\r\n");
+        head
+    }
+
+    /// Write the whole response to `writer` using vectored writes, returning
+    /// the number of body bytes sent
+    ///
+    /// A streamed body is copied from its reader in chunks after the head.
+    pub fn write_to(mut self, writer: &mut impl Write) -> io::Result<u64> {
+        let head = self.head();
+        let Some(stream) = self.stream.take() else {
+            write_all_vectored(writer, &head, &self.body)?;
+            return Ok(self.body.len() as u64);
+        };
+
+        write_all_vectored(writer, &head, &[])?;
+        let mut reader = stream.reader;
+        let mut chunk = PooledBuf::take(STREAM_CHUNK_SIZE);
+        chunk.resize(STREAM_CHUNK_SIZE, 0);
+        let mut sent = 0;
+        loop {
+            let limit = match stream.len {
+                Some(len) if sent == len => break,
+                Some(len) => chunk.len().min(usize::try_from(len - sent).unwrap_or(usize::MAX)),
+                None => chunk.len(),
+            };
+            let n = match reader.read(&mut chunk[..limit]) {
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+            match (n, stream.len) {
+                (0, Some(len)) => return Err(short_body(sent, len)),
+                (0, None) => break,
+                (n, Some(_)) => writer.write_all(&chunk[..n])?,
+                (n, None) => {
+                    let size = format!("{:x}\r\n", n);
+                    write_all_vectored(writer, size.as_bytes(), &chunk[..n])?;
+                    writer.write_all(b"\r\n")?;
+                }
+            }
+            sent += n as u64;
         }
+        if stream.len.is_none() {
+            writer.write_all(b"0\r\n\r\n")?;
+        }
+        Ok(sent)
+    }
+}
+
+/// Write `head` and `body` back to back, retrying partial vectored writes
+fn write_all_vectored(writer: &mut impl Write, head: &[u8], body: &[u8]) -> io::Result<()> {
+    let total = head.len() + body.len();
+    let mut sent = 0;
+    while sent < total {
+        let head_left = &head[sent.min(head.len())..];
+        let body_left = &body[sent.saturating_sub(head.len())..];
+        match writer.write_vectored(&[IoSlice::new(head_left), IoSlice::new(body_left)]) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => sent += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+/// The error for a streamed body whose reader ended before its declared length
+fn short_body(sent: u64, len: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("response body ended after {} of {} bytes", sent, len),
+    )
+}
+
+/// Escape text for inclusion in HTML element content or attribute values
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// A response ready to send: serialized head plus the untouched body
+pub struct ResponseParts {
+    pub head: PooledBuf,
+    pub body: Vec<u8>,
+}
 
-        response.push_str("\r\n");
+impl ResponseParts {
+    /// Total bytes on the wire
+    pub fn len(&self) -> usize {
+        self.head.len() + self.body.len()
+    }
 
-        let mut bytes = response.into_bytes();
-        bytes.extend_from_slice(&self.body);
-        bytes
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// What is left of the head and body once `sent` bytes have been written
+    pub fn remaining(&self, sent: usize) -> [&[u8]; 2] {
+        let head = &self.head[sent.min(self.head.len())..];
+        let body = &self.body[sent.saturating_sub(self.head.len()).min(self.body.len())..];
+        [head, body]
     }
 }
 
 // Convenient constructors
 impl HttpResponse {
     pub fn ok() -> Self {
-        Self::new(200)
+        Self::new(StatusCode::Ok)
     }
 
     pub fn created() -> Self {
-        Self::new(201)
+        Self::new(StatusCode::Created)
+    }
+
+    pub fn accepted() -> Self {
+        Self::new(StatusCode::Accepted)
     }
 
     pub fn no_content() -> Self {
-        Self::new(204)
+        Self::new(StatusCode::NoContent)
+    }
+
+    pub fn moved_permanently(location: impl Into<String>) -> Self {
+        Self::new(StatusCode::MovedPermanently).header("Location", location)
+    }
+
+    pub fn found(location: impl Into<String>) -> Self {
+        Self::new(StatusCode::Found).header("Location", location)
+    }
+
+    pub fn see_other(location: impl Into<String>) -> Self {
+        Self::new(StatusCode::SeeOther).header("Location", location)
+    }
+
+    pub fn temporary_redirect(location: impl Into<String>) -> Self {
+        Self::new(StatusCode::TemporaryRedirect).header("Location", location)
+    }
+
+    pub fn permanent_redirect(location: impl Into<String>) -> Self {
+        Self::new(StatusCode::PermanentRedirect).header("Location", location)
+    }
+
+    pub fn not_modified() -> Self {
+        Self::new(StatusCode::NotModified)
     }
 
     pub fn bad_request() -> Self {
-        Self::new(400)
+        Self::new(StatusCode::BadRequest)
+    }
+
+    pub fn unauthorized() -> Self {
+        Self::status_page(StatusCode::Unauthorized)
+    }
+
+    pub fn forbidden() -> Self {
+        Self::status_page(StatusCode::Forbidden)
     }
 
     pub fn not_found() -> Self {
-        Self::new(404).text("404 - Not Found")
+        Self::status_page(StatusCode::NotFound)
     }
 
     pub fn method_not_allowed() -> Self {
-        Self::new(405).text("405 - Method Not Allowed")
+        Self::status_page(StatusCode::MethodNotAllowed)
+    }
+
+    pub fn conflict() -> Self {
+        Self::status_page(StatusCode::Conflict)
+    }
+
+    pub fn content_too_large() -> Self {
+        Self::status_page(StatusCode::ContentTooLarge)
+    }
+
+    pub fn too_many_requests() -> Self {
+        Self::status_page(StatusCode::TooManyRequests)
     }
 
     pub fn internal_error() -> Self {
-        Self::new(500).text("500 - Internal Server Error")
+        Self::status_page(StatusCode::InternalServerError)
+    }
+
+    pub fn not_implemented() -> Self {
+        Self::status_page(StatusCode::NotImplemented)
+    }
+
+    pub fn service_unavailable() -> Self {
+        Self::status_page(StatusCode::ServiceUnavailable)
+    }
+
+    /// An error status with a short text body, e.g. "404 - Not Found"
+    fn status_page(status: StatusCode) -> Self {
+        Self::new(status).text(format!("{} - {}", status.as_u16(), status.reason_phrase()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compress_adds_vary() {
+        let response = HttpResponse::ok()
+            .text("hello hello hello hello")
+            .compress(Compression::Gzip)
+            .unwrap();
+        assert_eq!(response.get_header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.get_header("content-encoding"), Some("gzip"));
+
+        let response = HttpResponse::ok().text("hello").compress(Compression::None).unwrap();
+        assert_eq!(response.get_header("Vary"), Some("Accept-Encoding"));
+        assert_eq!(response.get_header("Content-Encoding"), None);
+    }
+
+    #[test]
+    fn test_multi_value_headers() {
+        let response = HttpResponse::ok()
+            .vary("Accept")
+            .vary("Accept-Encoding")
+            .vary("accept")
+            .append_header("Set-Cookie", "a=1")
+            .append_header("Set-Cookie", "b=2")
+            .header("Content-Type", "text/plain")
+            .header("content-type", "text/html");
+
+        assert_eq!(response.header_values("Vary").count(), 2);
+        assert_eq!(response.header_values("Set-Cookie").collect::<Vec<_>>(), ["a=1", "b=2"]);
+        assert_eq!(response.header_values("Content-Type").collect::<Vec<_>>(), ["text/html"]);
+
+        let mut raw = Vec::new();
+        response.write_to(&mut raw).unwrap();
+        let raw = String::from_utf8(raw).unwrap();
+        assert!(raw.contains("Set-Cookie: a=1\r\nSet-Cookie: b=2\r\n"));
+    }
+
+    #[test]
+    fn test_status_line_uses_reason_phrase() {
+        let mut raw = Vec::new();
+        HttpResponse::too_many_requests().write_to(&mut raw).unwrap();
+        let raw = String::from_utf8(raw).unwrap();
+        assert!(raw.starts_with("HTTP/1.1 429 Too Many Requests\r\n"), "{}", raw);
+        assert!(raw.ends_with("\r\n\r\n429 - Too Many Requests"));
+
+        let response = HttpResponse::moved_permanently("/new");
+        assert_eq!(response.status(), StatusCode::MovedPermanently);
+        assert_eq!(response.get_header("Location"), Some("/new"));
+    }
+
+    #[test]
+    fn test_negotiated() {
+        let request = |accept: &str| {
+            let raw = format!("GET /report HTTP/1.1\r\nAccept: {}\r\n\r\n", accept);
+            HttpRequest::parse(&mut raw.as_bytes()).unwrap()
+        };
+        let respond = |accept| {
+            HttpResponse::negotiated(&request(accept), &["application/json", "text/plain"], |media_type| {
+                Ok(HttpResponse::ok().header("Content-Type", media_type))
+            })
+            .unwrap()
+        };
+
+        let response = respond("text/plain");
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert_eq!(response.get_header("Content-Type"), Some("text/plain"));
+        assert_eq!(response.get_header("Vary"), Some("Accept"));
+        assert_eq!(respond("*/*").get_header("Content-Type"), Some("application/json"));
+
+        let response = respond("image/png");
+        assert_eq!(response.status(), StatusCode::NotAcceptable);
+        assert_eq!(response.get_header("Vary"), Some("Accept"));
+        assert_eq!(
+            String::from_utf8(response.body).unwrap(),
+            "406 Not Acceptable: Available representations: application/json, text/plain\n"
+        );
+    }
+
+    /// Accepts at most `limit` bytes per call, to exercise partial writes
+    struct Trickle {
+        written: Vec<u8>,
+        limit: usize,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let n = buf.len().min(self.limit);
+            self.written.extend_from_slice(&buf[..n]);
+            Ok(n)
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            let mut n = 0;
+            for buf in bufs {
+                let take = buf.len().min(self.limit - n);
+                self.written.extend_from_slice(&buf[..take]);
+                n += take;
+            }
+            Ok(n)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_to_handles_partial_writes() {
+        let body = vec![b'x'; 100];
+        let mut trickle = Trickle { written: Vec::new(), limit: 7 };
+        HttpResponse::ok().body(body.clone()).write_to(&mut trickle).unwrap();
+
+        let expected = b"HTTP/1.1 200 OK\r\nContent-Length: 100\r\n\r\n";
+        assert_eq!(&trickle.written[..expected.len()], expected);
+        assert_eq!(&trickle.written[expected.len()..], &body[..]);
+
+        let parts = HttpResponse::ok().body("abc").into_parts().unwrap();
+        let head_len = parts.head.len();
+        assert_eq!(parts.remaining(head_len + 1), [&b""[..], &b"bc"[..]]);
+        assert_eq!(parts.remaining(parts.len()), [&b""[..], &b""[..]]);
+    }
+
+    #[test]
+    fn test_streamed_bodies() {
+        let mut raw = Vec::new();
+        let sent = HttpResponse::ok().stream(&b"hello world"[..], Some(5)).write_to(&mut raw).unwrap();
+        assert_eq!(sent, 5);
+        assert_eq!(raw, b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello");
+
+        // Unknown length goes out chunked, split however the reader returns it
+        let mut trickle = Trickle { written: Vec::new(), limit: 3 };
+        let reader = io::Cursor::new(b"hello".to_vec()).chain(&b" world"[..]);
+        let sent = HttpResponse::ok().stream(reader, None).write_to(&mut trickle).unwrap();
+        assert_eq!(sent, 11);
+        let expected = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n6\r\n world\r\n0\r\n\r\n";
+        assert_eq!(trickle.written, expected);
+
+        // A reader that ends early can't be framed correctly
+        let e = HttpResponse::ok().stream(&b"hi"[..], Some(5)).write_to(&mut Vec::new()).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        assert!(HttpResponse::ok().stream(&b"hi"[..], Some(5)).buffered().is_err());
+
+        let response = HttpResponse::ok().stream(&b"hello"[..], None);
+        assert!(response.is_streamed());
+        let parts = response.compress(Compression::Gzip).unwrap().into_parts().unwrap();
+        assert_eq!(&parts.body[..], b"hello");
+        assert!(String::from_utf8_lossy(&parts.head).contains("Content-Length: 5\r\n"));
     }
 }
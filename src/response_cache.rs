@@ -0,0 +1,335 @@
+//! Shared cache for responses of dynamic GET routes
+//!
+//! Added to a router with [`Router::response_cache`], the cache answers
+//! repeated requests without calling the handler for as long as the handler
+//! allowed with `Cache-Control: max-age` (or `s-maxage`). Responses are stored
+//! per path and query, and per value of each request header their `Vary`
+//! lists, the way a shared HTTP cache would.
+//!
+//! [`Router::response_cache`]: crate::router::Router::response_cache
+
+use crate::error::Result;
+use crate::metrics::{write_header, write_metric};
+use crate::request::{HttpMethod, HttpRequest};
+use crate::response::HttpResponse;
+use crate::router::Next;
+use crate::status::StatusCode;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// One stored response and the request header values it was chosen by
+struct CacheEntry {
+    vary: Vec<(String, Option<String>)>,
+    response: HttpResponse,
+    stored: Instant,
+    expires: Instant,
+    last_used: u64,
+}
+
+struct CacheState {
+    entries: HashMap<String, Vec<CacheEntry>>,
+    total_bytes: usize,
+    clock: u64,
+}
+
+/// Size-bounded cache of successful GET responses
+///
+/// When the cache grows past `max_bytes` of response bodies, the least
+/// recently used entries are evicted.
+pub struct ResponseCache {
+    max_bytes: usize,
+    state: Mutex<CacheState>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ResponseCache {
+    /// Create a cache holding at most `max_bytes` of response bodies
+    pub fn new(max_bytes: usize) -> Self {
+        ResponseCache {
+            max_bytes,
+            state: Mutex::new(CacheState {
+                entries: HashMap::new(),
+                total_bytes: 0,
+                clock: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Serve `request` from the cache, or run the handler and store what it
+    /// returns if the response allows it
+    pub fn handle(&self, request: &HttpRequest, next: Next<'_>) -> Result<HttpResponse> {
+        if !is_cacheable_request(request) {
+            return next.run(request);
+        }
+        let key = request.path.clone();
+        if !forces_reload(request) {
+            if let Some(response) = self.get(&key, request) {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+                return Ok(response);
+            }
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        let response = next.run(request)?;
+        if let Some(max_age) = freshness(&response) {
+            if let Some(stored) = response.try_clone() {
+                self.insert(key, request, stored, max_age);
+            }
+        }
+        Ok(response)
+    }
+
+    /// A fresh stored response for `key` whose Vary headers match `request`
+    fn get(&self, key: &str, request: &HttpRequest) -> Option<HttpResponse> {
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let clock = state.clock;
+        let now = Instant::now();
+
+        let variants = state.entries.get_mut(key)?;
+        let mut expired = 0;
+        variants.retain(|entry| {
+            let fresh = entry.expires > now;
+            if !fresh {
+                expired += entry.response.body_len();
+            }
+            fresh
+        });
+        let found = variants
+            .iter_mut()
+            .find(|entry| entry.vary.iter().all(|(name, value)| header_value(request, name) == *value))
+            .and_then(|entry| {
+                entry.last_used = clock;
+                let age = now.duration_since(entry.stored).as_secs();
+                Some(entry.response.try_clone()?.header("Age", age.to_string()))
+            });
+        if variants.is_empty() {
+            state.entries.remove(key);
+        }
+        state.total_bytes -= expired;
+        found
+    }
+
+    /// Store a response, replacing the variant with the same Vary values and
+    /// evicting old entries as needed
+    fn insert(&self, key: String, request: &HttpRequest, response: HttpResponse, max_age: Duration) {
+        let size = response.body_len();
+        if size > self.max_bytes {
+            return;
+        }
+        let vary = vary_fields(&response)
+            .into_iter()
+            .map(|name| {
+                let value = header_value(request, &name);
+                (name, value)
+            })
+            .collect::<Vec<_>>();
+
+        let mut state = self.state.lock().unwrap();
+        state.clock += 1;
+        let now = Instant::now();
+        let entry = CacheEntry {
+            vary,
+            response,
+            stored: now,
+            expires: now + max_age,
+            last_used: state.clock,
+        };
+        let variants = state.entries.entry(key).or_default();
+        let replaced = variants.iter().position(|existing| existing.vary == entry.vary);
+        let replaced = replaced.map_or(0, |i| variants.swap_remove(i).response.body_len());
+        variants.push(entry);
+        state.total_bytes = state.total_bytes + size - replaced;
+
+        while state.total_bytes > self.max_bytes {
+            let oldest = state
+                .entries
+                .iter()
+                .flat_map(|(key, variants)| variants.iter().enumerate().map(move |(i, entry)| (key, i, entry.last_used)))
+                .min_by_key(|(_, _, last_used)| *last_used)
+                .map(|(key, i, _)| (key.clone(), i));
+            let Some((key, i)) = oldest else { break };
+            let variants = state.entries.get_mut(&key).expect("key was just found");
+            let evicted = variants.swap_remove(i).response.body_len();
+            if variants.is_empty() {
+                state.entries.remove(&key);
+            }
+            state.total_bytes -= evicted;
+        }
+    }
+
+    /// Drop every stored response whose path starts with `prefix` (all of
+    /// them for an empty prefix); returns how many were dropped
+    pub fn purge(&self, prefix: &str) -> usize {
+        let mut state = self.state.lock().unwrap();
+        let mut purged = 0;
+        let mut freed = 0;
+        state.entries.retain(|key, variants| {
+            if !key.starts_with(prefix) {
+                return true;
+            }
+            purged += variants.len();
+            freed += variants.iter().map(|entry| entry.response.body_len()).sum::<usize>();
+            false
+        });
+        state.total_bytes -= freed;
+        purged
+    }
+
+    /// Number of stored responses
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.values().map(Vec::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Requests answered from the cache
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    /// Cacheable requests that went to the handler
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+
+    /// Share of cacheable requests answered from the cache
+    pub fn hit_ratio(&self) -> f64 {
+        let (hits, misses) = (self.hits(), self.misses());
+        if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        }
+    }
+
+    /// Append the cache's metric families in Prometheus text format
+    pub fn render_prometheus(&self, out: &mut String) {
+        write_metric(out, "http_response_cache_hits_total", "counter", "Requests answered from the response cache", self.hits());
+        write_metric(
+            out,
+            "http_response_cache_misses_total",
+            "counter",
+            "Cacheable requests that were passed to the handler",
+            self.misses(),
+        );
+        write_header(out, "http_response_cache_hit_ratio", "gauge", "Share of cacheable requests answered from the cache");
+        let _ = writeln!(out, "http_response_cache_hit_ratio {}\n", self.hit_ratio());
+        write_metric(out, "http_response_cache_entries", "gauge", "Responses stored in the response cache", self.len() as u64);
+        let bytes = self.state.lock().unwrap().total_bytes;
+        write_metric(out, "http_response_cache_bytes", "gauge", "Body bytes stored in the response cache", bytes as u64);
+    }
+
+    /// The cache's metrics as JSON
+    pub fn render_json(&self) -> Value {
+        json!({
+            "hits": self.hits(),
+            "misses": self.misses(),
+            "hit_ratio": self.hit_ratio(),
+            "entries": self.len(),
+            "bytes": self.state.lock().unwrap().total_bytes,
+        })
+    }
+}
+
+/// Only plain GETs are shared; a request with credentials may get a response
+/// meant for that user alone
+fn is_cacheable_request(request: &HttpRequest) -> bool {
+    request.method == HttpMethod::GET && request.get_header("authorization").is_none()
+}
+
+/// Whether the client asked for a response straight from the handler
+fn forces_reload(request: &HttpRequest) -> bool {
+    let directives = request.headers.combined("cache-control").unwrap_or_default().to_ascii_lowercase();
+    directives.split(',').any(|d| matches!(d.trim(), "no-cache" | "no-store"))
+        || request.get_header("pragma").is_some_and(|value| value.eq_ignore_ascii_case("no-cache"))
+}
+
+/// How long a response may be served from the cache, if it may be stored at all
+fn freshness(response: &HttpResponse) -> Option<Duration> {
+    if response.status() != StatusCode::Ok || response.get_header("set-cookie").is_some() {
+        return None;
+    }
+    if vary_fields(response).iter().any(|name| name == "*") {
+        return None;
+    }
+    let directives: Vec<String> = response
+        .header_values("cache-control")
+        .flat_map(|value| value.split(','))
+        .map(|d| d.trim().to_ascii_lowercase())
+        .collect();
+    if directives.iter().any(|d| matches!(d.as_str(), "no-store" | "no-cache" | "private")) {
+        return None;
+    }
+    let seconds = |name: &str| {
+        directives
+            .iter()
+            .find_map(|d| d.strip_prefix(name)?.strip_prefix('=')?.trim_matches('"').parse::<u64>().ok())
+    };
+    // s-maxage is meant for shared caches like this one and takes precedence
+    let max_age = seconds("s-maxage").or_else(|| seconds("max-age"))?;
+    (max_age > 0).then(|| Duration::from_secs(max_age))
+}
+
+/// Lowercased request header names listed in the response's Vary header
+fn vary_fields(response: &HttpResponse) -> Vec<String> {
+    response
+        .header_values("vary")
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+fn header_value(request: &HttpRequest, name: &str) -> Option<String> {
+    request.headers.combined(name).map(|value| value.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_freshness_follows_cache_control() {
+        let with = |cache_control: &str| HttpResponse::ok().header("Cache-Control", cache_control);
+        assert_eq!(freshness(&with("public, max-age=60")), Some(Duration::from_secs(60)));
+        assert_eq!(freshness(&with("max-age=60, s-maxage=5")), Some(Duration::from_secs(5)));
+        assert_eq!(freshness(&with("max-age=0")), None);
+        assert_eq!(freshness(&with("private, max-age=60")), None);
+        assert_eq!(freshness(&with("no-store")), None);
+        assert_eq!(freshness(&HttpResponse::ok()), None);
+        assert_eq!(freshness(&with("max-age=60").vary("*")), None);
+        assert_eq!(freshness(&with("max-age=60").header("Set-Cookie", "a=1")), None);
+        assert_eq!(freshness(&HttpResponse::not_found().header("Cache-Control", "max-age=60")), None);
+    }
+
+    #[test]
+    fn test_purge_and_eviction() {
+        let cache = ResponseCache::new(10);
+        let request = HttpRequest::parse(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).unwrap();
+        let response = || HttpResponse::ok().body("abcd");
+        let hour = Duration::from_secs(3600);
+
+        cache.insert("/a".to_string(), &request, response(), hour);
+        cache.insert("/b".to_string(), &request, response(), hour);
+        assert!(cache.get("/a", &request).is_some());
+        cache.insert("/c".to_string(), &request, response(), hour);
+        assert!(cache.get("/b", &request).is_none());
+        assert_eq!(cache.len(), 2);
+
+        cache.insert("/api/x".to_string(), &request, response(), hour);
+        assert_eq!(cache.purge("/api/"), 1);
+        assert_eq!(cache.purge(""), 1);
+        assert!(cache.is_empty());
+        assert_eq!(cache.render_json()["bytes"], 0);
+    }
+}
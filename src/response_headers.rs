@@ -0,0 +1,107 @@
+//! Static headers added to responses by path
+//!
+//! `--response-header '/assets/*=Access-Control-Allow-Origin: *'` adds the
+//! header to every response under `/assets/`. A pattern is an exact path, or
+//! a prefix followed by `*`; a lone `*` matches every path. Headers a handler
+//! sets itself are left alone, so rules only fill in defaults.
+
+use crate::error::Result;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::router::Next;
+
+/// One `PATTERN=Name: value` rule
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeaderRule {
+    pub pattern: String,
+    pub name: String,
+    pub value: String,
+}
+
+impl HeaderRule {
+    /// Parse `PATTERN=Name: value`
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let invalid = || format!("Response header rules take PATTERN=Name: value, got '{}'", spec);
+        let (pattern, header) = spec.split_once('=').ok_or_else(invalid)?;
+        let (name, value) = header.split_once(':').ok_or_else(invalid)?;
+        let name = name.trim();
+        let valid_name = !name.is_empty() && name.bytes().all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if !(pattern.starts_with('/') || pattern == "*") || !valid_name || value.contains(['\r', '\n']) {
+            return Err(invalid());
+        }
+        Ok(HeaderRule {
+            pattern: pattern.to_string(),
+            name: name.to_string(),
+            value: value.trim().to_string(),
+        })
+    }
+
+    pub fn matches(&self, path: &str) -> bool {
+        path_matches(&self.pattern, path)
+    }
+}
+
+/// Whether `path` matches `pattern`: an exact path, or a prefix followed by `*`
+pub fn path_matches(pattern: &str, path: &str) -> bool {
+    match pattern.strip_suffix('*') {
+        Some(prefix) => path.starts_with(prefix),
+        None => path == pattern,
+    }
+}
+
+/// Middleware adding the headers of every rule matching the request path
+#[derive(Debug, Clone, Default)]
+pub struct ResponseHeaders {
+    pub rules: Vec<HeaderRule>,
+}
+
+impl ResponseHeaders {
+    /// Parse rules given as `PATTERN=Name: value`
+    pub fn parse(specs: &[String]) -> std::result::Result<Self, String> {
+        let rules = specs.iter().map(|spec| HeaderRule::parse(spec)).collect::<std::result::Result<_, _>>()?;
+        Ok(ResponseHeaders { rules })
+    }
+
+    /// Add a rule
+    pub fn rule(mut self, pattern: &str, name: &str, value: &str) -> Self {
+        self.rules.push(HeaderRule {
+            pattern: pattern.to_string(),
+            name: name.to_string(),
+            value: value.to_string(),
+        });
+        self
+    }
+
+    pub fn handle(&self, request: &HttpRequest, next: Next<'_>) -> Result<HttpResponse> {
+        let mut response = next.run(request)?;
+        let path = request.path.split('?').next().unwrap_or_default();
+        // Decided before any rule applies, so rules can add several values
+        let set_by_handler: Vec<bool> = self.rules.iter().map(|rule| response.get_header(&rule.name).is_some()).collect();
+        for (rule, set) in self.rules.iter().zip(set_by_handler) {
+            if !set && rule.matches(path) {
+                response = response.append_header(rule.name.clone(), rule.value.clone());
+            }
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_match_rules() {
+        let rule = HeaderRule::parse("/assets/*=Cache-Control: public, max-age=3600").unwrap();
+        assert_eq!((rule.name.as_str(), rule.value.as_str()), ("Cache-Control", "public, max-age=3600"));
+        assert!(rule.matches("/assets/app.js"));
+        assert!(!rule.matches("/assets"));
+        assert!(HeaderRule::parse("/private=X-Robots-Tag: noindex").unwrap().matches("/private"));
+        assert!(!HeaderRule::parse("/private=X-Robots-Tag: noindex").unwrap().matches("/private/x"));
+        assert!(HeaderRule::parse("*=X-Frame-Options: DENY").unwrap().matches("/anything"));
+
+        for spec in ["/assets/*", "/a=NoColon", "assets=X: y", "/a=Bad Name: y", "/a=X: y\r\nInjected: z"] {
+            assert!(HeaderRule::parse(spec).is_err(), "{}", spec);
+        }
+    }
+}
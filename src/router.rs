@@ -1,25 +1,925 @@
-use crate::compression::Compression;
+use crate::audit::{AuditLog, FileSize};
+use crate::build_info;
+use crate::cache::CompressedCache;
+use crate::cgi::Cgi;
+use crate::circuit_breaker::{self, CircuitBreaker};
+use crate::compression::{self, Compression, CompressionPolicy};
+use crate::conditional::{self, EtagStrategy, FileValidators, Validators};
+use crate::config::Config;
+use crate::cors::Cors;
+use crate::dashboard;
+use crate::digest::DigestIndex;
 use crate::error::{Result, ServerError};
+use crate::extensions::Extensions;
+use crate::extract::{FromRequest, Query};
+use crate::fastcgi::{FastCgi, FastCgiAddress};
+use crate::health::{self, HealthStatus, Readiness};
+use crate::kv::Kv;
+use crate::live_reload::{self, LiveReload};
+use crate::metrics::ServerMetrics;
+use crate::openapi;
+use crate::precompress;
+use crate::problem::Problem;
+use crate::range::{self, RangeRequest};
 use crate::request::{HttpMethod, HttpRequest};
 use crate::response::HttpResponse;
+use crate::response_cache::ResponseCache;
+use crate::response_headers::ResponseHeaders;
+use crate::status::StatusCode;
+use crate::template;
+use crate::timeout::{AbandonedHandlers, RouteTimeouts, DEFAULT_MAX_ABANDONED};
+use crate::watch::{Change, FileWatcher};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use regex::Regex;
+use serde::Deserialize;
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::sync::atomic::Ordering;
-use std::sync::Arc;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// Which listener a connection was accepted on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Listener {
+    Public,
+    Admin,
+    /// The public listener, for a connection that opened with a request for
+    /// an operational endpoint while the worker pool was saturated; it is
+    /// served on the probe lane, one request per connection
+    Probe,
+}
+
+/// What the built-in `/files/` endpoints allow
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileAccess {
+    /// Download, upload, and delete
+    ReadWrite,
+    /// Download only; uploads and deletes get 405 Method Not Allowed
+    ReadOnly,
+    /// No file endpoints; `/files/` paths are unmatched
+    Disabled,
+}
+
+impl FileAccess {
+    /// The access `--read-only` and `--disable-files` select
+    pub fn from_config(config: &Config) -> Self {
+        if config.disable_files {
+            FileAccess::Disabled
+        } else if config.read_only {
+            FileAccess::ReadOnly
+        } else {
+            FileAccess::ReadWrite
+        }
+    }
+}
+
+/// Name of the server in `/api/info` and the title of `/openapi.json`
+const API_TITLE: &str = "Rust HTTP Server";
+
+/// Methods [`Router::any`] registers a handler for
+const GATEWAY_METHODS: [HttpMethod; 6] =
+    [HttpMethod::GET, HttpMethod::HEAD, HttpMethod::POST, HttpMethod::PUT, HttpMethod::PATCH, HttpMethod::DELETE];
+
+/// A synchronous request handler
+///
+/// Implemented for any `Fn(&HttpRequest) -> Result<HttpResponse>` that can be
+/// shared across worker threads.
+pub trait Handler: Send + Sync + 'static {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse>;
+
+    /// Whether the body should be left on the connection for the handler to
+    /// read with [`HttpRequest::body_reader`]; see [`streaming`]
+    fn streams_body(&self) -> bool {
+        false
+    }
+
+    /// Add what the handler knows about its requests and responses to the
+    /// OpenAPI operation of its routes; see [`openapi`](crate::openapi)
+    fn describe(&self, _operation: &mut serde_json::Value) {}
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&HttpRequest) -> Result<HttpResponse> + Send + Sync + 'static,
+{
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        self(request)
+    }
+}
+
+/// Mark a handler as reading its body with [`HttpRequest::body_reader`],
+/// so large uploads are copied in chunks instead of buffered first
+///
+/// ```
+/// use http_server::request::HttpRequest;
+/// use http_server::response::HttpResponse;
+/// use http_server::router::{streaming, Router};
+/// use std::io;
+///
+/// let router = Router::empty().post("/upload", streaming(|request: &HttpRequest| {
+///     let size = io::copy(&mut request.body_reader(), &mut io::sink())?;
+///     Ok(HttpResponse::ok().body(size.to_string()))
+/// }));
+/// ```
+///
+/// Only the threaded runtime reads the body straight from the socket; the
+/// tokio and io_uring runtimes buffer it and the reader reads from memory.
+pub fn streaming(handler: impl Handler) -> impl Handler {
+    Streaming(handler)
+}
+
+struct Streaming<H>(H);
+
+impl<H: Handler> Handler for Streaming<H> {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        self.0.call(request)
+    }
+
+    fn streams_body(&self) -> bool {
+        true
+    }
+
+    fn describe(&self, operation: &mut serde_json::Value) {
+        self.0.describe(operation);
+    }
+}
+
+/// Code that runs around handlers: authentication, logging, headers, ...
+///
+/// Implemented for any `Fn(&HttpRequest, Next) -> Result<HttpResponse>`.
+/// Call `next.run(request)` to continue to the handler, or return a response
+/// without calling it to short-circuit.
+pub trait Middleware: Send + Sync + 'static {
+    fn call(&self, request: &HttpRequest, next: Next<'_>) -> Result<HttpResponse>;
+}
+
+impl<F> Middleware for F
+where
+    F: Fn(&HttpRequest, Next<'_>) -> Result<HttpResponse> + Send + Sync + 'static,
+{
+    fn call(&self, request: &HttpRequest, next: Next<'_>) -> Result<HttpResponse> {
+        self(request, next)
+    }
+}
+
+/// The rest of the middleware chain, ending in the handler
+pub struct Next<'a> {
+    middleware: &'a [Arc<dyn Middleware>],
+    endpoint: &'a dyn Fn(&HttpRequest) -> Result<HttpResponse>,
+}
+
+impl Next<'_> {
+    /// Run the remaining middleware and the handler
+    pub fn run(self, request: &HttpRequest) -> Result<HttpResponse> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.call(request, Next { middleware: rest, endpoint: self.endpoint }),
+            None => (self.endpoint)(request),
+        }
+    }
+}
+
+/// The registered route a request matched, filled in by the router
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouteMatch {
+    /// The pattern as registered, including any mount prefixes
+    pub pattern: &'static str,
+    /// Values of the pattern's `{name}` segments, in order
+    pub params: Vec<(String, String)>,
+}
+
+/// One segment of a route pattern
+#[derive(Debug, Clone)]
+enum Segment {
+    Literal(String),
+    /// `{name}` or `{name:regex}`; the regex must match the whole segment
+    Param(String, Option<Regex>),
+    /// `*name`, the rest of the path; only valid as the last segment
+    CatchAll(String),
+}
+
+impl Segment {
+    fn parse(segment: &str, pattern: &str) -> Self {
+        if let Some(param) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) {
+            let (name, constraint) = match param.split_once(':') {
+                Some((name, regex)) => {
+                    let regex = Regex::new(&format!("^(?:{})$", regex))
+                        .unwrap_or_else(|e| panic!("route pattern {:?} has an invalid constraint: {}", pattern, e));
+                    (name, Some(regex))
+                }
+                None => (param, None),
+            };
+            assert!(!name.is_empty(), "route pattern {:?} has an unnamed parameter", pattern);
+            return Segment::Param(name.to_string(), constraint);
+        }
+        if let Some(name) = segment.strip_prefix('*') {
+            assert!(!name.is_empty(), "route pattern {:?} has an unnamed wildcard", pattern);
+            return Segment::CatchAll(name.to_string());
+        }
+        assert!(
+            !segment.contains(['{', '}']),
+            "route pattern {:?}: parameters must span a whole segment",
+            pattern
+        );
+        Segment::Literal(segment.to_string())
+    }
+}
+
+/// Conditions on a request's headers and query string, which routes mounted
+/// with [`Router::mount_when`] require besides their path, e.g. to send
+/// `X-API-Version: 2` or `?beta=1` to other handlers than other requests
+///
+/// Every condition must hold. Header names are case-insensitive; values and
+/// query parameters are compared exactly, after percent-decoding the query.
+///
+/// A guard can also take a share of the clients, for a canary release: each
+/// client falls in one of 100 buckets by a hash of its address or a cookie,
+/// so it stays on the same side of the split from request to request.
+#[derive(Debug, Clone, Default)]
+pub struct Guard {
+    predicates: Vec<Predicate>,
+}
+
+#[derive(Debug, Clone)]
+enum Predicate {
+    /// A header, with the value if given
+    Header(String, Option<String>),
+    /// A query parameter, with the value if given
+    Query(String, Option<String>),
+    /// Clients whose bucket is in the range
+    Bucket(std::ops::Range<u32>, SplitKey),
+}
+
+/// What places a client in a bucket of a traffic split
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SplitKey {
+    /// The address of the connection, which is the proxy's for clients
+    /// behind one
+    ClientIp,
+    /// The value of the named cookie, or the address for clients without it
+    Cookie(String),
+}
+
+impl SplitKey {
+    /// The client's bucket, 0 to 99
+    fn bucket(&self, request: &HttpRequest) -> u32 {
+        let cookie = match self {
+            SplitKey::Cookie(name) => request.headers.combined("cookie").and_then(|header| {
+                let mut cookies = header.split(';').filter_map(|cookie| cookie.trim().split_once('='));
+                cookies.find(|(cookie, _)| cookie == name).map(|(_, value)| value.to_string())
+            }),
+            SplitKey::ClientIp => None,
+        };
+        let key = cookie.unwrap_or_else(|| request.peer_addr.map(|peer| peer.ip().to_string()).unwrap_or_default());
+        // A fixed hash, so every process and restart splits the same way
+        let digest = Sha256::digest(key.as_bytes());
+        let hash = u64::from_be_bytes(digest[..8].try_into().unwrap_or_default());
+        (hash % 100) as u32
+    }
+}
+
+impl Guard {
+    pub fn new() -> Self {
+        Guard::default()
+    }
+
+    /// Require header `name` with `value`
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.predicates.push(Predicate::Header(name.to_string(), Some(value.to_string())));
+        self
+    }
+
+    /// Require header `name`, with any value
+    pub fn has_header(mut self, name: &str) -> Self {
+        self.predicates.push(Predicate::Header(name.to_string(), None));
+        self
+    }
+
+    /// Require query parameter `name` with `value`
+    pub fn query(mut self, name: &str, value: &str) -> Self {
+        self.predicates.push(Predicate::Query(name.to_string(), Some(value.to_string())));
+        self
+    }
+
+    /// Require query parameter `name`, with any value
+    pub fn has_query(mut self, name: &str) -> Self {
+        self.predicates.push(Predicate::Query(name.to_string(), None));
+        self
+    }
+
+    /// Require the client's bucket by `key`, from 0 to 99, to be in
+    /// `buckets`; `0..5` takes 5% of the clients, and `5..10` another 5%
+    pub fn bucket(mut self, buckets: std::ops::Range<u32>, key: SplitKey) -> Self {
+        self.predicates.push(Predicate::Bucket(buckets, key));
+        self
+    }
+
+    /// Whether `request` meets every condition
+    pub fn allows(&self, request: &HttpRequest) -> bool {
+        let mut query: Option<Vec<(String, String)>> = None;
+        self.predicates.iter().all(|predicate| match predicate {
+            Predicate::Header(name, value) => {
+                request.get_header(name).is_some_and(|actual| value.as_ref().map_or(true, |value| actual == value))
+            }
+            Predicate::Query(name, value) => {
+                let query = query.get_or_insert_with(|| {
+                    let query = request.path.split_once('?').map_or("", |(_, query)| query);
+                    serde_urlencoded::from_str(query).unwrap_or_default()
+                });
+                query.iter().any(|(key, actual)| key == name && value.as_ref().map_or(true, |value| actual == value))
+            }
+            Predicate::Bucket(buckets, key) => buckets.contains(&key.bucket(request)),
+        })
+    }
+}
+
+/// A handler registered for a method and path pattern
+struct Route {
+    method: HttpMethod,
+    // Leaked once at registration so it can label metrics like built-in routes
+    pattern: &'static str,
+    segments: Vec<Segment>,
+    handler: Arc<dyn Handler>,
+    // Middleware of the groups the route was mounted through, outermost first
+    middleware: Vec<Arc<dyn Middleware>>,
+    // Conditions of the groups the route was mounted through
+    guard: Guard,
+}
+
+impl Route {
+    fn new(
+        method: HttpMethod,
+        pattern: &str,
+        handler: Arc<dyn Handler>,
+        middleware: Vec<Arc<dyn Middleware>>,
+        guard: Guard,
+    ) -> Self {
+        assert!(pattern.starts_with('/'), "route pattern {:?} must start with '/'", pattern);
+        let segments: Vec<Segment> = pattern.split('/').map(|segment| Segment::parse(segment, pattern)).collect();
+        assert!(
+            !segments[..segments.len() - 1].iter().any(|segment| matches!(segment, Segment::CatchAll(_))),
+            "route pattern {:?}: a wildcard must be the last segment",
+            pattern
+        );
+        Route {
+            method,
+            pattern: Box::leak(pattern.to_string().into_boxed_str()),
+            segments,
+            handler,
+            middleware,
+            guard,
+        }
+    }
+
+    /// Parameter values if `request` meets the guard and its `path` matches
+    /// the pattern
+    fn accepts(&self, request: &HttpRequest, path: &str) -> Option<Vec<(String, String)>> {
+        if !self.guard.allows(request) {
+            return None;
+        }
+        self.matches(path)
+    }
+
+    /// Parameter values if `path` matches the pattern
+    fn matches(&self, path: &str) -> Option<Vec<(String, String)>> {
+        let mut params = Vec::new();
+        let mut rest = Some(path);
+        for segment in &self.segments {
+            let current = rest?;
+            let (part, remaining) = match current.split_once('/') {
+                Some((part, remaining)) => (part, Some(remaining)),
+                None => (current, None),
+            };
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Param(name, constraint)
+                    if !part.is_empty() && constraint.as_ref().map_or(true, |regex| regex.is_match(part)) =>
+                {
+                    params.push((name.clone(), part.to_string()));
+                }
+                Segment::CatchAll(name) if !current.is_empty() => {
+                    params.push((name.clone(), current.to_string()));
+                    return Some(params);
+                }
+                _ => return None,
+            }
+            rest = remaining;
+        }
+        // Every part of the path must be consumed
+        rest.is_none().then_some(params)
+    }
+}
 
 /// Router handles incoming requests and generates responses
+///
+/// Routes registered with [`get`](Self::get), [`post`](Self::post), etc. are
+/// tried before the built-in endpoints, so they can also replace them.
 pub struct Router {
-    pub file_directory: String,
+    file_directory: Arc<RwLock<PathBuf>>,
+    pub readiness: Readiness,
+    compressed_cache: Arc<CompressedCache>,
+    file_watcher: Option<FileWatcher>,
+    live_reload: Option<Arc<LiveReload>>,
+    compression: CompressionPolicy,
+    digests: Option<Arc<DigestIndex>>,
+    validators: Arc<FileValidators>,
+    /// Whether the validators of the whole directory are kept ready
+    file_index: bool,
+    separate_admin: bool,
+    admin_token: Option<String>,
+    /// Whether the testing endpoints are served
+    debug_endpoints: bool,
+    max_delay: Duration,
+    timeouts: RouteTimeouts,
+    abandoned: Arc<AbandonedHandlers>,
+    health_min_free: u64,
+    file_access: FileAccess,
+    builtins: bool,
+    routes: Vec<Route>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    state: Arc<Extensions>,
+    response_cache: Option<Arc<ResponseCache>>,
+    circuit_breakers: Vec<Arc<CircuitBreaker>>,
+    fallback: Option<Box<dyn Handler>>,
 }
 
 impl Router {
-    pub fn new(file_directory: String) -> Self {
-        Router { file_directory }
+    pub fn new(config: &Config) -> Self {
+        let router = Router {
+            file_directory: Arc::new(RwLock::new(PathBuf::from(&config.directory))),
+            readiness: Readiness::default(),
+            compressed_cache: Arc::new(CompressedCache::new(config.compression_cache_mb * 1024 * 1024)),
+            file_watcher: None,
+            live_reload: None,
+            compression: config.compression_policy(),
+            digests: (!config.file_digests.is_empty()).then(|| Arc::new(DigestIndex::new(&config.file_digests))),
+            validators: Arc::new(FileValidators::new(config.etag)),
+            file_index: false,
+            separate_admin: config.admin_addr.is_some(),
+            admin_token: config.admin_token.clone(),
+            debug_endpoints: config.debug_endpoints,
+            max_delay: Duration::from_secs(config.max_delay_secs),
+            // The rules were checked by Config::validate
+            timeouts: RouteTimeouts::parse(config.handler_timeout_secs, &config.route_timeout).unwrap_or_default(),
+            abandoned: Arc::new(AbandonedHandlers::new(config.max_abandoned_handlers)),
+            health_min_free: config.health_min_free_mb * 1024 * 1024,
+            file_access: FileAccess::from_config(config),
+            builtins: true,
+            routes: Vec::new(),
+            middleware: Vec::new(),
+            state: Arc::default(),
+            response_cache: None,
+            circuit_breakers: Vec::new(),
+            fallback: None,
+        };
+        // The rules were checked by Config::validate
+        let router = match ResponseHeaders::parse(&config.response_header) {
+            Ok(headers) if !headers.rules.is_empty() => router.response_headers(headers),
+            _ => router,
+        };
+        // The policies were checked by Config::validate
+        let router = match config.cors() {
+            Ok(cors) if !cors.rules.is_empty() => router.cors(cors),
+            _ => router,
+        };
+        let router = match (config.watch, config.watch_files) {
+            (true, _) => router.live_reload(),
+            (false, true) => router.watch_files(),
+            (false, false) => router,
+        };
+        let router = if config.file_index { router.index_files() } else { router };
+        let router = match &config.cgi_dir {
+            Some(directory) => {
+                let timeout = Duration::from_secs(config.cgi_timeout_secs);
+                router.cgi(Cgi::new(&config.cgi_prefix, directory, timeout))
+            }
+            None => router,
+        };
+        let router = match &config.kv_dir {
+            Some(directory) => match Kv::open(&config.kv_prefix, directory) {
+                Ok(kv) => router.kv(kv),
+                Err(e) => {
+                    log::error!("Cannot open the key-value store in {}: {}", directory, e);
+                    router
+                }
+            },
+            None => router,
+        };
+        // The addresses were checked by Config::validate
+        match config.fastcgi_addr.as_deref().map(FastCgiAddress::parse) {
+            Some(Ok(address)) => {
+                let root = config.fastcgi_root.as_deref().unwrap_or(&config.directory);
+                let timeout = Duration::from_secs(config.fastcgi_timeout_secs);
+                let fastcgi = FastCgi::new(&config.fastcgi_prefix, address, root, timeout).retries(config.fastcgi_retries);
+                let fastcgi = match config.fastcgi_shadow_addr.as_deref().map(FastCgiAddress::parse) {
+                    Some(Ok(shadow)) => fastcgi.shadow(shadow, config.fastcgi_shadow_percent),
+                    _ => fastcgi,
+                };
+                let breaker = |fastcgi: FastCgi| match config.fastcgi_breaker_percent {
+                    0 => fastcgi,
+                    percent => fastcgi.breaker(percent, Duration::from_secs(config.fastcgi_breaker_cooldown_secs)),
+                };
+                let router = match config.fastcgi_canary_addr.as_deref().map(FastCgiAddress::parse) {
+                    Some(Ok(canary)) => {
+                        let canary = FastCgi::new(&config.fastcgi_prefix, canary, root, timeout);
+                        let canary = canary.retries(config.fastcgi_retries);
+                        let key = match &config.fastcgi_canary_cookie {
+                            Some(cookie) => SplitKey::Cookie(cookie.clone()),
+                            None => SplitKey::ClientIp,
+                        };
+                        let guard = Guard::new().bucket(0..config.fastcgi_canary_percent, key);
+                        router.mount_when("", guard, Router::empty().fastcgi(breaker(canary)))
+                    }
+                    _ => router,
+                };
+                router.fastcgi(breaker(fastcgi))
+            }
+            _ => router,
+        }
+    }
+
+    /// A router without the built-in endpoints, for grouping routes to
+    /// [`mount`](Self::mount) or serving only registered routes
+    pub fn empty() -> Self {
+        Router {
+            file_directory: Arc::default(),
+            readiness: Readiness::default(),
+            compressed_cache: Arc::new(CompressedCache::new(0)),
+            file_watcher: None,
+            live_reload: None,
+            compression: CompressionPolicy::default(),
+            digests: None,
+            validators: Arc::new(FileValidators::new(EtagStrategy::Strong)),
+            file_index: false,
+            separate_admin: false,
+            admin_token: None,
+            debug_endpoints: false,
+            max_delay: Duration::from_secs(10),
+            timeouts: RouteTimeouts::default(),
+            abandoned: Arc::new(AbandonedHandlers::new(DEFAULT_MAX_ABANDONED)),
+            health_min_free: 0,
+            file_access: FileAccess::Disabled,
+            builtins: false,
+            routes: Vec::new(),
+            middleware: Vec::new(),
+            state: Arc::default(),
+            response_cache: None,
+            circuit_breakers: Vec::new(),
+            fallback: None,
+        }
+    }
+
+    /// Register a handler for `method` on a path pattern such as
+    /// `/users/{id}`; `{name}` segments match any non-empty segment and are
+    /// available from [`HttpRequest::param`]
+    ///
+    /// `{name:regex}` only matches segments the regex matches in full, e.g.
+    /// `{id:[0-9]+}`, and a final `*name` segment matches the rest of the
+    /// path, e.g. `/static/*path`. Routes are tried in registration order.
+    ///
+    /// # Panics
+    ///
+    /// If the pattern doesn't start with `/`, has a malformed parameter or
+    /// constraint, or has a wildcard before the last segment.
+    pub fn add_route(mut self, method: HttpMethod, pattern: &str, handler: impl Handler) -> Self {
+        self.routes.push(Route::new(method, pattern, Arc::new(handler), Vec::new(), Guard::default()));
+        self
+    }
+
+    pub fn get(self, pattern: &str, handler: impl Handler) -> Self {
+        self.add_route(HttpMethod::GET, pattern, handler)
+    }
+
+    pub fn post(self, pattern: &str, handler: impl Handler) -> Self {
+        self.add_route(HttpMethod::POST, pattern, handler)
+    }
+
+    pub fn put(self, pattern: &str, handler: impl Handler) -> Self {
+        self.add_route(HttpMethod::PUT, pattern, handler)
+    }
+
+    pub fn patch(self, pattern: &str, handler: impl Handler) -> Self {
+        self.add_route(HttpMethod::PATCH, pattern, handler)
+    }
+
+    pub fn delete(self, pattern: &str, handler: impl Handler) -> Self {
+        self.add_route(HttpMethod::DELETE, pattern, handler)
+    }
+
+    /// Register `handler` for GET, HEAD, POST, PUT, PATCH, and DELETE
+    pub fn any(mut self, pattern: &str, handler: impl Handler) -> Self {
+        let handler: Arc<dyn Handler> = Arc::new(handler);
+        for method in GATEWAY_METHODS {
+            self.routes.push(Route::new(method, pattern, Arc::clone(&handler), Vec::new(), Guard::default()));
+        }
+        self
+    }
+
+    /// An OpenAPI document describing the registered routes, including
+    /// mounted ones; see [`openapi`]
+    pub fn openapi(&self, title: &str) -> serde_json::Value {
+        let routes = self.routes.iter().map(|route| (&route.method, route.pattern, &*route.handler));
+        openapi::document(title, routes)
+    }
+
+    /// Run `middleware` around every request this router handles; on a
+    /// mounted router, around the requests for its routes only. Middleware
+    /// added first runs first.
+    pub fn layer(mut self, middleware: impl Middleware) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Serve `router`'s routes under `prefix`, e.g. `/api/v1` + `/users` at
+    /// `/api/v1/users`, wrapped in its middleware
+    ///
+    /// Only registered routes are mounted; the mounted router's built-in
+    /// endpoints and fallback are not.
+    pub fn mount(self, prefix: &str, router: Router) -> Self {
+        self.mount_when(prefix, Guard::new(), router)
+    }
+
+    /// [`mount`](Self::mount) `router`, its routes only matching requests
+    /// that meet `guard`; others fall through to the routes after them, e.g.
+    /// `mount_when("/api", Guard::new().header("X-API-Version", "2"), v2)`
+    /// before `mount("/api", v1)`
+    pub fn mount_when(mut self, prefix: &str, guard: Guard, router: Router) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        assert!(
+            prefix.is_empty() || prefix.starts_with('/'),
+            "mount prefix {:?} must start with '/'",
+            prefix
+        );
+        for route in router.routes {
+            let pattern = match route.pattern {
+                "/" if !prefix.is_empty() => prefix.to_string(),
+                pattern => format!("{}{}", prefix, pattern),
+            };
+            let middleware = router.middleware.iter().cloned().chain(route.middleware).collect();
+            let mut route_guard = guard.clone();
+            route_guard.predicates.extend(route.guard.predicates);
+            self.routes.push(Route::new(route.method, &pattern, route.handler, middleware, route_guard));
+        }
+        Arc::make_mut(&mut self.state).extend(&router.state);
+        if self.response_cache.is_none() {
+            self.response_cache = router.response_cache;
+        }
+        self.circuit_breakers.extend(router.circuit_breakers);
+        self
+    }
+
+    /// Share `value` with every handler and middleware, which read it with
+    /// [`HttpRequest::state`]; one value per type, so wrap values in
+    /// newtypes to share several of the same type
+    ///
+    /// State of a mounted router is merged into the parent's; where both
+    /// hold a value of the same type, the parent's is used.
+    pub fn with_state<T: Send + Sync + 'static>(mut self, value: T) -> Self {
+        Arc::make_mut(&mut self.state).insert(value);
+        self
+    }
+
+    /// Run CGI scripts for every request under the [`Cgi`] prefix
+    pub fn cgi(self, cgi: Cgi) -> Self {
+        self.any(&cgi.pattern(), cgi)
+    }
+
+    /// Pass every request under the [`FastCgi`] prefix to its application,
+    /// including the prefix itself, which runs the index script
+    pub fn fastcgi(mut self, fastcgi: FastCgi) -> Self {
+        self.circuit_breakers.extend(fastcgi.circuit_breakers());
+        let index = format!("{}/", fastcgi.prefix);
+        self.any(&fastcgi.pattern(), fastcgi.clone()).any(&index, fastcgi)
+    }
+
+    /// Serve the [`Kv`] store's keys under its prefix, and the list of keys
+    /// at the prefix itself
+    pub fn kv(self, kv: Kv) -> Self {
+        let pattern = kv.pattern();
+        self.get(&kv.prefix.clone(), kv.clone())
+            .get(&pattern, kv.clone())
+            .put(&pattern, kv.clone())
+            .delete(&pattern, kv)
+    }
+
+    /// Give up on handlers that run too long, answering 504; see
+    /// [`RouteTimeouts`]. Enforced by the server runtimes, which can abandon a
+    /// handler, not by [`route`](Self::route) itself
+    pub fn timeouts(mut self, timeouts: RouteTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// How long the handler for `request` may run, if it is limited
+    pub fn timeout_for(&self, request: &HttpRequest) -> Option<Duration> {
+        self.timeouts.timeout_for(&request.path)
+    }
+
+    /// Handlers the runtimes abandoned after they timed out
+    pub fn abandoned_handlers(&self) -> &Arc<AbandonedHandlers> {
+        &self.abandoned
+    }
+
+    /// Cache successful GET responses whose handlers allow it with
+    /// `Cache-Control: max-age`, and answer repeats from the cache, see
+    /// [`ResponseCache`]
+    ///
+    /// The cache runs as middleware at this point in the [`layer`](Self::layer)
+    /// order. Its hit and miss counts are added to `/metrics`, and
+    /// `POST /admin/cache/purge` empties it. A mounted router's cache is
+    /// reported by the parent unless the parent has its own.
+    pub fn response_cache(mut self, cache: ResponseCache) -> Self {
+        let cache = Arc::new(cache);
+        self.response_cache = Some(Arc::clone(&cache));
+        self.layer(move |request: &HttpRequest, next: Next<'_>| cache.handle(request, next))
+    }
+
+    /// Add headers to responses by path, see [`ResponseHeaders`]
+    pub fn response_headers(self, headers: ResponseHeaders) -> Self {
+        self.layer(move |request: &HttpRequest, next: Next<'_>| headers.handle(request, next))
+    }
+
+    /// Record file uploads and deletions in `log`, see [`AuditLog`]
+    pub fn audit_log(self, log: AuditLog) -> Self {
+        self.layer(move |request: &HttpRequest, next: Next<'_>| log.handle(request, next))
+    }
+
+    /// Answer CORS preflights and allow cross-origin requests, see [`Cors`]
+    pub fn cors(self, cors: Cors) -> Self {
+        self.layer(move |request: &HttpRequest, next: Next<'_>| cors.handle(request, next))
+    }
+
+    /// Compress responses according to `policy`: the built-in endpoints
+    /// negotiate with it, and the routes of this router get it as
+    /// middleware. Mount a router with its own policy to configure a group
+    /// of routes differently, e.g. to leave already compressed downloads
+    /// alone or use a higher level for large JSON responses.
+    pub fn compression(mut self, policy: CompressionPolicy) -> Self {
+        self.compression = policy.clone();
+        self.layer(move |request: &HttpRequest, next: Next<'_>| policy.handle(request, next))
+    }
+
+    /// Handle requests that match no route with `handler` instead of the
+    /// plain 404, e.g. to serve a branded error page or a catch-all
+    pub fn fallback(mut self, handler: impl Handler) -> Self {
+        self.fallback = Some(Box::new(handler));
+        self
+    }
+
+    /// How the built-in file endpoints compute `ETag`s; defaults to
+    /// `--etag` for [`Router::new`] and strong tags otherwise
+    pub fn etags(self, strategy: EtagStrategy) -> Self {
+        self.validators.set_strategy(strategy);
+        if self.file_index {
+            self.index_directory();
+        }
+        self
+    }
+
+    /// Serve the testing endpoints, `/inspect`, `/anything`, `/status/{code}`,
+    /// and `/delay/{seconds}`; off by default
+    pub fn debug_endpoints(mut self, enabled: bool) -> Self {
+        self.debug_endpoints = enabled;
+        self
+    }
+
+    /// Restrict the built-in file endpoints, e.g. to serve public downloads
+    /// without accepting uploads
+    pub fn file_access(mut self, access: FileAccess) -> Self {
+        self.file_access = access;
+        self
+    }
+
+    /// Directory files are served from and uploaded to
+    pub fn file_directory(&self) -> PathBuf {
+        self.file_directory.read().unwrap().clone()
+    }
+
+    /// Switch the served directory; requests already in flight keep the old one
+    pub fn set_file_directory(&self, directory: &str) {
+        let directory = PathBuf::from(directory);
+        let previous = std::mem::replace(&mut *self.file_directory.write().unwrap(), directory.clone());
+        if let Some(watcher) = self.file_watcher.as_ref().filter(|_| previous != directory) {
+            let _ = watcher.unwatch(&previous);
+            if let Err(e) = watcher.watch(&directory) {
+                log::warn!("Not watching {} for changes: {}", directory.display(), e);
+            }
+            // Entries for the old directory are no longer invalidated
+            self.compressed_cache.invalidate(|_| true);
+            if self.file_index {
+                self.validators.invalidate(|_| true);
+                self.index_directory();
+            }
+        }
+    }
+
+    /// Watch the file directory for changes instead of checking each file's
+    /// mtime per request; cached compressed bodies and digests of a changed
+    /// file are dropped as soon as the change is reported
+    ///
+    /// If the watcher cannot be started, a warning is logged and the per
+    /// request checks stay in place.
+    pub fn watch_files(mut self) -> Self {
+        let cache = Arc::clone(&self.compressed_cache);
+        let digests = self.digests.clone();
+        let validators = Arc::clone(&self.validators);
+        let live_reload = self.live_reload.clone();
+        let file_directory = Arc::clone(&self.file_directory);
+        let started = FileWatcher::new(move |change| {
+            // The files endpoint only serves direct children of the directory
+            let matches = |cached: &Path| match &change {
+                Change::Path(changed) => cached.file_name() == changed.file_name(),
+                Change::Unknown => true,
+            };
+            cache.invalidate(matches);
+            validators.invalidate(matches);
+            let directory = file_directory.read().unwrap().clone();
+            let changed: Vec<PathBuf> = match &change {
+                Change::Path(changed) => changed.file_name().map(|name| directory.join(name)).into_iter().collect(),
+                Change::Unknown => fs::read_dir(&directory).into_iter().flatten().flatten().map(|e| e.path()).collect(),
+            };
+            // Uploads still being written are indexed once renamed into place
+            for path in changed.iter().filter(|path| !is_partial_upload(path)) {
+                validators.reindex(path);
+            }
+            if let Some(digests) = &digests {
+                digests.invalidate(matches);
+            }
+            if let Some(live_reload) = &live_reload {
+                live_reload.notify();
+            }
+        });
+        let directory = self.file_directory();
+        match started.and_then(|watcher| watcher.watch(&directory).map(|_| watcher)) {
+            Ok(watcher) => self.file_watcher = Some(watcher),
+            Err(e) => log::warn!("Not watching {} for changes, checking mtimes instead: {}", directory.display(), e),
+        }
+        self
+    }
+
+    /// Compute the `ETag` and `Last-Modified` of every file in the file
+    /// directory now, and recompute them as changes are reported, so file
+    /// requests are served without a stat or hashing the file
+    ///
+    /// Starts [`watch_files`](Self::watch_files) if it isn't running; if the
+    /// watcher cannot be started, files are checked per request as before.
+    pub fn index_files(mut self) -> Self {
+        if self.file_watcher.is_none() {
+            self = self.watch_files();
+        }
+        if self.file_watcher.is_some() {
+            self.file_index = true;
+            self.index_directory();
+        }
+        self
+    }
+
+    fn index_directory(&self) {
+        let directory = self.file_directory();
+        let started = Instant::now();
+        match self.validators.index(&directory) {
+            Ok(count) => log::info!("Indexed {} files in {} in {:?}", count, directory.display(), started.elapsed()),
+            Err(e) => log::warn!("Cannot index {}, checking files per request: {}", directory.display(), e),
+        }
+    }
+
+    /// The validators of a file about to be served; an indexed directory
+    /// has them ready, so only files missing from the index are looked at
+    fn served_validators(&self, filepath: &Path) -> io::Result<Validators> {
+        match self.validators.current(filepath).filter(|_| self.file_index) {
+            Some(validators) => Ok(validators),
+            None => self.validators.get(filepath),
+        }
+    }
+
+    /// Development mode: watch the file directory as with
+    /// [`watch_files`](Self::watch_files), add a script to HTML responses,
+    /// and serve the event stream it listens on at `/__livereload`, so pages
+    /// reload when a file changes; see [`live_reload`]
+    pub fn live_reload(mut self) -> Self {
+        let live_reload = Arc::new(LiveReload::new());
+        self.live_reload = Some(Arc::clone(&live_reload));
+        self.watch_files()
+            .layer(move |request: &HttpRequest, next: Next<'_>| live_reload.handle(request, next))
+    }
+
+    /// Whether a path is an operational endpoint (metrics, health, admin)
+    pub fn is_admin_path(path: &str) -> bool {
+        matches!(path, "/metrics" | "/health" | "/dashboard")
+            || path.starts_with("/health/")
+            || path.starts_with("/admin/")
     }
 
     /// Route an incoming request to the appropriate handler
-    pub fn route(&self, request: HttpRequest, metrics: &crate::ServerMetrics) -> Result<Vec<u8>> {
+    pub fn route(&self, request: &HttpRequest, metrics: &Arc<ServerMetrics>, listener: Listener) -> Result<HttpResponse> {
+        // With a separate admin listener, each listener only serves its own endpoints
+        if self.separate_admin && Self::is_admin_path(&request.path) != (listener == Listener::Admin) {
+            return Ok(HttpResponse::not_found());
+        }
+
         log::info!(
             "{} {} - {} bytes",
             request.method.as_str(),
@@ -27,9 +927,47 @@ impl Router {
             request.body.len()
         );
 
+        // Already set if the request was routed before
+        let _ = request.state.set(Arc::clone(&self.state));
+        let endpoint = |request: &HttpRequest| self.dispatch(request, metrics);
+        Next { middleware: &self.middleware, endpoint: &endpoint }.run(request)
+    }
+
+    /// Whether `request` goes to a handler that streams its body, so the
+    /// server should leave the body on the connection instead of reading it
+    pub fn streams_body(&self, request: &HttpRequest) -> bool {
+        let path = request.path.split('?').next().unwrap_or_default();
+        let mut routes = self.routes.iter().filter(|route| route.method == request.method);
+        if let Some(route) = routes.find(|route| route.accepts(request, path).is_some()) {
+            return route.handler.streams_body();
+        }
+        self.builtins
+            && matches!(request.method, HttpMethod::POST | HttpMethod::PUT)
+            && path.starts_with("/files/")
+            && self.file_access == FileAccess::ReadWrite
+    }
+
+    /// Run the matching registered route, built-in endpoint, or fallback
+    fn dispatch(&self, request: &HttpRequest, metrics: &Arc<ServerMetrics>) -> Result<HttpResponse> {
+        let path = request.path.split('?').next().unwrap_or_default();
+        for route in self.routes.iter().filter(|route| route.method == request.method) {
+            if let Some(params) = route.accepts(request, path) {
+                // Already set if the request was routed before; the match is the same
+                let _ = request.route.set(RouteMatch { pattern: route.pattern, params });
+                let endpoint = |request: &HttpRequest| route.handler.call(request);
+                return Next { middleware: &route.middleware, endpoint: &endpoint }.run(request);
+            }
+        }
+        if !self.builtins {
+            return self.unmatched(request);
+        }
+
         // Determine compression
-        let compression = if request.body.len() > 100 || request.path.starts_with("/echo/") {
-            Compression::from_accept_encoding(&request.get_accepted_encodings())
+        let compression = if request.body.len() > 100
+            || request.path.starts_with("/echo/")
+            || request.path.starts_with("/files/")
+        {
+            self.compression.negotiate(&request.get_accepted_encodings())
         } else {
             Compression::None
         };
@@ -37,149 +975,322 @@ impl Router {
         let response = match (&request.method, request.path.as_str()) {
             // Root endpoint
             (HttpMethod::GET, "/") | (HttpMethod::GET, "/index.html") => {
-                self.handle_index(&request)
+                self.handle_index(request)
             }
 
             // Health check endpoint with system stats
-            (HttpMethod::GET, "/health") => self.handle_health(&request, metrics),
+            (HttpMethod::GET, "/health") => self.handle_health(request, metrics),
+
+            // Liveness and readiness probes for orchestrators and load balancers
+            (HttpMethod::GET, "/health/live") => self.handle_liveness(request, metrics),
+            (HttpMethod::GET, "/health/ready") => self.handle_readiness(request),
 
             // Metrics endpoint (Prometheus-style)
-            (HttpMethod::GET, "/metrics") => self.handle_metrics(&request, metrics),
+            (HttpMethod::GET, "/metrics") => self.handle_metrics(request, metrics),
+
+            // Authenticated operational endpoints
+            (_, path) if path.starts_with("/admin/") => self.handle_admin(request, metrics),
+            (HttpMethod::GET, "/dashboard") => self.handle_dashboard(request, metrics),
 
             // Echo endpoint - returns whatever is in the path
             (HttpMethod::GET, path) if path.starts_with("/echo/") => {
-                self.handle_echo(&request, compression)
+                self.handle_echo(request, compression)
             }
 
             // User-agent endpoint - returns the User-Agent header
-            (HttpMethod::GET, "/user-agent") => self.handle_user_agent(&request),
+            (HttpMethod::GET, "/user-agent") => self.handle_user_agent(request),
 
-            // Files endpoints - GET and POST
+            // Files endpoints - GET, POST/PUT, and DELETE unless restricted
+            (_, path) if path.starts_with("/files/") && self.file_access == FileAccess::Disabled => {
+                self.unmatched(request)
+            }
+            (HttpMethod::POST | HttpMethod::PUT | HttpMethod::DELETE, path)
+                if path.starts_with("/files/") && self.file_access == FileAccess::ReadOnly =>
+            {
+                Ok(HttpResponse::method_not_allowed().header("Allow", "GET"))
+            }
             (HttpMethod::GET, path) if path.starts_with("/files/") => {
-                self.handle_get_file(&request, compression)
+                self.handle_get_file(request, compression)
             }
-            (HttpMethod::POST, path) if path.starts_with("/files/") => {
-                self.handle_post_file(&request)
+            (HttpMethod::POST | HttpMethod::PUT, path) if path.starts_with("/files/") => {
+                self.handle_post_file(request)
             }
             (HttpMethod::DELETE, path) if path.starts_with("/files/") => {
-                self.handle_delete_file(&request)
+                self.handle_delete_file(request)
             }
 
             // API info endpoint
-            (HttpMethod::GET, "/api/info") => self.handle_api_info(&request),
+            (HttpMethod::GET, "/api/info") => self.handle_api_info(request),
+
+            // The registered routes, for client generators, and a page to try them
+            (HttpMethod::GET, "/openapi.json") => Ok(HttpResponse::ok().json(&self.openapi(API_TITLE))?),
+            (HttpMethod::GET, "/docs") => {
+                let context = json!({ "title": API_TITLE, "spec_url": "/openapi.json", "version": build_info::VERSION });
+                Ok(HttpResponse::ok().html(template::current().docs.render(&context)))
+            }
+
+            // The running build
+            (HttpMethod::GET, "/version") => Self::represent(request, StatusCode::Ok, "Version", &build_info::to_json()),
 
             // Headers endpoint - returns all request headers
-            (HttpMethod::GET, "/headers") => self.handle_headers(&request, compression),
+            (HttpMethod::GET, "/headers") => self.handle_headers(request, compression),
 
-            // Default: 404 Not Found
-            _ => Ok(HttpResponse::not_found()),
+            // Request inspector - reflects any request back as JSON
+            (_, path) if self.debug_endpoints && Self::is_inspect_path(path) => self.handle_inspect(request, compression),
+
+            // Live reload events, in development mode
+            (HttpMethod::GET, live_reload::EVENTS_PATH) => match &self.live_reload {
+                Some(live_reload) => Ok(live_reload.events(request)),
+                None => self.unmatched(request),
+            },
+
+            // Testing endpoints - a chosen status code, or a slow response
+            (_, path) if self.debug_endpoints && path.starts_with("/status/") => self.handle_status(request),
+            (_, path) if self.debug_endpoints && path.starts_with("/delay/") => self.handle_delay(request),
+
+            _ => self.unmatched(request),
         }?;
 
-        Ok(response.build())
-    }
-
-    /// Handle root endpoint
-    fn handle_index(&self, _request: &HttpRequest) -> Result<HttpResponse> {
-        Ok(HttpResponse::ok().html(
-            r#"
-<!DOCTYPE html>
-<html>
-<head>
-    <title>Rust HTTP Server</title>
-    <style>
-        body { 
-            font-family: 'Segoe UI', Arial, sans-serif; 
-            max-width: 800px; 
-            margin: 50px auto; 
-            padding: 20px;
-            background: linear-gradient(135deg, #667eea 0%, #764ba2 100%);
-            color: white;
-        }
-        .container {
-            background: rgba(255, 255, 255, 0.1);
-            border-radius: 15px;
-            padding: 30px;
-            backdrop-filter: blur(10px);
-        }
-        h1 { margin-top: 0; }
-        .feature { 
-            background: rgba(255, 255, 255, 0.2); 
-            padding: 15px; 
-            margin: 10px 0; 
-            border-radius: 8px;
-            border-left: 4px solid #fff;
-        }
-        code { 
-            background: rgba(0, 0, 0, 0.3); 
-            padding: 2px 6px; 
-            border-radius: 3px;
-            font-family: 'Courier New', monospace;
-        }
-        .endpoint { margin: 8px 0; }
-    </style>
-</head>
-<body>
-    <div class="container">
-        <h1>🦀 Production-Ready Rust HTTP Server</h1>
-        <p><strong>Version 1.0.0</strong> - Built with best practices in mind</p>
-        
-        <div class="feature">
-            <h3>Features</h3>
-            <ul>
-                <li>High-performance concurrent request handling (100+ req/sec)</li>
-                <li>Graceful shutdown with connection draining</li>
-                <li>Real-time Prometheus-style metrics</li>
-                <li>Request tracing with unique IDs</li>
-                <li>Multiple compression algorithms (Gzip, Deflate, Brotli)</li>
-                <li>Comprehensive error handling</li>
-                <li>Structured logging</li>
-                <li>File serving and uploads</li>
-                <li>RESTful API design</li>
-            </ul>
-        </div>
-        
-        <div class="feature">
-            <h3>Available Endpoints</h3>
-            <div class="endpoint"><code>GET /</code> - This page</div>
-            <div class="endpoint"><code>GET /health</code> - Health check with metrics</div>
-            <div class="endpoint"><code>GET /metrics</code> - Prometheus-style metrics</div>
-            <div class="endpoint"><code>GET /echo/{text}</code> - Echo service</div>
-            <div class="endpoint"><code>GET /user-agent</code> - Get User-Agent header</div>
-            <div class="endpoint"><code>GET /files/{filename}</code> - Download file</div>
-            <div class="endpoint"><code>POST /files/{filename}</code> - Upload file</div>
-            <div class="endpoint"><code>DELETE /files/{filename}</code> - Delete file</div>
-            <div class="endpoint"><code>GET /headers</code> - View request headers</div>
-            <div class="endpoint"><code>GET /api/info</code> - Server information</div>
-        </div>
-    </div>
-</body>
-</html>
-"#,
-        ))
+        Ok(response)
+    }
+
+    /// 405 if a registered route has the path under another method, else the
+    /// fallback or 404 Not Found
+    fn unmatched(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let path = request.path.split('?').next().unwrap_or_default();
+        let mut allowed: Vec<&str> = Vec::new();
+        for route in &self.routes {
+            if route.accepts(request, path).is_some() && !allowed.contains(&route.method.as_str()) {
+                allowed.push(route.method.as_str());
+            }
+        }
+        if !allowed.is_empty() {
+            return Ok(HttpResponse::method_not_allowed().header("Allow", allowed.join(", ")));
+        }
+        match &self.fallback {
+            Some(fallback) => fallback.call(request),
+            None => Ok(HttpResponse::not_found()),
+        }
+    }
+
+    /// Get the route pattern a path is served by, used to label metrics
+    /// without letting raw paths blow up label cardinality
+    pub fn route_pattern(path: &str) -> &'static str {
+        match path {
+            "/" | "/index.html" => "/",
+            "/health" => "/health",
+            "/health/live" => "/health/live",
+            "/health/ready" => "/health/ready",
+            "/metrics" => "/metrics",
+            "/user-agent" => "/user-agent",
+            "/api/info" => "/api/info",
+            "/version" => "/version",
+            "/openapi.json" => "/openapi.json",
+            "/docs" => "/docs",
+            "/headers" => "/headers",
+            p if Self::is_inspect_path(p) => "/inspect",
+            p if p.starts_with("/status/") => "/status/:code",
+            p if p.starts_with("/delay/") => "/delay/:seconds",
+            live_reload::EVENTS_PATH => live_reload::EVENTS_PATH,
+            "/admin/log-level" => "/admin/log-level",
+            "/admin/drain" => "/admin/drain",
+            "/admin/connections" => "/admin/connections",
+            "/admin/metrics/stream" => "/admin/metrics/stream",
+            "/dashboard" => "/dashboard",
+            p if p.starts_with("/echo/") => "/echo/:text",
+            p if p.starts_with("/files/") => "/files/:name",
+            _ => "unmatched",
+        }
+    }
+
+    /// Handle root endpoint: the landing page, or the endpoint list as JSON or text
+    fn handle_index(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        HttpResponse::negotiated(request, &["text/html", "application/json", "text/plain"], |media_type| {
+            match media_type {
+                "application/json" => HttpResponse::ok().json(&self.api_info()),
+                "text/plain" => Ok(HttpResponse::ok().text(Self::text_document("Rust HTTP Server", &self.api_info()))),
+                _ => {
+                    let context = json!({ "version": env!("CARGO_PKG_VERSION") });
+                    Ok(HttpResponse::ok().html(template::current().index.render(&context)))
+                }
+            }
+        })
+    }
+
+    /// Handle liveness probe: the process is up and serving requests
+    fn handle_liveness(&self, _request: &HttpRequest, metrics: &ServerMetrics) -> Result<HttpResponse> {
+        HttpResponse::ok().json(&json!({
+            "status": "alive",
+            "uptime_seconds": metrics.uptime_seconds(),
+        }))
+    }
+
+    /// Handle readiness probe: 503 unless the server should receive new traffic
+    fn handle_readiness(&self, _request: &HttpRequest) -> Result<HttpResponse> {
+        let (ready, checks) = self.readiness.check(&self.file_directory());
+        let (status_code, status) = if ready {
+            (StatusCode::Ok, "ready")
+        } else {
+            (StatusCode::ServiceUnavailable, "not ready")
+        };
+        HttpResponse::new(status_code).json(&json!({ "status": status, "checks": checks }))
+    }
+
+    /// Check the admin token, given as a bearer token or, for browsers, as the
+    /// password of a Basic login; returns the response refusing the request,
+    /// if it is refused, asking for credentials with `challenge`
+    fn check_admin_token(&self, request: &HttpRequest, challenge: &str) -> Option<HttpResponse> {
+        // Without a configured token the admin endpoints don't exist
+        let Some(token) = &self.admin_token else {
+            return Some(HttpResponse::not_found());
+        };
+        let authorization = request.get_header("authorization").unwrap_or_default();
+        let presented = match authorization.split_once(' ') {
+            Some(("Bearer", presented)) => presented.trim().to_string(),
+            Some(("Basic", credentials)) => BASE64
+                .decode(credentials.trim())
+                .ok()
+                .and_then(|decoded| String::from_utf8(decoded).ok())
+                .and_then(|decoded| decoded.split_once(':').map(|(_, password)| password.to_string()))
+                .unwrap_or_default(),
+            _ => String::new(),
+        };
+        if constant_time_eq(presented.as_bytes(), token.as_bytes()) {
+            return None;
+        }
+        log::warn!("Rejected unauthenticated admin request: {}", request.request_line());
+        Some(HttpResponse::unauthorized().header("WWW-Authenticate", challenge))
+    }
+
+    /// Render the operator dashboard for holders of the admin token
+    fn handle_dashboard(&self, request: &HttpRequest, metrics: &ServerMetrics) -> Result<HttpResponse> {
+        if let Some(refused) = self.check_admin_token(request, "Basic realm=\"admin\", charset=\"UTF-8\"") {
+            return Ok(refused);
+        }
+        Ok(HttpResponse::ok().html(dashboard::render(metrics)).header("Cache-Control", "no-store"))
+    }
+
+    /// Check the admin bearer token and dispatch /admin/* requests
+    fn handle_admin(&self, request: &HttpRequest, metrics: &Arc<ServerMetrics>) -> Result<HttpResponse> {
+        if let Some(refused) = self.check_admin_token(request, "Bearer") {
+            return Ok(refused);
+        }
+
+        match (&request.method, request.path.split('?').next().unwrap_or_default()) {
+            (HttpMethod::GET, "/admin/log-level") => {
+                HttpResponse::ok().json(&json!({ "filter": crate::log_level::current() }))
+            }
+            (HttpMethod::PUT, "/admin/log-level") => self.handle_set_log_level(request),
+            (_, "/admin/log-level") => Ok(HttpResponse::method_not_allowed()),
+            (HttpMethod::POST, "/admin/drain") => self.handle_drain(),
+            (_, "/admin/drain") => Ok(HttpResponse::method_not_allowed()),
+            (HttpMethod::POST, "/admin/cache/purge") => self.handle_cache_purge(request),
+            (_, "/admin/cache/purge") => Ok(HttpResponse::method_not_allowed()),
+            (HttpMethod::GET, "/admin/connections") => HttpResponse::ok().json(&metrics.connections.list_json()),
+            (_, "/admin/connections") => Ok(HttpResponse::method_not_allowed()),
+            (HttpMethod::GET, "/admin/metrics/stream") => self.handle_metrics_stream(request, metrics),
+            (_, "/admin/metrics/stream") => Ok(HttpResponse::method_not_allowed()),
+            _ => Ok(HttpResponse::not_found()),
+        }
+    }
+
+    /// Stream metrics snapshots every `?interval=` seconds
+    fn handle_metrics_stream(&self, request: &HttpRequest, metrics: &Arc<ServerMetrics>) -> Result<HttpResponse> {
+        #[derive(Deserialize)]
+        struct StreamQuery {
+            interval: Option<u64>,
+        }
+        let Query(query) = Query::<StreamQuery>::from_request(request)?;
+        let interval = query.interval.unwrap_or(dashboard::REFRESH_SECS);
+        if !(1..=dashboard::MAX_STREAM_INTERVAL_SECS).contains(&interval) {
+            return Err(ServerError::InvalidRequest(format!(
+                "interval must be between 1 and {} seconds",
+                dashboard::MAX_STREAM_INTERVAL_SECS
+            )));
+        }
+        Ok(dashboard::stream(Arc::clone(metrics), Duration::from_secs(interval)))
+    }
+
+    /// Replace the runtime log filter with the spec in the request body
+    fn handle_set_log_level(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let spec = String::from_utf8_lossy(&request.body).trim().to_string();
+        if spec.is_empty() {
+            return Err(ServerError::InvalidRequest("Missing log filter in request body".to_string()));
+        }
+        let previous = crate::log_level::set(&spec).map_err(ServerError::InvalidRequest)?;
+        log::warn!("Log filter changed from '{}' to '{}'", previous, spec);
+
+        HttpResponse::ok().json(&json!({ "previous": previous, "filter": spec }))
+    }
+
+    /// Start draining: readiness fails and connections are closed after each response
+    fn handle_drain(&self) -> Result<HttpResponse> {
+        if !self.readiness.is_draining() {
+            log::warn!("Drain requested via admin endpoint; readiness will now fail");
+            self.readiness.start_draining();
+        }
+        HttpResponse::accepted().json(&json!({ "status": "draining" }))
+    }
+
+    /// Drop cached responses whose path starts with the prefix in the request
+    /// body, or all of them for an empty body
+    fn handle_cache_purge(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let Some(cache) = &self.response_cache else {
+            return Ok(HttpResponse::not_found());
+        };
+        let prefix = String::from_utf8_lossy(&request.body).trim().to_string();
+        let purged = cache.purge(&prefix);
+        log::warn!("Purged {} cached responses under '{}' via admin endpoint", purged, prefix);
+        HttpResponse::ok().json(&json!({ "purged": purged, "prefix": prefix }))
     }
 
     /// Handle health check endpoint with system stats
-    fn handle_health(&self, _request: &HttpRequest, metrics: &crate::ServerMetrics) -> Result<HttpResponse> {
+    fn handle_health(&self, request: &HttpRequest, metrics: &ServerMetrics) -> Result<HttpResponse> {
         let request_count = metrics.request_count.load(Ordering::Relaxed);
         let error_count = metrics.error_count.load(Ordering::Relaxed);
         let active_connections = metrics.active_connections.load(Ordering::Relaxed);
-        let total_response_time = metrics.total_response_time_ms.load(Ordering::Relaxed);
+        let latency = metrics.overall_latency();
         let uptime = metrics.uptime_seconds();
         
-        let avg_response_time = if request_count > 0 {
-            total_response_time as f64 / request_count as f64
+        let avg_response_time = if latency.count() > 0 {
+            latency.sum() * 1000.0 / latency.count() as f64
         } else {
             0.0
         };
 
-        let health = json!({
-            "status": "healthy",
+        let status_codes = metrics.status_code_counts();
+        let (bytes_received, bytes_sent) = metrics.total_transfer();
+        let process = crate::platform::process_stats();
+
+        // The served directory only matters while files are served
+        let (status, directory) = match self.file_access {
+            FileAccess::Disabled => (HealthStatus::Healthy, serde_json::Value::Null),
+            access => health::check_directory(&self.file_directory(), self.health_min_free, access == FileAccess::ReadWrite),
+        };
+
+        let mut health = json!({
+            "status": status.as_str(),
             "timestamp": chrono::Utc::now().to_rfc3339(),
             "uptime_seconds": uptime,
+            "process": {
+                "resident_memory_bytes": process.resident_memory_bytes,
+                "open_fds": process.open_fds,
+                "threads": process.threads,
+                "cpu_seconds": process.cpu_seconds,
+            },
             "metrics": {
                 "total_requests": request_count,
                 "total_errors": error_count,
+                "slow_requests": metrics.slow_request_count.load(Ordering::Relaxed),
                 "active_connections": active_connections,
+                "worker_pool": {
+                    "size": metrics.worker_pool_size.load(Ordering::Relaxed),
+                    "active": metrics.workers_busy.load(Ordering::Relaxed),
+                    "queued": metrics.jobs_queued.load(Ordering::Relaxed),
+                },
+                "responses_by_status": status_codes,
+                "bytes_received": bytes_received,
+                "bytes_sent": bytes_sent,
                 "avg_response_time_ms": format!("{:.2}", avg_response_time),
                 "error_rate": if request_count > 0 { 
                     format!("{:.2}%", (error_count as f64 / request_count as f64) * 100.0) 
@@ -188,49 +1299,41 @@ impl Router {
                 }
             }
         });
+        if !directory.is_null() {
+            health["directory"] = directory;
+        }
 
-        HttpResponse::ok().json(&health)
+        let status_code = match status {
+            HealthStatus::Unhealthy => StatusCode::ServiceUnavailable,
+            _ => StatusCode::Ok,
+        };
+        Self::represent(request, status_code, "Server Health", &health)
     }
 
     /// Handle metrics endpoint (Prometheus-style)
-    fn handle_metrics(&self, _request: &HttpRequest, metrics: &crate::ServerMetrics) -> Result<HttpResponse> {
-        let request_count = metrics.request_count.load(Ordering::Relaxed);
-        let error_count = metrics.error_count.load(Ordering::Relaxed);
-        let active_connections = metrics.active_connections.load(Ordering::Relaxed);
-        let total_response_time = metrics.total_response_time_ms.load(Ordering::Relaxed);
-        let uptime = metrics.uptime_seconds();
-
-        // Prometheus exposition format
-        let prometheus_output = format!(
-            "# HELP http_requests_total The total number of HTTP requests\n\
-             # TYPE http_requests_total counter\n\
-             http_requests_total {}\n\
-             \n\
-             # HELP http_errors_total The total number of HTTP errors\n\
-             # TYPE http_errors_total counter\n\
-             http_errors_total {}\n\
-             \n\
-             # HELP http_active_connections Current number of active connections\n\
-             # TYPE http_active_connections gauge\n\
-             http_active_connections {}\n\
-             \n\
-             # HELP http_response_time_milliseconds_total Total response time in milliseconds\n\
-             # TYPE http_response_time_milliseconds_total counter\n\
-             http_response_time_milliseconds_total {}\n\
-             \n\
-             # HELP http_server_uptime_seconds Server uptime in seconds\n\
-             # TYPE http_server_uptime_seconds counter\n\
-             http_server_uptime_seconds {}\n",
-            request_count,
-            error_count,
-            active_connections,
-            total_response_time,
-            uptime
-        );
-
-        Ok(HttpResponse::ok()
-            .header("Content-Type", "text/plain; version=0.0.4")
-            .text(prometheus_output))
+    fn handle_metrics(&self, request: &HttpRequest, metrics: &ServerMetrics) -> Result<HttpResponse> {
+        // Prometheus text stays the default for scrapers that send no or wildcard Accept
+        let response = match request.negotiate(&["text/plain", "application/json"]) {
+            Some("application/json") => {
+                let mut document = metrics.render_json();
+                if let Some(cache) = &self.response_cache {
+                    document["response_cache"] = cache.render_json();
+                }
+                if !self.circuit_breakers.is_empty() {
+                    document["upstream_circuits"] = circuit_breaker::render_json(&self.circuit_breakers);
+                }
+                HttpResponse::ok().json(&document)?
+            }
+            _ => {
+                let mut text = metrics.render_prometheus();
+                if let Some(cache) = &self.response_cache {
+                    cache.render_prometheus(&mut text);
+                }
+                circuit_breaker::render_prometheus(&self.circuit_breakers, &mut text);
+                HttpResponse::ok().text(text).header("Content-Type", "text/plain; version=0.0.4")
+            }
+        };
+        Ok(response.vary("Accept"))
     }
 
     /// Handle echo endpoint
@@ -239,18 +1342,14 @@ impl Router {
         
         let response = HttpResponse::ok().text(echo_str);
 
-        if compression != Compression::None {
-            response.compress(compression)
-        } else {
-            Ok(response)
-        }
+        response.compress_level(compression, self.compression.level)
     }
 
     /// Handle user-agent endpoint
     fn handle_user_agent(&self, request: &HttpRequest) -> Result<HttpResponse> {
         let user_agent = request
             .get_header("user-agent")
-            .cloned()
+            .map(str::to_string)
             .unwrap_or_else(|| "Unknown".to_string());
 
         Ok(HttpResponse::ok().text(user_agent))
@@ -267,26 +1366,107 @@ impl Router {
             ));
         }
 
-        let filepath = PathBuf::from(&self.file_directory).join(filename);
+        let filepath = self.file_directory().join(filename);
+        let content_type = Self::guess_content_type(filename);
+        let compression = if compression::is_compressible(content_type) {
+            compression
+        } else {
+            Compression::None
+        };
 
-        let content = fs::read(&filepath).map_err(|_| {
-            ServerError::FileNotFound(format!("File not found: {}", filename))
-        })?;
+        let not_found = |_| ServerError::FileNotFound(filename.to_string());
+        let ok = |validators: &Validators, compression: Compression, body: &[u8]| {
+            let response = HttpResponse::ok().header("Content-Type", content_type).header("Accept-Ranges", "bytes");
+            let response = validators.apply(response, compression);
+            match &self.digests {
+                Some(index) => index.get(&filepath, validators.modified(), compression, body).apply(response),
+                None => response,
+            }
+        };
 
-        log::info!("Serving file: {} ({} bytes)", filename, content.len());
+        // Ranges count bytes of the file itself, so partial responses are
+        // never compressed. An unusable Range header gets the whole file, as
+        // does an If-Range naming a version that has since changed.
+        if let Some(header) = request.get_header("Range") {
+            let validators = self.served_validators(&filepath).map_err(not_found)?;
+            if request.get_header("If-Range").map_or(true, |value| validators.if_range_matches(value)) {
+                let content = read_file(&filepath).map_err(not_found)?;
+                match range::parse(header, content.len() as u64) {
+                    Some(RangeRequest::Ranges(ranges)) => {
+                        log::info!("Serving file: {} ({} ranges of {} bytes)", filename, ranges.len(), content.len());
+                        let response = range::partial_response(&content, content_type, &ranges).header("Accept-Ranges", "bytes");
+                        let response = validators.apply(response, Compression::None);
+                        return Ok(match &self.digests {
+                            Some(index) => {
+                                index.get(&filepath, validators.modified(), Compression::None, &content).apply_partial(response)
+                            }
+                            None => response,
+                        });
+                    }
+                    Some(RangeRequest::Unsatisfiable) => {
+                        let detail = format!("{} is {} bytes long", filename, content.len());
+                        return Ok(Problem::new(StatusCode::RangeNotSatisfiable, "range_not_satisfiable", detail)
+                            .instance(&request.path)
+                            .to_response(Some(request))
+                            .header("Content-Range", format!("bytes */{}", content.len())));
+                    }
+                    None => {}
+                }
+            }
+        }
 
-        let response = HttpResponse::ok()
-            .header("Content-Type", Self::guess_content_type(filename))
-            .body(content);
+        // While watched, a cached body is dropped as soon as its file changes,
+        // so it can be served without a stat
+        let watched = self.file_watcher.is_some() && compression != Compression::None;
+        let current = watched
+            .then(|| self.compressed_cache.get_current(&filepath, compression).zip(self.validators.current(&filepath)))
+            .flatten()
+            .filter(|((_, mtime), validators)| validators.modified() == *mtime);
+        if let Some(((cached, _), validators)) = current {
+            log::info!("Serving file: {} ({} bytes, cached {})", filename, cached.len(), compression.name());
+            return Ok(ok(&validators, compression, &cached).body(cached.as_slice()).encoded(compression));
+        }
+        let generation = self.compressed_cache.generation();
 
-        if compression != Compression::None {
-            response.compress(compression)
-        } else {
-            Ok(response)
+        let validators = self.served_validators(&filepath).map_err(not_found)?;
+        let mtime = validators.modified();
+
+        if compression != Compression::None && !watched {
+            if let Some(cached) = self.compressed_cache.get(&filepath, mtime, compression) {
+                log::info!("Serving file: {} ({} bytes, cached {})", filename, cached.len(), compression.name());
+                return Ok(ok(&validators, compression, &cached).body(cached.as_slice()).encoded(compression));
+            }
+        }
+
+        if let Some(compressed) = precompress::read_sidecar(&filepath, mtime, compression) {
+            log::info!("Serving file: {} ({} bytes, precompressed {})", filename, compressed.len(), compression.name());
+            let compressed = Arc::new(compressed);
+            self.compressed_cache
+                .insert_if_unchanged(generation, &filepath, mtime, compression, Arc::clone(&compressed));
+            return Ok(ok(&validators, compression, &compressed).body(compressed.as_slice()).encoded(compression));
+        }
+
+        let content = read_file(&filepath).map_err(not_found)?;
+
+        log::info!("Serving file: {} ({} bytes)", filename, content.len());
+
+        if compression == Compression::None {
+            return ok(&validators, compression, &content).body(content).compress(compression);
         }
+
+        let compressed = Arc::new(compression.compress_level(&content, self.compression.level)?);
+        self.compressed_cache
+            .insert_if_unchanged(generation, &filepath, mtime, compression, Arc::clone(&compressed));
+
+        Ok(ok(&validators, compression, &compressed).body(compressed.as_slice()).encoded(compression))
     }
 
-    /// Handle POST file endpoint (file upload)
+    /// The validators of a file, or `None` if it does not exist
+    fn file_validators(&self, filepath: &Path) -> Option<Validators> {
+        self.validators.get(filepath).ok()
+    }
+
+    /// Handle POST and PUT file endpoint (file upload)
     fn handle_post_file(&self, request: &HttpRequest) -> Result<HttpResponse> {
         let filename = &request.path[7..]; // Skip "/files/"
 
@@ -297,24 +1477,41 @@ impl Router {
             ));
         }
 
-        let filepath = PathBuf::from(&self.file_directory).join(filename);
+        let filepath = self.file_directory().join(filename);
+        conditional::check_preconditions(request, self.file_validators(&filepath).as_ref())?;
 
         // Ensure directory exists
         if let Some(parent) = filepath.parent() {
             fs::create_dir_all(parent)?;
         }
 
-        fs::write(&filepath, &request.body)?;
+        // Copy the body in chunks to a temporary file next to the target and
+        // rename it into place, so a failed upload never replaces the file
+        let partial = filepath.with_file_name(format!(".{}.{}.part", filename, uuid::Uuid::new_v4()));
+        let size = fs::File::create(&partial).and_then(|mut file| io::copy(&mut request.body_reader(), &mut file));
+        let size = match size.and_then(|size| fs::rename(&partial, &filepath).map(|_| size)) {
+            Ok(size) => size,
+            Err(e) => {
+                let _ = fs::remove_file(&partial);
+                return Err(e.into());
+            }
+        };
 
-        log::info!("File uploaded: {} ({} bytes)", filename, request.body.len());
+        log::info!("File uploaded: {} ({} bytes)", filename, size);
+        request.insert_extension(FileSize(size));
 
         let response = json!({
             "message": "File uploaded successfully",
             "filename": filename,
-            "size": request.body.len()
+            "size": size
         });
 
-        HttpResponse::created().json(&response)
+        // The new version's tag, for the client's next If-Match
+        let response = HttpResponse::created().json(&response)?;
+        Ok(match self.file_validators(&filepath) {
+            Some(validators) => validators.apply(response, Compression::None),
+            None => response,
+        })
     }
 
     /// Handle DELETE file endpoint
@@ -328,13 +1525,18 @@ impl Router {
             ));
         }
 
-        let filepath = PathBuf::from(&self.file_directory).join(filename);
+        let filepath = self.file_directory().join(filename);
+        conditional::check_preconditions(request, self.file_validators(&filepath).as_ref())?;
 
+        let size = fs::metadata(&filepath).map(|metadata| metadata.len());
         fs::remove_file(&filepath).map_err(|_| {
-            ServerError::FileNotFound(format!("File not found: {}", filename))
+            ServerError::FileNotFound(filename.to_string())
         })?;
 
         log::info!("File deleted: {}", filename);
+        if let Ok(size) = size {
+            request.insert_extension(FileSize(size));
+        }
 
         let response = json!({
             "message": "File deleted successfully",
@@ -345,9 +1547,30 @@ impl Router {
     }
 
     /// Handle API info endpoint
-    fn handle_api_info(&self, _request: &HttpRequest) -> Result<HttpResponse> {
-        let info = json!({
-            "name": "Rust HTTP Server",
+    fn handle_api_info(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        Self::represent(request, StatusCode::Ok, "API Info", &self.api_info())
+    }
+
+    /// Server name, features, and endpoints
+    fn api_info(&self) -> serde_json::Value {
+        let files = "/files/{filename}";
+        let mut get = vec!["/", "/health", "/health/live", "/health/ready", "/echo/{text}", "/user-agent"];
+        if self.file_access != FileAccess::Disabled {
+            get.push(files);
+        }
+        get.extend(["/headers", "/api/info", "/version", "/openapi.json", "/docs"]);
+        let mut endpoints = json!({ "GET": get });
+        if self.debug_endpoints {
+            endpoints["ANY"] = json!(["/inspect", "/anything", "/status/{code}", "/delay/{seconds}"]);
+        }
+        if self.file_access == FileAccess::ReadWrite {
+            endpoints["POST"] = json!([files]);
+            endpoints["PUT"] = json!([files]);
+            endpoints["DELETE"] = json!([files]);
+        }
+
+        json!({
+            "name": API_TITLE,
             "version": "1.0.0",
             "features": [
                 "Concurrent connections",
@@ -357,14 +1580,72 @@ impl Router {
                 "Comprehensive error handling",
                 "Structured logging"
             ],
-            "endpoints": {
-                "GET": ["/", "/health", "/echo/{text}", "/user-agent", "/files/{filename}", "/headers", "/api/info"],
-                "POST": ["/files/{filename}"],
-                "DELETE": ["/files/{filename}"]
+            "endpoints": endpoints
+        })
+    }
+
+    /// Respond with a JSON document as JSON, an HTML table, or `key: value`
+    /// lines, whichever the client prefers
+    fn represent(request: &HttpRequest, status: StatusCode, title: &str, document: &serde_json::Value) -> Result<HttpResponse> {
+        HttpResponse::negotiated(request, &["application/json", "text/html", "text/plain"], |media_type| {
+            match media_type {
+                "text/html" => Ok(HttpResponse::new(status).html(Self::html_document(title, document))),
+                "text/plain" => Ok(HttpResponse::new(status).text(Self::text_document(title, document))),
+                _ => HttpResponse::new(status).json(document),
             }
-        });
+        })
+    }
+
+    fn text_document(title: &str, document: &serde_json::Value) -> String {
+        let mut text = format!("{}\n", title);
+        for (key, value) in Self::flatten(document) {
+            text.push_str(&format!("{}: {}\n", key, value));
+        }
+        text
+    }
+
+    fn html_document(title: &str, document: &serde_json::Value) -> String {
+        let rows: Vec<serde_json::Value> = Self::flatten(document)
+            .into_iter()
+            .map(|(key, value)| json!({ "key": key, "value": value }))
+            .collect();
+        let context = json!({ "title": title, "rows": rows, "document": document });
+        template::current().document.render(&context)
+    }
+
+    /// Flatten nested objects into dotted keys; lists of plain values are
+    /// joined with commas
+    fn flatten(document: &serde_json::Value) -> Vec<(String, String)> {
+        fn walk(prefix: &str, value: &serde_json::Value, fields: &mut Vec<(String, String)>) {
+            let key = |name: &str| if prefix.is_empty() { name.to_string() } else { format!("{}.{}", prefix, name) };
+            match value {
+                serde_json::Value::Object(map) => {
+                    for (name, value) in map {
+                        walk(&key(name), value, fields);
+                    }
+                }
+                serde_json::Value::Array(items) if items.iter().any(|item| item.is_object() || item.is_array()) => {
+                    for (index, item) in items.iter().enumerate() {
+                        walk(&key(&index.to_string()), item, fields);
+                    }
+                }
+                serde_json::Value::Array(items) => {
+                    let items: Vec<String> = items.iter().map(plain).collect();
+                    fields.push((prefix.to_string(), items.join(", ")));
+                }
+                value => fields.push((prefix.to_string(), plain(value))),
+            }
+        }
+        fn plain(value: &serde_json::Value) -> String {
+            match value {
+                serde_json::Value::String(text) => text.clone(),
+                value => value.to_string(),
+            }
+        }
 
-        HttpResponse::ok().json(&info)
+        let mut fields = Vec::new();
+        walk("", document, &mut fields);
+        fields
     }
 
     /// Handle headers endpoint
@@ -372,15 +1653,112 @@ impl Router {
         let headers_json = json!(request.headers);
         let response = HttpResponse::ok().json(&headers_json)?;
 
-        if compression != Compression::None {
-            response.compress(compression)
-        } else {
-            Ok(response)
+        response.compress_level(compression, self.compression.level)
+    }
+
+    /// `/inspect` and `/anything` (as httpbin calls it), with or without a
+    /// subpath or query
+    fn is_inspect_path(path: &str) -> bool {
+        let path = path.split('?').next().unwrap_or_default();
+        ["/inspect", "/anything"]
+            .iter()
+            .any(|prefix| path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/')))
+    }
+
+    /// Handle request inspector: the request as JSON, for debugging clients
+    /// and proxies
+    fn handle_inspect(&self, request: &HttpRequest, compression: Compression) -> Result<HttpResponse> {
+        // Repeated names become arrays, as in httpbin
+        fn fields(pairs: Vec<(String, String)>) -> serde_json::Map<String, serde_json::Value> {
+            let mut map = serde_json::Map::new();
+            for (name, value) in pairs {
+                match map.get_mut(&name) {
+                    Some(serde_json::Value::Array(values)) => values.push(value.into()),
+                    Some(first) => *first = json!([first.take(), value]),
+                    None => {
+                        map.insert(name, value.into());
+                    }
+                }
+            }
+            map
         }
+
+        let (path, query) = request.path.split_once('?').unwrap_or((&request.path, ""));
+        let query: Vec<(String, String)> = serde_urlencoded::from_str(query).unwrap_or_default();
+        let cookies = request
+            .headers
+            .combined("cookie")
+            .map(|header| {
+                let pairs = header.split(';').filter_map(|cookie| cookie.trim().split_once('='));
+                pairs.map(|(name, value)| (name.to_string(), value.to_string())).collect()
+            })
+            .unwrap_or_default();
+
+        let media_type = request.get_header("content-type").unwrap_or_default().split(';').next().unwrap_or_default();
+        let media_type = media_type.trim().to_ascii_lowercase();
+        let json_body = (media_type == "application/json" || media_type.ends_with("+json"))
+            .then(|| serde_json::from_slice::<serde_json::Value>(&request.body).ok())
+            .flatten();
+        let form = (media_type == "application/x-www-form-urlencoded")
+            .then(|| serde_urlencoded::from_bytes::<Vec<(String, String)>>(&request.body).ok().map(fields))
+            .flatten();
+        let (body, body_encoding) = match std::str::from_utf8(&request.body) {
+            Ok(text) => (text.to_string(), "utf-8"),
+            Err(_) => (BASE64.encode(&request.body), "base64"),
+        };
+
+        let document = json!({
+            "method": request.method.as_str(),
+            "path": path,
+            "query": fields(query),
+            "headers": request.headers,
+            "cookies": fields(cookies),
+            "body": body,
+            "body_encoding": body_encoding,
+            "json": json_body,
+            "form": form,
+            "peer": request.peer_addr.map(|peer| peer.to_string()),
+        });
+        HttpResponse::ok().json(&document)?.compress_level(compression, self.compression.level)
+    }
+
+    /// Handle status endpoint: answer with the status code in the path, for
+    /// testing how clients handle errors
+    fn handle_status(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let code = request.path.split('?').next().unwrap_or_default().trim_start_matches("/status/");
+        // 1xx are interim responses and cannot end an exchange
+        let status = code
+            .parse()
+            .ok()
+            .and_then(StatusCode::from_u16)
+            .filter(|status| !status.is_informational())
+            .ok_or_else(|| ServerError::InvalidRequest(format!("Not a final status code: {}", code)))?;
+        let response = HttpResponse::new(status);
+        Ok(match status {
+            StatusCode::NoContent | StatusCode::ResetContent | StatusCode::NotModified => response,
+            _ => response.text(format!("{} - {}", status.as_u16(), status.reason_phrase())),
+        })
+    }
+
+    /// Handle delay endpoint: answer after the given number of seconds, at
+    /// most `--max-delay-secs`, for testing client timeouts
+    ///
+    /// The wait holds a worker, so the bound also limits how many workers
+    /// slow requests can tie up at once.
+    fn handle_delay(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let seconds = request.path.split('?').next().unwrap_or_default().trim_start_matches("/delay/");
+        let delay = seconds
+            .parse::<f64>()
+            .ok()
+            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+            .ok_or_else(|| ServerError::InvalidRequest(format!("Not a number of seconds: {}", seconds)))?
+            .min(self.max_delay);
+        std::thread::sleep(delay);
+        HttpResponse::ok().json(&json!({ "delay": delay.as_secs_f64() }))
     }
 
     /// Guess content type from file extension
-    fn guess_content_type(filename: &str) -> &'static str {
+    pub fn guess_content_type(filename: &str) -> &'static str {
         let ext = Path::new(filename)
             .extension()
             .and_then(|e| e.to_str())
@@ -402,3 +1780,22 @@ impl Router {
         }
     }
 }
+
+/// Read a whole file, through io_uring when running on an io_uring worker
+fn read_file(path: &Path) -> std::io::Result<Vec<u8>> {
+    #[cfg(all(target_os = "linux", feature = "io-uring"))]
+    return crate::uring_server::read_file(path);
+    #[cfg(not(all(target_os = "linux", feature = "io-uring")))]
+    fs::read(path)
+}
+
+/// Whether `path` is the temporary file of an upload, see `handle_post_file`
+fn is_partial_upload(path: &Path) -> bool {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    name.starts_with('.') && name.ends_with(".part")
+}
+
+/// Compare secrets without short-circuiting on the first differing byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
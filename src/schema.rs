@@ -0,0 +1,277 @@
+//! JSON Schema validation of request bodies
+//!
+//! [`validate`] wraps a handler so that its JSON body is checked against a
+//! schema before the handler runs. A body that fails gets a 422 whose
+//! `errors` list names every violating field by its JSON pointer, so clients
+//! can fix them all at once; a body that isn't JSON gets 415, and malformed
+//! JSON 400. The schema also becomes the request body of the route's OpenAPI
+//! operation.
+//!
+//! The keywords request bodies commonly need are supported: `type` (a name or
+//! a list), `properties`, `required`, `additionalProperties` (`false` or a
+//! schema), `items`, `enum`, `const`, `minimum`, `maximum`,
+//! `exclusiveMinimum`, `exclusiveMaximum`, `minLength`, `maxLength`,
+//! `pattern`, `minItems`, and `maxItems`. Annotations such as `title`,
+//! `description`, and `format` are ignored. Schemas using the combining
+//! keywords or `$ref` are refused when built, rather than half checked.
+
+use crate::error::Result;
+use crate::extract::{FromRequest, Json};
+use crate::openapi;
+use crate::problem::{FieldError, Problem};
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::router::Handler;
+use crate::status::StatusCode;
+use regex::Regex;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+
+/// Keywords that would change what a schema accepts but aren't supported
+const UNSUPPORTED: [&str; 7] = ["$ref", "allOf", "anyOf", "oneOf", "not", "if", "patternProperties"];
+
+/// A schema checked for supported keywords and valid patterns
+#[derive(Debug, Clone)]
+pub struct JsonSchema {
+    schema: Value,
+    patterns: HashMap<String, Regex>,
+}
+
+impl JsonSchema {
+    pub fn new(schema: Value) -> std::result::Result<Self, String> {
+        let mut patterns = HashMap::new();
+        compile(&schema, "", &mut patterns)?;
+        Ok(JsonSchema { schema, patterns })
+    }
+
+    pub fn schema(&self) -> &Value {
+        &self.schema
+    }
+
+    /// Every way `value` breaks the schema; empty if it conforms
+    pub fn validate(&self, value: &Value) -> Vec<FieldError> {
+        let mut errors = Vec::new();
+        self.check(&self.schema, value, "", &mut errors);
+        errors
+    }
+
+    fn check(&self, schema: &Value, value: &Value, pointer: &str, errors: &mut Vec<FieldError>) {
+        let mut fail = |detail: String| {
+            errors.push(FieldError {
+                pointer: pointer.to_string(),
+                detail,
+            })
+        };
+        if let Some(types) = schema.get("type") {
+            let names: Vec<&str> = match types {
+                Value::Array(types) => types.iter().filter_map(Value::as_str).collect(),
+                types => types.as_str().into_iter().collect(),
+            };
+            if !names.iter().any(|name| is_type(value, name)) {
+                fail(format!("must be {}", names.join(" or ")));
+                return;
+            }
+        }
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                fail(format!("must be one of {}", Value::Array(allowed.clone())));
+            }
+        }
+        if let Some(expected) = schema.get("const") {
+            if expected != value {
+                fail(format!("must be {}", expected));
+            }
+        }
+
+        let bound = |keyword| schema.get(keyword).and_then(Value::as_f64);
+        if let Some(number) = value.as_f64() {
+            for (keyword, broken, relation) in [
+                ("minimum", number < bound("minimum").unwrap_or(f64::MIN), "at least"),
+                ("maximum", number > bound("maximum").unwrap_or(f64::MAX), "at most"),
+                ("exclusiveMinimum", number <= bound("exclusiveMinimum").unwrap_or(f64::MIN), "greater than"),
+                ("exclusiveMaximum", number >= bound("exclusiveMaximum").unwrap_or(f64::MAX), "less than"),
+            ] {
+                if broken {
+                    fail(format!("must be {} {}", relation, schema[keyword]));
+                }
+            }
+        }
+        if let Some(text) = value.as_str() {
+            let length = text.chars().count() as f64;
+            if let Some(min) = bound("minLength").filter(|min| length < *min) {
+                fail(format!("must be at least {} characters long", min));
+            }
+            if let Some(max) = bound("maxLength").filter(|max| length > *max) {
+                fail(format!("must be at most {} characters long", max));
+            }
+            if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+                if !self.patterns[pattern].is_match(text) {
+                    fail(format!("must match the pattern {}", pattern));
+                }
+            }
+        }
+        if let Some(items) = value.as_array() {
+            let count = items.len() as f64;
+            if let Some(min) = bound("minItems").filter(|min| count < *min) {
+                fail(format!("must have at least {} items", min));
+            }
+            if let Some(max) = bound("maxItems").filter(|max| count > *max) {
+                fail(format!("must have at most {} items", max));
+            }
+            if let Some(item_schema) = schema.get("items").filter(|items| items.is_object()) {
+                for (i, item) in items.iter().enumerate() {
+                    self.check(item_schema, item, &format!("{}/{}", pointer, i), errors);
+                }
+            }
+        }
+        if let Some(object) = value.as_object() {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for name in schema.get("required").and_then(Value::as_array).into_iter().flatten() {
+                if let Some(name) = name.as_str().filter(|name| !object.contains_key(*name)) {
+                    errors.push(FieldError {
+                        pointer: child(pointer, name),
+                        detail: "is required".to_string(),
+                    });
+                }
+            }
+            for (name, field) in object {
+                match (properties.and_then(|properties| properties.get(name)), schema.get("additionalProperties")) {
+                    (Some(property), _) => self.check(property, field, &child(pointer, name), errors),
+                    (None, Some(Value::Bool(false))) => errors.push(FieldError {
+                        pointer: child(pointer, name),
+                        detail: "is not allowed".to_string(),
+                    }),
+                    (None, Some(additional)) if additional.is_object() => {
+                        self.check(additional, field, &child(pointer, name), errors)
+                    }
+                    (None, _) => {}
+                }
+            }
+        }
+    }
+}
+
+/// Check the keywords of `schema` at `path` and compile its patterns
+fn compile(schema: &Value, path: &str, patterns: &mut HashMap<String, Regex>) -> std::result::Result<(), String> {
+    let Some(object) = schema.as_object() else {
+        return Err(format!("schema at '{}' is not an object", path));
+    };
+    if let Some(keyword) = UNSUPPORTED.iter().find(|keyword| object.contains_key(**keyword)) {
+        return Err(format!("schema at '{}' uses {}, which is not supported", path, keyword));
+    }
+    if let Some(pattern) = object.get("pattern").and_then(Value::as_str) {
+        let regex = Regex::new(pattern).map_err(|e| format!("schema at '{}' has an invalid pattern: {}", path, e))?;
+        patterns.insert(pattern.to_string(), regex);
+    }
+    for (name, property) in object.get("properties").and_then(Value::as_object).into_iter().flatten() {
+        compile(property, &child(&format!("{}/properties", path), name), patterns)?;
+    }
+    for keyword in ["items", "additionalProperties"] {
+        if let Some(nested) = object.get(keyword).filter(|nested| !nested.is_boolean()) {
+            compile(nested, &format!("{}/{}", path, keyword), patterns)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|n| n.fract() == 0.0),
+        _ => false,
+    }
+}
+
+/// The JSON pointer of member `name` of the value at `pointer`
+fn child(pointer: &str, name: &str) -> String {
+    format!("{}/{}", pointer, name.replace('~', "~0").replace('/', "~1"))
+}
+
+/// Check the JSON body of requests against `schema` before `handler` runs
+pub fn validate(schema: JsonSchema, handler: impl Handler) -> impl Handler {
+    Validated { schema, handler }
+}
+
+struct Validated<H> {
+    schema: JsonSchema,
+    handler: H,
+}
+
+impl<H: Handler> Handler for Validated<H> {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let Json(body) = Json::<Value>::from_request(request)?;
+        let errors = self.schema.validate(&body);
+        if errors.is_empty() {
+            return self.handler.call(request);
+        }
+        let detail = match errors.len() {
+            1 => "The body has an invalid field".to_string(),
+            n => format!("The body has {} invalid fields", n),
+        };
+        Ok(Problem::new(StatusCode::UnprocessableContent, "unprocessable_content", detail)
+            .instance(&request.path)
+            .errors(errors)
+            .to_response(Some(request)))
+    }
+
+    fn describe(&self, operation: &mut Value) {
+        self.handler.describe(operation);
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": { "application/json": { "schema": self.schema.schema } },
+        });
+        openapi::error_response(operation, 400, "Malformed JSON body");
+        openapi::error_response(operation, 415, "The body isn't JSON");
+        openapi::error_response(operation, 422, "The body doesn't match the schema; `errors` lists the fields");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_every_violation_is_reported_with_its_pointer() {
+        let schema = JsonSchema::new(json!({
+            "type": "object",
+            "required": ["name", "age"],
+            "additionalProperties": false,
+            "properties": {
+                "name": { "type": "string", "minLength": 1, "pattern": "^[a-z]+$" },
+                "age": { "type": "integer", "minimum": 0 },
+                "role": { "enum": ["admin", "user"] },
+                "tags": { "type": "array", "maxItems": 2, "items": { "type": "string" } },
+                "a/b": { "type": ["string", "null"] },
+            },
+        }))
+        .unwrap();
+        assert!(schema.validate(&json!({ "name": "ann", "age": 30, "tags": ["x"], "a/b": null })).is_empty());
+
+        let errors = schema.validate(&json!({ "name": "Ann", "age": 1.5, "role": "root", "tags": [1, "x", "y"], "a/b": 2, "x": 0 }));
+        let errors: Vec<(&str, &str)> = errors.iter().map(|e| (e.pointer.as_str(), e.detail.as_str())).collect();
+        assert_eq!(
+            errors,
+            [
+                ("/a~1b", "must be string or null"),
+                ("/age", "must be integer"),
+                ("/name", "must match the pattern ^[a-z]+$"),
+                ("/role", "must be one of [\"admin\",\"user\"]"),
+                ("/tags", "must have at most 2 items"),
+                ("/tags/0", "must be string"),
+                ("/x", "is not allowed"),
+            ]
+        );
+        let errors = schema.validate(&json!({ "age": -1 }));
+        assert_eq!(errors[0].pointer, "/name");
+        assert_eq!(errors[0].detail, "is required");
+        assert_eq!(errors[1].detail, "must be at least 0");
+
+        assert!(JsonSchema::new(json!({ "anyOf": [] })).unwrap_err().contains("anyOf"));
+        assert!(JsonSchema::new(json!({ "properties": { "x": { "pattern": "(" } } })).is_err());
+    }
+}
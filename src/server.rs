@@ -0,0 +1,926 @@
+//! Connection handling shared by every runtime: parsing, routing, and
+//! recording each request, plus the blocking accept loops
+
+use crate::access_log::{AccessLogEntry, AccessLogger};
+use crate::buffer_pool::PooledReader;
+use crate::config::Config;
+use crate::error::ServerError;
+use crate::ip_filter::{self, DenyAction};
+use crate::metrics::{CountingStream, ServerMetrics, SlowRequest};
+use crate::problem::Problem;
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::status::StatusCode;
+use crate::router::{Listener, Router};
+use crate::telemetry::{Span, Tracer};
+use crate::throttle::{RateLimit, ThrottledReader};
+use crate::upload_limit::{UploadLimiter, UploadPermit};
+use crate::worker_pool::AdaptivePool;
+use crate::{log_level, net, platform, request_id, template};
+use std::io::{self, BufRead, Read};
+use std::net::{IpAddr, SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+/// Status recorded for requests the client abandoned before the response was
+/// sent; nginx's non-standard 499 Client Closed Request
+pub const CLIENT_CLOSED_REQUEST: u16 = 499;
+
+/// Longest the accept loop waits for a request line to tell probes apart
+const PROBE_PEEK: Duration = Duration::from_millis(20);
+
+/// Retry-After sent with 503s for connections shed because the queue is full,
+/// and for uploads refused for want of a slot
+const SHED_RETRY_AFTER_SECS: u64 = 1;
+
+/// Most connections the admin listener handles at once
+const ADMIN_CONNECTIONS: usize = 4;
+
+/// How often a connection idling between requests checks whether the server
+/// has started draining
+pub const DRAIN_POLL: Duration = Duration::from_millis(250);
+
+/// State shared by every connection handler
+pub struct ServerState {
+    pub config: Config,
+    pub router: Router,
+    pub metrics: Arc<ServerMetrics>,
+    pub uploads: UploadLimiter,
+    pub ingress: RateLimit,
+    pub egress: RateLimit,
+    pub access_log: Option<AccessLogger>,
+    pub tracer: Option<Tracer>,
+}
+
+/// Finish a routed response and write it, returning its status and body size
+fn respond(
+    response: HttpResponse,
+    request_id: &str,
+    span: Option<&Span>,
+    stream: &mut impl std::io::Write,
+    config: &Config,
+    keep_open: bool,
+) -> Result<(u16, usize), ServerError> {
+    let response = finish_response(response, request_id, span, config, keep_open);
+    let status = response.status_code();
+
+    // Write response back to client
+    let body_len = write_response(stream, response)?;
+
+    Ok((status, body_len as usize))
+}
+
+/// Send a response, writing the head and body together without copying the
+/// body; returns the body length
+fn write_response(stream: &mut impl std::io::Write, response: HttpResponse) -> std::io::Result<u64> {
+    let body_len = response.write_to(stream)?;
+    stream.flush()?;
+    Ok(body_len)
+}
+
+/// Count a parsed request, assign its request ID, and open a span when tracing is enabled
+pub fn begin_request(request: &HttpRequest, peer_addr: Option<SocketAddr>, state: &ServerState) -> (String, Option<Span>) {
+    state.metrics.request_count.fetch_add(1, Ordering::Relaxed);
+    let request_id = request_id::resolve(
+        request.get_header("x-request-id"),
+        peer_addr.map(|addr| addr.ip()),
+        &state.config.trusted_proxies,
+    );
+    let span = state.tracer.as_ref().map(|tracer| {
+        let mut span = tracer.start_span(request, Router::route_pattern(&request.path));
+        span.set_attribute("http.request.id", request_id.as_str());
+        span
+    });
+    (request_id, span)
+}
+
+/// Whether the IP allow/deny lists turn away a connection from `peer` before
+/// any request is read
+///
+/// Trusted proxies are let through so that the clients they forward for can
+/// be checked per request by [`blocked_client`].
+pub fn blocked_peer(peer: IpAddr, state: &ServerState) -> bool {
+    let config = &state.config;
+    let trusted = config.trusted_proxies.iter().any(|proxy| proxy.to_canonical() == peer.to_canonical());
+    let blocked = !trusted && !config.allows_ip(peer);
+    if blocked {
+        state.metrics.ip_rejections.fetch_add(1, Ordering::Relaxed);
+        log::debug!("Rejected connection from {} by the IP allow/deny lists", peer);
+    }
+    blocked
+}
+
+/// The client address the IP allow/deny lists block this request for, if
+/// any: the peer, or the client a trusted proxy forwarded the request for
+pub fn blocked_client(request: &HttpRequest, peer_addr: Option<SocketAddr>, state: &ServerState) -> Option<IpAddr> {
+    let config = &state.config;
+    if config.allow_ips.is_empty() && config.deny_ips.is_empty() {
+        return None;
+    }
+    let peer = peer_addr?.ip();
+    let forwarded_for = request.headers.combined("x-forwarded-for");
+    let client = ip_filter::client_ip(peer, forwarded_for.as_deref(), &config.trusted_proxies);
+    if config.allows_ip(client) {
+        return None;
+    }
+    state.metrics.ip_rejections.fetch_add(1, Ordering::Relaxed);
+    log::debug!("Rejected request from {} (peer {}) by the IP allow/deny lists", client, peer);
+    Some(client)
+}
+
+/// Add the request ID, trace context, and connection headers to a routed response
+pub fn finish_response(
+    mut response: HttpResponse,
+    request_id: &str,
+    span: Option<&Span>,
+    config: &Config,
+    keep_open: bool,
+) -> HttpResponse {
+    response = response.header(request_id::HEADER, request_id);
+    if let Some(span) = span {
+        response = response.header("traceresponse", span.context().to_header());
+    }
+    // Tell the client whether the connection stays open after this response
+    if keep_open {
+        response
+            .header("Connection", "keep-alive")
+            .header("Keep-Alive", format!("timeout={}", config.keep_alive_timeout_secs))
+    } else {
+        response.header("Connection", "close")
+    }
+}
+
+/// Log a failed request and build the problem response for it; the
+/// connection may be mid-request, so it is always closed afterwards
+pub fn error_response(
+    e: &ServerError,
+    request: Option<&HttpRequest>,
+    peer_addr: Option<SocketAddr>,
+    request_id: Option<&str>,
+) -> HttpResponse {
+    log::error!(
+        "Error handling request from {:?}: {}",
+        peer_addr.unwrap_or_else(|| "unknown".parse().unwrap()),
+        e
+    );
+    let mut problem = Problem::from(e);
+    if let Some(request) = request {
+        problem = problem.instance(&request.path);
+    }
+    if let Some(id) = request_id {
+        problem = problem.request_id(id);
+    }
+    let mut response = problem.to_response(request).header("Connection", "close");
+    if matches!(e, ServerError::Overloaded(_)) {
+        response = response.header("Retry-After", SHED_RETRY_AFTER_SECS.to_string());
+    }
+    match request_id {
+        Some(id) => response.header(request_id::HEADER, id),
+        None => response,
+    }
+}
+
+/// Note a client that disconnected mid-request or mid-response; this is routine
+/// and not a server error, so it is only logged at debug level
+pub fn client_aborted(e: &ServerError, peer_addr: Option<SocketAddr>, metrics: &ServerMetrics) {
+    log::debug!("Client {:?} disconnected before the response was sent: {}", peer_addr, e);
+    metrics.client_aborts.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The route pattern a request is counted under in the metrics: the
+/// registered route it matched, or else the built-in endpoint
+pub fn route_label(request: &HttpRequest) -> &'static str {
+    match request.route.get() {
+        Some(matched) => matched.pattern,
+        None => Router::route_pattern(&request.path),
+    }
+}
+
+/// What happened to one request, recorded once its response has been sent
+pub struct RequestOutcome<'a> {
+    pub peer_addr: Option<SocketAddr>,
+    pub request: Option<&'a HttpRequest>,
+    pub request_id: Option<&'a str>,
+    pub span: Option<Span>,
+    pub failed: bool,
+    pub status: u16,
+    pub bytes: usize,
+    pub elapsed: Duration,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// Update metrics, finish the span, and write the slow-request and access logs
+pub fn record_request(state: &ServerState, outcome: RequestOutcome) {
+    let metrics = &state.metrics;
+    let RequestOutcome { peer_addr, request, status, bytes, elapsed, .. } = outcome;
+
+    if outcome.failed {
+        metrics.error_count.fetch_add(1, Ordering::Relaxed);
+    }
+    metrics.record_status(status);
+    let route = request.map_or("invalid", route_label);
+    if let Some(request) = request {
+        metrics.record_request(request.method.as_str(), route, status, elapsed);
+    }
+    metrics.record_transfer(route, outcome.bytes_in, outcome.bytes_out);
+
+    if let (Some(tracer), Some(span)) = (&state.tracer, outcome.span) {
+        tracer.finish(span, status);
+    }
+
+    // Flag pathologically slow requests
+    let slow_threshold = state.config.slow_request_ms;
+    if slow_threshold > 0 && elapsed.as_millis() >= u128::from(slow_threshold) {
+        metrics.slow_request_count.fetch_add(1, Ordering::Relaxed);
+        metrics.record_slow_request(SlowRequest {
+            time: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            request_line: request.map_or_else(|| "-".to_string(), |r| r.request_line()),
+            client: peer_addr.map(|addr| addr.ip()),
+            status,
+            duration: elapsed,
+        });
+        log::warn!(
+            "Slow request: {} took {} ms (client: {}, status: {}, request body: {} bytes, response body: {} bytes)",
+            request.map_or_else(|| "-".to_string(), |r| r.request_line()),
+            elapsed.as_millis(),
+            peer_addr.map_or_else(|| "unknown".to_string(), |addr| addr.to_string()),
+            status,
+            request.map_or(0, |r| r.body.len()),
+            bytes
+        );
+    }
+
+    if let Some(access_log) = &state.access_log {
+        access_log.log(&AccessLogEntry {
+            client_ip: peer_addr.map(|addr| addr.ip()),
+            time: chrono::Local::now(),
+            request,
+            request_id: outcome.request_id,
+            status,
+            bytes,
+            duration: elapsed,
+        });
+    }
+}
+
+/// Handle a client connection, serving requests until the client closes it,
+/// it sits idle past the timeout, or the keep-alive request limit is reached
+fn handle_client(stream: TcpStream, state: Arc<ServerState>, listener: Listener) {
+    use std::io::BufRead;
+
+    let peer_addr = stream.peer_addr().ok();
+    let config = &state.config;
+    let metrics = &state.metrics;
+
+    // TCP_NODELAY plus any configured keepalive and linger settings
+    if let Err(e) = net::configure_stream(&stream, &config.socket_options()) {
+        log::debug!("Failed to apply socket options for {:?}: {}", peer_addr, e);
+    }
+
+    // The admin listener handles few connections at a time, and the probe
+    // lane has few workers, so neither keeps connections open and both give
+    // up on stalled clients quickly
+    let (idle_timeout, keep_alive_timeout) = match listener {
+        Listener::Admin | Listener::Probe => (Some(Duration::from_secs(5)), None),
+        Listener::Public => (
+            (config.idle_timeout_secs > 0).then(|| Duration::from_secs(config.idle_timeout_secs)),
+            (config.keep_alive_timeout_secs > 0).then(|| Duration::from_secs(config.keep_alive_timeout_secs)),
+        ),
+    };
+    let _ = stream.set_read_timeout(idle_timeout);
+
+    // Track active connection
+    metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+    let connection = metrics.connections.open(peer_addr);
+
+    // Operational endpoints are never slowed down by the bandwidth limits
+    let public = listener == Listener::Public;
+    let stream = CountingStream::new(stream)
+        .throttle_reads(public.then(|| state.ingress.connection()).flatten())
+        .throttle_writes(public.then(|| state.egress.connection()).flatten());
+    let mut reader = PooledReader::with_capacity(8192, stream);
+    let mut served = 0;
+    loop {
+        // Wait for the next request; a clean close or idle timeout ends the connection
+        let waited = match (served, keep_alive_timeout) {
+            (0, _) | (_, None) => reader.fill_buf().map(|buf| !buf.is_empty()),
+            (_, Some(timeout)) => await_next_request(&mut reader, timeout, &state),
+        };
+        match waited {
+            Ok(true) => {}
+            Ok(false) => break,
+            Err(e) => {
+                log::debug!("Closing idle connection from {:?}: {}", peer_addr, e);
+                break;
+            }
+        }
+
+        served += 1;
+        metrics.connections.request_started(connection);
+        let may_keep_alive = keep_alive_timeout.is_some() && served < config.max_keep_alive_requests;
+        let keep_open = serve_request(&mut reader, peer_addr, &state, listener, may_keep_alive);
+        let counts = reader.get_ref();
+        metrics.connections.request_finished(connection, counts.bytes_read(), counts.bytes_written());
+        if !keep_open {
+            break;
+        }
+
+        // Between requests the shorter keep-alive timeout applies
+        let _ = reader.get_ref().get_ref().set_read_timeout(keep_alive_timeout);
+    }
+
+    let counts = reader.get_ref();
+    metrics.connections.close(connection, counts.bytes_read(), counts.bytes_written());
+    metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Wait up to `timeout` for another request on a kept-alive connection;
+/// returns whether one arrived
+///
+/// Responses carry `Connection: close` while the server drains, but an idle
+/// connection has no response to carry it, so it is closed within
+/// [`DRAIN_POLL`] of draining starting instead of holding up shutdown.
+fn await_next_request(
+    reader: &mut PooledReader<CountingStream<TcpStream>>,
+    timeout: Duration,
+    state: &ServerState,
+) -> io::Result<bool> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        if state.router.readiness.is_draining() {
+            return Ok(false);
+        }
+        reader.get_ref().get_ref().set_read_timeout(Some(left.min(DRAIN_POLL)))?;
+        let arrived = match reader.fill_buf() {
+            Ok(buf) => !buf.is_empty(),
+            Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(e) => return Err(e),
+        };
+        // The rest of the request gets the whole keep-alive timeout again
+        reader.get_ref().get_ref().set_read_timeout(Some(timeout))?;
+        return Ok(arrived);
+    }
+}
+
+/// Parse, route, and log one request from the connection; returns whether the
+/// connection should stay open for another request
+fn serve_request(
+    reader: &mut PooledReader<CountingStream<TcpStream>>,
+    peer_addr: Option<SocketAddr>,
+    state: &Arc<ServerState>,
+    listener: Listener,
+    may_keep_alive: bool,
+) -> bool {
+    let start_time = Instant::now();
+    let (read_before, written_before) = (reader.get_ref().bytes_read(), reader.get_ref().bytes_written());
+    // Bytes already buffered were read from the socket before this request started
+    let read_before = read_before - reader.buffer().len() as u64;
+
+    // Parse the HTTP request head; the body follows once the client is let in
+    let request = HttpRequest::parse_head(reader).map(|mut request| {
+        request.peer_addr = peer_addr;
+        request
+    });
+
+    // Assign a request ID that tags every log line for this request
+    let (request_id, span) = match &request {
+        Ok(request) => {
+            let (id, span) = begin_request(request, peer_addr, state);
+            (Some(id), span)
+        }
+        Err(_) => (None, None),
+    };
+    let _scope = request_id.as_deref().map(request_id::Scope::enter);
+
+    // Blocked clients get a 403, or with --deny-action close, nothing at all
+    let blocked = listener != Listener::Admin
+        && request.as_ref().is_ok_and(|request| blocked_client(request, peer_addr, state).is_some());
+    if blocked && state.config.deny_action == DenyAction::Close {
+        return false;
+    }
+    // A large upload holds its slot until the handler is done with the body
+    let mut streamed = 0;
+    let mut _upload = None;
+    let request = match request {
+        Ok(mut request) if !blocked => read_body(&mut request, reader, state).map(|(n, permit)| {
+            streamed = n;
+            _upload = permit;
+            request
+        }),
+        request => request,
+    };
+
+    // Reuse the connection only if the client wants to and the server isn't draining
+    let keep_open = may_keep_alive
+        && !blocked
+        && !state.router.readiness.is_draining()
+        && request.as_ref().is_ok_and(|request| request.wants_keep_alive());
+
+    let (request, result) = match request {
+        Ok(request) => {
+            let request = Arc::new(request);
+            let id = request_id.as_deref().unwrap_or_default();
+            log::debug!("Request {}: {} {}", id, request.method.as_str(), request.path);
+            let response = if blocked {
+                Ok(HttpResponse::forbidden())
+            } else {
+                route_in_time(state, &request, listener)
+            };
+            let result = response
+                .and_then(|response| respond(response, id, span.as_ref(), reader.get_mut(), &state.config, keep_open));
+            (Some(request), result)
+        }
+        Err(e) => (None, Err(e)),
+    };
+    let elapsed = start_time.elapsed();
+
+    let (failed, status, bytes, keep_open) = match result {
+        Ok((status, bytes)) => (false, status, bytes, keep_open),
+        // Nobody is left to send an error response to
+        Err(e) if e.is_client_abort() => {
+            client_aborted(&e, peer_addr, &state.metrics);
+            (false, CLIENT_CLOSED_REQUEST, 0, false)
+        }
+        Err(e) => {
+            let response = error_response(&e, request.as_deref(), peer_addr, request_id.as_deref());
+            let bytes = response.body_len();
+            // A streamed body that failed midway has its head out already;
+            // closing the connection is all that is left to do
+            if reader.get_ref().bytes_written() == written_before {
+                let _ = write_response(reader.get_mut(), response);
+            }
+            (true, e.status_code(), bytes, false)
+        }
+    };
+
+    // Skip whatever a streaming handler left of the body, so the next request
+    // is read from the right place
+    let mut unread = 0;
+    let keep_open = match request.as_ref().map(|request| request.body_stream.lock()) {
+        Some(Ok(mut guard)) => match guard.as_mut() {
+            Some(body) if keep_open && body.remaining() > 0 => body.discard().is_ok(),
+            Some(body) => {
+                unread = body.remaining();
+                keep_open
+            }
+            None => keep_open,
+        },
+        _ => keep_open,
+    };
+
+    let stream = reader.get_ref();
+    let read_after = stream.bytes_read() - reader.buffer().len() as u64;
+    record_request(
+        state,
+        RequestOutcome {
+            peer_addr,
+            request: request.as_deref(),
+            request_id: request_id.as_deref(),
+            span,
+            failed,
+            status,
+            bytes,
+            elapsed,
+            bytes_in: read_after - read_before + streamed - unread,
+            bytes_out: stream.bytes_written() - written_before,
+        },
+    );
+
+    keep_open
+}
+
+/// Route a request, giving up with 504 once its handler runs past the
+/// route's timeout
+///
+/// A timed handler runs on a thread of its own so the worker can answer when
+/// time is up; the handler is left to finish and its response is dropped,
+/// and while too many abandoned handlers are still running, timed requests
+/// get 503 instead. Handlers that stream the request body read from this
+/// connection and are never timed.
+pub(crate) fn route_in_time(
+    state: &Arc<ServerState>,
+    request: &Arc<HttpRequest>,
+    listener: Listener,
+) -> Result<HttpResponse, ServerError> {
+    let timeout = match state.router.timeout_for(request) {
+        Some(timeout) if !state.router.streams_body(request) => timeout,
+        _ => return state.router.route(request, &state.metrics, listener),
+    };
+    let (run, wait) = state.router.abandoned_handlers().start()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let (handler_state, handler_request) = (Arc::clone(state), Arc::clone(request));
+    let request_id = request_id::current();
+    std::thread::Builder::new()
+        .name("handler".to_string())
+        .spawn(move || {
+            let _run = run;
+            let _scope = request_id.as_deref().map(request_id::Scope::enter);
+            let response = handler_state.router.route(&handler_request, &handler_state.metrics, listener);
+            let _ = tx.send(response);
+        })
+        .map_err(|e| ServerError::InternalError(format!("Failed to start handler thread: {}", e)))?;
+
+    match rx.recv_timeout(timeout) {
+        Ok(response) => response,
+        Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+            wait.abandon();
+            let route = route_label(request);
+            state.metrics.record_handler_timeout(route);
+            log::warn!("Handler for {} {} timed out after {:?}", request.method.as_str(), request.path, timeout);
+            Err(ServerError::GatewayTimeout(format!("The handler did not respond within {:?}", timeout)))
+        }
+        Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+            Err(ServerError::InternalError("Handler thread panicked".to_string()))
+        }
+    }
+}
+
+/// Read the body of a request whose head was just parsed, or leave it on the
+/// connection if the handler streams it; returns how many body bytes will be
+/// read from the socket directly rather than through `reader`, and the upload
+/// slot a large body holds
+///
+/// A body over --max-body-mb, or a large one without a free upload slot, is
+/// refused before any of it is read.
+fn read_body(
+    request: &mut HttpRequest,
+    reader: &mut PooledReader<CountingStream<TcpStream>>,
+    state: &ServerState,
+) -> Result<(u64, Option<UploadPermit>), ServerError> {
+    request.check_body_limit(state.config.max_body_size())?;
+    let length = request.content_length()?;
+    let permit = state.uploads.admit(length as u64, &state.metrics)?;
+    if length == 0 || !state.router.streams_body(request) {
+        request.read_body(reader)?;
+        return Ok((0, permit));
+    }
+
+    // Hand over what is already buffered, then read the rest from a second
+    // handle on the socket, which shares the read timeout and rate limit
+    let buffered = &reader.buffer()[..reader.buffer().len().min(length)];
+    let buffered = buffered.to_vec();
+    reader.consume(buffered.len());
+    let socket = ThrottledReader::new(reader.get_ref().get_ref().try_clone()?, reader.get_ref().read_throttle());
+    let streamed = (length - buffered.len()) as u64;
+    request.stream_body(io::Cursor::new(buffered).chain(socket))?;
+    Ok((streamed, permit))
+}
+
+/// Accept loop for the admin listener; connections are handled on their own
+/// threads, so a metrics stream doesn't hold up scrapes, but at most
+/// [`ADMIN_CONNECTIONS`] at a time
+pub fn serve_admin(listener: TcpListener, state: Arc<ServerState>) {
+    let open = Arc::new(AtomicUsize::new(0));
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::error!("Failed to accept admin connection: {}", e);
+                continue;
+            }
+        };
+        // The last connection is handled on this thread, which stops
+        // accepting until it is done
+        if open.load(Ordering::Relaxed) + 1 >= ADMIN_CONNECTIONS {
+            handle_client(stream, Arc::clone(&state), Listener::Admin);
+            continue;
+        }
+        open.fetch_add(1, Ordering::Relaxed);
+        let (state, handled) = (Arc::clone(&state), Arc::clone(&open));
+        let spawned = std::thread::Builder::new().name("admin".to_string()).spawn(move || {
+            handle_client(stream, state, Listener::Admin);
+            handled.fetch_sub(1, Ordering::Relaxed);
+        });
+        if let Err(e) = spawned {
+            open.fetch_sub(1, Ordering::Relaxed);
+            log::error!("Failed to start admin connection thread: {}", e);
+        }
+    }
+}
+
+/// Re-read the configuration and apply the settings that can change without a
+/// restart; an invalid configuration is rejected and the current one kept
+pub fn reload_config(state: &ServerState) {
+    let args: Vec<std::ffi::OsString> = std::env::args_os().collect();
+    let config = match Config::load(&args) {
+        Ok(config) => config,
+        Err(e) => {
+            log::error!("Configuration reload failed, keeping current settings: {}", e.to_string().trim());
+            return;
+        }
+    };
+    if let Err(e) = config.validate() {
+        log::error!("Configuration reload failed, keeping current settings: {}", e);
+        return;
+    }
+    apply_reloadable(state, &config);
+}
+
+/// Apply the settings of `config` that can change without a restart
+pub fn apply_reloadable(state: &ServerState, config: &Config) {
+    state.router.set_file_directory(&config.directory);
+    match config.templates() {
+        Ok(templates) => template::install(templates),
+        Err(e) => log::error!("Keeping current page templates: {}", e),
+    }
+    let filter = config.log_filter();
+    if log_level::current().as_deref() != Some(filter.as_str()) {
+        if let Err(e) = log_level::set(&filter) {
+            log::error!("Failed to apply log filter: {}", e);
+        }
+    }
+    let (per_connection, total) = config.ingress_rates();
+    state.ingress.set(per_connection, total);
+    let (per_connection, total) = config.egress_rates();
+    state.egress.set(per_connection, total);
+    log::info!(
+        "Configuration reloaded (directory: {}, log filter: {}, page templates, bandwidth limits); \
+         other settings apply on restart",
+        config.directory,
+        filter
+    );
+}
+
+/// Accept connections from one listener and hand them to the worker pool until
+/// `shutdown` is set; the listener is closed on return
+///
+/// While every worker is busy, connections whose first request is for an
+/// operational endpoint go to the `probes` pool instead, so health checks
+/// and scrapes keep answering even while application traffic is shed.
+fn accept_loop(
+    listener: TcpListener,
+    state: Arc<ServerState>,
+    pool: AdaptivePool,
+    probes: Option<ThreadPool>,
+    shutdown: Arc<AtomicBool>,
+) {
+    while !shutdown.load(Ordering::Relaxed) {
+        // Poll rather than block so the shutdown flag is noticed promptly
+        match platform::wait_for_connection(&listener, Duration::from_millis(250)) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => {
+                log::error!("Failed to wait for connections: {}", e);
+                std::thread::sleep(Duration::from_millis(100));
+                continue;
+            }
+        }
+
+        match listener.accept() {
+            Ok((stream, peer_addr)) => {
+                if state.config.deny_action == DenyAction::Close && blocked_peer(peer_addr.ip(), &state) {
+                    continue;
+                }
+                // Accepted sockets may inherit the listener's non-blocking mode
+                let _ = stream.set_nonblocking(false);
+                let saturated = state.metrics.jobs_queued.load(Ordering::Relaxed) > 0
+                    || state.metrics.workers_busy.load(Ordering::Relaxed) >= pool.size() as u64;
+                if let Some(probes) = probes.as_ref().filter(|_| saturated && opens_with_probe(&stream)) {
+                    state.metrics.probe_lane.fetch_add(1, Ordering::Relaxed);
+                    let state = Arc::clone(&state);
+                    probes.execute(move || handle_client(stream, state, Listener::Probe));
+                    continue;
+                }
+                let max_queue = state.config.max_queue;
+                if max_queue > 0 && state.metrics.jobs_queued.load(Ordering::Relaxed) >= max_queue {
+                    shed(stream, peer_addr, &state.metrics);
+                    continue;
+                }
+                let state = Arc::clone(&state);
+                let queued_at = Instant::now();
+                state.metrics.job_queued();
+                pool.execute(move || {
+                    state.metrics.job_started(queued_at.elapsed());
+                    handle_client(stream, Arc::clone(&state), Listener::Public);
+                    state.metrics.job_finished();
+                });
+            }
+            // Another acceptor or process sharing the socket took the connection first
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {}
+            Err(e) => {
+                log::error!("Failed to accept connection: {}", e);
+            }
+        }
+    }
+}
+
+/// Whether a connection's first request is for an operational endpoint
+///
+/// Peeks at the request line without consuming it, waiting at most
+/// [`PROBE_PEEK`] for it to arrive; this holds up the accept loop, so it is
+/// only done while the worker pool is saturated.
+fn opens_with_probe(stream: &TcpStream) -> bool {
+    let mut head = [0; 64];
+    let _ = stream.set_read_timeout(Some(PROBE_PEEK));
+    let n = stream.peek(&mut head).unwrap_or(0);
+    let _ = stream.set_read_timeout(None);
+    is_probe_request(&head[..n])
+}
+
+/// Whether `head`, the start of a request, asks for an operational endpoint
+/// (`/health`, `/metrics`, `/admin/...`); a request line cut short never does
+fn is_probe_request(head: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(head);
+    let mut parts = line.splitn(3, ' ');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(_method), Some(target), Some(_version)) => {
+            Router::is_admin_path(target.split('?').next().unwrap_or_default())
+        }
+        _ => false,
+    }
+}
+
+/// Turn a connection away because the worker queue is full
+///
+/// The 503 is written from the accept loop without reading the request, so
+/// the client learns at once instead of timing out behind the backlog; a
+/// fresh socket's send buffer takes it without blocking.
+fn shed(mut stream: TcpStream, peer_addr: SocketAddr, metrics: &ServerMetrics) {
+    metrics.jobs_shed.fetch_add(1, Ordering::Relaxed);
+    log::debug!("Worker queue full, shedding connection from {}", peer_addr);
+    let _ = stream.set_write_timeout(Some(Duration::from_millis(100)));
+    let response = Problem::new(StatusCode::ServiceUnavailable, "overloaded", "The server is at capacity, retry shortly")
+        .to_response(None)
+        .header("Retry-After", SHED_RETRY_AFTER_SECS.to_string())
+        .header("Connection", "close");
+    let _ = response.write_to(&mut stream);
+    // Closing with the request still unread would reset the connection and
+    // could discard the 503, so take whatever has arrived first
+    let _ = stream.shutdown(std::net::Shutdown::Write);
+    let _ = stream.set_nonblocking(true);
+    let _ = stream.read(&mut [0; 4096]);
+}
+
+/// Run one accept loop per listener, all feeding a shared worker pool, until
+/// `shutdown` is set; the calling thread runs the first one
+pub fn run_acceptors(listeners: Vec<TcpListener>, state: &Arc<ServerState>, shutdown: &Arc<AtomicBool>) -> std::io::Result<()> {
+    let pool = AdaptivePool::new(state.config.workers, state.config.max_workers, Arc::clone(&state.metrics));
+    pool.supervise(Arc::clone(shutdown))?;
+    // Operational endpoints are already kept apart on a separate admin listener
+    let probes = (state.config.probe_workers > 0 && state.config.admin_addr.is_none())
+        .then(|| ThreadPool::with_name("probe".to_string(), state.config.probe_workers));
+    let mut listeners = listeners.into_iter();
+    let main_listener = listeners.next().expect("at least one listener is bound");
+    let acceptor_threads = listeners
+        .enumerate()
+        .map(|(i, listener)| {
+            let state = Arc::clone(state);
+            let pool = pool.clone();
+            let probes = probes.clone();
+            let shutdown = Arc::clone(shutdown);
+            std::thread::Builder::new()
+                .name(format!("acceptor-{}", i + 1))
+                .spawn(move || accept_loop(listener, state, pool, probes, shutdown))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    accept_loop(main_listener, Arc::clone(state), pool, probes, Arc::clone(shutdown));
+    for thread in acceptor_threads {
+        let _ = thread.join();
+    }
+    Ok(())
+}
+
+/// Wait up to `timeout` (`--drain-timeout-secs`) for in-flight connections to
+/// finish after the listeners have closed
+pub fn wait_for_drain(metrics: &ServerMetrics, timeout: Duration) {
+    log::info!("Shutdown initiated, no longer accepting new connections");
+
+    // Wait for active connections to finish
+    log::info!("Waiting for {} active connections to finish...", 
+        metrics.active_connections.load(Ordering::Relaxed));
+    
+    // Give threads time to finish (with timeout)
+    let shutdown_start = Instant::now();
+    
+    while metrics.active_connections.load(Ordering::Relaxed) > 0 
+        && shutdown_start.elapsed() < timeout {
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    
+    let remaining = metrics.active_connections.load(Ordering::Relaxed);
+    if remaining > 0 {
+        log::warn!("Shutdown timeout reached with {} connections still active", remaining);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shed_answers_503_with_retry_after() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, peer_addr) = listener.accept().unwrap();
+        let metrics = ServerMetrics::new();
+        shed(stream, peer_addr, &metrics);
+
+        let mut response = String::new();
+        client.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"), "{}", response);
+        assert!(response.contains("\r\nRetry-After: 1\r\n"));
+        assert!(response.contains("\"code\":\"overloaded\""));
+        assert_eq!(metrics.jobs_shed.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_probe_requests_are_recognized_without_consuming_them() {
+        assert!(is_probe_request(b"GET /health/ready HTTP/1.1\r\nHost: x\r\n"));
+        assert!(is_probe_request(b"GET /metrics?format=json HTTP/1.1\r\n"));
+        assert!(!is_probe_request(b"GET /healthy HTTP/1.1\r\n"));
+        assert!(!is_probe_request(b"GET /health"));
+        assert!(!is_probe_request(b""));
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        io::Write::write_all(&mut client, b"GET /health HTTP/1.1\r\n\r\n").unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+        assert!(opens_with_probe(&stream));
+        let mut head = [0; 11];
+        stream.read_exact(&mut head).unwrap();
+        assert_eq!(&head, b"GET /health");
+    }
+
+    #[test]
+    fn test_slow_handler_times_out_with_504() {
+        use crate::timeout::RouteTimeouts;
+        use clap::Parser;
+
+        let config = Config::parse_from(["http-server", "--debug-endpoints", "--max-abandoned-handlers", "2"]);
+        let timeouts = RouteTimeouts::new(Some(Duration::from_millis(50))).route("/echo/*", None);
+        let stuck = |_: &HttpRequest| {
+            std::thread::sleep(Duration::from_millis(500));
+            Ok(HttpResponse::ok())
+        };
+        let state = Arc::new(ServerState {
+            router: Router::new(&config).timeouts(timeouts).get("/stuck/{id}", stuck),
+            uploads: config.upload_limiter(),
+            ingress: RateLimit::new(0, 0, Arc::default()),
+            egress: RateLimit::new(0, 0, Arc::default()),
+            config,
+            metrics: Arc::new(ServerMetrics::new()),
+            access_log: None,
+            tracer: None,
+        });
+        let request = |raw: &[u8]| Arc::new(HttpRequest::parse(&mut &raw[..]).unwrap());
+
+        let slow = request(b"GET /delay/1 HTTP/1.1\r\n\r\n");
+        let error = route_in_time(&state, &slow, Listener::Public).unwrap_err();
+        assert_eq!(error.status_code(), 504);
+        let fast = request(b"GET /echo/hi HTTP/1.1\r\n\r\n");
+        assert!(route_in_time(&state, &fast, Listener::Public).is_ok());
+
+        // Registered routes are counted under their pattern
+        let stuck = request(b"GET /stuck/1 HTTP/1.1\r\n\r\n");
+        assert_eq!(route_in_time(&state, &stuck, Listener::Public).unwrap_err().status_code(), 504);
+
+        let metrics = state.metrics.render_prometheus();
+        assert!(metrics.contains("http_handler_timeouts_total{route=\"/delay/:seconds\"} 1\n"), "{}", metrics);
+        assert!(metrics.contains("http_handler_timeouts_total{route=\"/stuck/{id}\"} 1\n"), "{}", metrics);
+
+        // Both abandoned handlers are still running: timed requests are shed
+        // until they return, untimed ones are not
+        assert_eq!(state.router.abandoned_handlers().running(), 2);
+        assert_eq!(route_in_time(&state, &stuck, Listener::Public).unwrap_err().status_code(), 503);
+        assert!(route_in_time(&state, &fast, Listener::Public).is_ok());
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while state.router.abandoned_handlers().running() > 0 && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(route_in_time(&state, &stuck, Listener::Public).unwrap_err().status_code(), 504);
+    }
+
+    #[test]
+    fn test_reload_applies_bandwidth_limits() {
+        use clap::Parser;
+
+        let config = Config::parse_from(["http-server"]);
+        let metrics = Arc::new(ServerMetrics::new());
+        let state = ServerState {
+            router: Router::new(&config),
+            uploads: config.upload_limiter(),
+            ingress: config.ingress_limit(Arc::clone(&metrics.ingress)),
+            egress: config.egress_limit(Arc::clone(&metrics.egress)),
+            config,
+            metrics,
+            access_log: None,
+            tracer: None,
+        };
+        assert!(state.egress.connection().is_none());
+        assert!(state.ingress.connection().is_none());
+
+        let reloaded = Config::parse_from(["http-server", "--egress-rate-kb", "64", "--ingress-rate-total-kb", "8"]);
+        apply_reloadable(&state, &reloaded);
+        assert!(state.egress.connection().is_some());
+        assert_eq!(state.metrics.egress.to_json()["limit_per_connection"], 65536);
+        assert!(state.ingress.connection().is_some());
+        assert_eq!(state.metrics.ingress.to_json()["limit_total"], 8192);
+    }
+}
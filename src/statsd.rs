@@ -0,0 +1,207 @@
+use crate::metrics::{Histogram, RouteKey, ServerMetrics};
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::net::UdpSocket;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Keep datagrams under a typical MTU so they are never fragmented
+const MAX_PACKET_BYTES: usize = 1432;
+
+/// Wire format used when pushing metrics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StatsdFormat {
+    /// Plain StatsD: global series only
+    Statsd,
+    /// DogStatsD: per-route series tagged with method, route, and status class
+    Dogstatsd,
+}
+
+/// Counter values at the previous flush, used to push deltas
+#[derive(Default)]
+struct Snapshot {
+    errors: u64,
+    slow_requests: u64,
+    bytes: (u64, u64),
+    routes: BTreeMap<RouteKey, Histogram>,
+}
+
+/// Periodically pushes ServerMetrics to a StatsD endpoint over UDP
+pub struct StatsdExporter {
+    prefix: String,
+    format: StatsdFormat,
+    previous: Snapshot,
+}
+
+impl StatsdExporter {
+    /// Spawn the background exporter thread
+    pub fn spawn(
+        addr: &str,
+        prefix: &str,
+        format: StatsdFormat,
+        interval: Duration,
+        metrics: Arc<ServerMetrics>,
+    ) -> std::io::Result<()> {
+        let socket = UdpSocket::bind("0.0.0.0:0")?;
+        socket.connect(addr)?;
+
+        let mut exporter = StatsdExporter {
+            prefix: prefix.trim_end_matches('.').to_string(),
+            format,
+            previous: Snapshot::default(),
+        };
+
+        std::thread::Builder::new()
+            .name("statsd-exporter".to_string())
+            .spawn(move || loop {
+                std::thread::sleep(interval);
+                for packet in exporter.packets(&metrics) {
+                    if let Err(e) = socket.send(packet.as_bytes()) {
+                        log::debug!("Failed to send StatsD packet: {}", e);
+                    }
+                }
+            })?;
+        Ok(())
+    }
+
+    /// Build the metric lines for this flush, grouped into datagrams
+    fn packets(&mut self, metrics: &ServerMetrics) -> Vec<String> {
+        let mut packets = Vec::new();
+        let mut packet = String::new();
+        for line in self.lines(metrics) {
+            if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET_BYTES {
+                packets.push(std::mem::take(&mut packet));
+            }
+            if !packet.is_empty() {
+                packet.push('\n');
+            }
+            packet.push_str(&line);
+        }
+        if !packet.is_empty() {
+            packets.push(packet);
+        }
+        packets
+    }
+
+    /// Compute counter deltas since the last flush plus current gauge values
+    fn lines(&mut self, metrics: &ServerMetrics) -> Vec<String> {
+        let current = Snapshot {
+            errors: metrics.error_count.load(Ordering::Relaxed),
+            slow_requests: metrics.slow_request_count.load(Ordering::Relaxed),
+            bytes: metrics.total_transfer(),
+            routes: metrics.route_latencies(),
+        };
+        let previous = std::mem::replace(&mut self.previous, current);
+        let current = &self.previous;
+        let p = &self.prefix;
+        let mut lines = Vec::new();
+
+        // Per-route deltas; plain StatsD has no tags, so routes are folded together
+        let mut route_deltas = Vec::new();
+        for (key, histogram) in &current.routes {
+            let mut delta = *histogram;
+            if let Some(before) = previous.routes.get(key) {
+                delta.subtract(before);
+            }
+            if delta.count() > 0 {
+                route_deltas.push((*key, delta));
+            }
+        }
+        let series: Vec<(String, Histogram)> = match self.format {
+            StatsdFormat::Dogstatsd => route_deltas
+                .into_iter()
+                .map(|((method, route, status), delta)| {
+                    (format!("|#method:{},route:{},status:{}", method, route, status), delta)
+                })
+                .collect(),
+            StatsdFormat::Statsd if !route_deltas.is_empty() => {
+                let total = route_deltas.iter().fold(Histogram::default(), |mut total, (_, h)| {
+                    total.merge(h);
+                    total
+                });
+                vec![(String::new(), total)]
+            }
+            StatsdFormat::Statsd => Vec::new(),
+        };
+        for (tags, delta) in series {
+            let count = delta.count();
+            let mean_ms = delta.sum() * 1000.0 / count as f64;
+            lines.push(format!("{}.requests:{}|c{}", p, count, tags));
+            // A timer sampled at 1/count stands in for `count` observations of the mean
+            lines.push(format!(
+                "{}.request.duration:{:.3}|ms|@{}{}",
+                p,
+                mean_ms,
+                1.0 / count as f64,
+                tags
+            ));
+        }
+
+        for (name, now, before) in [
+            ("errors", current.errors, previous.errors),
+            ("slow_requests", current.slow_requests, previous.slow_requests),
+            ("bytes.received", current.bytes.0, previous.bytes.0),
+            ("bytes.sent", current.bytes.1, previous.bytes.1),
+        ] {
+            if now > before {
+                lines.push(format!("{}.{}:{}|c", p, name, now - before));
+            }
+        }
+
+        for (name, value) in [
+            ("connections.active", &metrics.active_connections),
+            ("workers.active", &metrics.workers_busy),
+            ("workers.queued", &metrics.jobs_queued),
+        ] {
+            lines.push(format!("{}.{}:{}|g", p, name, value.load(Ordering::Relaxed)));
+        }
+
+        lines
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn exporter(format: StatsdFormat) -> StatsdExporter {
+        StatsdExporter {
+            prefix: "web".to_string(),
+            format,
+            previous: Snapshot::default(),
+        }
+    }
+
+    #[test]
+    fn test_statsd_pushes_deltas() {
+        let metrics = ServerMetrics::new();
+        let mut exporter = exporter(StatsdFormat::Statsd);
+
+        metrics.record_request("GET", "/", 200, Duration::from_millis(10));
+        metrics.record_request("GET", "/health", 200, Duration::from_millis(30));
+        let lines = exporter.lines(&metrics);
+        assert!(lines.contains(&"web.requests:2|c".to_string()));
+        assert!(lines.contains(&"web.request.duration:20.000|ms|@0.5".to_string()));
+        assert!(lines.contains(&"web.connections.active:0|g".to_string()));
+
+        metrics.record_request("GET", "/", 200, Duration::from_millis(10));
+        metrics.error_count.fetch_add(1, Ordering::Relaxed);
+        let lines = exporter.lines(&metrics);
+        assert!(lines.contains(&"web.requests:1|c".to_string()));
+        assert!(lines.contains(&"web.errors:1|c".to_string()));
+
+        let lines = exporter.lines(&metrics);
+        assert!(!lines.iter().any(|l| l.starts_with("web.requests")));
+    }
+
+    #[test]
+    fn test_dogstatsd_tags_routes() {
+        let metrics = ServerMetrics::new();
+        let mut exporter = exporter(StatsdFormat::Dogstatsd);
+
+        metrics.record_request("GET", "/files/:name", 404, Duration::from_millis(5));
+        let lines = exporter.lines(&metrics);
+        assert!(lines.contains(&"web.requests:1|c|#method:GET,route:/files/:name,status:4xx".to_string()));
+    }
+}
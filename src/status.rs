@@ -0,0 +1,177 @@
+//! HTTP status codes with their reason phrases
+//!
+//! Covers the codes registered in the IANA HTTP Status Code Registry, with
+//! reason phrases from RFC 9110 where it defines them.
+
+use std::fmt;
+
+macro_rules! status_codes {
+    ($($variant:ident = $code:literal, $reason:literal;)*) => {
+        /// A registered HTTP status code
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+        #[repr(u16)]
+        pub enum StatusCode {
+            $($variant = $code,)*
+        }
+
+        impl StatusCode {
+            /// Look up a registered status code; `None` for unregistered ones
+            pub fn from_u16(code: u16) -> Option<Self> {
+                match code {
+                    $($code => Some(StatusCode::$variant),)*
+                    _ => None,
+                }
+            }
+
+            /// Reason phrase sent on the status line, e.g. "Not Found"
+            pub fn reason_phrase(self) -> &'static str {
+                match self {
+                    $(StatusCode::$variant => $reason,)*
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    Continue = 100, "Continue";
+    SwitchingProtocols = 101, "Switching Protocols";
+    Processing = 102, "Processing";
+    EarlyHints = 103, "Early Hints";
+
+    Ok = 200, "OK";
+    Created = 201, "Created";
+    Accepted = 202, "Accepted";
+    NonAuthoritativeInformation = 203, "Non-Authoritative Information";
+    NoContent = 204, "No Content";
+    ResetContent = 205, "Reset Content";
+    PartialContent = 206, "Partial Content";
+    MultiStatus = 207, "Multi-Status";
+    AlreadyReported = 208, "Already Reported";
+    ImUsed = 226, "IM Used";
+
+    MultipleChoices = 300, "Multiple Choices";
+    MovedPermanently = 301, "Moved Permanently";
+    Found = 302, "Found";
+    SeeOther = 303, "See Other";
+    NotModified = 304, "Not Modified";
+    UseProxy = 305, "Use Proxy";
+    TemporaryRedirect = 307, "Temporary Redirect";
+    PermanentRedirect = 308, "Permanent Redirect";
+
+    BadRequest = 400, "Bad Request";
+    Unauthorized = 401, "Unauthorized";
+    PaymentRequired = 402, "Payment Required";
+    Forbidden = 403, "Forbidden";
+    NotFound = 404, "Not Found";
+    MethodNotAllowed = 405, "Method Not Allowed";
+    NotAcceptable = 406, "Not Acceptable";
+    ProxyAuthenticationRequired = 407, "Proxy Authentication Required";
+    RequestTimeout = 408, "Request Timeout";
+    Conflict = 409, "Conflict";
+    Gone = 410, "Gone";
+    LengthRequired = 411, "Length Required";
+    PreconditionFailed = 412, "Precondition Failed";
+    ContentTooLarge = 413, "Content Too Large";
+    UriTooLong = 414, "URI Too Long";
+    UnsupportedMediaType = 415, "Unsupported Media Type";
+    RangeNotSatisfiable = 416, "Range Not Satisfiable";
+    ExpectationFailed = 417, "Expectation Failed";
+    ImATeapot = 418, "I'm a teapot";
+    MisdirectedRequest = 421, "Misdirected Request";
+    UnprocessableContent = 422, "Unprocessable Content";
+    Locked = 423, "Locked";
+    FailedDependency = 424, "Failed Dependency";
+    TooEarly = 425, "Too Early";
+    UpgradeRequired = 426, "Upgrade Required";
+    PreconditionRequired = 428, "Precondition Required";
+    TooManyRequests = 429, "Too Many Requests";
+    RequestHeaderFieldsTooLarge = 431, "Request Header Fields Too Large";
+    UnavailableForLegalReasons = 451, "Unavailable For Legal Reasons";
+
+    InternalServerError = 500, "Internal Server Error";
+    NotImplemented = 501, "Not Implemented";
+    BadGateway = 502, "Bad Gateway";
+    ServiceUnavailable = 503, "Service Unavailable";
+    GatewayTimeout = 504, "Gateway Timeout";
+    HttpVersionNotSupported = 505, "HTTP Version Not Supported";
+    VariantAlsoNegotiates = 506, "Variant Also Negotiates";
+    InsufficientStorage = 507, "Insufficient Storage";
+    LoopDetected = 508, "Loop Detected";
+    NotExtended = 510, "Not Extended";
+    NetworkAuthenticationRequired = 511, "Network Authentication Required";
+}
+
+impl StatusCode {
+    /// The numeric code
+    pub fn as_u16(self) -> u16 {
+        self as u16
+    }
+
+    /// 1xx
+    pub fn is_informational(self) -> bool {
+        (100..200).contains(&self.as_u16())
+    }
+
+    /// 2xx
+    pub fn is_success(self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+
+    /// 3xx
+    pub fn is_redirection(self) -> bool {
+        (300..400).contains(&self.as_u16())
+    }
+
+    /// 4xx
+    pub fn is_client_error(self) -> bool {
+        (400..500).contains(&self.as_u16())
+    }
+
+    /// 5xx
+    pub fn is_server_error(self) -> bool {
+        (500..600).contains(&self.as_u16())
+    }
+}
+
+impl From<StatusCode> for u16 {
+    fn from(status: StatusCode) -> u16 {
+        status.as_u16()
+    }
+}
+
+impl TryFrom<u16> for StatusCode {
+    type Error = u16;
+
+    /// Fails with the code itself if it isn't registered
+    fn try_from(code: u16) -> Result<Self, u16> {
+        StatusCode::from_u16(code).ok_or(code)
+    }
+}
+
+/// Formats as the status line does, e.g. "404 Not Found"
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.as_u16(), self.reason_phrase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_status_codes() {
+        for code in 0..1000 {
+            if let Some(status) = StatusCode::from_u16(code) {
+                assert_eq!(status.as_u16(), code);
+                assert!(!status.reason_phrase().is_empty());
+            }
+        }
+        assert_eq!(StatusCode::from_u16(429), Some(StatusCode::TooManyRequests));
+        assert_eq!(StatusCode::try_from(299), Err(299));
+        assert_eq!(StatusCode::MovedPermanently.to_string(), "301 Moved Permanently");
+        assert!(StatusCode::ServiceUnavailable.is_server_error());
+        assert!(StatusCode::Forbidden.is_client_error() && !StatusCode::Forbidden.is_success());
+    }
+}
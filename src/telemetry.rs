@@ -0,0 +1,322 @@
+use crate::request::HttpRequest;
+use serde_json::{json, Value};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Maximum number of spans sent in one export request
+const MAX_BATCH: usize = 512;
+
+/// How often buffered spans are flushed to the collector
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// W3C trace context carried by the `traceparent` header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub sampled: bool,
+}
+
+impl TraceContext {
+    /// Parse a `traceparent` header value (`00-<trace-id>-<parent-id>-<flags>`)
+    pub fn parse(header: &str) -> Option<Self> {
+        let parts: Vec<&str> = header.trim().split('-').collect();
+        if parts.len() < 4 || parts[0].len() != 2 || parts[0] == "ff" {
+            return None;
+        }
+        // Version 00 has exactly four fields; later versions may append more
+        if parts[0] == "00" && parts.len() != 4 {
+            return None;
+        }
+        if parts[1].len() != 32 || parts[2].len() != 16 || parts[3].len() != 2 {
+            return None;
+        }
+
+        let trace_id = u128::from_str_radix(parts[1], 16).ok()?;
+        let span_id = u64::from_str_radix(parts[2], 16).ok()?;
+        let flags = u8::from_str_radix(parts[3], 16).ok()?;
+        if trace_id == 0 || span_id == 0 {
+            return None;
+        }
+
+        Some(TraceContext {
+            trace_id,
+            span_id,
+            sampled: flags & 0x01 == 0x01,
+        })
+    }
+
+    /// Format as a `traceparent`/`traceresponse` header value
+    pub fn to_header(self) -> String {
+        format!(
+            "00-{:032x}-{:016x}-{:02x}",
+            self.trace_id,
+            self.span_id,
+            u8::from(self.sampled)
+        )
+    }
+}
+
+/// A finished or in-progress server span for one request
+pub struct Span {
+    context: TraceContext,
+    parent_span_id: Option<u64>,
+    name: String,
+    start: SystemTime,
+    started: Instant,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+impl Span {
+    /// Trace context identifying this span
+    pub fn context(&self) -> TraceContext {
+        self.context
+    }
+
+    /// Record an additional attribute on the span
+    pub fn set_attribute(&mut self, key: &'static str, value: impl Into<Value>) {
+        self.attributes.push((key, value.into()));
+    }
+
+    /// Encode the span in the OTLP/JSON representation
+    fn to_otlp(&self, status: u16, duration: Duration) -> Value {
+        let start_ns = self.start.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+        let end_ns = start_ns + duration.as_nanos();
+
+        let mut attributes: Vec<Value> = self
+            .attributes
+            .iter()
+            .map(|(key, value)| json!({ "key": key, "value": Self::any_value(value) }))
+            .collect();
+        attributes.push(json!({
+            "key": "http.response.status_code",
+            "value": { "intValue": status.to_string() }
+        }));
+
+        // Server spans only report an error status for 5xx responses
+        let status_code = if status >= 500 { 2 } else { 0 };
+
+        let mut span = json!({
+            "traceId": format!("{:032x}", self.context.trace_id),
+            "spanId": format!("{:016x}", self.context.span_id),
+            "name": self.name,
+            "kind": 2,
+            "startTimeUnixNano": start_ns.to_string(),
+            "endTimeUnixNano": end_ns.to_string(),
+            "attributes": attributes,
+            "status": { "code": status_code },
+        });
+        if let Some(parent) = self.parent_span_id {
+            span["parentSpanId"] = json!(format!("{:016x}", parent));
+        }
+        span
+    }
+
+    /// Wrap a JSON value in an OTLP AnyValue
+    fn any_value(value: &Value) -> Value {
+        match value {
+            Value::Bool(b) => json!({ "boolValue": b }),
+            Value::Number(n) if n.is_i64() || n.is_u64() => json!({ "intValue": n.to_string() }),
+            Value::Number(n) => json!({ "doubleValue": n }),
+            Value::String(s) => json!({ "stringValue": s }),
+            other => json!({ "stringValue": other.to_string() }),
+        }
+    }
+}
+
+/// Creates request spans and exports them to an OTLP/HTTP collector
+pub struct Tracer {
+    sender: Sender<Value>,
+}
+
+impl Tracer {
+    /// Start a tracer exporting to `endpoint` (e.g. `http://localhost:4318`)
+    pub fn new(endpoint: &str, service_name: &str) -> Result<Self, String> {
+        let target = ExportTarget::parse(endpoint)?;
+        let (sender, receiver) = mpsc::channel();
+        let resource = json!({
+            "attributes": [
+                { "key": "service.name", "value": { "stringValue": service_name } },
+                { "key": "service.version", "value": { "stringValue": env!("CARGO_PKG_VERSION") } }
+            ]
+        });
+
+        std::thread::Builder::new()
+            .name("otlp-exporter".to_string())
+            .spawn(move || Self::export_loop(receiver, target, resource))
+            .map_err(|e| format!("Failed to start OTLP exporter: {}", e))?;
+
+        Ok(Tracer { sender })
+    }
+
+    /// Start a server span for a request, continuing any incoming trace
+    pub fn start_span(&self, request: &HttpRequest, route: &str) -> Span {
+        let parent = request
+            .get_header("traceparent")
+            .and_then(TraceContext::parse);
+        let ids = uuid::Uuid::new_v4().as_u64_pair();
+
+        let context = TraceContext {
+            trace_id: parent.map_or_else(|| uuid::Uuid::new_v4().as_u128(), |p| p.trace_id),
+            span_id: (ids.0 ^ ids.1) | 1,
+            sampled: parent.map_or(true, |p| p.sampled),
+        };
+
+        let mut span = Span {
+            context,
+            parent_span_id: parent.map(|p| p.span_id),
+            name: format!("{} {}", request.method.as_str(), route),
+            start: SystemTime::now(),
+            started: Instant::now(),
+            attributes: Vec::new(),
+        };
+        span.set_attribute("http.request.method", request.method.as_str());
+        span.set_attribute("http.route", route.to_string());
+        span.set_attribute("url.path", request.path.as_str());
+        span.set_attribute("network.protocol.version", request.version.trim_start_matches("HTTP/"));
+        if let Some(user_agent) = request.get_header("user-agent") {
+            span.set_attribute("user_agent.original", user_agent);
+        }
+        span
+    }
+
+    /// End a span with the response status and queue it for export
+    pub fn finish(&self, span: Span, status: u16) {
+        if !span.context.sampled {
+            return;
+        }
+        let otlp = span.to_otlp(status, span.started.elapsed());
+        let _ = self.sender.send(otlp);
+    }
+
+    /// Background loop batching spans and posting them to the collector
+    fn export_loop(receiver: Receiver<Value>, target: ExportTarget, resource: Value) {
+        let mut batch = Vec::new();
+        let mut last_flush = Instant::now();
+
+        loop {
+            let timeout = FLUSH_INTERVAL.saturating_sub(last_flush.elapsed());
+            match receiver.recv_timeout(timeout) {
+                Ok(span) => batch.push(span),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => {
+                    Self::export_batch(&target, &resource, &mut batch);
+                    return;
+                }
+            }
+
+            if batch.len() >= MAX_BATCH || last_flush.elapsed() >= FLUSH_INTERVAL {
+                Self::export_batch(&target, &resource, &mut batch);
+                last_flush = Instant::now();
+            }
+        }
+    }
+
+    /// Send buffered spans as a single OTLP export request
+    fn export_batch(target: &ExportTarget, resource: &Value, batch: &mut Vec<Value>) {
+        if batch.is_empty() {
+            return;
+        }
+
+        let payload = json!({
+            "resourceSpans": [{
+                "resource": resource,
+                "scopeSpans": [{
+                    "scope": { "name": "http-server", "version": env!("CARGO_PKG_VERSION") },
+                    "spans": std::mem::take(batch),
+                }]
+            }]
+        });
+        if let Err(e) = target.post(&payload.to_string()) {
+            log::warn!("Failed to export spans to {}: {}", target.host, e);
+        }
+    }
+}
+
+/// Collector address parsed from an `http://host:port/path` endpoint
+struct ExportTarget {
+    host: String,
+    path: String,
+}
+
+impl ExportTarget {
+    fn parse(endpoint: &str) -> Result<Self, String> {
+        let rest = endpoint
+            .strip_prefix("http://")
+            .ok_or_else(|| format!("OTLP endpoint must be an http:// URL: {}", endpoint))?;
+        let (authority, path) = match rest.find('/') {
+            Some(i) if i + 1 < rest.len() => (&rest[..i], rest[i..].to_string()),
+            Some(i) => (&rest[..i], "/v1/traces".to_string()),
+            None => (rest, "/v1/traces".to_string()),
+        };
+        if authority.is_empty() {
+            return Err(format!("OTLP endpoint has no host: {}", endpoint));
+        }
+        let host = if authority.contains(':') {
+            authority.to_string()
+        } else {
+            format!("{}:4318", authority)
+        };
+        Ok(ExportTarget { host, path })
+    }
+
+    /// POST a JSON payload and check for a 2xx response
+    fn post(&self, body: &str) -> std::io::Result<()> {
+        let mut stream = TcpStream::connect(&self.host)?;
+        stream.set_read_timeout(Some(Duration::from_secs(10)))?;
+        stream.set_write_timeout(Some(Duration::from_secs(10)))?;
+        write!(
+            stream,
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            self.path,
+            self.host,
+            body.len(),
+            body
+        )?;
+
+        let mut status_line = [0u8; 12];
+        stream.read_exact(&mut status_line)?;
+        match &status_line[9..10] {
+            b"2" => Ok(()),
+            _ => Err(std::io::Error::other(format!(
+                "collector responded with {}",
+                String::from_utf8_lossy(&status_line[9..12])
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_traceparent_round_trip() {
+        let header = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+        let context = TraceContext::parse(header).unwrap();
+        assert_eq!(context.trace_id, 0x4bf92f3577b34da6a3ce929d0e0e4736);
+        assert_eq!(context.span_id, 0x00f067aa0ba902b7);
+        assert!(context.sampled);
+        assert_eq!(context.to_header(), header);
+    }
+
+    #[test]
+    fn test_invalid_traceparent() {
+        assert!(TraceContext::parse("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(TraceContext::parse("00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01").is_none());
+        assert!(TraceContext::parse("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(TraceContext::parse("00-4bf92f3577b34da6-00f067aa0ba902b7-01").is_none());
+        assert!(TraceContext::parse("garbage").is_none());
+    }
+
+    #[test]
+    fn test_export_target() {
+        let target = ExportTarget::parse("http://collector:4318").unwrap();
+        assert_eq!((target.host.as_str(), target.path.as_str()), ("collector:4318", "/v1/traces"));
+        let target = ExportTarget::parse("http://collector/custom/traces").unwrap();
+        assert_eq!((target.host.as_str(), target.path.as_str()), ("collector:4318", "/custom/traces"));
+        assert!(ExportTarget::parse("https://collector").is_err());
+    }
+}
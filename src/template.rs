@@ -0,0 +1,320 @@
+//! Templates for the HTML pages the server generates itself
+//!
+//! The landing page, the HTML form of JSON documents such as `/health`, HTML
+//! error pages, the operator dashboard, and the API explorer are rendered
+//! from templates. The built-in ones are in the repository's `templates/`
+//! directory; `--template-dir` points at a directory whose `index.html`,
+//! `document.html`, `error.html`, `dashboard.html`, and `docs.html` replace
+//! them, so pages can be branded without rebuilding. Templates are reloaded
+//! on SIGHUP.
+//!
+//! The syntax is deliberately small:
+//!
+//! - `{{ name }}` inserts a value, HTML-escaped; `{{ item.key }}` reaches into objects
+//! - `{% if name %}…{% else %}…{% endif %}` tests a value; empty strings,
+//!   lists, and objects, `0`, `false`, and missing values are false
+//! - `{% for item in list %}…{% endfor %}` repeats for each element of a list
+
+use crate::response::escape_html;
+use serde_json::Value;
+use std::path::Path;
+use std::sync::{Arc, OnceLock, RwLock};
+
+static INSTALLED: RwLock<Option<Arc<Templates>>> = RwLock::new(None);
+static BUILTIN: OnceLock<Arc<Templates>> = OnceLock::new();
+
+/// A parsed template
+#[derive(Debug, Clone)]
+pub struct Template {
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Text(String),
+    Value(Vec<String>),
+    If(Vec<String>, Vec<Node>, Vec<Node>),
+    For(String, Vec<String>, Vec<Node>),
+}
+
+/// A `{% ... %}` tag that closes or splits a block
+enum Closing {
+    Else,
+    EndIf,
+    EndFor,
+}
+
+impl Template {
+    /// Parse a template; errors name the line of the offending tag
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut parser = Parser { source, pos: 0 };
+        match parser.nodes()? {
+            (nodes, None) => Ok(Template { nodes }),
+            (_, Some(_)) => Err(parser.error("unexpected closing tag")),
+        }
+    }
+
+    /// Render with the values in `context`, which should be a JSON object
+    pub fn render(&self, context: &Value) -> String {
+        let mut out = String::new();
+        render(&self.nodes, &mut vec![Scope::Context(context)], &mut out);
+        out
+    }
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, message: &str) -> String {
+        let line = self.source[..self.pos].matches('\n').count() + 1;
+        format!("line {}: {}", line, message)
+    }
+
+    /// Parse nodes up to the end of input or a closing tag, which is returned
+    fn nodes(&mut self) -> Result<(Vec<Node>, Option<Closing>), String> {
+        let mut nodes = Vec::new();
+        loop {
+            let rest = &self.source[self.pos..];
+            let next = [rest.find("{{"), rest.find("{%")].into_iter().flatten().min();
+            let Some(start) = next else {
+                if !rest.is_empty() {
+                    nodes.push(Node::Text(rest.to_string()));
+                }
+                self.pos = self.source.len();
+                return Ok((nodes, None));
+            };
+            if start > 0 {
+                nodes.push(Node::Text(rest[..start].to_string()));
+            }
+            self.pos += start;
+            let close = if rest[start..].starts_with("{{") { "}}" } else { "%}" };
+            let end = rest[start + 2..].find(close).ok_or_else(|| self.error("unclosed tag"))?;
+            let tag = rest[start + 2..start + 2 + end].trim();
+            let tag_pos = self.pos;
+            self.pos += 2 + end + 2;
+
+            if close == "}}" {
+                nodes.push(Node::Value(self.path(tag, tag_pos)?));
+                continue;
+            }
+            let words: Vec<&str> = tag.split_whitespace().collect();
+            match words.as_slice() {
+                ["if", name] => {
+                    let condition = self.path(name, tag_pos)?;
+                    let (then, closing) = self.nodes()?;
+                    let otherwise = match closing {
+                        Some(Closing::Else) => match self.nodes()? {
+                            (otherwise, Some(Closing::EndIf)) => otherwise,
+                            _ => return Err(self.error("{% else %} without {% endif %}")),
+                        },
+                        Some(Closing::EndIf) => Vec::new(),
+                        _ => return Err(self.error("{% if %} without {% endif %}")),
+                    };
+                    nodes.push(Node::If(condition, then, otherwise));
+                }
+                ["for", item, "in", list] => {
+                    let list = self.path(list, tag_pos)?;
+                    match self.nodes()? {
+                        (body, Some(Closing::EndFor)) => nodes.push(Node::For(item.to_string(), list, body)),
+                        _ => return Err(self.error("{% for %} without {% endfor %}")),
+                    }
+                }
+                ["else"] => return Ok((nodes, Some(Closing::Else))),
+                ["endif"] => return Ok((nodes, Some(Closing::EndIf))),
+                ["endfor"] => return Ok((nodes, Some(Closing::EndFor))),
+                _ => {
+                    self.pos = tag_pos;
+                    return Err(self.error(&format!("unknown tag {{% {} %}}", tag)));
+                }
+            }
+        }
+    }
+
+    /// Split a dotted name such as `row.key`
+    fn path(&mut self, name: &str, tag_pos: usize) -> Result<Vec<String>, String> {
+        let valid = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !name.split('.').all(valid) {
+            self.pos = tag_pos;
+            return Err(self.error(&format!("invalid name '{}'", name)));
+        }
+        Ok(name.split('.').map(str::to_string).collect())
+    }
+}
+
+/// Names visible while rendering: the context object, then one binding per
+/// enclosing loop
+enum Scope<'a> {
+    Context(&'a Value),
+    Loop(&'a str, &'a Value),
+}
+
+/// Look a dotted name up, innermost scope first
+fn lookup<'a>(scopes: &[Scope<'a>], path: &[String]) -> Option<&'a Value> {
+    let (first, rest) = path.split_first()?;
+    let value = scopes.iter().rev().find_map(|scope| match scope {
+        Scope::Context(context) => context.get(first),
+        Scope::Loop(name, value) => (*name == first).then_some(*value),
+    })?;
+    rest.iter().try_fold(value, |value, key| match value {
+        Value::Array(items) => items.get(key.parse::<usize>().ok()?),
+        _ => value.get(key),
+    })
+}
+
+fn truthy(value: Option<&Value>) -> bool {
+    match value {
+        None | Some(Value::Null) => false,
+        Some(Value::Bool(b)) => *b,
+        Some(Value::Number(n)) => n.as_f64() != Some(0.0),
+        Some(Value::String(s)) => !s.is_empty(),
+        Some(Value::Array(items)) => !items.is_empty(),
+        Some(Value::Object(map)) => !map.is_empty(),
+    }
+}
+
+fn render<'a>(nodes: &'a [Node], scopes: &mut Vec<Scope<'a>>, out: &mut String) {
+    for node in nodes {
+        match node {
+            Node::Text(text) => out.push_str(text),
+            Node::Value(path) => match lookup(scopes, path) {
+                None | Some(Value::Null) => {}
+                Some(Value::String(s)) => out.push_str(&escape_html(s)),
+                Some(value) => out.push_str(&escape_html(&value.to_string())),
+            },
+            Node::If(condition, then, otherwise) => {
+                let branch = if truthy(lookup(scopes, condition)) { then } else { otherwise };
+                render(branch, scopes, out);
+            }
+            Node::For(item, list, body) => {
+                let Some(Value::Array(items)) = lookup(scopes, list) else { continue };
+                for value in items {
+                    scopes.push(Scope::Loop(item, value));
+                    render(body, scopes, out);
+                    scopes.pop();
+                }
+            }
+        }
+    }
+}
+
+/// The set of page templates the server renders
+#[derive(Debug, Clone)]
+pub struct Templates {
+    /// Landing page at `/`; gets `version`
+    pub index: Template,
+    /// JSON documents shown as HTML; gets `title`, `rows` (each with `key`
+    /// and `value`), and the original `document`
+    pub document: Template,
+    /// Error pages; gets the problem details fields (`status`, `title`,
+    /// `detail`, `code`, `instance`, `request_id`)
+    pub error: Template,
+    /// Operator dashboard at `/dashboard`; gets the metrics snapshot of
+    /// [`dashboard::snapshot`](crate::dashboard::snapshot), `refresh_secs`,
+    /// and `version`
+    pub dashboard: Template,
+    /// API explorer at `/docs`; gets `title`, `spec_url` (where the OpenAPI
+    /// document is), and `version`
+    pub docs: Template,
+}
+
+impl Templates {
+    /// The templates compiled into the server
+    pub fn builtin() -> Self {
+        let parse = |source| Template::parse(source).expect("built-in templates are valid");
+        Templates {
+            index: parse(include_str!("../templates/index.html")),
+            document: parse(include_str!("../templates/document.html")),
+            error: parse(include_str!("../templates/error.html")),
+            dashboard: parse(include_str!("../templates/dashboard.html")),
+            docs: parse(include_str!("../templates/docs.html")),
+        }
+    }
+
+    /// The built-in templates, replaced by any of `index.html`,
+    /// `document.html`, `error.html`, `dashboard.html`, and `docs.html` found
+    /// in `directory`
+    pub fn load(directory: &Path) -> Result<Self, String> {
+        if !directory.is_dir() {
+            return Err(format!("Template directory {} does not exist", directory.display()));
+        }
+        let mut templates = Templates::builtin();
+        for (name, template) in [
+            ("index.html", &mut templates.index),
+            ("document.html", &mut templates.document),
+            ("error.html", &mut templates.error),
+            ("dashboard.html", &mut templates.dashboard),
+            ("docs.html", &mut templates.docs),
+        ] {
+            let path = directory.join(name);
+            if !path.exists() {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+            *template = Template::parse(&source).map_err(|e| format!("Invalid template {}: {}", path.display(), e))?;
+        }
+        Ok(templates)
+    }
+}
+
+/// Use `templates` for every page rendered from now on
+pub fn install(templates: Templates) {
+    *INSTALLED.write().unwrap() = Some(Arc::new(templates));
+}
+
+/// The installed templates, or the built-in ones
+pub fn current() -> Arc<Templates> {
+    match INSTALLED.read().unwrap().as_ref() {
+        Some(templates) => Arc::clone(templates),
+        None => Arc::clone(BUILTIN.get_or_init(|| Arc::new(Templates::builtin()))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_render_values_conditions_and_loops() {
+        let template = Template::parse(
+            "<h1>{{ title }}</h1>{% for row in rows %}[{{ row.key }}={{ row.value }}]{% endfor %}\
+             {% if owner %} by {{ owner.name }}{% else %} anonymous{% endif %} {{ rows.1.key }}{{ missing }}",
+        )
+        .unwrap();
+        let context = json!({
+            "title": "<Status>",
+            "rows": [{"key": "a", "value": 1}, {"key": "b", "value": true}],
+        });
+        assert_eq!(template.render(&context), "<h1>&lt;Status&gt;</h1>[a=1][b=true] anonymous b");
+        let context = json!({"title": "", "rows": [], "owner": {"name": "ops"}});
+        assert_eq!(template.render(&context), "<h1></h1> by ops ");
+    }
+
+    #[test]
+    fn test_load_replaces_only_the_templates_present() {
+        let dir = std::env::temp_dir().join(format!("http-server-templates-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("error.html"), "<p>{{ status }}: {{ detail }}</p>").unwrap();
+        let templates = Templates::load(&dir).unwrap();
+        assert_eq!(templates.error.render(&json!({"status": 404, "detail": "gone"})), "<p>404: gone</p>");
+        assert!(templates.index.render(&json!({"version": "9.9"})).contains("Version 9.9"));
+
+        std::fs::write(dir.join("index.html"), "{% for x in y %}").unwrap();
+        let e = Templates::load(&dir).unwrap_err();
+        assert!(e.contains("index.html") && e.contains("line 1"), "{}", e);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_errors_name_the_line() {
+        assert_eq!(Template::parse("a\n{% if x %}b").unwrap_err(), "line 2: {% if %} without {% endif %}");
+        assert_eq!(Template::parse("{{ a").unwrap_err(), "line 1: unclosed tag");
+        assert_eq!(Template::parse("\n\n{% include x %}").unwrap_err(), "line 3: unknown tag {% include x %}");
+        assert_eq!(Template::parse("{{ a b }}").unwrap_err(), "line 1: invalid name 'a b'");
+        assert!(Template::parse("{% endfor %}").is_err());
+        assert!(Templates::load(Path::new("/nonexistent")).is_err());
+    }
+}
@@ -0,0 +1,1302 @@
+//! Helpers for testing handlers without opening sockets
+//!
+//! A [`TestRequest`] is serialized and run through the same parser the server
+//! uses, routed, and the response is serialized and parsed back, so tests see
+//! exactly what a client would:
+//!
+//! ```
+//! use http_server::testing::{self, TestRequest};
+//!
+//! let router = testing::router(".");
+//! let response = TestRequest::get("/echo/hello").header("Accept-Encoding", "identity").send(&router);
+//! assert_eq!(response.status, 200);
+//! assert_eq!(response.text(), "hello");
+//! ```
+
+use crate::config::Config;
+use crate::metrics::ServerMetrics;
+use crate::request::HttpRequest;
+use crate::router::{Listener, Router};
+use clap::Parser;
+use serde::de::DeserializeOwned;
+use std::sync::Arc;
+
+/// A router over `directory` with otherwise default settings
+pub fn router(directory: &str) -> Router {
+    let config = Config::parse_from(["http-server", "--directory", directory]);
+    Router::new(&config)
+}
+
+/// Builder for a request to send through a [`Router`]
+#[derive(Debug, Clone)]
+pub struct TestRequest {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl TestRequest {
+    pub fn new(method: &str, path: &str) -> Self {
+        TestRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn get(path: &str) -> Self {
+        Self::new("GET", path)
+    }
+
+    pub fn post(path: &str) -> Self {
+        Self::new("POST", path)
+    }
+
+    pub fn put(path: &str) -> Self {
+        Self::new("PUT", path)
+    }
+
+    pub fn delete(path: &str) -> Self {
+        Self::new("DELETE", path)
+    }
+
+    /// Add a request header
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Set the request body; Content-Length is added when the request is sent
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// The request as it would arrive on the wire
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = format!("{} {} HTTP/1.1\r\n", self.method, self.path).into_bytes();
+        let has_length = self.headers.iter().any(|(name, _)| name.eq_ignore_ascii_case("content-length"));
+        for (name, value) in &self.headers {
+            raw.extend_from_slice(format!("{}: {}\r\n", name, value).as_bytes());
+        }
+        if !has_length && !self.body.is_empty() {
+            raw.extend_from_slice(format!("Content-Length: {}\r\n", self.body.len()).as_bytes());
+        }
+        raw.extend_from_slice(b"\r\n");
+        raw.extend_from_slice(&self.body);
+        raw
+    }
+
+    /// Parse the request with the server's parser
+    pub fn to_request(&self) -> crate::error::Result<HttpRequest> {
+        HttpRequest::parse(&mut &self.to_bytes()[..])
+    }
+
+    /// Route the request on the public listener and return the parsed response
+    ///
+    /// Errors become the response the server would send for them.
+    pub fn send(&self, router: &Router) -> TestResponse {
+        self.send_with_metrics(router, &Arc::new(ServerMetrics::new()))
+    }
+
+    /// Like [`send`](Self::send), recording into the given metrics
+    pub fn send_with_metrics(&self, router: &Router, metrics: &Arc<ServerMetrics>) -> TestResponse {
+        // Streaming handlers get the body left unread, as from the server
+        let raw = self.to_bytes();
+        let mut reader = &raw[..];
+        let request = HttpRequest::parse_head(&mut reader).and_then(|mut request| {
+            if router.streams_body(&request) {
+                request.stream_body(std::io::Cursor::new(reader.to_vec()))?;
+            } else {
+                request.read_body(&mut reader)?;
+            }
+            Ok(request)
+        });
+        let response = match request {
+            Ok(request) => router
+                .route(&request, metrics, Listener::Public)
+                .unwrap_or_else(|e| e.to_response_for(&request)),
+            Err(e) => e.to_response(),
+        };
+
+        let mut raw = Vec::new();
+        response.write_to(&mut raw).expect("writing to a Vec cannot fail");
+        TestResponse::parse(&raw).expect("the server produced a malformed response")
+    }
+}
+
+/// A response as a client would see it
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    /// Parse a serialized response; `None` if it is malformed
+    pub fn parse(raw: &[u8]) -> Option<Self> {
+        let head_end = raw.windows(4).position(|w| w == b"\r\n\r\n")?;
+        let head = std::str::from_utf8(&raw[..head_end]).ok()?;
+        let mut lines = head.split("\r\n");
+        let status = lines.next()?.split(' ').nth(1)?.parse().ok()?;
+        let headers = lines
+            .map(|line| {
+                let (name, value) = line.split_once(':')?;
+                Some((name.to_string(), value.trim().to_string()))
+            })
+            .collect::<Option<Vec<_>>>()?;
+        let chunked = headers
+            .iter()
+            .any(|(name, value)| name.eq_ignore_ascii_case("transfer-encoding") && value.eq_ignore_ascii_case("chunked"));
+        let body = &raw[head_end + 4..];
+        let body = if chunked { decode_chunked(body)? } else { body.to_vec() };
+        Some(TestResponse { status, headers, body })
+    }
+
+    /// First value of a header (case-insensitive)
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// The body as text, with invalid UTF-8 replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+
+    /// Deserialize a JSON body
+    pub fn json<T: DeserializeOwned>(&self) -> serde_json::Result<T> {
+        serde_json::from_slice(&self.body)
+    }
+}
+
+/// Join the chunks of a chunked body; `None` if the framing is broken
+fn decode_chunked(mut raw: &[u8]) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let line_end = raw.windows(2).position(|w| w == b"\r\n")?;
+        let size = std::str::from_utf8(&raw[..line_end]).ok()?;
+        let size = usize::from_str_radix(size.split(';').next()?.trim(), 16).ok()?;
+        raw = &raw[line_end + 2..];
+        if size == 0 {
+            return (raw == b"\r\n").then_some(body);
+        }
+        body.extend_from_slice(raw.get(..size)?);
+        raw = raw.get(size..)?.strip_prefix(b"\r\n")?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit::{self, AuditLog, AuditUser};
+    use crate::cgi::Cgi;
+    use crate::compression::{Compression, CompressionPolicy};
+    use crate::conditional::EtagStrategy;
+    use crate::extract::{self, Json, Path, Query};
+    use crate::openapi;
+    use crate::response::HttpResponse;
+    use crate::response_cache::ResponseCache;
+    use crate::router::{streaming, FileAccess, Guard, Next, SplitKey};
+    use crate::schema::{self, JsonSchema};
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    #[test]
+    fn test_requests_round_trip_through_the_router() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let router = router(dir.to_str().unwrap());
+
+        let response = TestRequest::post("/files/note.txt").body("hello").send(&router);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.json::<serde_json::Value>().unwrap()["size"], 5);
+
+        let response = TestRequest::get("/files/note.txt").header("Accept-Encoding", "identity").send(&router);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("content-length"), Some("5"));
+        assert_eq!(response.text(), "hello");
+
+        let response = TestRequest::get("/user-agent").header("User-Agent", "tests/1.0").send(&router);
+        assert_eq!(response.text(), "tests/1.0");
+
+        assert_eq!(TestRequest::get("/missing").send(&router).status, 404);
+
+        // Errors come back as problem details
+        let response = TestRequest::get("/files/absent.txt").send(&router);
+        assert_eq!(response.status, 404);
+        assert_eq!(response.header("content-type"), Some("application/problem+json"));
+        let problem = response.json::<serde_json::Value>().unwrap();
+        assert_eq!(problem["code"], "file_not_found");
+        assert_eq!(problem["instance"], "/files/absent.txt");
+        // Unknown methods are rejected by the parser
+        assert_eq!(TestRequest::new("BREW", "/").send(&router).status, 400);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_range_requests_get_partial_content() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-range-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+        let router = router(dir.to_str().unwrap());
+        let get = |range: &str| {
+            TestRequest::get("/files/digits.txt").header("Range", range).header("Accept-Encoding", "gzip").send(&router)
+        };
+
+        let response = get("bytes=2-4");
+        assert_eq!(response.status, 206);
+        assert_eq!(response.header("content-range"), Some("bytes 2-4/10"));
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.text(), "234");
+        assert_eq!(get("bytes=-3").text(), "789");
+
+        let response = get("bytes=0-1, 8-");
+        assert_eq!(response.status, 206);
+        let content_type = response.header("content-type").unwrap().to_string();
+        let boundary = content_type.strip_prefix("multipart/byteranges; boundary=").unwrap();
+        let expected = format!(
+            "--{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-1/10\r\n\r\n01\r\n\
+             --{b}\r\nContent-Type: text/plain\r\nContent-Range: bytes 8-9/10\r\n\r\n89\r\n--{b}--\r\n",
+            b = boundary
+        );
+        assert_eq!(response.text(), expected);
+
+        let response = get("bytes=10-");
+        assert_eq!(response.status, 416);
+        assert_eq!(response.header("content-range"), Some("bytes */10"));
+
+        // A malformed header is ignored
+        let response = get("bytes=5-1");
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("accept-ranges"), Some("bytes"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_if_range_only_resumes_the_same_version() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-if-range-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("video.bin"), "first version").unwrap();
+        let router = router(dir.to_str().unwrap());
+        let resume = |validator: &str| {
+            TestRequest::get("/files/video.bin").header("Range", "bytes=6-").header("If-Range", validator).send(&router)
+        };
+
+        let response = TestRequest::get("/files/video.bin").send(&router);
+        let etag = response.header("etag").unwrap().to_string();
+        let last_modified = response.header("last-modified").unwrap().to_string();
+        assert_eq!(resume(&etag).status, 206);
+        assert_eq!(resume(&etag).header("etag"), Some(etag.as_str()));
+        assert_eq!(resume(&last_modified).text(), "version");
+
+        // A changed file is sent whole
+        std::fs::write(dir.join("video.bin"), "fresh version").unwrap();
+        let file = std::fs::File::options().write(true).open(dir.join("video.bin")).unwrap();
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(10)).unwrap();
+        let response = resume(&etag);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "fresh version");
+        assert_ne!(response.header("etag"), Some(etag.as_str()));
+        assert_eq!(resume(&last_modified).status, 200);
+        assert_eq!(resume(&format!("W/{}", etag)).status, 200);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_changes_check_if_match_and_if_unmodified_since() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-if-match-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let router = router(dir.to_str().unwrap());
+        let put = |condition: (&str, &str)| {
+            TestRequest::new("PUT", "/files/notes.md").header(condition.0, condition.1).body("edit").send(&router)
+        };
+
+        // Only an existing file matches If-Match: *
+        assert_eq!(put(("If-Match", "*")).status, 412);
+        let response = TestRequest::post("/files/notes.md").body("draft").send(&router);
+        assert_eq!(response.status, 201);
+        let etag = response.header("etag").unwrap().to_string();
+        assert_eq!(TestRequest::get("/files/notes.md").send(&router).header("etag"), Some(etag.as_str()));
+
+        // Someone else saves in between
+        std::fs::write(dir.join("notes.md"), "their draft").unwrap();
+        let file = std::fs::File::options().write(true).open(dir.join("notes.md")).unwrap();
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(10)).unwrap();
+        let response = put(("If-Match", &etag));
+        assert_eq!(response.status, 412);
+        assert_eq!(response.json::<serde_json::Value>().unwrap()["code"], "precondition_failed");
+        assert_eq!(std::fs::read_to_string(dir.join("notes.md")).unwrap(), "their draft");
+
+        let current = TestRequest::get("/files/notes.md").header("Accept-Encoding", "gzip").send(&router);
+        assert_eq!(put(("If-Match", &format!("\"other\", {}", current.header("etag").unwrap()))).status, 201);
+        assert_eq!(std::fs::read_to_string(dir.join("notes.md")).unwrap(), "edit");
+
+        let delete = |condition: (&str, &str)| {
+            TestRequest::new("DELETE", "/files/notes.md").header(condition.0, condition.1).send(&router)
+        };
+        assert_eq!(delete(("If-Unmodified-Since", "Sun, 06 Nov 1994 08:49:37 GMT")).status, 412);
+        assert_eq!(delete(("If-Unmodified-Since", "not a date")).status, 200);
+        assert!(!dir.join("notes.md").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_downloads_carry_digests() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-digest-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("hello.txt"), "hello").unwrap();
+        let config = Config::parse_from(["http-server", "--directory", dir.to_str().unwrap(), "--file-digests", "sha-256,md5"]);
+        let router = Router::new(&config);
+        let sha256 = "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:";
+
+        let response = TestRequest::get("/files/hello.txt").header("Accept-Encoding", "identity").send(&router);
+        assert_eq!(response.header("repr-digest"), Some(sha256));
+        assert_eq!(response.header("content-md5"), Some("XUFAKrxLKna5cZ2REBfFkg=="));
+
+        // A range still names the digest of the whole file, but not the MD5 of the part
+        let response = TestRequest::get("/files/hello.txt").header("Range", "bytes=0-1").send(&router);
+        assert_eq!(response.header("repr-digest"), Some(sha256));
+        assert_eq!(response.header("content-md5"), None);
+
+        let response = TestRequest::get("/files/hello.txt").header("Accept-Encoding", "gzip").send(&router);
+        let gzipped = crate::digest::Digests::compute(&[crate::digest::DigestAlgorithm::Sha256], &response.body);
+        assert_eq!(response.header("repr-digest"), Some(gzipped.headers[0].1.as_str()));
+        assert_ne!(response.header("repr-digest"), Some(sha256));
+
+        let plain = super::router(dir.to_str().unwrap());
+        assert_eq!(TestRequest::get("/files/hello.txt").send(&plain).header("repr-digest"), None);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_etag_strategies_per_router() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-etag-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.js"), "let x = 1;").unwrap();
+        let etag = |router: &Router| TestRequest::get("/files/app.js").send(router).header("etag").map(str::to_string);
+
+        let config = Config::parse_from(["http-server", "--directory", dir.to_str().unwrap(), "--etag", "weak"]);
+        let weak = etag(&Router::new(&config)).unwrap();
+        assert!(weak.starts_with("W/\"a-"), "{}", weak);
+        let off = router(dir.to_str().unwrap()).etags(EtagStrategy::Off);
+        assert_eq!(etag(&off), None);
+        assert!(TestRequest::get("/files/app.js").send(&off).header("last-modified").is_some());
+
+        // Strong tags only change with the contents
+        let strong = router(dir.to_str().unwrap());
+        let tag = etag(&strong).unwrap();
+        assert!(tag.starts_with('"'), "{}", tag);
+        let file = std::fs::File::options().write(true).open(dir.join("app.js")).unwrap();
+        file.set_modified(std::time::SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert_eq!(etag(&strong), Some(tag.clone()));
+        std::fs::write(dir.join("app.js"), "let x = 2;").unwrap();
+        assert_ne!(etag(&strong), Some(tag));
+
+        // Weak tags cannot satisfy If-Match
+        let weak = Router::new(&config);
+        let tag = etag(&weak).unwrap();
+        let response = TestRequest::new("PUT", "/files/app.js").header("If-Match", &tag).body("x").send(&weak);
+        assert_eq!(response.status, 412);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_inspect_reflects_the_request() {
+        assert_eq!(TestRequest::get("/inspect").send(&router(".")).status, 404);
+        let router = router(".").debug_endpoints(true);
+        let response = TestRequest::new("PATCH", "/inspect/deep/path?tag=a&tag=b&q=1")
+            .header("Cookie", "session=abc; theme=dark")
+            .header("Content-Type", "application/json")
+            .header("X-Trace", "1")
+            .body(r#"{"id": 7}"#)
+            .send(&router);
+        assert_eq!(response.status, 200);
+        let inspected = response.json::<serde_json::Value>().unwrap();
+        assert_eq!(inspected["method"], "PATCH");
+        assert_eq!(inspected["path"], "/inspect/deep/path");
+        assert_eq!(inspected["query"], serde_json::json!({"tag": ["a", "b"], "q": "1"}));
+        assert_eq!(inspected["headers"]["x-trace"], "1");
+        assert_eq!(inspected["cookies"], serde_json::json!({"session": "abc", "theme": "dark"}));
+        assert_eq!(inspected["body"], r#"{"id": 7}"#);
+        assert_eq!(inspected["json"], serde_json::json!({"id": 7}));
+        assert_eq!(inspected["form"], serde_json::Value::Null);
+
+        let inspected = TestRequest::post("/anything")
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .body("name=Ada+Lovelace&lang=en")
+            .send(&router)
+            .json::<serde_json::Value>()
+            .unwrap();
+        assert_eq!(inspected["form"], serde_json::json!({"name": "Ada Lovelace", "lang": "en"}));
+
+        let inspected = TestRequest::put("/inspect").body(vec![0xff, 0x00]).send(&router).json::<serde_json::Value>().unwrap();
+        assert_eq!(inspected["body"], "/wA=");
+        assert_eq!(inspected["body_encoding"], "base64");
+        assert_eq!(TestRequest::get("/inspection").send(&router).status, 404);
+    }
+
+    #[test]
+    fn test_response_header_rules() {
+        let config = Config::parse_from([
+            "http-server",
+            "--response-header",
+            "/echo/*=Access-Control-Allow-Origin: *",
+            "--response-header",
+            "/health=X-Robots-Tag: noindex",
+            "--response-header",
+            "*=Vary: Origin",
+        ]);
+        let router = Router::new(&config);
+
+        let response = TestRequest::get("/echo/hi").header("Accept-Encoding", "identity").send(&router);
+        assert_eq!(response.header("access-control-allow-origin"), Some("*"));
+        assert_eq!(response.header("x-robots-tag"), None);
+        // The handler's own Vary stays as it is
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        let response = TestRequest::get("/health?verbose").send(&router);
+        assert_eq!(response.header("x-robots-tag"), Some("noindex"));
+        assert_eq!(response.header("access-control-allow-origin"), None);
+        assert_eq!(TestRequest::get("/user-agent").send(&router).header("vary"), Some("Origin"));
+    }
+
+    #[test]
+    fn test_status_and_delay_endpoints() {
+        // Only served when enabled
+        assert_eq!(TestRequest::get("/status/503").send(&router(".")).status, 404);
+        assert_eq!(TestRequest::get("/delay/0").send(&router(".")).status, 404);
+
+        let router = router(".").debug_endpoints(true);
+        let response = TestRequest::post("/status/503").send(&router);
+        assert_eq!(response.status, 503);
+        assert_eq!(response.text(), "503 - Service Unavailable");
+        assert_eq!(TestRequest::get("/status/204").send(&router).body, b"");
+        for code in ["100", "999", "abc", ""] {
+            assert_eq!(TestRequest::get(&format!("/status/{}", code)).send(&router).status, 400, "{}", code);
+        }
+
+        let response = TestRequest::get("/delay/0.05").send(&router);
+        assert_eq!(response.json::<serde_json::Value>().unwrap()["delay"], 0.05);
+        assert_eq!(TestRequest::get("/delay/-1").send(&router).status, 400);
+
+        // Longer delays are cut to --max-delay-secs
+        let config = Config::parse_from(["http-server", "--debug-endpoints", "--max-delay-secs", "0"]);
+        let started = std::time::Instant::now();
+        let response = TestRequest::get("/delay/60").send(&Router::new(&config));
+        assert_eq!(response.json::<serde_json::Value>().unwrap()["delay"], 0.0);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_kv_store_round_trip() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-kv-{}", std::process::id()));
+        let config = Config::parse_from(["http-server", "--kv-dir", dir.to_str().unwrap()]);
+        let router = Router::new(&config);
+        let put = |path: &str, body: &str| {
+            TestRequest::put(path).header("Content-Type", "application/json").body(body).send(&router)
+        };
+
+        let response = put("/kv/users/1", r#"{"name":"Ada"}"#);
+        assert_eq!(response.status, 201);
+        assert_eq!(response.header("location"), Some("/kv/users/1"));
+        assert_eq!(put("/kv/users/1", r#"{"name":"Grace"}"#).status, 200);
+        assert_eq!(put("/kv/users/2", "[1, 2]").status, 201);
+        assert_eq!(put("/kv/users/3", "{").status, 400);
+        assert_eq!(TestRequest::put("/kv/users/3").body("text").send(&router).status, 415);
+
+        let value = TestRequest::get("/kv/users/1").send(&router).json::<serde_json::Value>().unwrap();
+        assert_eq!(value, serde_json::json!({"name": "Grace"}));
+        let list = TestRequest::get("/kv?prefix=users/").send(&router).json::<serde_json::Value>().unwrap();
+        assert_eq!(list, serde_json::json!({"keys": ["users/1", "users/2"]}));
+
+        assert_eq!(TestRequest::delete("/kv/users/2").send(&router).status, 204);
+        let response = TestRequest::get("/kv/users/2").send(&router);
+        assert_eq!(response.status, 404);
+        assert_eq!(response.json::<serde_json::Value>().unwrap()["code"], "key_not_found");
+        assert_eq!(TestRequest::post("/kv/users/1").send(&router).header("allow"), Some("GET, PUT, DELETE"));
+
+        // The store outlives the router
+        let router = Router::new(&config);
+        let list = TestRequest::get("/kv").send(&router).json::<serde_json::Value>().unwrap();
+        assert_eq!(list["keys"], serde_json::json!(["users/1"]));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compression_policies_per_mount() {
+        let report = |_: &HttpRequest| Ok(HttpResponse::ok().text("row\n".repeat(500)));
+        let small = |_: &HttpRequest| Ok(HttpResponse::ok().text("tiny"));
+        let api = Router::empty()
+            .get("/report", report)
+            .get("/small", small)
+            .compression(CompressionPolicy::default().algorithms(&[Compression::Gzip]).level(9).min_size(100));
+        let raw = Router::empty().get("/report", report).compression(CompressionPolicy::disabled());
+        let router = router(".").mount("/api", api).mount("/raw", raw).compression(CompressionPolicy::default());
+        let get = |path: &str| TestRequest::get(path).header("Accept-Encoding", "br, gzip").send(&router);
+
+        let response = get("/api/report");
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.header("vary"), Some("Accept-Encoding"));
+        assert_eq!(response.body, Compression::Gzip.compress_level("row\n".repeat(500).as_bytes(), Some(9)).unwrap());
+        assert_eq!(get("/api/small").header("content-encoding"), None);
+        // The innermost policy decides, even when it is to do nothing
+        assert_eq!(get("/raw/report").header("content-encoding"), None);
+
+        // Built-in endpoints follow the configured policy
+        assert_eq!(get("/echo/hello").header("content-encoding"), Some("br"));
+        let config = Config::parse_from(["http-server", "--compression-algorithms", "gzip,deflate"]);
+        let response = TestRequest::get("/echo/hello").header("Accept-Encoding", "br, gzip").send(&Router::new(&config));
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        let config = Config::parse_from(["http-server", "--disable-compression"]);
+        let response = TestRequest::get("/echo/hello").header("Accept-Encoding", "br, gzip").send(&Router::new(&config));
+        assert_eq!(response.header("content-encoding"), None);
+        assert_eq!(response.text(), "hello");
+    }
+
+    #[test]
+    fn test_precompressed_sidecars_are_served() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-sidecar-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.js"), "let x = 1;").unwrap();
+        let modified = std::fs::metadata(dir.join("app.js")).unwrap().modified().unwrap();
+        // Not a real gzip body, to tell it apart from one compressed on the fly
+        std::fs::write(dir.join("app.js.gz"), "from the sidecar").unwrap();
+        let sidecar = std::fs::File::options().write(true).open(dir.join("app.js.gz")).unwrap();
+        sidecar.set_modified(modified).unwrap();
+        let router = router(dir.to_str().unwrap());
+
+        let response = TestRequest::get("/files/app.js").header("Accept-Encoding", "gzip").send(&router);
+        assert_eq!(response.header("content-encoding"), Some("gzip"));
+        assert_eq!(response.text(), "from the sidecar");
+        let response = TestRequest::get("/files/app.js").header("Accept-Encoding", "br").send(&router);
+        assert_eq!(response.body, Compression::Brotli.compress(b"let x = 1;").unwrap());
+
+        // Once the file changes, the sidecar is out of date
+        std::fs::write(dir.join("app.js"), "let x = 2;").unwrap();
+        let file = std::fs::File::options().write(true).open(dir.join("app.js")).unwrap();
+        file.set_modified(modified + Duration::from_secs(10)).unwrap();
+        let response = TestRequest::get("/files/app.js").header("Accept-Encoding", "gzip").send(&router);
+        assert_eq!(response.body, Compression::Gzip.compress(b"let x = 2;").unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watched_files_are_served_fresh_from_the_cache() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config::parse_from(["http-server", "--directory", dir.to_str().unwrap(), "--watch-files"]);
+        let router = Router::new(&config);
+        let gzip = |text: &str| crate::compression::Compression::Gzip.compress(text.as_bytes()).unwrap();
+        let get = || TestRequest::get("/files/site.css").header("Accept-Encoding", "gzip").send(&router);
+
+        std::fs::write(dir.join("site.css"), "body { color: red }").unwrap();
+        assert_eq!(get().body, gzip("body { color: red }"));
+        assert_eq!(get().body, gzip("body { color: red }"));
+
+        std::fs::write(dir.join("site.css"), "body { color: blue }").unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while get().body != gzip("body { color: blue }") && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(get().body, gzip("body { color: blue }"));
+
+        std::fs::remove_file(dir.join("site.css")).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while get().status != 404 && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(get().status, 404);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_indexed_files_keep_current_etags() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-index-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("app.js"), "hello").unwrap();
+        let config = Config::parse_from(["http-server", "--directory", dir.to_str().unwrap(), "--file-index"]);
+        let router = Router::new(&config);
+        let etag = || TestRequest::get("/files/app.js").send(&router).header("etag").map(str::to_string);
+        assert_eq!(etag().as_deref(), Some("\"2cf24dba5fb0a30e26e83b2ac5b9e29e\""));
+
+        std::fs::write(dir.join("app.js"), "changed").unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while etag().as_deref() == Some("\"2cf24dba5fb0a30e26e83b2ac5b9e29e\"") && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        let response = TestRequest::get("/files/app.js").send(&router);
+        assert_eq!(response.text(), "changed");
+        assert_ne!(response.header("etag"), Some("\"2cf24dba5fb0a30e26e83b2ac5b9e29e\""));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watch_mode_reloads_pages_on_change() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-live-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config::parse_from(["http-server", "--directory", dir.to_str().unwrap(), "--watch"]);
+        let plain = router(dir.to_str().unwrap());
+        let router = Router::new(&config);
+        std::fs::write(dir.join("page.html"), "<html><body>Hi</body></html>").unwrap();
+
+        let response = TestRequest::get("/files/page.html").header("Accept-Encoding", "gzip").send(&router);
+        assert_eq!(response.header("content-encoding"), None);
+        let expected = format!("<html><body>Hi{}</body></html>", crate::live_reload::SCRIPT);
+        assert_eq!(response.text(), expected);
+        assert!(TestRequest::get("/").header("Accept", "text/html").send(&router).text().contains("EventSource"));
+        assert!(!TestRequest::get("/files/page.html").send(&plain).text().contains("EventSource"));
+
+        // Writing the page was a change the stream has not seen
+        let response = TestRequest::get("/__livereload").header("Last-Event-ID", "0").send(&router);
+        assert_eq!(response.header("content-type"), Some("text/event-stream"));
+        assert!(response.text().starts_with("retry: 1000\nid: 0\n\n"), "{}", response.text());
+        assert!(response.text().ends_with("data: reload\n\n"), "{}", response.text());
+        assert_eq!(TestRequest::get("/__livereload").send(&plain).status, 404);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_cors_policies() {
+        let config = Config::parse_from([
+            "http-server",
+            "--cors-origins",
+            "*",
+            "--cors-max-age-secs",
+            "600",
+            "--cors-rule",
+            "/echo/*=origins=https://app.example.com;methods=GET;headers=X-Token;expose=X-Request-Id;credentials",
+        ]);
+        let router = Router::new(&config);
+        let preflight = |path: &str, origin: &str, headers: &str| {
+            TestRequest::new("OPTIONS", path)
+                .header("Origin", origin)
+                .header("Access-Control-Request-Method", "GET")
+                .header("Access-Control-Request-Headers", headers)
+                .send(&router)
+        };
+
+        // Any origin, and whatever headers the browser asks for
+        let response = preflight("/headers", "https://a.test", "Content-Type");
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("access-control-allow-origin"), Some("*"));
+        assert_eq!(response.header("access-control-allow-headers"), Some("Content-Type"));
+        assert_eq!(response.header("access-control-max-age"), Some("600"));
+        let response = TestRequest::get("/user-agent").header("Origin", "https://a.test").send(&router);
+        assert_eq!((response.status, response.header("access-control-allow-origin")), (200, Some("*")));
+        assert_eq!(response.header("vary"), None);
+
+        // The rule echoes its origin and allows credentials
+        let response = preflight("/echo/hi", "https://app.example.com", "x-token");
+        assert_eq!(response.status, 204);
+        assert_eq!(response.header("access-control-allow-origin"), Some("https://app.example.com"));
+        assert_eq!(response.header("access-control-allow-credentials"), Some("true"));
+        assert_eq!(response.header("access-control-max-age"), None);
+        assert_eq!(preflight("/echo/hi", "https://a.test", "").status, 403);
+        assert_eq!(preflight("/echo/hi", "https://app.example.com", "X-Other").status, 403);
+
+        let get = |origin: &str| TestRequest::get("/echo/hi").header("Origin", origin).send(&router);
+        let response = get("https://app.example.com");
+        assert_eq!(response.text(), "hi");
+        assert_eq!(response.header("access-control-allow-origin"), Some("https://app.example.com"));
+        assert_eq!(response.header("access-control-expose-headers"), Some("X-Request-Id"));
+        assert!(response.headers.iter().any(|(name, value)| name == "Vary" && value == "Origin"));
+        let response = get("https://a.test");
+        assert_eq!((response.status, response.header("access-control-allow-origin")), (200, None));
+
+        let unsafe_config = Config::parse_from(["http-server", "--cors-origins", "*", "--cors-credentials"]);
+        assert!(unsafe_config.validate().unwrap_err().contains("explicit origins"));
+    }
+
+    #[test]
+    fn test_file_changes_are_audited() {
+        let dir = std::env::temp_dir().join(format!("http-server-testing-audit-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("files")).unwrap();
+        let log = AuditLog::open(dir.join("audit.log"), &[]).unwrap();
+        let router = router(dir.join("files").to_str().unwrap()).audit_log(log).layer(
+            |request: &HttpRequest, next: Next<'_>| {
+                if request.get_header("authorization") == Some("Bearer ada-token") {
+                    request.insert_extension(AuditUser("ada".to_string()));
+                }
+                next.run(request)
+            },
+        );
+
+        let upload = TestRequest::put("/files/notes.txt").header("Authorization", "Bearer ada-token").body("hello");
+        assert_eq!(upload.send(&router).status, 201);
+        assert_eq!(TestRequest::get("/files/notes.txt").send(&router).status, 200);
+        assert_eq!(TestRequest::delete("/files/notes.txt").send(&router).status, 200);
+        assert_eq!(TestRequest::delete("/files/notes.txt").send(&router).status, 404);
+
+        let content = std::fs::read_to_string(dir.join("audit.log")).unwrap();
+        let lines: Vec<serde_json::Value> = content.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        let fields = |line: &serde_json::Value| (line["method"].clone(), line["size"].clone(), line["status"].clone());
+        assert_eq!(fields(&lines[0]), ("PUT".into(), 5.into(), 201.into()));
+        assert_eq!((lines[0]["user"].as_str(), lines[0]["filename"].as_str()), (Some("ada"), Some("notes.txt")));
+        assert_eq!(lines[0]["result"], "success");
+        assert_eq!(fields(&lines[1]), ("DELETE".into(), 5.into(), 200.into()));
+        assert_eq!(lines[1]["user"], serde_json::Value::Null);
+        assert_eq!(fields(&lines[2]), ("DELETE".into(), serde_json::Value::Null, 404.into()));
+        assert_eq!(lines[2]["result"], "failure");
+        assert_eq!(audit::verify(&dir.join("audit.log")), Ok(3));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_health_reports_missing_directory() {
+        let dir = std::env::temp_dir().join(format!("http-server-health-{}", std::process::id()));
+        let router = router(dir.to_str().unwrap());
+        let response = TestRequest::get("/health").send(&router);
+        assert_eq!(response.status, 503);
+        let health = response.json::<serde_json::Value>().unwrap();
+        assert_eq!(health["status"], "unhealthy");
+        assert_eq!(health["directory"]["exists"], "missing");
+
+        std::fs::create_dir_all(&dir).unwrap();
+        let health = TestRequest::get("/health").send(&router).json::<serde_json::Value>().unwrap();
+        assert_eq!(health["directory"]["exists"], "ok");
+        assert_eq!(health["directory"]["readable"], "ok");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_builtin_endpoints_negotiate_format() {
+        let router = router(".");
+        let get = |path: &str, accept: &str| TestRequest::get(path).header("Accept", accept).send(&router);
+
+        let response = get("/health", "*/*");
+        assert_eq!(response.header("content-type"), Some("application/json"));
+        assert_eq!(response.header("vary"), Some("Accept"));
+        assert_eq!(response.json::<serde_json::Value>().unwrap()["status"], "healthy");
+
+        let response = get("/health", "text/plain");
+        assert!(response.text().starts_with("Server Health\n"));
+        assert!(response.text().contains("\nstatus: healthy\n"), "{}", response.text());
+        assert!(get("/api/info", "text/html").text().contains("<tr><th>endpoints.POST</th><td>/files/{filename}</td></tr>"));
+
+        assert_eq!(get("/", "text/html,*/*;q=0.8").header("content-type"), Some("text/html"));
+        let info = get("/", "application/json").json::<serde_json::Value>().unwrap();
+        assert_eq!(info["name"], "Rust HTTP Server");
+
+        let version = get("/version", "*/*").json::<serde_json::Value>().unwrap();
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+        assert!(version["rustc_version"].as_str().unwrap().starts_with("rustc "));
+        assert!(get("/version", "text/plain").text().contains("\ngit_commit: "));
+
+        let response = get("/api/info", "image/png");
+        assert_eq!(response.status, 406);
+        assert!(response.text().contains("application/json, text/html, text/plain"));
+    }
+
+    #[test]
+    fn test_fallback_handles_unmatched_requests() {
+        let router = router(".").fallback(|request: &HttpRequest| {
+            Ok(HttpResponse::not_found().html(format!("<h1>Nothing at {}</h1>", request.path)))
+        });
+
+        let response = TestRequest::get("/nowhere").send(&router);
+        assert_eq!(response.status, 404);
+        assert_eq!(response.text(), "<h1>Nothing at /nowhere</h1>");
+        // Matched routes are unaffected
+        assert_eq!(TestRequest::get("/echo/hi").header("Accept-Encoding", "identity").send(&router).text(), "hi");
+    }
+
+    #[test]
+    fn test_mounted_routers_run_group_middleware() {
+        let calls = Arc::new(Mutex::new(Vec::new()));
+        let trace = |name: &'static str| {
+            let calls = Arc::clone(&calls);
+            move |request: &HttpRequest, next: Next<'_>| {
+                calls.lock().unwrap().push(name);
+                next.run(request)
+            }
+        };
+        let require_key = |request: &HttpRequest, next: Next<'_>| match request.get_header("x-api-key") {
+            Some("secret") => next.run(request),
+            _ => Ok(HttpResponse::unauthorized()),
+        };
+
+        let users = Router::empty()
+            .layer(trace("users"))
+            .get("/{id}", |request: &HttpRequest| {
+                Ok(HttpResponse::ok().text(format!("user {}", request.param("id").unwrap())))
+            })
+            .delete("/{id}", |_: &HttpRequest| Ok(HttpResponse::no_content()));
+        let api = Router::empty()
+            .layer(require_key)
+            .layer(trace("api"))
+            .get("/", |_: &HttpRequest| Ok(HttpResponse::ok().text("api")))
+            .mount("/users", users);
+        let router = router(".").layer(trace("root")).mount("/api/v1", api);
+
+        let response = TestRequest::get("/api/v1/users/42").header("X-Api-Key", "secret").send(&router);
+        assert_eq!(response.text(), "user 42");
+        assert_eq!(*calls.lock().unwrap(), ["root", "api", "users"]);
+        assert_eq!(TestRequest::get("/api/v1").header("X-Api-Key", "secret").send(&router).text(), "api");
+
+        // Group middleware can short-circuit; root middleware still runs
+        calls.lock().unwrap().clear();
+        assert_eq!(TestRequest::get("/api/v1/users/42").send(&router).status, 401);
+        assert_eq!(*calls.lock().unwrap(), ["root"]);
+
+        let response = TestRequest::post("/api/v1/users/42").header("X-Api-Key", "secret").send(&router);
+        assert_eq!(response.status, 405);
+        assert_eq!(response.header("allow"), Some("GET, DELETE"));
+        assert_eq!(TestRequest::get("/api/v1/users/").send(&router).status, 404);
+
+        // Built-in endpoints are still served
+        assert_eq!(TestRequest::get("/echo/hi").header("Accept-Encoding", "identity").send(&router).text(), "hi");
+    }
+
+    #[test]
+    fn test_wildcard_and_constrained_segments() {
+        let echo = |request: &HttpRequest| {
+            let params: Vec<String> =
+                request.route.get().unwrap().params.iter().map(|(name, value)| format!("{}={}", name, value)).collect();
+            Ok(HttpResponse::ok().text(params.join(" ")))
+        };
+        let router = Router::empty()
+            .get("/users/{id:[0-9]+}", echo)
+            .get("/users/{name:[a-z]{2,8}}/posts", echo)
+            .get("/static/*path", echo);
+        let get = |path: &str| TestRequest::get(path).header("Accept-Encoding", "identity").send(&router);
+
+        assert_eq!(get("/users/42").text(), "id=42");
+        assert_eq!(get("/users/ann/posts").text(), "name=ann");
+        assert_eq!(get("/static/css/site.css").text(), "path=css/site.css");
+        assert_eq!(get("/static/a/").text(), "path=a/");
+        assert_eq!(get("/static/main.js?v=2").text(), "path=main.js");
+
+        // Constraints match the whole segment; anything else is a 404
+        for path in ["/users/42x", "/users/x42", "/users/", "/users/a/posts", "/users/annabelle1/posts", "/static/", "/static"] {
+            assert_eq!(get(path).status, 404, "{}", path);
+        }
+    }
+
+    #[test]
+    fn test_handlers_read_shared_state() {
+        struct Greeting(&'static str);
+        struct Visits(std::sync::atomic::AtomicUsize);
+
+        let greet = |request: &HttpRequest| {
+            let visits = request.state::<Visits>().unwrap();
+            let count = visits.0.fetch_add(1, std::sync::atomic::Ordering::Relaxed) + 1;
+            Ok(HttpResponse::ok().text(format!("{} #{}", request.state::<Greeting>().unwrap().0, count)))
+        };
+        let admin = Router::empty()
+            .with_state(Greeting("ignored"))
+            .with_state(Visits(Default::default()))
+            .get("/greet", greet);
+        let router = Router::empty().with_state(Greeting("hello")).get("/greet", greet).mount("/admin", admin);
+
+        assert_eq!(TestRequest::get("/greet").send(&router).text(), "hello #1");
+        // Mounted state is merged in, but the parent's value wins
+        assert_eq!(TestRequest::get("/admin/greet").send(&router).text(), "hello #2");
+        assert_eq!(TestRequest::get("/greet").to_request().unwrap().state::<Greeting>().map(|g| g.0), None);
+    }
+
+    #[test]
+    fn test_guarded_routes_only_take_matching_requests() {
+        let text = |body: &'static str| move |_: &HttpRequest| Ok(HttpResponse::ok().text(body));
+        let v2 = Router::empty().get("/users", text("v2")).delete("/users", text("deleted"));
+        let beta = Router::empty().get("/users", text("beta"));
+        let router = Router::empty()
+            .mount_when("/api", Guard::new().header("X-API-Version", "2"), v2)
+            .mount_when("/api", Guard::new().query("beta", "1").has_header("cookie"), beta)
+            .get("/api/users", text("v1"));
+
+        assert_eq!(TestRequest::get("/api/users").send(&router).text(), "v1");
+        assert_eq!(TestRequest::get("/api/users").header("x-api-version", "2").send(&router).text(), "v2");
+        assert_eq!(TestRequest::get("/api/users").header("X-API-Version", "3").send(&router).text(), "v1");
+        assert_eq!(TestRequest::get("/api/users?beta=1").send(&router).text(), "v1");
+        let beta_user = TestRequest::get("/api/users?x=%20&beta=1").header("Cookie", "id=7");
+        assert_eq!(beta_user.send(&router).text(), "beta");
+
+        // Routes a request's guard rules out don't count towards 405
+        assert_eq!(TestRequest::delete("/api/users").header("X-API-Version", "2").send(&router).text(), "deleted");
+        assert_eq!(TestRequest::delete("/api/users").send(&router).header("allow"), Some("GET"));
+    }
+
+    #[test]
+    fn test_traffic_split_keeps_clients_on_one_side() {
+        let canary = Guard::new().bucket(0..20, SplitKey::Cookie("session".to_string()));
+        let request = |cookie: &str, peer: &str| {
+            let mut request = TestRequest::get("/php/").header("Cookie", cookie).to_request().unwrap();
+            request.peer_addr = Some(peer.parse().unwrap());
+            request
+        };
+        let share = (0..1000).filter(|i| canary.allows(&request(&format!("session={}", i), "192.0.2.1:4000"))).count();
+        assert!((140..260).contains(&share), "{} of 1000 clients", share);
+        for i in 0..50 {
+            let side = canary.allows(&request(&format!("theme=dark; session={}", i), "192.0.2.1:4000"));
+            assert_eq!(canary.allows(&request(&format!("session={}", i), "198.51.100.7:5000")), side);
+        }
+        // Without the cookie, the address decides
+        let by_address = Guard::new().bucket(0..20, SplitKey::ClientIp);
+        for i in 0..50 {
+            let peer = format!("192.0.2.{}:4000", i);
+            assert_eq!(canary.allows(&request("theme=dark", &peer)), by_address.allows(&request("session=1", &peer)));
+        }
+
+        let text = |body: &'static str| move |_: &HttpRequest| Ok(HttpResponse::ok().text(body));
+        let router = Router::empty()
+            .mount_when("", Guard::new().bucket(0..0, SplitKey::ClientIp), Router::empty().get("/", text("canary")))
+            .get("/", text("stable"));
+        assert_eq!(TestRequest::get("/").send(&router).text(), "stable");
+    }
+
+    #[test]
+    fn test_middleware_passes_extensions_to_handlers() {
+        #[derive(Debug, PartialEq)]
+        struct User(String);
+
+        let authenticate = |request: &HttpRequest, next: Next<'_>| {
+            match request.get_header("authorization").and_then(|value| value.strip_prefix("Bearer ")) {
+                Some(token) => request.insert_extension(User(format!("user-{}", token))),
+                None => return Ok(HttpResponse::unauthorized()),
+            }
+            next.run(request)
+        };
+        let audit = |request: &HttpRequest, next: Next<'_>| {
+            // Later middleware sees what earlier middleware attached
+            assert!(request.extension::<User>().is_some());
+            next.run(request)
+        };
+        let router = Router::empty().layer(authenticate).layer(audit).get("/me", |request: &HttpRequest| {
+            Ok(HttpResponse::ok().text(request.extension::<User>().map_or(String::new(), |user| user.0.clone())))
+        });
+
+        let response = TestRequest::get("/me").header("Authorization", "Bearer 7").send(&router);
+        assert_eq!(response.text(), "user-7");
+        assert_eq!(TestRequest::get("/me").send(&router).status, 401);
+    }
+
+    #[test]
+    fn test_extractor_failures_become_problem_responses() {
+        #[derive(serde::Deserialize)]
+        struct Rename {
+            name: String,
+        }
+        #[derive(serde::Deserialize)]
+        struct Options {
+            notify: Option<bool>,
+        }
+
+        let rename = |Path(id): Path<u64>, Query(options): Query<Options>, Json(body): Json<Rename>| {
+            Ok(HttpResponse::ok().text(format!("{} {} {:?}", id, body.name, options.notify)))
+        };
+        let router = Router::empty().put("/users/{id}", extract::handler(rename));
+        let put = |path: &str, content_type: &str, body: &str| {
+            TestRequest::put(path).header("Content-Type", content_type).body(body).send(&router)
+        };
+
+        let response = put("/users/7?notify=true", "application/json; charset=utf-8", r#"{"name":"ann"}"#);
+        assert_eq!(response.text(), "7 ann Some(true)");
+
+        let cases = [
+            ("/users/seven", "application/json", r#"{"name":"ann"}"#, 400, "invalid_request"),
+            ("/users/7?notify=maybe", "application/json", r#"{"name":"ann"}"#, 400, "invalid_request"),
+            ("/users/7", "text/plain", r#"{"name":"ann"}"#, 415, "unsupported_media_type"),
+            ("/users/7", "application/json", r#"{"name":"#, 400, "invalid_request"),
+            ("/users/7", "application/json", r#"{"name":7}"#, 422, "unprocessable_content"),
+        ];
+        for (path, content_type, body, status, code) in cases {
+            let response = put(path, content_type, body);
+            assert_eq!(response.status, status, "{} {}", path, body);
+            assert_eq!(response.json::<serde_json::Value>().unwrap()["code"], code);
+        }
+    }
+
+    #[test]
+    fn test_openapi_describes_registered_routes() {
+        #[derive(serde::Deserialize)]
+        struct Rename {
+            name: String,
+        }
+
+        let ok = |_: &HttpRequest| Ok(HttpResponse::ok());
+        let rename = |Path(id): Path<u64>, Json(body): Json<Rename>| {
+            Ok(HttpResponse::ok().text(format!("{} {}", id, body.name)))
+        };
+        let get_user = openapi::describe(ok, serde_json::json!({
+            "summary": "Get a user",
+            "responses": { "200": { "description": "The user", "content": { "application/json": {} } } },
+        }));
+        let users = Router::empty().put("/{id:[0-9]+}", extract::handler(rename)).get("/{id}", get_user);
+        let router = router(".").mount("/api/users", users).get("/static/*path", ok);
+
+        let document = TestRequest::get("/openapi.json").send(&router).json::<serde_json::Value>().unwrap();
+        assert_eq!(document["openapi"], "3.0.3");
+        let paths = &document["paths"];
+        let put = &paths["/api/users/{id}"]["put"];
+        assert_eq!(put["operationId"], "put_api_users_id");
+        assert_eq!(put["parameters"][0]["schema"]["pattern"], "^(?:[0-9]+)$");
+        assert!(put["requestBody"]["content"]["application/json"].is_object());
+        assert!(put["responses"]["422"].is_object());
+        let get = &paths["/api/users/{id}"]["get"];
+        assert_eq!(get["summary"], "Get a user");
+        assert!(get["responses"]["200"]["content"]["application/json"].is_object());
+        assert!(get["responses"]["default"].is_object());
+        assert_eq!(paths["/static/{path}"]["get"]["parameters"][0]["name"], "path");
+        // Only registered routes, not the built-in endpoints
+        assert_eq!(paths.as_object().unwrap().len(), 2);
+
+        // The explorer loads the document from the browser
+        let page = TestRequest::get("/docs").send(&router);
+        assert_eq!(page.header("content-type"), Some("text/html"));
+        assert!(page.text().contains("<body data-spec=\"/openapi.json\">"), "{}", page.text());
+    }
+
+    #[test]
+    fn test_schema_violations_are_listed_before_the_handler_runs() {
+        let user = JsonSchema::new(serde_json::json!({
+            "type": "object",
+            "required": ["name"],
+            "properties": { "name": { "type": "string" }, "age": { "type": "integer", "minimum": 0 } },
+        }))
+        .unwrap();
+        let calls = Arc::new(Mutex::new(0));
+        let counted = Arc::clone(&calls);
+        let create = move |_: &HttpRequest| {
+            *counted.lock().unwrap() += 1;
+            Ok(HttpResponse::created())
+        };
+        let router = router(".").post("/users", schema::validate(user, create));
+        let post = |body: &str| TestRequest::post("/users").header("Content-Type", "application/json").body(body);
+
+        assert_eq!(post(r#"{"name": "ann", "age": 3}"#).send(&router).status, 201);
+        let response = post(r#"{"age": -1}"#).send(&router);
+        assert_eq!(response.status, 422);
+        let problem = response.json::<serde_json::Value>().unwrap();
+        assert_eq!(problem["code"], "unprocessable_content");
+        assert_eq!(problem["instance"], "/users");
+        assert_eq!(problem["errors"][0], serde_json::json!({ "pointer": "/name", "detail": "is required" }));
+        assert_eq!(problem["errors"][1], serde_json::json!({ "pointer": "/age", "detail": "must be at least 0" }));
+        assert_eq!(post("{").send(&router).status, 400);
+        let text = post("{}").header("Accept", "text/plain").send(&router).text();
+        assert!(text.ends_with("\n  /name: is required\n"), "{}", text);
+        assert_eq!(*calls.lock().unwrap(), 1);
+
+        let document = router.openapi("Users");
+        let operation = &document["paths"]["/users"]["post"];
+        assert_eq!(operation["requestBody"]["content"]["application/json"]["schema"]["required"][0], "name");
+        assert!(operation["responses"]["422"].is_object());
+    }
+
+    #[test]
+    fn test_file_access_restrictions() {
+        let read_only = Router::new(&Config::parse_from(["http-server", "--directory", "src", "--read-only"]));
+        let response = TestRequest::get("/files/lib.rs").header("Accept-Encoding", "identity").send(&read_only);
+        assert_eq!(response.status, 200);
+        for request in [TestRequest::post("/files/new.txt").body("x"), TestRequest::delete("/files/lib.rs")] {
+            let response = request.send(&read_only);
+            assert_eq!(response.status, 405);
+            assert_eq!(response.header("allow"), Some("GET"));
+        }
+        let info = TestRequest::get("/api/info").send(&read_only).json::<serde_json::Value>().unwrap();
+        assert!(info["endpoints"].get("POST").is_none());
+
+        let disabled = router("src").file_access(FileAccess::Disabled);
+        assert_eq!(TestRequest::get("/files/lib.rs").send(&disabled).status, 404);
+        assert_eq!(TestRequest::delete("/files/lib.rs").send(&disabled).status, 404);
+        assert_eq!(TestRequest::get("/echo/hi").header("Accept-Encoding", "identity").send(&disabled).text(), "hi");
+    }
+
+    #[test]
+    fn test_streaming_handlers_read_the_body_from_the_connection() {
+        let router = Router::empty()
+            .post(
+                "/upload",
+                streaming(|request: &HttpRequest| {
+                    assert!(request.body.is_empty());
+                    let mut body = String::new();
+                    request.body_reader().read_to_string(&mut body)?;
+                    Ok(HttpResponse::ok().body(body.to_uppercase()))
+                }),
+            )
+            .post("/buffered", |request: &HttpRequest| {
+                let mut body = String::new();
+                request.body_reader().read_to_string(&mut body)?;
+                Ok(HttpResponse::ok().body(format!("{} {}", request.body.len(), body)))
+            });
+
+        assert!(router.streams_body(&TestRequest::post("/upload").to_request().unwrap()));
+        assert!(!router.streams_body(&TestRequest::post("/buffered").to_request().unwrap()));
+        assert_eq!(TestRequest::post("/upload").body("hello").send(&router).text(), "HELLO");
+        assert_eq!(TestRequest::post("/buffered").body("hello").send(&router).text(), "5 hello");
+
+        // Built-in uploads stream unless uploads are turned off
+        let upload = TestRequest::post("/files/a.txt").to_request().unwrap();
+        assert!(super::router("src").streams_body(&upload));
+        assert!(!super::router("src").file_access(FileAccess::ReadOnly).streams_body(&upload));
+    }
+
+    #[test]
+    fn test_streamed_responses_are_framed_by_length() {
+        let router = Router::empty()
+            .get("/sized", |_: &HttpRequest| Ok(HttpResponse::ok().stream(&b"0123456789"[..], Some(4))))
+            .get("/generated", |_: &HttpRequest| {
+                let lines = (1..=3).map(|n| format!("line {}\n", n)).collect::<String>();
+                Ok(HttpResponse::ok().stream(std::io::Cursor::new(lines), None))
+            });
+
+        let response = TestRequest::get("/sized").send(&router);
+        assert_eq!(response.header("content-length"), Some("4"));
+        assert_eq!(response.text(), "0123");
+
+        let response = TestRequest::get("/generated").send(&router);
+        assert_eq!(response.header("transfer-encoding"), Some("chunked"));
+        assert_eq!(response.header("content-length"), None);
+        assert_eq!(response.text(), "line 1\nline 2\nline 3\n");
+    }
+
+    #[test]
+    fn test_response_cache_serves_repeats_without_the_handler() {
+        let calls = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&calls);
+        let config = Config::parse_from(["http-server", "--admin-token", "secret"]);
+        let router = Router::new(&config)
+            .response_cache(ResponseCache::new(1024 * 1024))
+            .get("/report", move |request: &HttpRequest| {
+                *counter.lock().unwrap() += 1;
+                let language = request.get_header("accept-language").unwrap_or("en").to_string();
+                Ok(HttpResponse::ok()
+                    .text(format!("report in {}", language))
+                    .header("Cache-Control", "max-age=60")
+                    .vary("Accept-Language"))
+            })
+            .get("/live", |_: &HttpRequest| Ok(HttpResponse::ok().text("live")));
+        let metrics = Arc::new(ServerMetrics::new());
+        let get = |path: &str, language: &str| {
+            TestRequest::get(path).header("Accept-Language", language).send_with_metrics(&router, &metrics)
+        };
+
+        assert_eq!(get("/report", "en").text(), "report in en");
+        let response = get("/report", "en");
+        assert_eq!(response.text(), "report in en");
+        assert_eq!(response.header("age"), Some("0"));
+        assert_eq!(get("/report", "de").text(), "report in de");
+        assert_eq!(*calls.lock().unwrap(), 2);
+
+        // Clients can ask for a fresh copy, which replaces the stored one
+        let response = TestRequest::get("/report")
+            .header("Accept-Language", "en")
+            .header("Cache-Control", "no-cache")
+            .send(&router);
+        assert_eq!(response.header("age"), None);
+        assert_eq!(*calls.lock().unwrap(), 3);
+
+        // Responses without max-age are not stored
+        get("/live", "en");
+        assert_eq!(get("/live", "en").header("age"), None);
+
+        let text = TestRequest::get("/metrics").send_with_metrics(&router, &metrics).text();
+        assert!(text.contains("http_response_cache_hits_total 1\n"), "{}", text);
+        assert!(text.contains("http_response_cache_entries 2\n"), "{}", text);
+        let json = TestRequest::get("/metrics").header("Accept", "application/json").send(&router);
+        assert_eq!(json.json::<serde_json::Value>().unwrap()["response_cache"]["hits"], 1);
+
+        let purge = TestRequest::post("/admin/cache/purge").header("Authorization", "Bearer secret").send(&router);
+        assert_eq!(purge.json::<serde_json::Value>().unwrap()["purged"], 2);
+        get("/report", "en");
+        assert_eq!(*calls.lock().unwrap(), 4);
+    }
+
+    #[test]
+    fn test_admin_lists_open_connections() {
+        let router = Router::new(&Config::parse_from(["http-server", "--admin-token", "secret"]));
+        let metrics = Arc::new(ServerMetrics::new());
+        let connection = metrics.connections.open(Some("198.51.100.4:40000".parse().unwrap()));
+        metrics.connections.request_started(connection);
+
+        let list = |token: &str| {
+            TestRequest::get("/admin/connections").header("Authorization", token).send_with_metrics(&router, &metrics)
+        };
+        assert_eq!(list("Bearer wrong").status, 401);
+        let listing = list("Bearer secret").json::<serde_json::Value>().unwrap();
+        assert_eq!(listing["count"], 1);
+        assert_eq!(listing["connections"][0]["peer_addr"], "198.51.100.4:40000");
+        assert_eq!(listing["connections"][0]["state"], "active");
+    }
+
+    #[test]
+    fn test_dashboard_needs_the_admin_token() {
+        let router = Router::new(&Config::parse_from(["http-server", "--admin-token", "secret"]));
+        let refused = TestRequest::get("/dashboard").send(&router);
+        assert_eq!(refused.status, 401);
+        assert!(refused.header("WWW-Authenticate").unwrap().starts_with("Basic "));
+
+        // Browsers log in with the token as the password; "ops:secret"
+        let page = TestRequest::get("/dashboard").header("Authorization", "Basic b3BzOnNlY3JldA==").send(&router);
+        assert_eq!(page.status, 200);
+        assert!(page.text().contains("Server Dashboard"));
+        let page = TestRequest::get("/dashboard").header("Authorization", "Bearer secret").send(&router);
+        assert_eq!(page.status, 200);
+
+        let unconfigured = Router::new(&Config::parse_from(["http-server"]));
+        assert_eq!(TestRequest::get("/dashboard").send(&unconfigured).status, 404);
+    }
+
+    #[test]
+    fn test_metrics_stream_needs_the_admin_token_and_a_sane_interval() {
+        let router = Router::new(&Config::parse_from(["http-server", "--admin-token", "secret"]));
+        assert_eq!(TestRequest::get("/admin/metrics/stream").send(&router).status, 401);
+        let stream = |query: &str| {
+            TestRequest::get(&format!("/admin/metrics/stream{}", query)).header("Authorization", "Bearer secret")
+        };
+        assert_eq!(stream("?interval=0").send(&router).status, 400);
+        assert_eq!(stream("?interval=soon").send(&router).status, 400);
+        let post = TestRequest::post("/admin/metrics/stream").header("Authorization", "Bearer secret");
+        assert_eq!(post.send(&router).status, 405);
+
+        // Sending the body would take a minute, so only the head is checked
+        let request = HttpRequest::parse(&mut &stream("?interval=1").to_bytes()[..]).unwrap();
+        let response = router.route(&request, &Arc::new(ServerMetrics::new()), Listener::Public).unwrap();
+        assert_eq!(response.get_header("Content-Type"), Some("text/event-stream"));
+        assert!(response.is_streamed());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_cgi_scripts_get_the_request_and_shape_the_response() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("http-server-cgi-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = |name: &str, source: &str| {
+            let path = dir.join(name);
+            std::fs::write(&path, source).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        };
+        script(
+            "echo.sh",
+            "#!/bin/sh\nprintf 'Content-Type: text/plain\\nX-Method: %s\\n\\n' \"$REQUEST_METHOD\"\n\
+             printf '%s|%s|%s|' \"$PATH_INFO\" \"$QUERY_STRING\" \"$HTTP_X_TOKEN\"\ncat\n",
+        );
+        script("missing.sh", "#!/bin/sh\nprintf 'Status: 404 Not Found\\n\\n'\n");
+        script("broken.sh", "#!/bin/sh\necho oops\n");
+        script("slow.sh", "#!/bin/sh\nsleep 5\n");
+        std::fs::write(dir.join("plain.txt"), "").unwrap();
+
+        let router = Router::empty().cgi(Cgi::new("/cgi-bin", &dir, Duration::from_millis(200)));
+        let response = TestRequest::post("/cgi-bin/echo.sh/a/b?x=1").header("X-Token", "t").body("body").send(&router);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.header("x-method"), Some("POST"));
+        assert_eq!(response.text(), "/a/b|x=1|t|body");
+
+        assert_eq!(TestRequest::get("/cgi-bin/missing.sh").send(&router).status, 404);
+        assert_eq!(TestRequest::get("/cgi-bin/nothing.sh").send(&router).status, 404);
+        assert_eq!(TestRequest::get("/cgi-bin/plain.txt").send(&router).status, 403);
+        assert_eq!(TestRequest::get("/cgi-bin/broken.sh").send(&router).status, 502);
+        assert_eq!(TestRequest::get("/cgi-bin/slow.sh").send(&router).status, 504);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
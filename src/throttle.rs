@@ -0,0 +1,331 @@
+//! Bandwidth limits
+//!
+//! `--egress-rate-kb` caps how fast responses are written to each connection
+//! and `--egress-rate-total-kb` how fast they are written to all of them
+//! together, so one client downloading a large file can't take the whole
+//! uplink; `--ingress-rate-kb` and `--ingress-rate-total-kb` do the same for
+//! reading request bodies. All are token buckets holding a second's worth of
+//! bytes: short requests and responses go at full speed, and only sustained
+//! transfers are paced. Transfers are made in small chunks, each waiting
+//! until the buckets have refilled. The limits are reloaded on SIGHUP and
+//! apply to the connections opened after that.
+
+use crate::metrics::{write_header, write_metric};
+use serde_json::{json, Value};
+use std::fmt::Write;
+use std::io::{self, Read};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Smallest and largest number of bytes written between two waits
+const MIN_CHUNK: usize = 1024;
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// Bytes per second refilling a bucket of a second's worth, which may go into
+/// debt by the last chunk written
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket for `rate` bytes per second
+    pub fn new(rate: u64) -> Self {
+        TokenBucket {
+            rate: rate as f64,
+            tokens: rate as f64,
+            refilled: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.refilled = now;
+    }
+
+    /// How long until the bucket is out of debt, if it is in debt
+    pub fn delay(&mut self) -> Option<Duration> {
+        self.refill();
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+
+    /// Take `bytes` that were just transferred
+    pub fn charge(&mut self, bytes: usize) {
+        self.refill();
+        self.tokens -= bytes as f64;
+    }
+}
+
+/// Configured limits and what they held back, for the metrics
+#[derive(Debug, Default)]
+pub struct ThrottleStats {
+    per_connection: AtomicU64,
+    total: AtomicU64,
+    throttled_bytes: AtomicU64,
+    throttled_micros: AtomicU64,
+}
+
+impl ThrottleStats {
+    /// Append the limits and counters in Prometheus text format, as
+    /// `http_{direction}_*`
+    pub fn render_prometheus(&self, out: &mut String, direction: &str) {
+        let name = format!("http_{}_rate_limit_bytes", direction);
+        write_header(out, &name, "gauge", "Configured limit in bytes per second (0 = unlimited)");
+        for (scope, limit) in [("connection", &self.per_connection), ("total", &self.total)] {
+            let _ = writeln!(out, "{}{{scope=\"{}\"}} {}", name, scope, limit.load(Ordering::Relaxed));
+        }
+        out.push('\n');
+        write_metric(
+            out,
+            &format!("http_{}_throttled_bytes_total", direction),
+            "counter",
+            "Bytes that had to wait for the rate limit",
+            self.throttled_bytes.load(Ordering::Relaxed),
+        );
+        let name = format!("http_{}_throttled_seconds_total", direction);
+        write_header(out, &name, "counter", "Time spent waiting for the rate limit");
+        let seconds = self.throttled_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{} {}\n", name, seconds);
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "limit_per_connection": self.per_connection.load(Ordering::Relaxed),
+            "limit_total": self.total.load(Ordering::Relaxed),
+            "throttled_bytes": self.throttled_bytes.load(Ordering::Relaxed),
+            "throttled_seconds": self.throttled_micros.load(Ordering::Relaxed) as f64 / 1e6,
+        })
+    }
+}
+
+/// Rate limits for one direction of traffic, shared by every connection
+#[derive(Debug, Clone)]
+pub struct RateLimit {
+    limits: Arc<RwLock<Limits>>,
+    stats: Arc<ThrottleStats>,
+}
+
+#[derive(Debug)]
+struct Limits {
+    /// Bytes per second for each connection (0 = unlimited)
+    per_connection: u64,
+    /// Bytes per second for all connections together (0 = unlimited)
+    total_rate: u64,
+    total: Option<Arc<Mutex<TokenBucket>>>,
+}
+
+impl RateLimit {
+    /// Limits in bytes per second (0 = unlimited), reported through `stats`
+    pub fn new(per_connection: u64, total: u64, stats: Arc<ThrottleStats>) -> Self {
+        let limits = Limits {
+            per_connection: 0,
+            total_rate: 0,
+            total: None,
+        };
+        let limit = RateLimit {
+            limits: Arc::new(RwLock::new(limits)),
+            stats,
+        };
+        limit.set(per_connection, total);
+        limit
+    }
+
+    /// Change the limits for the connections opened from now on; a total
+    /// that stays the same keeps its bucket, and what is owed on it
+    pub fn set(&self, per_connection: u64, total: u64) {
+        self.stats.per_connection.store(per_connection, Ordering::Relaxed);
+        self.stats.total.store(total, Ordering::Relaxed);
+        let mut limits = self.limits.write().unwrap();
+        limits.per_connection = per_connection;
+        if limits.total_rate != total {
+            limits.total_rate = total;
+            limits.total = (total > 0).then(|| Arc::new(Mutex::new(TokenBucket::new(total))));
+        }
+    }
+
+    /// The throttle for a new connection, or `None` when nothing is limited
+    pub fn connection(&self) -> Option<Throttle> {
+        let limits = self.limits.read().unwrap();
+        if limits.per_connection == 0 && limits.total.is_none() {
+            return None;
+        }
+        let rates = [limits.per_connection, limits.total_rate];
+        let rate = rates.into_iter().filter(|rate| *rate > 0).min().unwrap_or_default();
+        Some(Throttle {
+            connection: (limits.per_connection > 0).then(|| Arc::new(Mutex::new(TokenBucket::new(limits.per_connection)))),
+            total: limits.total.clone(),
+            chunk: usize::try_from(rate / 10).unwrap_or(MAX_CHUNK).clamp(MIN_CHUNK, MAX_CHUNK),
+            stats: Arc::clone(&self.stats),
+            delayed: false,
+            #[cfg(feature = "tokio")]
+            sleep: None,
+        })
+    }
+}
+
+/// Paces the transfers of one connection
+pub struct Throttle {
+    connection: Option<Arc<Mutex<TokenBucket>>>,
+    total: Option<Arc<Mutex<TokenBucket>>>,
+    /// Most bytes to transfer at once
+    chunk: usize,
+    stats: Arc<ThrottleStats>,
+    /// Whether the next transfer waited for the limit
+    delayed: bool,
+    #[cfg(feature = "tokio")]
+    sleep: Option<std::pin::Pin<Box<tokio::time::Sleep>>>,
+}
+
+impl Throttle {
+    /// How long to wait before transferring more, counting it as throttled
+    fn delay(&mut self) -> Option<Duration> {
+        let connection = self.connection.as_ref().and_then(|connection| connection.lock().unwrap().delay());
+        let total = self.total.as_ref().and_then(|total| total.lock().unwrap().delay());
+        let delay = connection.max(total)?;
+        self.delayed = true;
+        let micros = u64::try_from(delay.as_micros()).unwrap_or(u64::MAX);
+        self.stats.throttled_micros.fetch_add(micros, Ordering::Relaxed);
+        Some(delay)
+    }
+
+    /// Block until more may be transferred
+    pub fn wait(&mut self) {
+        while let Some(delay) = self.delay() {
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Wait on the Tokio timer until more may be transferred
+    #[cfg(feature = "tokio")]
+    pub fn poll_wait(&mut self, cx: &mut std::task::Context<'_>) -> std::task::Poll<()> {
+        use std::future::Future;
+        loop {
+            if let Some(sleep) = self.sleep.as_mut() {
+                std::task::ready!(sleep.as_mut().poll(cx));
+                self.sleep = None;
+            }
+            match self.delay() {
+                Some(delay) => self.sleep = Some(Box::pin(tokio::time::sleep(delay))),
+                None => return std::task::Poll::Ready(()),
+            }
+        }
+    }
+
+    /// How much of `len` bytes to transfer next
+    pub fn limit(&self, len: usize) -> usize {
+        len.min(self.chunk)
+    }
+
+    /// Take `bytes` that were just transferred from the buckets
+    pub fn charge(&mut self, bytes: usize) {
+        if let Some(connection) = self.connection.as_ref() {
+            connection.lock().unwrap().charge(bytes);
+        }
+        if let Some(total) = self.total.as_ref() {
+            total.lock().unwrap().charge(bytes);
+        }
+        if std::mem::take(&mut self.delayed) {
+            self.stats.throttled_bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Another handle on the same buckets, for a second reader or writer of the
+/// connection
+impl Clone for Throttle {
+    fn clone(&self) -> Self {
+        Throttle {
+            connection: self.connection.clone(),
+            total: self.total.clone(),
+            chunk: self.chunk,
+            stats: Arc::clone(&self.stats),
+            delayed: false,
+            #[cfg(feature = "tokio")]
+            sleep: None,
+        }
+    }
+}
+
+/// A reader paced by a throttle, if any
+pub struct ThrottledReader<R> {
+    inner: R,
+    throttle: Option<Throttle>,
+}
+
+impl<R> ThrottledReader<R> {
+    pub fn new(inner: R, throttle: Option<Throttle>) -> Self {
+        ThrottledReader { inner, throttle }
+    }
+}
+
+impl<R: Read> Read for ThrottledReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(throttle) = self.throttle.as_mut() else {
+            return self.inner.read(buf);
+        };
+        throttle.wait();
+        let len = throttle.limit(buf.len());
+        let n = self.inner.read(&mut buf[..len])?;
+        throttle.charge(n);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transfers_are_paced_after_the_burst() {
+        let stats = Arc::new(ThrottleStats::default());
+        assert!(RateLimit::new(0, 0, Arc::clone(&stats)).connection().is_none());
+
+        // 10 KiB/s: the first second's worth goes at once, the rest waits
+        let mut throttle = RateLimit::new(10 * 1024, 0, Arc::clone(&stats)).connection().unwrap();
+        assert_eq!(throttle.limit(1 << 20), MIN_CHUNK);
+        let start = Instant::now();
+        let mut sent = 0;
+        while sent < 13 * 1024 {
+            throttle.wait();
+            let n = throttle.limit(13 * 1024 - sent);
+            throttle.charge(n);
+            sent += n;
+        }
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(1), "{:?}", elapsed);
+        assert!(stats.throttled_bytes.load(Ordering::Relaxed) >= 1024);
+
+        // A second handle on the connection shares its bucket
+        let mut reader = ThrottledReader::new(&[0u8; 4096][..], Some(throttle.clone()));
+        let start = Instant::now();
+        assert_eq!(reader.read(&mut [0; 4096]).unwrap(), MIN_CHUNK);
+        assert!(start.elapsed() >= Duration::from_millis(50), "{:?}", start.elapsed());
+
+        let mut out = String::new();
+        stats.render_prometheus(&mut out, "egress");
+        assert!(out.contains("http_egress_rate_limit_bytes{scope=\"connection\"} 10240\n"));
+        assert!(out.contains("http_egress_rate_limit_bytes{scope=\"total\"} 0\n"));
+    }
+
+    #[test]
+    fn test_limits_can_change_for_new_connections() {
+        let limit = RateLimit::new(0, 0, Arc::default());
+        let unlimited = limit.connection();
+        limit.set(0, 10 * 1024);
+        assert!(unlimited.is_none());
+        let first = limit.connection().unwrap();
+        // The same total keeps its bucket, shared with earlier connections
+        limit.set(4 * 1024, 10 * 1024);
+        let second = limit.connection().unwrap();
+        assert!(Arc::ptr_eq(first.total.as_ref().unwrap(), second.total.as_ref().unwrap()));
+        assert!(first.connection.is_none() && second.connection.is_some());
+        limit.set(0, 0);
+        assert!(limit.connection().is_none());
+    }
+}
@@ -0,0 +1,182 @@
+//! Handler execution timeouts
+//!
+//! `--handler-timeout-secs` bounds how long any handler may run, and
+//! `--route-timeout PATTERN=SECS` overrides it for paths matching a pattern
+//! (an exact path, or a prefix followed by `*`; the first match wins, and 0
+//! turns the timeout off). A handler still running when its time is up is
+//! abandoned: the client gets 504 and the worker moves on, while the handler
+//! finishes in the background and its response is dropped.
+//!
+//! An abandoned handler keeps its thread until it returns, so a stuck
+//! dependency would keep adding threads. [`AbandonedHandlers`] counts them,
+//! and once `--max-abandoned-handlers` are still running, requests that would
+//! be timed are refused with 503 until some finish.
+
+use crate::error::ServerError;
+use crate::response_headers::path_matches;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Abandoned handlers allowed to run at once unless configured otherwise
+pub const DEFAULT_MAX_ABANDONED: usize = 64;
+
+/// Timeout rules by path pattern, with a default for every other path
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RouteTimeouts {
+    pub default: Option<Duration>,
+    pub rules: Vec<(String, Option<Duration>)>,
+}
+
+impl RouteTimeouts {
+    /// A `default` timeout for every path (`None` for none)
+    pub fn new(default: Option<Duration>) -> Self {
+        RouteTimeouts {
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Time paths matching `pattern` out after `timeout` instead, or never
+    /// with `None`; checked after the rules so far
+    pub fn route(mut self, pattern: &str, timeout: Option<Duration>) -> Self {
+        self.rules.push((pattern.to_string(), timeout));
+        self
+    }
+
+    /// Parse `--handler-timeout-secs` and the `--route-timeout` rules
+    pub fn parse(default_secs: u64, rules: &[String]) -> Result<Self, String> {
+        let seconds = |secs: u64| (secs > 0).then(|| Duration::from_secs(secs));
+        rules.iter().try_fold(Self::new(seconds(default_secs)), |timeouts, spec| {
+            let invalid = || format!("--route-timeout takes PATTERN=SECS, got '{}'", spec);
+            let (pattern, secs) = spec.split_once('=').ok_or_else(invalid)?;
+            if !pattern.starts_with('/') {
+                return Err(invalid());
+            }
+            let secs = secs.trim().parse().map_err(|_| invalid())?;
+            Ok(timeouts.route(pattern, seconds(secs)))
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.default.is_none() && self.rules.iter().all(|(_, timeout)| timeout.is_none())
+    }
+
+    /// How long a handler for `path` may run
+    pub fn timeout_for(&self, path: &str) -> Option<Duration> {
+        let path = path.split('?').next().unwrap_or_default();
+        match self.rules.iter().find(|(pattern, _)| path_matches(pattern, path)) {
+            Some((_, timeout)) => *timeout,
+            None => self.default,
+        }
+    }
+}
+
+/// Handlers still running after their request timed out
+#[derive(Debug)]
+pub struct AbandonedHandlers {
+    /// Abandoned handlers allowed at once (0 = no limit)
+    limit: usize,
+    running: AtomicUsize,
+}
+
+/// The side of a timed handler that runs it; finishes the handler when dropped
+#[derive(Debug)]
+pub struct TimedRun {
+    handlers: Arc<AbandonedHandlers>,
+    settled: Arc<AtomicBool>,
+}
+
+/// The side of a timed handler that waits for it
+#[derive(Debug)]
+pub struct TimedWait {
+    handlers: Arc<AbandonedHandlers>,
+    settled: Arc<AtomicBool>,
+}
+
+impl AbandonedHandlers {
+    pub fn new(limit: usize) -> Self {
+        AbandonedHandlers {
+            limit,
+            running: AtomicUsize::new(0),
+        }
+    }
+
+    /// How many abandoned handlers have yet to return
+    pub fn running(&self) -> usize {
+        self.running.load(Ordering::Relaxed)
+    }
+
+    /// Start timing a handler, or refuse with 503 while the limit of
+    /// abandoned handlers is reached
+    pub fn start(self: &Arc<Self>) -> Result<(TimedRun, TimedWait), ServerError> {
+        if self.limit > 0 && self.running() >= self.limit {
+            log::warn!("{} timed out handlers are still running, refusing timed requests", self.running());
+            return Err(ServerError::Overloaded(format!(
+                "{} timed out handlers are still running, retry shortly",
+                self.running()
+            )));
+        }
+        let settled = Arc::new(AtomicBool::new(false));
+        let run = TimedRun {
+            handlers: Arc::clone(self),
+            settled: Arc::clone(&settled),
+        };
+        Ok((run, TimedWait { handlers: Arc::clone(self), settled }))
+    }
+}
+
+impl TimedWait {
+    /// Stop waiting; the handler counts as abandoned until it returns
+    pub fn abandon(self) {
+        if !self.settled.swap(true, Ordering::AcqRel) {
+            self.handlers.running.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for TimedRun {
+    fn drop(&mut self) {
+        // Whichever side settles second knows the handler was abandoned
+        if self.settled.swap(true, Ordering::AcqRel) {
+            self.handlers.running.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_override_the_default() {
+        let rules = ["/files/*=120".to_string(), "/delay/*=0".to_string()];
+        let timeouts = RouteTimeouts::parse(5, &rules).unwrap();
+        assert_eq!(timeouts.timeout_for("/echo/hi"), Some(Duration::from_secs(5)));
+        assert_eq!(timeouts.timeout_for("/files/big.iso?x=1"), Some(Duration::from_secs(120)));
+        assert_eq!(timeouts.timeout_for("/delay/30"), None);
+        assert!(RouteTimeouts::parse(0, &[]).unwrap().is_empty());
+
+        for spec in ["/files/*", "files=10", "/files=soon"] {
+            assert!(RouteTimeouts::parse(0, &[spec.to_string()]).is_err(), "{}", spec);
+        }
+    }
+
+    #[test]
+    fn test_abandoned_handlers_are_capped() {
+        let handlers = Arc::new(AbandonedHandlers::new(1));
+        // Finished in time: nothing is counted
+        let (run, wait) = handlers.start().unwrap();
+        drop(run);
+        wait.abandon();
+        assert_eq!(handlers.running(), 0);
+
+        let (run, wait) = handlers.start().unwrap();
+        wait.abandon();
+        assert_eq!(handlers.running(), 1);
+        assert_eq!(handlers.start().unwrap_err().status_code(), 503);
+        drop(run);
+        assert_eq!(handlers.running(), 0);
+        assert!(handlers.start().is_ok());
+    }
+}
@@ -0,0 +1,121 @@
+use std::io;
+use std::net::TcpListener;
+
+/// Environment variable carrying the inherited listening sockets' fd numbers,
+/// comma-separated
+const LISTEN_FD_ENV: &str = "HTTP_SERVER_LISTEN_FD";
+
+/// Environment variable carrying the pid of the process being replaced
+const PARENT_PID_ENV: &str = "HTTP_SERVER_UPGRADE_PARENT";
+
+/// Take over the listening sockets handed down by a previous process, if any
+#[cfg(unix)]
+pub fn inherited_listeners() -> Vec<TcpListener> {
+    let Ok(fds) = std::env::var(LISTEN_FD_ENV) else {
+        return Vec::new();
+    };
+    std::env::remove_var(LISTEN_FD_ENV);
+
+    fds.split(',')
+        .filter_map(|fd| fd.trim().parse::<i32>().ok())
+        .filter_map(inherit_listener)
+        .collect()
+}
+
+/// Take ownership of a listening socket passed down as `fd` by the process
+/// that started us
+#[cfg(unix)]
+pub fn inherit_listener(fd: i32) -> Option<TcpListener> {
+    use std::os::fd::FromRawFd;
+
+    // SAFETY: the parent process passed us ownership of this open socket
+    let listener = unsafe { TcpListener::from_raw_fd(fd) };
+    set_cloexec(&listener, true).ok()?;
+    Some(listener)
+}
+
+#[cfg(not(unix))]
+pub fn inherited_listeners() -> Vec<TcpListener> {
+    Vec::new()
+}
+
+/// Tell the process we replaced that we're accepting, so it can drain and exit
+#[cfg(unix)]
+pub fn notify_parent() {
+    let Some(pid) = std::env::var(PARENT_PID_ENV).ok().and_then(|pid| pid.parse::<libc::pid_t>().ok()) else {
+        return;
+    };
+    std::env::remove_var(PARENT_PID_ENV);
+
+    log::info!("Taking over from process {}, asking it to drain", pid);
+    // SAFETY: kill has no memory-safety preconditions
+    if unsafe { libc::kill(pid, libc::SIGTERM) } != 0 {
+        log::warn!("Failed to signal previous process {}: {}", pid, io::Error::last_os_error());
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify_parent() {}
+
+/// Start a new copy of the current binary that inherits `listeners`; the new
+/// process sends us SIGTERM once it is accepting connections
+pub fn spawn_successor(listeners: &[TcpListener]) -> io::Result<std::process::Child> {
+    spawn_inheriting(listeners, &[], &[(PARENT_PID_ENV, std::process::id().to_string())])
+}
+
+/// Start a copy of the current binary with the same arguments that inherits
+/// `listeners` as its listening sockets, and `others` for `env` to name
+#[cfg(unix)]
+pub fn spawn_inheriting(
+    listeners: &[TcpListener],
+    others: &[&TcpListener],
+    env: &[(&str, String)],
+) -> io::Result<std::process::Child> {
+    use std::os::fd::AsRawFd;
+
+    let exe = std::env::current_exe()?;
+    let fds: Vec<String> = listeners.iter().map(|l| l.as_raw_fd().to_string()).collect();
+    let inherited = || listeners.iter().chain(others.iter().copied());
+    for listener in inherited() {
+        set_cloexec(listener, false)?;
+    }
+    let child = std::process::Command::new(exe)
+        .args(std::env::args_os().skip(1))
+        .env(LISTEN_FD_ENV, fds.join(","))
+        .env_remove(PARENT_PID_ENV)
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .spawn();
+    for listener in inherited() {
+        set_cloexec(listener, true)?;
+    }
+    child
+}
+
+#[cfg(not(unix))]
+pub fn spawn_inheriting(
+    _listeners: &[TcpListener],
+    _others: &[&TcpListener],
+    _env: &[(&str, String)],
+) -> io::Result<std::process::Child> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "passing on sockets requires a Unix platform"))
+}
+
+/// Control whether the socket is closed when the process execs
+#[cfg(unix)]
+fn set_cloexec(listener: &TcpListener, cloexec: bool) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let fd = listener.as_raw_fd();
+    // SAFETY: fcntl on a valid, owned descriptor
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFD);
+        if flags == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let flags = if cloexec { flags | libc::FD_CLOEXEC } else { flags & !libc::FD_CLOEXEC };
+        if libc::fcntl(fd, libc::F_SETFD, flags) == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
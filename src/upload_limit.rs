@@ -0,0 +1,131 @@
+//! Limit on concurrent large uploads
+//!
+//! A worker reading a large request body is tied up for as long as the client
+//! takes to send it, so a few slow uploads can occupy the whole pool.
+//! [`UploadLimiter`] lets at most `--max-uploads` bodies of `--large-upload-kb`
+//! or more be read at once. Further large uploads wait up to
+//! `--upload-wait-secs` for one to finish and are then refused with 503,
+//! before any of their body is read. Smaller requests are never held back.
+
+use crate::error::ServerError;
+use crate::metrics::ServerMetrics;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
+
+/// Large uploads in progress
+#[derive(Debug, Default)]
+struct Slots {
+    active: Mutex<usize>,
+    freed: Condvar,
+}
+
+/// Admission control for request bodies above a size threshold
+#[derive(Debug, Clone)]
+pub struct UploadLimiter {
+    /// Large uploads allowed at once (0 = no limit)
+    max: usize,
+    /// Bodies of at least this many bytes count as large
+    threshold: u64,
+    /// How long a large upload may wait for a slot
+    wait: Duration,
+    slots: Arc<Slots>,
+}
+
+/// A slot held by a large upload; released when dropped
+pub struct UploadPermit {
+    slots: Arc<Slots>,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl UploadLimiter {
+    pub fn new(max: usize, threshold: u64, wait: Duration) -> Self {
+        UploadLimiter {
+            max,
+            threshold,
+            wait,
+            slots: Arc::default(),
+        }
+    }
+
+    /// Whether a body of `length` bytes is subject to the limit
+    pub fn is_large(&self, length: u64) -> bool {
+        self.max > 0 && length > 0 && length >= self.threshold
+    }
+
+    /// Admit a body of `length` bytes, waiting for a slot if it is large and
+    /// all slots are taken
+    ///
+    /// Returns the permit to hold while the body is read and handled, `None`
+    /// for a body that isn't large, or a 503 error once the wait is over.
+    pub fn admit(&self, length: u64, metrics: &Arc<ServerMetrics>) -> Result<Option<UploadPermit>, ServerError> {
+        if !self.is_large(length) {
+            return Ok(None);
+        }
+        let deadline = Instant::now() + self.wait;
+        let mut active = self.slots.active.lock().unwrap();
+        if *active >= self.max {
+            metrics.uploads_waited.fetch_add(1, Ordering::Relaxed);
+        }
+        while *active >= self.max {
+            let now = Instant::now();
+            if now >= deadline {
+                metrics.uploads_rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(ServerError::Overloaded(format!(
+                    "{} large uploads are already in progress, retry shortly",
+                    self.max
+                )));
+            }
+            active = self.slots.freed.wait_timeout(active, deadline - now).unwrap().0;
+        }
+        *active += 1;
+        metrics.uploads_active.fetch_add(1, Ordering::Relaxed);
+        Ok(Some(UploadPermit {
+            slots: Arc::clone(&self.slots),
+            metrics: Arc::clone(metrics),
+        }))
+    }
+}
+
+impl Drop for UploadPermit {
+    fn drop(&mut self) {
+        *self.slots.active.lock().unwrap() -= 1;
+        self.metrics.uploads_active.fetch_sub(1, Ordering::Relaxed);
+        self.slots.freed.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_large_uploads_wait_for_a_slot_then_are_refused() {
+        let metrics = Arc::new(ServerMetrics::new());
+        let limiter = UploadLimiter::new(1, 1024, Duration::from_millis(20));
+        assert!(limiter.admit(1023, &metrics).unwrap().is_none());
+
+        let first = limiter.admit(4096, &metrics).unwrap().expect("a permit for a large upload");
+        assert_eq!(metrics.uploads_active.load(Ordering::Relaxed), 1);
+        let refused = limiter.admit(1024, &metrics).err().map(|e| e.status_code());
+        assert_eq!(refused, Some(503));
+        assert_eq!(metrics.uploads_waited.load(Ordering::Relaxed), 1);
+        assert_eq!(metrics.uploads_rejected.load(Ordering::Relaxed), 1);
+        // Small bodies are never held back
+        assert!(limiter.admit(10, &metrics).unwrap().is_none());
+
+        // A waiting upload gets the slot as soon as it is freed
+        let waiting = {
+            let (limiter, metrics) = (limiter.clone(), Arc::clone(&metrics));
+            let limiter = UploadLimiter {
+                wait: Duration::from_secs(10),
+                ..limiter
+            };
+            std::thread::spawn(move || limiter.admit(2048, &metrics).map(|permit| permit.is_some()))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        drop(first);
+        assert!(waiting.join().unwrap().unwrap());
+        assert_eq!(metrics.uploads_active.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,603 @@
+//! Experimental io_uring execution mode
+//!
+//! Each public listener gets a thread with its own ring. Accepts, receives,
+//! and sends are submitted through that ring, so one thread serves many
+//! connections without blocking. Requests are routed synchronously on the ring
+//! thread. Static file reads use a second, per-thread ring (see [`read_file`]).
+
+use crate::error::ServerError;
+use crate::request::{HttpRequest, MAX_HEAD_SIZE};
+use crate::response::{HttpResponse, ResponseParts};
+use crate::router::Listener;
+use crate::ip_filter::DenyAction;
+use crate::server::{
+    begin_request, blocked_client, blocked_peer, client_aborted, error_response, finish_response, record_request,
+    route_in_time, RequestOutcome, ServerState,
+};
+use crate::{net, request_id};
+use io_uring::{opcode, squeue, types, IoUring};
+use std::cell::RefCell;
+use std::io;
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+/// Submission queue entries per connection ring
+const RING_ENTRIES: u32 = 1024;
+
+/// Receive buffer size per connection
+const CHUNK_SIZE: usize = 8192;
+
+/// How often each ring thread checks the shutdown flag
+const TICK: Duration = Duration::from_millis(250);
+
+// Operation kinds, stored in the low bits of each entry's user data; the
+// connection's slot number goes in the remaining bits
+const OP_ACCEPT: u64 = 1;
+const OP_RECV: u64 = 2;
+const OP_SEND: u64 = 3;
+const OP_LINK_TIMEOUT: u64 = 4;
+const OP_TICK: u64 = 5;
+const OP_CANCEL: u64 = 6;
+const OP_BITS: u32 = 3;
+
+thread_local! {
+    /// Ring used for file reads on this thread, when it is an io_uring worker
+    static FILE_RING: RefCell<Option<IoUring>> = const { RefCell::new(None) };
+}
+
+/// Serve the public listeners on io_uring threads until `shutdown` is set
+///
+/// Returns once every thread has stopped accepting; in-flight connections keep
+/// being served on those threads in the background.
+pub fn run(listeners: Vec<TcpListener>, state: Arc<ServerState>, shutdown: Arc<AtomicBool>) -> io::Result<()> {
+    // Create every ring up front so an unsupported kernel fails startup cleanly
+    let rings = listeners
+        .iter()
+        .map(|_| Ok((IoUring::new(RING_ENTRIES)?, IoUring::new(8)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let (stopped_tx, stopped_rx) = mpsc::channel();
+    let count = listeners.len();
+    for (i, (listener, (ring, file_ring))) in listeners.into_iter().zip(rings).enumerate() {
+        let (state, shutdown, stopped) = (Arc::clone(&state), Arc::clone(&shutdown), stopped_tx.clone());
+        std::thread::Builder::new().name(format!("uring-{}", i)).spawn(move || {
+            FILE_RING.with(|cell| *cell.borrow_mut() = Some(file_ring));
+            let mut event_loop = EventLoop {
+                ring,
+                listener: Some(listener),
+                state,
+                shutdown,
+                stopped,
+                connections: Vec::new(),
+                tick: types::Timespec::from(TICK),
+            };
+            if let Err(e) = event_loop.run() {
+                log::error!("io_uring event loop failed: {}", e);
+                let _ = event_loop.stopped.send(());
+            }
+        })?;
+    }
+
+    for _ in 0..count {
+        let _ = stopped_rx.recv();
+    }
+    Ok(())
+}
+
+/// Read a whole file, submitting the open and reads through this thread's
+/// ring; falls back to `std::fs::read` on threads without one
+pub fn read_file(path: &Path) -> io::Result<Vec<u8>> {
+    FILE_RING.with(|cell| match cell.borrow_mut().as_mut() {
+        Some(ring) => read_file_with(ring, path),
+        None => std::fs::read(path),
+    })
+}
+
+fn read_file_with(ring: &mut IoUring, path: &Path) -> io::Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let open = opcode::OpenAt::new(types::Fd(libc::AT_FDCWD), c_path.as_ptr())
+        .flags(libc::O_RDONLY | libc::O_CLOEXEC)
+        .build();
+    // SAFETY: the path outlives the blocking submission below
+    let fd = unsafe { submit_one(ring, &open)? };
+    // SAFETY: the kernel returned a new descriptor that we now own
+    let file = unsafe { std::fs::File::from_raw_fd(fd) };
+
+    let mut content: Vec<u8> = Vec::with_capacity(file.metadata()?.len() as usize + 1);
+    loop {
+        if content.len() == content.capacity() {
+            content.reserve(CHUNK_SIZE);
+        }
+        let spare = content.capacity() - content.len();
+        let read = opcode::Read::new(
+            types::Fd(file.as_raw_fd()),
+            // SAFETY: the pointer stays within the vector's allocation
+            unsafe { content.as_mut_ptr().add(content.len()) },
+            spare.min(u32::MAX as usize) as u32,
+        )
+        .offset(content.len() as u64)
+        .build();
+        // SAFETY: the buffer outlives the blocking submission
+        let n = unsafe { submit_one(ring, &read)? } as usize;
+        if n == 0 {
+            return Ok(content);
+        }
+        // SAFETY: the kernel initialized `n` more bytes
+        unsafe { content.set_len(content.len() + n) };
+    }
+}
+
+/// Submit one entry and wait for its result
+///
+/// # Safety
+/// Any buffers referenced by `entry` must stay valid until this returns.
+unsafe fn submit_one(ring: &mut IoUring, entry: &squeue::Entry) -> io::Result<i32> {
+    ring.submission()
+        .push(entry)
+        .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+    ring.submit_and_wait(1)?;
+    let result = ring
+        .completion()
+        .next()
+        .ok_or_else(|| io::Error::other("io_uring returned no completion"))?
+        .result();
+    if result < 0 {
+        return Err(io::Error::from_raw_os_error(-result));
+    }
+    Ok(result)
+}
+
+/// One client connection owned by a ring thread
+///
+/// Boxed so its buffers stay put while the kernel reads or writes them.
+struct Connection {
+    stream: TcpStream,
+    peer_addr: Option<SocketAddr>,
+    /// Bytes received but not yet consumed by a request
+    pending: Vec<u8>,
+    chunk: Box<[u8]>,
+    /// Response being sent; its head goes back to the pool once it's done
+    out: Option<ResponseParts>,
+    sent: usize,
+    /// Head and body of `out` for the in-flight `sendmsg`
+    iov: [libc::iovec; 2],
+    msg: libc::msghdr,
+    served: usize,
+    keep_open: bool,
+    timeout: types::Timespec,
+    /// Id in the connection registry
+    id: u64,
+    bytes_received: u64,
+    bytes_sent: u64,
+}
+
+struct EventLoop {
+    ring: IoUring,
+    listener: Option<TcpListener>,
+    state: Arc<ServerState>,
+    shutdown: Arc<AtomicBool>,
+    stopped: mpsc::Sender<()>,
+    connections: Vec<Option<Box<Connection>>>,
+    tick: types::Timespec,
+}
+
+impl EventLoop {
+    fn run(&mut self) -> io::Result<()> {
+        self.submit_accept()?;
+        self.push(opcode::Timeout::new(&self.tick).build().user_data(OP_TICK))?;
+
+        while self.listener.is_some() || self.connections.iter().any(Option::is_some) {
+            match self.ring.submit_and_wait(1) {
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+            let completions: Vec<(u64, i32)> = self.ring.completion().map(|c| (c.user_data(), c.result())).collect();
+            for (user_data, result) in completions {
+                let slot = (user_data >> OP_BITS) as usize;
+                match user_data & ((1 << OP_BITS) - 1) {
+                    OP_ACCEPT => self.on_accept(result)?,
+                    OP_RECV => self.on_recv(slot, result)?,
+                    OP_SEND => self.on_send(slot, result)?,
+                    OP_TICK => self.on_tick()?,
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Queue an entry, flushing the submission queue first if it is full
+    fn push(&mut self, entry: squeue::Entry) -> io::Result<()> {
+        // SAFETY: every buffer an entry references lives in a boxed connection
+        // (or the event loop) that outlives the operation
+        unsafe {
+            if self.ring.submission().push(&entry).is_err() {
+                self.ring.submit()?;
+                self.ring
+                    .submission()
+                    .push(&entry)
+                    .map_err(|_| io::Error::other("io_uring submission queue is full"))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn submit_accept(&mut self) -> io::Result<()> {
+        let Some(listener) = &self.listener else {
+            return Ok(());
+        };
+        let accept = opcode::Accept::new(types::Fd(listener.as_raw_fd()), std::ptr::null_mut(), std::ptr::null_mut())
+            .flags(libc::SOCK_CLOEXEC)
+            .build()
+            .user_data(OP_ACCEPT);
+        self.push(accept)
+    }
+
+    /// Stop accepting once shutdown starts, and close idle keep-alive
+    /// connections once draining starts
+    fn on_tick(&mut self) -> io::Result<()> {
+        if self.state.router.readiness.is_draining() {
+            let idle: Vec<usize> = (0..self.connections.len())
+                .filter(|&slot| {
+                    self.connections[slot].as_ref().is_some_and(|connection| {
+                        connection.served > 0 && connection.out.is_none() && connection.pending.is_empty()
+                    })
+                })
+                .collect();
+            // The cancelled receive completes with ECANCELED, which closes the connection
+            for slot in idle {
+                self.push(opcode::AsyncCancel::new((slot as u64) << OP_BITS | OP_RECV).build().user_data(OP_CANCEL))?;
+            }
+        }
+        if self.shutdown.load(Ordering::Relaxed) && self.listener.is_some() {
+            self.push(opcode::AsyncCancel::new(OP_ACCEPT).build().user_data(OP_CANCEL))?;
+            self.ring.submit()?;
+            self.listener = None;
+            let _ = self.stopped.send(());
+        }
+        self.push(opcode::Timeout::new(&self.tick).build().user_data(OP_TICK))
+    }
+
+    fn on_accept(&mut self, result: i32) -> io::Result<()> {
+        if result >= 0 {
+            // SAFETY: the kernel returned a new connected socket that we now own
+            let stream = unsafe { TcpStream::from_raw_fd(result) };
+            self.open(stream)?;
+        } else if result != -libc::ECANCELED {
+            log::error!("Failed to accept connection: {}", io::Error::from_raw_os_error(-result));
+        }
+        self.submit_accept()
+    }
+
+    fn open(&mut self, stream: TcpStream) -> io::Result<()> {
+        let peer_addr = stream.peer_addr().ok();
+        if self.state.config.deny_action == DenyAction::Close
+            && peer_addr.is_some_and(|addr| blocked_peer(addr.ip(), &self.state))
+        {
+            return Ok(());
+        }
+        if let Err(e) = net::configure_stream(&stream, &self.state.config.socket_options()) {
+            log::debug!("Failed to apply socket options for {:?}: {}", peer_addr, e);
+        }
+        self.state.metrics.active_connections.fetch_add(1, Ordering::Relaxed);
+        let id = self.state.metrics.connections.open(peer_addr);
+
+        let connection = Box::new(Connection {
+            stream,
+            peer_addr,
+            pending: Vec::new(),
+            chunk: vec![0; CHUNK_SIZE].into_boxed_slice(),
+            out: None,
+            sent: 0,
+            iov: [libc::iovec { iov_base: std::ptr::null_mut(), iov_len: 0 }; 2],
+            // SAFETY: an all-zero msghdr is valid; it's filled in before each send
+            msg: unsafe { std::mem::zeroed() },
+            served: 0,
+            keep_open: false,
+            timeout: types::Timespec::new(),
+            id,
+            bytes_received: 0,
+            bytes_sent: 0,
+        });
+        let slot = match self.connections.iter().position(Option::is_none) {
+            Some(slot) => {
+                self.connections[slot] = Some(connection);
+                slot
+            }
+            None => {
+                self.connections.push(Some(connection));
+                self.connections.len() - 1
+            }
+        };
+        self.submit_recv(slot)
+    }
+
+    fn close(&mut self, slot: usize) {
+        if let Some(connection) = self.connections[slot].take() {
+            let metrics = &self.state.metrics;
+            metrics.connections.close(connection.id, connection.bytes_received, connection.bytes_sent);
+            metrics.active_connections.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Wait for more request bytes, giving up after the idle or keep-alive timeout
+    fn submit_recv(&mut self, slot: usize) -> io::Result<()> {
+        let config = &self.state.config;
+        let Some(connection) = self.connections[slot].as_mut() else {
+            return Ok(());
+        };
+        let timeout_secs = if connection.served == 0 { config.idle_timeout_secs } else { config.keep_alive_timeout_secs };
+
+        let recv = opcode::Recv::new(
+            types::Fd(connection.stream.as_raw_fd()),
+            connection.chunk.as_mut_ptr(),
+            connection.chunk.len() as u32,
+        )
+        .build()
+        .user_data((slot as u64) << OP_BITS | OP_RECV);
+
+        if timeout_secs == 0 {
+            return self.push(recv);
+        }
+        connection.timeout = types::Timespec::from(Duration::from_secs(timeout_secs));
+        let timeout = opcode::LinkTimeout::new(&connection.timeout)
+            .build()
+            .user_data((slot as u64) << OP_BITS | OP_LINK_TIMEOUT);
+        self.push(recv.flags(squeue::Flags::IO_LINK))?;
+        self.push(timeout)
+    }
+
+    fn on_recv(&mut self, slot: usize, result: i32) -> io::Result<()> {
+        let Some(connection) = self.connections[slot].as_mut() else {
+            return Ok(());
+        };
+        if result <= 0 {
+            // A clean close, an idle timeout (the recv is cancelled), or an error
+            if result < 0 && result != -libc::ECANCELED {
+                log::debug!(
+                    "Closing connection from {:?}: {}",
+                    connection.peer_addr,
+                    io::Error::from_raw_os_error(-result)
+                );
+            }
+            self.close(slot);
+            return Ok(());
+        }
+        connection.bytes_received += result as u64;
+        connection.pending.extend_from_slice(&connection.chunk[..result as usize]);
+        self.process(slot)
+    }
+
+    /// Serve the next buffered request if it has fully arrived, otherwise read more
+    fn process(&mut self, slot: usize) -> io::Result<()> {
+        let state = Arc::clone(&self.state);
+        let Some(connection) = self.connections[slot].as_mut() else {
+            return Ok(());
+        };
+        let len = match request_len(&connection.pending, state.config.max_body_size()) {
+            Ok(Some(len)) => len,
+            Ok(None) => return self.submit_recv(slot),
+            // Headers never ended; answer with 400 and consume everything
+            Err(()) => connection.pending.len(),
+        };
+
+        connection.served += 1;
+        state.metrics.connections.request_started(connection.id);
+        let may_keep_alive = state.config.keep_alive_timeout_secs > 0 && connection.served < state.config.max_keep_alive_requests;
+        let Some((out, keep_open)) = serve_request(&connection.pending[..len], connection.peer_addr, &state, may_keep_alive)
+        else {
+            self.close(slot);
+            return Ok(());
+        };
+        connection.pending.drain(..len);
+        connection.out = Some(out);
+        connection.sent = 0;
+        connection.keep_open = keep_open;
+        self.submit_send(slot)
+    }
+
+    /// Send what is left of the response, head and body in one `sendmsg`
+    fn submit_send(&mut self, slot: usize) -> io::Result<()> {
+        let Some(connection) = self.connections[slot].as_mut() else {
+            return Ok(());
+        };
+        let Some(out) = &connection.out else {
+            return Ok(());
+        };
+        for (iov, part) in connection.iov.iter_mut().zip(out.remaining(connection.sent)) {
+            iov.iov_base = part.as_ptr() as *mut libc::c_void;
+            iov.iov_len = part.len();
+        }
+        connection.msg.msg_iov = connection.iov.as_mut_ptr();
+        connection.msg.msg_iovlen = connection.iov.len() as _;
+        let send = opcode::SendMsg::new(types::Fd(connection.stream.as_raw_fd()), &connection.msg)
+            .flags(libc::MSG_NOSIGNAL as u32)
+            .build()
+            .user_data((slot as u64) << OP_BITS | OP_SEND);
+        self.push(send)
+    }
+
+    fn on_send(&mut self, slot: usize, result: i32) -> io::Result<()> {
+        let Some(connection) = self.connections[slot].as_mut() else {
+            return Ok(());
+        };
+        if result < 0 {
+            // The request was already recorded when its response was built
+            let e = ServerError::from(io::Error::from_raw_os_error(-result));
+            if e.is_client_abort() {
+                client_aborted(&e, connection.peer_addr, &self.state.metrics);
+            } else {
+                log::debug!("Failed to write response to {:?}: {}", connection.peer_addr, e);
+            }
+            self.close(slot);
+            return Ok(());
+        }
+        connection.sent += result as usize;
+        connection.bytes_sent += result as u64;
+        if connection.sent < connection.out.as_ref().map_or(0, ResponseParts::len) {
+            return self.submit_send(slot);
+        }
+        connection.out = None;
+        let metrics = &self.state.metrics;
+        metrics.connections.request_finished(connection.id, connection.bytes_received, connection.bytes_sent);
+        if !connection.keep_open {
+            self.close(slot);
+            return Ok(());
+        }
+        // Handle any pipelined request before reading again
+        self.process(slot)
+    }
+}
+
+/// Length of the first complete request in `buf`, `None` if more bytes are
+/// needed, or an error if the headers are too large
+///
+/// A body longer than `max_body` isn't waited for: the head alone is served,
+/// and answered with 413 once parsed.
+fn request_len(buf: &[u8], max_body: Option<usize>) -> Result<Option<usize>, ()> {
+    let Some(head_len) = buf.windows(4).position(|w| w == b"\r\n\r\n").map(|i| i + 4) else {
+        return if buf.len() > MAX_HEAD_SIZE { Err(()) } else { Ok(None) };
+    };
+    // An invalid or repeated Content-Length frames no more than the last one
+    // here; HttpRequest::parse then rejects the request
+    let content_length = String::from_utf8_lossy(&buf[..head_len])
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .rfind(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .map_or(0, |(_, value)| value.trim().parse().unwrap_or(0));
+    if max_body.is_some_and(|max| content_length > max) {
+        return Ok(Some(head_len));
+    }
+    let len = head_len + content_length;
+    Ok((buf.len() >= len).then_some(len))
+}
+
+/// Parse, route, and log one complete request; returns the response bytes and
+/// whether the connection should stay open, or `None` to close it unanswered
+fn serve_request(
+    raw: &[u8],
+    peer_addr: Option<SocketAddr>,
+    state: &Arc<ServerState>,
+    may_keep_alive: bool,
+) -> Option<(ResponseParts, bool)> {
+    let start_time = Instant::now();
+    // A body over --max-body-mb is refused before it is read
+    let mut reader = raw;
+    let request = HttpRequest::parse_head(&mut reader).and_then(|mut request| {
+        request.peer_addr = peer_addr;
+        request.check_body_limit(state.config.max_body_size())?;
+        request.read_body(&mut reader)?;
+        Ok(request)
+    });
+
+    let (request_id, span) = match &request {
+        Ok(request) => {
+            let (id, span) = begin_request(request, peer_addr, state);
+            (Some(id), span)
+        }
+        Err(_) => (None, None),
+    };
+    let _scope = request_id.as_deref().map(request_id::Scope::enter);
+
+    // Blocked clients get a 403, or with --deny-action close, nothing at all
+    let blocked = request.as_ref().is_ok_and(|request| blocked_client(request, peer_addr, state).is_some());
+    if blocked && state.config.deny_action == DenyAction::Close {
+        return None;
+    }
+
+    // Reuse the connection only if the client wants to and the server isn't draining
+    let keep_open = may_keep_alive
+        && !blocked
+        && !state.router.readiness.is_draining()
+        && request.as_ref().is_ok_and(|request| request.wants_keep_alive());
+
+    let (request, result) = match request {
+        Ok(request) => {
+            let id = request_id.as_deref().unwrap_or_default();
+            log::debug!("Request {}: {} {}", id, request.method.as_str(), request.path);
+            let request = Arc::new(request);
+            let result = if blocked {
+                Ok(HttpResponse::forbidden())
+            } else {
+                route_in_time(state, &request, Listener::Public)
+            };
+            // The ring sends from a buffer, so a streamed body is read in here
+            let result = result
+                .and_then(|response| Ok(response.buffered()?))
+                .map(|response| finish_response(response, id, span.as_ref(), &state.config, keep_open));
+            (Some(request), result)
+        }
+        Err(e) => (None, Err(e)),
+    };
+
+    // Timing stops once the response is ready; the send completes later
+    let (response, failed, status, bytes, keep_open) = match result {
+        Ok(response) => {
+            let (status, bytes) = (response.status_code(), response.body_len());
+            (response, false, status, bytes, keep_open)
+        }
+        Err(e) => {
+            let response = error_response(&e, request.as_deref(), peer_addr, request_id.as_deref());
+            let bytes = response.body_len();
+            (response, true, e.status_code(), bytes, false)
+        }
+    };
+    let out = response.into_parts().expect("streamed bodies are buffered above");
+
+    record_request(
+        state,
+        RequestOutcome {
+            peer_addr,
+            request: request.as_deref(),
+            request_id: request_id.as_deref(),
+            span,
+            failed,
+            status,
+            bytes,
+            elapsed: start_time.elapsed(),
+            bytes_in: raw.len() as u64,
+            bytes_out: out.len() as u64,
+        },
+    );
+    Some((out, keep_open))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_request_len() {
+        assert_eq!(request_len(b"GET / HTTP/1.1\r\nHost: x\r\n", None), Ok(None));
+        assert_eq!(request_len(b"GET / HTTP/1.1\r\n\r\nGET /next", None), Ok(Some(18)));
+        let post = b"POST /files/a HTTP/1.1\r\ncontent-length: 5\r\n\r\nhel";
+        assert_eq!(request_len(post, None), Ok(None));
+        assert_eq!(request_len(&[post.as_slice(), b"lo"].concat(), None), Ok(Some(post.len() + 2)));
+        assert_eq!(request_len(&vec![b'a'; MAX_HEAD_SIZE + 1], None), Err(()));
+        // Too long a body is answered as soon as the head is in
+        assert_eq!(request_len(post, Some(4)), Ok(Some(post.len() - 3)));
+    }
+
+    #[test]
+    fn test_read_file_through_ring() {
+        let path = std::env::temp_dir().join(format!("http-server-uring-{}", std::process::id()));
+        let content: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        std::fs::write(&path, &content).unwrap();
+
+        // Kernels or sandboxes without io_uring can't run this
+        if let Ok(mut ring) = IoUring::new(8) {
+            assert_eq!(read_file_with(&mut ring, &path).unwrap(), content);
+            assert!(read_file_with(&mut ring, Path::new("/no/such/file")).is_err());
+        }
+        assert_eq!(read_file(&path).unwrap(), content);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,356 @@
+//! Request handlers and middleware loaded from WebAssembly modules
+//!
+//! A plugin is a module exporting its `memory` and either `handle` (a
+//! handler) or `on_request` (middleware), both `() -> i32`. It reads the
+//! request and builds its response through functions imported from the
+//! `http` module; strings and byte buffers are passed as pointer and length
+//! into the plugin's memory:
+//!
+//! | Import | Signature | |
+//! |--------|-----------|-|
+//! | `method`, `path`, `body` | `(buf, cap) -> len` | Copy the request method, path with query, or body into `buf` |
+//! | `header`, `param` | `(name, name_len, buf, cap) -> len` | Copy a request header or route parameter; -1 if absent |
+//! | `set_status` | `(code)` | Set the response status |
+//! | `set_header` | `(name, name_len, value, value_len)` | Add a response header |
+//! | `write_body` | `(buf, len)` | Append to the response body |
+//! | `log` | `(buf, len)` | Log a line at INFO |
+//!
+//! The copying functions return the full length even when it exceeds `cap`,
+//! copying only the first `cap` bytes, so a plugin can retry with a larger
+//! buffer. `handle` returns 0 once it has built the response; anything else
+//! is a 500. `on_request` returns 1 to answer with the response it built, or
+//! 0 to pass the request on, in which case headers it set are added to the
+//! response that comes back.
+//!
+//! Every request runs in a fresh instance with a fuel and memory budget, so
+//! plugins keep no state between requests and a runaway one is stopped.
+
+use crate::config::Config;
+use crate::error::{Result, ServerError};
+use crate::request::HttpRequest;
+use crate::response::HttpResponse;
+use crate::router::{Handler, Middleware, Next, Router};
+use crate::status::StatusCode;
+use std::path::Path;
+use std::sync::Arc;
+use wasmtime::{Caller, Engine, InstancePre, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+/// Instructions a plugin may run per request, roughly
+pub const DEFAULT_FUEL: u64 = 100_000_000;
+/// Linear memory a plugin may grow to per request
+pub const DEFAULT_MAX_MEMORY: usize = 16 * 1024 * 1024;
+
+/// The request as the plugin sees it, and the response it is building
+struct PluginState {
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    params: Vec<(String, String)>,
+    body: Vec<u8>,
+    status: Option<u16>,
+    response_headers: Vec<(String, String)>,
+    response_body: Vec<u8>,
+    limits: StoreLimits,
+}
+
+/// A compiled WebAssembly plugin
+#[derive(Clone)]
+pub struct WasmPlugin {
+    name: Arc<str>,
+    pre: Arc<InstancePre<PluginState>>,
+    engine: Engine,
+    pub fuel: u64,
+    pub max_memory: usize,
+}
+
+impl WasmPlugin {
+    /// Compile the module at `path`, a `.wasm` binary or `.wat` text
+    pub fn load(path: &Path) -> std::result::Result<Self, String> {
+        let bytes = std::fs::read(path).map_err(|e| format!("Failed to read plugin {}: {}", path.display(), e))?;
+        Self::from_bytes(&path.display().to_string(), &bytes)
+    }
+
+    /// Compile a module from its binary or text form; `name` labels logs and errors
+    pub fn from_bytes(name: &str, bytes: &[u8]) -> std::result::Result<Self, String> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(|e| format!("Failed to start the WebAssembly engine: {}", e))?;
+        let module = Module::new(&engine, bytes).map_err(|e| format!("Invalid plugin {}: {:#}", name, e))?;
+        let linker = host_functions(&engine).map_err(|e| format!("Failed to link plugin {}: {}", name, e))?;
+        let pre = linker
+            .instantiate_pre(&module)
+            .map_err(|e| format!("Plugin {} imports something the host doesn't provide: {:#}", name, e))?;
+        Ok(WasmPlugin {
+            name: name.into(),
+            pre: Arc::new(pre),
+            engine,
+            fuel: DEFAULT_FUEL,
+            max_memory: DEFAULT_MAX_MEMORY,
+        })
+    }
+
+    /// Whether the module exports `name`, e.g. `handle` or `on_request`
+    pub fn exports(&self, name: &str) -> bool {
+        self.pre.module().get_export(name).is_some()
+    }
+
+    /// Run the exported function `entry` on `request`
+    fn run(&self, entry: &str, request: &HttpRequest) -> Result<(i32, PluginState)> {
+        let state = PluginState {
+            method: request.method.as_str().to_string(),
+            path: request.path.clone(),
+            headers: request.headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect(),
+            params: request.route.get().map_or_else(Vec::new, |route| route.params.clone()),
+            body: request.body.clone(),
+            status: None,
+            response_headers: Vec::new(),
+            response_body: Vec::new(),
+            limits: StoreLimitsBuilder::new().memory_size(self.max_memory).instances(1).build(),
+        };
+        let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| &mut state.limits);
+        let failed = |e: wasmtime::Error| ServerError::InternalError(format!("Plugin {} failed: {:#}", self.name, e));
+        store.set_fuel(self.fuel).map_err(failed)?;
+        let instance = self.pre.instantiate(&mut store).map_err(failed)?;
+        let function = instance.get_typed_func::<(), i32>(&mut store, entry).map_err(failed)?;
+        let code = function.call(&mut store, ()).map_err(failed)?;
+        Ok((code, store.into_data()))
+    }
+}
+
+impl Handler for WasmPlugin {
+    fn call(&self, request: &HttpRequest) -> Result<HttpResponse> {
+        let (code, state) = self.run("handle", request)?;
+        if code != 0 {
+            return Err(ServerError::InternalError(format!("Plugin {} returned {}", self.name, code)));
+        }
+        state.into_response()
+    }
+}
+
+impl Middleware for WasmPlugin {
+    fn call(&self, request: &HttpRequest, next: Next<'_>) -> Result<HttpResponse> {
+        let (code, state) = self.run("on_request", request)?;
+        match code {
+            0 => Ok(state
+                .response_headers
+                .into_iter()
+                .fold(next.run(request)?, |response, (name, value)| response.append_header(name, value))),
+            1 => state.into_response(),
+            code => Err(ServerError::InternalError(format!("Plugin {} returned {}", self.name, code))),
+        }
+    }
+}
+
+impl PluginState {
+    fn into_response(self) -> Result<HttpResponse> {
+        let code = self.status.unwrap_or(200);
+        let status = StatusCode::from_u16(code)
+            .ok_or_else(|| ServerError::InternalError(format!("Plugin set an invalid status {}", code)))?;
+        let response = self
+            .response_headers
+            .into_iter()
+            .fold(HttpResponse::new(status), |response, (name, value)| response.append_header(name, value));
+        Ok(response.body(self.response_body))
+    }
+}
+
+/// The `http` imports described in the module docs
+fn host_functions(engine: &Engine) -> wasmtime::Result<Linker<PluginState>> {
+    let mut linker = Linker::new(engine);
+    linker.func_wrap("http", "method", |mut caller: Caller<'_, PluginState>, buf: i32, cap: i32| {
+        let method = caller.data().method.clone();
+        copy_out(&mut caller, method.as_bytes(), buf, cap)
+    })?;
+    linker.func_wrap("http", "path", |mut caller: Caller<'_, PluginState>, buf: i32, cap: i32| {
+        let path = caller.data().path.clone();
+        copy_out(&mut caller, path.as_bytes(), buf, cap)
+    })?;
+    linker.func_wrap("http", "body", |mut caller: Caller<'_, PluginState>, buf: i32, cap: i32| {
+        let body = std::mem::take(&mut caller.data_mut().body);
+        let len = copy_out(&mut caller, &body, buf, cap);
+        caller.data_mut().body = body;
+        len
+    })?;
+    linker.func_wrap(
+        "http",
+        "header",
+        |mut caller: Caller<'_, PluginState>, name: i32, name_len: i32, buf: i32, cap: i32| {
+            let name = read_string(&mut caller, name, name_len)?;
+            let values: Vec<&str> = caller
+                .data()
+                .headers
+                .iter()
+                .filter(|(header, _)| header.eq_ignore_ascii_case(&name))
+                .map(|(_, value)| value.as_str())
+                .collect();
+            if values.is_empty() {
+                return Ok(-1);
+            }
+            let value = values.join(", ");
+            copy_out(&mut caller, value.as_bytes(), buf, cap)
+        },
+    )?;
+    linker.func_wrap(
+        "http",
+        "param",
+        |mut caller: Caller<'_, PluginState>, name: i32, name_len: i32, buf: i32, cap: i32| {
+            let name = read_string(&mut caller, name, name_len)?;
+            let value = caller.data().params.iter().find(|(param, _)| *param == name).map(|(_, value)| value.clone());
+            match value {
+                Some(value) => copy_out(&mut caller, value.as_bytes(), buf, cap),
+                None => Ok(-1),
+            }
+        },
+    )?;
+    linker.func_wrap("http", "set_status", |mut caller: Caller<'_, PluginState>, code: i32| {
+        caller.data_mut().status = Some(u16::try_from(code).unwrap_or(0));
+    })?;
+    linker.func_wrap(
+        "http",
+        "set_header",
+        |mut caller: Caller<'_, PluginState>, name: i32, name_len: i32, value: i32, value_len: i32| {
+            let name = read_string(&mut caller, name, name_len)?;
+            let value = read_string(&mut caller, value, value_len)?;
+            if name.is_empty() || name.bytes().any(|b| !b.is_ascii_graphic() || b == b':') || value.contains(['\r', '\n']) {
+                anyhow::bail!("invalid response header {:?}", name);
+            }
+            caller.data_mut().response_headers.push((name, value));
+            Ok(())
+        },
+    )?;
+    linker.func_wrap("http", "write_body", |mut caller: Caller<'_, PluginState>, buf: i32, len: i32| {
+        let bytes = read_bytes(&mut caller, buf, len)?;
+        caller.data_mut().response_body.extend_from_slice(&bytes);
+        Ok(())
+    })?;
+    linker.func_wrap("http", "log", |mut caller: Caller<'_, PluginState>, buf: i32, len: i32| {
+        let line = read_string(&mut caller, buf, len)?;
+        log::info!("Plugin: {}", line);
+        Ok(())
+    })?;
+    Ok(linker)
+}
+
+fn memory(caller: &mut Caller<'_, PluginState>) -> wasmtime::Result<Memory> {
+    match caller.get_export("memory") {
+        Some(export) => export.into_memory().ok_or_else(|| wasmtime::Error::msg("export `memory` is not a memory")),
+        None => anyhow::bail!("plugin does not export `memory`"),
+    }
+}
+
+/// Bounds-checked range of guest memory
+fn range(memory_len: usize, ptr: i32, len: i32) -> wasmtime::Result<std::ops::Range<usize>> {
+    let (start, len) = (usize::try_from(ptr)?, usize::try_from(len)?);
+    match start.checked_add(len) {
+        Some(end) if end <= memory_len => Ok(start..end),
+        _ => anyhow::bail!("buffer at {} of {} bytes is out of bounds", ptr, len),
+    }
+}
+
+fn read_bytes(caller: &mut Caller<'_, PluginState>, ptr: i32, len: i32) -> wasmtime::Result<Vec<u8>> {
+    let memory = memory(caller)?;
+    let data = memory.data(&caller);
+    Ok(data[range(data.len(), ptr, len)?].to_vec())
+}
+
+fn read_string(caller: &mut Caller<'_, PluginState>, ptr: i32, len: i32) -> wasmtime::Result<String> {
+    Ok(String::from_utf8(read_bytes(caller, ptr, len)?)?)
+}
+
+/// Copy as much of `bytes` as fits in the guest buffer; returns the full length
+fn copy_out(caller: &mut Caller<'_, PluginState>, bytes: &[u8], buf: i32, cap: i32) -> wasmtime::Result<i32> {
+    let memory = memory(caller)?;
+    let data = memory.data_mut(caller);
+    let target = range(data.len(), buf, cap)?;
+    let n = bytes.len().min(target.len());
+    data[target.start..target.start + n].copy_from_slice(&bytes[..n]);
+    Ok(i32::try_from(bytes.len())?)
+}
+
+/// Add the plugins named by --wasm-handler and --wasm-middleware to `router`
+pub fn load_plugins(mut router: Router, config: &Config) -> std::result::Result<Router, String> {
+    for spec in &config.wasm_handler {
+        let (pattern, path) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("--wasm-handler takes ROUTE=FILE, got '{}'", spec))?;
+        let plugin = WasmPlugin::load(Path::new(path))?;
+        if !plugin.exports("handle") {
+            return Err(format!("Plugin {} does not export `handle`", path));
+        }
+        router = router.any(pattern, plugin);
+    }
+    for path in &config.wasm_middleware {
+        let plugin = WasmPlugin::load(Path::new(path))?;
+        if !plugin.exports("on_request") {
+            return Err(format!("Plugin {} does not export `on_request`", path));
+        }
+        router = router.layer(plugin);
+    }
+    Ok(router)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TestRequest;
+
+    const GREETER: &str = r#"(module
+        (import "http" "param" (func $param (param i32 i32 i32 i32) (result i32)))
+        (import "http" "set_header" (func $set_header (param i32 i32 i32 i32)))
+        (import "http" "write_body" (func $write_body (param i32 i32)))
+        (memory (export "memory") 1)
+        (data (i32.const 0) "name")
+        (data (i32.const 16) "Content-Type")
+        (data (i32.const 32) "text/plain")
+        (data (i32.const 64) "hello, ")
+        (func (export "handle") (result i32)
+            (local $len i32)
+            (local.set $len (call $param (i32.const 0) (i32.const 4) (i32.const 71) (i32.const 100)))
+            (call $set_header (i32.const 16) (i32.const 12) (i32.const 32) (i32.const 10))
+            (call $write_body (i32.const 64) (i32.add (i32.const 7) (local.get $len)))
+            (i32.const 0)))"#;
+
+    const GATE: &str = r#"(module
+        (import "http" "header" (func $header (param i32 i32 i32 i32) (result i32)))
+        (import "http" "set_status" (func $set_status (param i32)))
+        (import "http" "set_header" (func $set_header (param i32 i32 i32 i32)))
+        (memory (export "memory") 1)
+        (data (i32.const 0) "x-block")
+        (data (i32.const 16) "X-Plugin")
+        (data (i32.const 32) "gate")
+        (func (export "on_request") (result i32)
+            (if (i32.ge_s (call $header (i32.const 0) (i32.const 7) (i32.const 64) (i32.const 0)) (i32.const 0))
+                (then (call $set_status (i32.const 403)) (return (i32.const 1))))
+            (call $set_header (i32.const 16) (i32.const 8) (i32.const 32) (i32.const 4))
+            (i32.const 0)))"#;
+
+    #[test]
+    fn test_plugins_handle_and_filter_requests() {
+        let greeter = WasmPlugin::from_bytes("greeter", GREETER.as_bytes()).unwrap();
+        let gate = WasmPlugin::from_bytes("gate", GATE.as_bytes()).unwrap();
+        assert!(greeter.exports("handle") && !greeter.exports("on_request"));
+        let router = Router::empty().layer(gate).any("/hello/{name}", greeter);
+
+        let response = TestRequest::get("/hello/wasm").send(&router);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.text(), "hello, wasm");
+        assert_eq!(response.header("content-type"), Some("text/plain"));
+        assert_eq!(response.header("x-plugin"), Some("gate"));
+        assert_eq!(TestRequest::post("/hello/wasm").header("X-Block", "1").send(&router).status, 403);
+    }
+
+    #[test]
+    fn test_runaway_and_invalid_plugins_are_contained() {
+        let spin = r#"(module (memory (export "memory") 1)
+            (func (export "handle") (result i32) (loop $forever (br $forever)) (i32.const 0)))"#;
+        let mut plugin = WasmPlugin::from_bytes("spin", spin.as_bytes()).unwrap();
+        plugin.fuel = 10_000;
+        let router = Router::empty().get("/spin", plugin);
+        assert_eq!(TestRequest::get("/spin").send(&router).status, 500);
+
+        let unknown_import = r#"(module (import "env" "system" (func (param i32))))"#;
+        assert!(WasmPlugin::from_bytes("bad", unknown_import.as_bytes()).is_err());
+        assert!(WasmPlugin::from_bytes("bad", b"not wasm").is_err());
+    }
+}
@@ -0,0 +1,150 @@
+//! File change notifications
+//!
+//! With `--watch-files`, the server asks the operating system (inotify,
+//! FSEvents, ...) to report changes under the served directory instead of
+//! checking each file's mtime on every request: the compressed file cache is
+//! invalidated when a file changes, and the configuration is reloaded when the
+//! `--config` file or a page template is edited.
+
+use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+/// What a watcher reports
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    /// The file or directory at this path was created, modified, or removed
+    Path(PathBuf),
+    /// Events were lost, so anything may have changed
+    Unknown,
+}
+
+/// Watches directories and passes every change to a callback
+pub struct FileWatcher {
+    watcher: Mutex<RecommendedWatcher>,
+}
+
+impl FileWatcher {
+    /// Start a watcher; `on_change` runs on the watcher's own thread
+    pub fn new(on_change: impl Fn(Change) + Send + 'static) -> notify::Result<Self> {
+        let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| match event {
+            Ok(event) if event.need_rescan() => on_change(Change::Unknown),
+            Ok(event) if matches!(event.kind, EventKind::Access(_)) => {}
+            Ok(event) => event.paths.into_iter().for_each(|path| on_change(Change::Path(path))),
+            Err(e) => {
+                log::warn!("File watcher error: {}", e);
+                on_change(Change::Unknown)
+            }
+        })?;
+        Ok(FileWatcher {
+            watcher: Mutex::new(watcher),
+        })
+    }
+
+    /// Report changes to the entries of `directory` (not its subdirectories)
+    pub fn watch(&self, directory: &Path) -> notify::Result<()> {
+        self.watcher.lock().unwrap().watch(directory, RecursiveMode::NonRecursive)
+    }
+
+    /// Stop reporting changes in `directory`
+    pub fn unwatch(&self, directory: &Path) -> notify::Result<()> {
+        self.watcher.lock().unwrap().unwatch(directory)
+    }
+}
+
+/// Call `on_change` once things have been quiet for `delay` after changes to
+/// any of `files`; editors often write a file in several steps, and this
+/// coalesces them into one call
+///
+/// The directories containing the files are watched rather than the files
+/// themselves, so files replaced by a rename are still followed.
+pub fn on_file_change(
+    files: &[PathBuf],
+    delay: Duration,
+    on_change: impl Fn() + Send + 'static,
+) -> notify::Result<FileWatcher> {
+    let (sender, receiver) = mpsc::channel();
+    let names: Vec<PathBuf> = files.iter().map(|file| absolute(file)).collect();
+    let watched = names.clone();
+    let watcher = FileWatcher::new(move |change| {
+        let relevant = match &change {
+            Change::Path(path) => watched.iter().any(|file| file == path),
+            Change::Unknown => true,
+        };
+        if relevant {
+            let _ = sender.send(());
+        }
+    })?;
+
+    let mut directories: Vec<&Path> = names.iter().filter_map(|file| file.parent()).collect();
+    directories.sort();
+    directories.dedup();
+    for directory in directories {
+        watcher.watch(directory)?;
+    }
+
+    thread::spawn(move || {
+        while receiver.recv().is_ok() {
+            while receiver.recv_timeout(delay).is_ok() {}
+            on_change();
+        }
+    });
+    Ok(watcher)
+}
+
+/// `path` relative to the working directory, as watchers report paths
+fn absolute(path: &Path) -> PathBuf {
+    match std::env::current_dir() {
+        Ok(cwd) if path.is_relative() => cwd.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    fn wait_for(condition: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while Instant::now() < deadline {
+            if condition() {
+                return true;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        false
+    }
+
+    #[test]
+    fn test_changes_to_watched_files_are_coalesced() {
+        let dir = std::env::temp_dir().join(format!("http-server-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = dir.join("server.toml");
+        std::fs::write(&config, "port = 1").unwrap();
+
+        let calls = Arc::new(Mutex::new(0));
+        let counter = Arc::clone(&calls);
+        let _watcher = on_file_change(std::slice::from_ref(&config), Duration::from_millis(100), move || {
+            *counter.lock().unwrap() += 1;
+        })
+        .unwrap();
+
+        std::fs::write(dir.join("other.txt"), "ignored").unwrap();
+        std::fs::write(&config, "port = 2").unwrap();
+        std::fs::write(&config, "port = 3").unwrap();
+        assert!(wait_for(|| *calls.lock().unwrap() == 1));
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(*calls.lock().unwrap(), 1);
+
+        // Replacing the file by a rename, as editors do, is noticed too
+        std::fs::write(dir.join("server.toml.tmp"), "port = 4").unwrap();
+        std::fs::rename(dir.join("server.toml.tmp"), &config).unwrap();
+        assert!(wait_for(|| *calls.lock().unwrap() == 2));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+//! Worker pool that sizes itself to the load
+//!
+//! Each connection holds a worker for as long as it stays open, so a fixed
+//! pool either wastes threads when quiet or queues connections when busy.
+//! [`AdaptivePool`] starts at `--workers` threads and a supervisor grows it,
+//! up to `--max-workers`, as soon as connections wait in the queue; spare
+//! threads are retired again after the pool has been idle for a while.
+
+use crate::metrics::ServerMetrics;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use threadpool::ThreadPool;
+
+/// How often the supervisor checks the queue
+const TICK: Duration = Duration::from_millis(100);
+
+/// How long the pool must have had spare workers before shrinking
+pub const SHRINK_AFTER: Duration = Duration::from_secs(30);
+
+/// Thread pool for the blocking runtime, resized between `min` and `max`
+#[derive(Clone)]
+pub struct AdaptivePool {
+    pool: ThreadPool,
+    min: usize,
+    max: usize,
+    metrics: Arc<ServerMetrics>,
+}
+
+impl AdaptivePool {
+    /// A pool of `min` workers that may grow to `max`
+    pub fn new(min: usize, max: usize, metrics: Arc<ServerMetrics>) -> Self {
+        let max = max.max(min);
+        metrics.worker_pool_size.store(min as u64, Ordering::Relaxed);
+        AdaptivePool {
+            pool: ThreadPool::with_name("worker".to_string(), min),
+            min,
+            max,
+            metrics,
+        }
+    }
+
+    pub fn execute(&self, job: impl FnOnce() + Send + 'static) {
+        self.pool.execute(job);
+    }
+
+    /// Current number of workers
+    pub fn size(&self) -> usize {
+        self.pool.max_count()
+    }
+
+    /// Resize the pool for the current load; returns the new size. Clones
+    /// share the workers, so resizing any of them resizes all
+    pub fn adjust(&mut self, idle_for: Duration) -> usize {
+        let size = self.size();
+        let queued = self.metrics.jobs_queued.load(Ordering::Relaxed) as usize;
+        let busy = self.metrics.workers_busy.load(Ordering::Relaxed) as usize;
+        let target = target_size(size, queued, busy, self.min, self.max, idle_for);
+        if target != size {
+            log::debug!("Resizing worker pool from {} to {} ({} busy, {} queued)", size, target, busy, queued);
+            // Surplus workers exit as they finish their next connection
+            self.pool.set_num_threads(target);
+            self.metrics.worker_pool_size.store(target as u64, Ordering::Relaxed);
+        }
+        target
+    }
+
+    /// Resize the pool every [`TICK`] on a background thread until `shutdown`
+    /// is set
+    pub fn supervise(&self, shutdown: Arc<AtomicBool>) -> std::io::Result<()> {
+        if self.min == self.max {
+            return Ok(());
+        }
+        let mut pool = self.clone();
+        std::thread::Builder::new().name("worker-pool".to_string()).spawn(move || {
+            let mut busy_at = Instant::now();
+            while !shutdown.load(Ordering::Relaxed) {
+                std::thread::sleep(TICK);
+                let size = pool.size();
+                let busy = pool.metrics.workers_busy.load(Ordering::Relaxed) as usize;
+                if busy >= size || pool.metrics.jobs_queued.load(Ordering::Relaxed) > 0 {
+                    busy_at = Instant::now();
+                }
+                if pool.adjust(busy_at.elapsed()) < size {
+                    busy_at = Instant::now();
+                }
+            }
+        })?;
+        Ok(())
+    }
+}
+
+/// The pool size to move to from `size`
+///
+/// Queued connections each get a new worker at once, within `max`. After
+/// [`SHRINK_AFTER`] with spare workers, half of the spare ones are retired,
+/// down to `min`.
+pub fn target_size(size: usize, queued: usize, busy: usize, min: usize, max: usize, idle_for: Duration) -> usize {
+    if queued > 0 {
+        return (size + queued).min(max).max(size);
+    }
+    if idle_for >= SHRINK_AFTER && size > min {
+        let spare = size.saturating_sub(busy);
+        return (size - spare / 2).max(min);
+    }
+    size
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_target_size() {
+        // Grows to take every queued connection, up to the maximum
+        assert_eq!(target_size(4, 3, 4, 4, 16, Duration::ZERO), 7);
+        assert_eq!(target_size(14, 10, 14, 4, 16, Duration::ZERO), 16);
+        assert_eq!(target_size(16, 10, 16, 4, 16, Duration::ZERO), 16);
+        // Shrinks only after a quiet period, by half the spare workers
+        assert_eq!(target_size(16, 0, 2, 4, 16, Duration::from_secs(1)), 16);
+        assert_eq!(target_size(16, 0, 2, 4, 16, SHRINK_AFTER), 9);
+        assert_eq!(target_size(6, 0, 0, 4, 16, SHRINK_AFTER), 4);
+        assert_eq!(target_size(4, 0, 0, 4, 16, SHRINK_AFTER), 4);
+    }
+
+    #[test]
+    fn test_pool_grows_when_connections_queue() {
+        let metrics = Arc::new(ServerMetrics::new());
+        let mut pool = AdaptivePool::new(1, 8, Arc::clone(&metrics));
+        assert_eq!(metrics.worker_pool_size.load(Ordering::Relaxed), 1);
+        metrics.job_queued();
+        metrics.job_queued();
+        assert_eq!(pool.adjust(Duration::ZERO), 3);
+        assert_eq!(pool.size(), 3);
+        assert_eq!(metrics.worker_pool_size.load(Ordering::Relaxed), 3);
+    }
+}